@@ -0,0 +1,307 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::copy;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::mpsc;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::command::exec_command;
+use crate::dcm_xml::{
+    parse_dcm_as_xml, xml_get_frame_of_reference_uid, xml_get_patient_birth_date,
+    xml_get_patient_name, xml_get_patient_patient_id, xml_get_series_instance_uid,
+    xml_get_sop_instance_uid, xml_get_study_instance_uid,
+};
+use crate::error::G2DError;
+use crate::filename_template::gdt_date_to_iso;
+use crate::gdt::{file_to_string, parse_file, GdtBasicDiagnosticsObject};
+
+/// Attributes removed by the Basic Application Level Confidentiality Profile
+/// (action X) which can occur in files written or received by gdt2dicom
+const REMOVED_TAGS: [&str; 37] = [
+    "0008,0080", // InstitutionName
+    "0008,0081", // InstitutionAddress
+    "0008,0092", // ReferringPhysicianAddress
+    "0008,0094", // ReferringPhysicianTelephoneNumbers
+    "0008,1010", // StationName
+    "0008,1030", // StudyDescription
+    "0008,103e", // SeriesDescription
+    "0008,1040", // InstitutionalDepartmentName
+    "0008,1048", // PhysiciansOfRecord
+    "0008,1050", // PerformingPhysicianName
+    "0008,1060", // NameOfPhysiciansReadingStudy
+    "0008,1070", // OperatorsName
+    "0008,1110", // ReferencedStudySequence
+    "0008,1120", // ReferencedPatientSequence
+    "0008,1140", // ReferencedImageSequence
+    "0008,2112", // SourceImageSequence
+    "0010,1000", // OtherPatientIDs
+    "0010,1001", // OtherPatientNames
+    "0010,1010", // PatientAge
+    "0010,1020", // PatientSize
+    "0010,1030", // PatientWeight
+    "0010,1040", // PatientAddress
+    "0010,2154", // PatientTelephoneNumbers
+    "0010,2160", // EthnicGroup
+    "0010,21b0", // AdditionalPatientHistory
+    "0010,4000", // PatientComments
+    "0018,1000", // DeviceSerialNumber
+    "0032,1032", // RequestingPhysician
+    "0032,1060", // RequestedProcedureDescription
+    "0040,0275", // RequestAttributesSequence
+    "0040,1001", // RequestedProcedureID
+    "0040,2016", // PlacerOrderNumberImagingServiceRequest
+    "0040,2017", // FillerOrderNumberImagingServiceRequest
+    "0040,a730", // ContentSequence
+    "0040,a078", // AuthorObserverSequence
+    "0040,a073", // VerifyingObserverSequence
+    "0042,0010", // DocumentTitle
+];
+
+/// Attributes replaced by an empty value (action Z)
+const EMPTIED_TAGS: [&str; 5] = [
+    "0008,0050", // AccessionNumber
+    "0008,0090", // ReferringPhysicianName
+    "0010,0030", // PatientBirthDate
+    "0010,0040", // PatientSex
+    "0020,0010", // StudyID
+];
+
+/// Dates and times, kept with `keep_dates`
+/// (Retain Longitudinal Temporal Information Option)
+const EMPTIED_DATES: [&str; 2] = [
+    "0008,0020", // StudyDate
+    "0008,0030", // StudyTime
+];
+const REMOVED_DATES: [&str; 7] = [
+    "0008,0021", // SeriesDate
+    "0008,0022", // AcquisitionDate
+    "0008,0023", // ContentDate
+    "0008,002a", // AcquisitionDateTime
+    "0008,0031", // SeriesTime
+    "0008,0032", // AcquisitionTime
+    "0008,0033", // ContentTime
+];
+
+#[derive(Debug, Clone)]
+pub struct AnonymizeOptions {
+    /// Prefix of generated pseudonyms, e.g. ANON000001
+    pub prefix: String,
+    pub keep_dates: bool,
+}
+
+impl Default for AnonymizeOptions {
+    fn default() -> Self {
+        AnonymizeOptions {
+            prefix: "ANON".to_string(),
+            keep_dates: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pseudonym {
+    pub patient_id: String,
+    pub patient_name: String,
+}
+
+/// Mapping of original patient IDs to pseudonyms, stored as JSON.
+/// The key is used to derive replacement UIDs, so the same original UID
+/// always gets the same replacement. Keep the file secret, it allows to
+/// re-identify the patients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PseudonymMap {
+    pub key: String,
+    pub patients: BTreeMap<String, Pseudonym>,
+}
+
+impl PseudonymMap {
+    /// Loads the map, a new map with a random key is created if the file doesn't exist
+    pub fn load(path: &Path) -> Result<PseudonymMap, G2DError> {
+        if !path.exists() {
+            return Ok(PseudonymMap {
+                key: Uuid::new_v4().to_string(),
+                patients: BTreeMap::new(),
+            });
+        }
+        let data = std::fs::read(path)?;
+        let map: PseudonymMap = serde_json::from_slice(&data)?;
+        if Uuid::parse_str(&map.key).is_err() {
+            let message = format!("{}: key is not a UUID", path.display());
+            return Err(G2DError::IoError(Error::new(ErrorKind::Other, message)));
+        }
+        return Ok(map);
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), G2DError> {
+        let data = serde_json::to_vec_pretty(self)?;
+        std::fs::write(path, data)?;
+        return Ok(());
+    }
+
+    /// The pseudonym of a patient, a new one is assigned on first use
+    pub fn pseudonym(&mut self, patient_key: &str, prefix: &str) -> Pseudonym {
+        if let Some(p) = self.patients.get(patient_key) {
+            return p.clone();
+        }
+        let id = format!("{}{:06}", prefix, self.patients.len() + 1);
+        let pseudonym = Pseudonym {
+            patient_id: id.clone(),
+            patient_name: id,
+        };
+        self.patients
+            .insert(patient_key.to_string(), pseudonym.clone());
+        return pseudonym;
+    }
+
+    /// Replacement for a UID, stable for the key of the map
+    pub fn uid(&self, uid: &str) -> String {
+        let namespace = Uuid::parse_str(&self.key).unwrap_or(Uuid::NAMESPACE_OID);
+        let uuid = Uuid::new_v5(&namespace, uid.trim().as_bytes());
+        return format!("2.25.{}", uuid.as_u128());
+    }
+}
+
+/// Identifies the patient of a file: the patient ID, or family and given
+/// name with the birth date (YYYYMMDD) if there is no ID, so a GDT file and
+/// the DICOM files of the patient get the same pseudonym
+fn patient_key(patient_id: &str, family_name: &str, given_name: &str, birth_date: &str) -> String {
+    if !patient_id.trim().is_empty() {
+        return patient_id.trim().to_string();
+    }
+    return format!(
+        "{}^{}/{}",
+        family_name.trim(),
+        given_name.trim(),
+        birth_date.trim()
+    );
+}
+
+/// De-identifies a DICOM file with `dcmodify` following the Basic
+/// Application Level Confidentiality Profile. Patient name and ID are
+/// replaced by the pseudonym, UIDs by ones derived from the map key.
+/// Burned-in annotations and the content of encapsulated documents are not changed.
+pub fn anonymize_dcm_file(
+    input: &Path,
+    output: &Path,
+    map: &mut PseudonymMap,
+    options: &AnonymizeOptions,
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<(), G2DError> {
+    let events = parse_dcm_as_xml(&input.to_path_buf())?;
+    // Only the alphabetic group of the PN, like the name of a GDT file
+    let patient_name = xml_get_patient_name(&events).unwrap_or_default();
+    let mut name_components = patient_name.split('=').next().unwrap_or("").split('^');
+    let key = patient_key(
+        &xml_get_patient_patient_id(&events).unwrap_or_default(),
+        name_components.next().unwrap_or(""),
+        name_components.next().unwrap_or(""),
+        &xml_get_patient_birth_date(&events).unwrap_or_default(),
+    );
+    let pseudonym = map.pseudonym(&key, &options.prefix);
+    if input != output {
+        copy(input, output)?;
+    }
+
+    let mut args: Vec<OsString> = vec!["-nb".into(), "-ie".into(), "-imt".into(), "-ep".into()];
+    let mut removed: Vec<&str> = REMOVED_TAGS.to_vec();
+    let mut emptied: Vec<&str> = EMPTIED_TAGS.to_vec();
+    if !options.keep_dates {
+        removed.extend(REMOVED_DATES);
+        emptied.extend(EMPTIED_DATES);
+    }
+    for tag in removed {
+        args.push("-ea".into());
+        args.push(format!("({})", tag).into());
+    }
+    for tag in emptied {
+        args.push("-i".into());
+        args.push(format!("({})=", tag).into());
+    }
+    let replaced = [
+        ("0010,0010", Some(pseudonym.patient_name)),
+        ("0010,0020", Some(pseudonym.patient_id)),
+        (
+            "0020,000d",
+            xml_get_study_instance_uid(&events).map(|u| map.uid(&u)),
+        ),
+        (
+            "0020,000e",
+            xml_get_series_instance_uid(&events).map(|u| map.uid(&u)),
+        ),
+        (
+            "0008,0018",
+            xml_get_sop_instance_uid(&events).map(|u| map.uid(&u)),
+        ),
+        (
+            "0020,0052",
+            xml_get_frame_of_reference_uid(&events).map(|u| map.uid(&u)),
+        ),
+        ("0012,0062", Some("YES".to_string())),
+        (
+            "0012,0063",
+            Some(if options.keep_dates {
+                "Basic Application Confidentiality Profile\\Retain Longitudinal Temporal Information Full Dates Option".to_string()
+            } else {
+                "Basic Application Confidentiality Profile".to_string()
+            }),
+        ),
+    ];
+    for (tag, value) in replaced {
+        if let Some(value) = value {
+            args.push("-i".into());
+            args.push(format!("({})={}", tag, value).into());
+        }
+    }
+    args.push(output.as_os_str().to_os_string());
+
+    let result = exec_command("dcmodify", &args, true, log_sender)?;
+    if !result.status.success() {
+        let err_str = String::from_utf8_lossy(&result.stderr).to_string();
+        if let Some(l) = log_sender {
+            _ = l.send(err_str.clone());
+        }
+        return Err(G2DError::IoError(Error::new(ErrorKind::Other, err_str)));
+    }
+    return Ok(());
+}
+
+/// De-identifies a GDT file. Only the fields known to gdt2dicom are written,
+/// name, address and contact fields are dropped, the patient number is
+/// replaced by the pseudonym and attachments are removed.
+pub fn anonymize_gdt_file(
+    input: &Path,
+    output: &Path,
+    map: &mut PseudonymMap,
+    options: &AnonymizeOptions,
+) -> Result<(), G2DError> {
+    let mut file = parse_file(input)?;
+    let patient = &file.object_patient;
+    let key = patient_key(
+        &patient.patient_number,
+        &patient.patient_name,
+        &patient.patient_first_name,
+        &gdt_date_to_iso(patient.patient_dob.trim()),
+    );
+    let pseudonym = map.pseudonym(&key, &options.prefix);
+    let gender = std::mem::take(&mut file.object_patient.patient_gender);
+    file.object_patient = Default::default();
+    file.object_patient.patient_number = pseudonym.patient_id;
+    file.object_patient.patient_name = pseudonym.patient_name;
+    file.object_patient.patient_gender = gender;
+    file.object_basic_diagnostics = GdtBasicDiagnosticsObject::default();
+    file.object_physician_identification = Default::default();
+    file.object_annex.attachments.clear();
+    if !file.object_request.request_uid.is_empty() {
+        file.object_request.request_uid = map.uid(&file.object_request.request_uid);
+    }
+    file.object_request.request_identifier = String::new();
+    if !options.keep_dates {
+        file.object_request.date_of_examination = String::new();
+        file.object_request.time_of_examination = String::new();
+    }
+    std::fs::write(output, file_to_string(file))?;
+    return Ok(());
+}
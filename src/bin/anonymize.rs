@@ -0,0 +1,4 @@
+/// Same as `gdt2dicom anonymize`
+fn main() {
+    gdt2dicom::cli::alias_main("anonymize");
+}
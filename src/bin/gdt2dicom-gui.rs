@@ -6,6 +6,7 @@ use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
 use gdt2dicom::command::check_if_binary_exists;
+use gdt2dicom::config::read_config;
 use gtk::gio::{ActionEntry, Menu};
 use gtk::glib::clone;
 use gtk::prelude::*;
@@ -17,12 +18,17 @@ use gdt2dicom::gui::copyright_dialog::open_copyright_dialog;
 use gdt2dicom::gui::cstore_server::setup_cstore_server;
 use gdt2dicom::gui::dicom_server::setup_dicom_server;
 use gdt2dicom::gui::runtime;
-use gdt2dicom::gui::state::{
-    read_saved_states, write_state_to_file, CStoreServerState, DicomServerState, StateFile,
-};
 use gdt2dicom::gui::worklist_folder::setup_worklist_folder_ui;
+use gdt2dicom::gui::worklist_lifecycle::setup_worklist_lifecycle_ui;
+use gdt2dicom::state::{
+    conversions_request_tags, read_saved_states, write_state_to_file, CStoreServerState,
+    DicomServerState, StateFile,
+};
 
 fn main() -> glib::ExitCode {
+    if let Err(err) = read_config(None).and_then(|c| c.apply()) {
+        println!("Error in the config file: {}", err);
+    }
     let application = Application::builder()
         .application_id("ch.ywesee.gdt2dicom")
         .build();
@@ -93,6 +99,11 @@ fn main() -> glib::ExitCode {
             .unwrap_or(CStoreServerState::default());
 
         let state_arc = Arc::new(Mutex::new(saved_state.clone()));
+        // Hooks are only set in the state file
+        let hooks_arc = Arc::new(Mutex::new(saved_state.hooks.clone()));
+        let request_tags_arc = Arc::new(Mutex::new(conversions_request_tags(
+            &saved_state.conversions,
+        )));
 
         let state_arc1 = state_arc.clone();
         let on_worklist_path_updated = move |new_path| {
@@ -112,6 +123,12 @@ fn main() -> glib::ExitCode {
             y,
             on_worklist_path_updated,
         );
+        let (y, worklist_lifecycle_arc, worklist_lifecycle_receiver) = setup_worklist_lifecycle_ui(
+            &saved_state.worklist_lifecycle,
+            &grid_layout.clone(),
+            y,
+            worklist_dir_arc.clone(),
+        );
         let (y, dicom_server_state_receiver) = setup_dicom_server(
             dicom_server_state,
             &window,
@@ -124,6 +141,10 @@ fn main() -> glib::ExitCode {
             &window.clone(),
             &grid_layout.clone(),
             y,
+            worklist_dir_arc.clone(),
+            worklist_lifecycle_arc,
+            hooks_arc.clone(),
+            request_tags_arc.clone(),
         );
         let (_y, convert_list_state_receiver) = setup_auto_convert_list_ui(
             &saved_state.conversions,
@@ -131,6 +152,7 @@ fn main() -> glib::ExitCode {
             &grid_layout.clone(),
             y,
             worklist_dir_arc.clone(),
+            hooks_arc,
         );
 
         let state_arc1 = state_arc.clone();
@@ -149,6 +171,7 @@ fn main() -> glib::ExitCode {
         let state_arc1 = state_arc.clone();
         runtime().spawn(async move {
             while let Ok(convert_list_state) = convert_list_state_receiver.recv() {
+                *request_tags_arc.lock().unwrap() = conversions_request_tags(&convert_list_state);
                 let mut state = state_arc1.lock().unwrap();
                 let new_state = StateFile {
                     conversions: convert_list_state,
@@ -159,6 +182,19 @@ fn main() -> glib::ExitCode {
             }
         });
 
+        let state_arc1 = state_arc.clone();
+        runtime().spawn(async move {
+            while let Ok(worklist_lifecycle) = worklist_lifecycle_receiver.recv() {
+                let mut state = state_arc1.lock().unwrap();
+                let new_state = StateFile {
+                    worklist_lifecycle,
+                    ..state.deref().clone()
+                };
+                _ = write_state_to_file(&new_state);
+                *state = new_state;
+            }
+        });
+
         let state_arc1 = state_arc.clone();
         runtime().spawn(async move {
             while let Ok(cstore_server_state) = cstore_server_state_receiver.recv() {
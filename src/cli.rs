@@ -0,0 +1,150 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use env_logger::Env;
+
+use crate::config::{read_config, Config};
+use crate::error::{G2DError, VddsError};
+
+pub mod anonymize;
+pub mod convert;
+pub mod dicom2gdt;
+pub mod media;
+pub mod opp;
+pub mod serve;
+pub mod validate;
+pub mod vdds;
+pub mod worklist;
+
+/// Exit codes of all commands
+pub const EXIT_SUCCESS: i32 = 0;
+/// Converting failed, e.g. a file cannot be written or a dcmtk tool failed
+pub const EXIT_FAILURE: i32 = 1;
+/// Wrong options or config file, also an unknown `--bvs`
+pub const EXIT_INVALID_ARGUMENT: i32 = 2;
+/// Several BVS are registered and `--bvs` is missing
+pub const EXIT_MULTIPLE_BVS: i32 = 3;
+/// An input file is not valid GDT or XML, or `validate` found errors
+pub const EXIT_INVALID_INPUT: i32 = 4;
+/// The BVS replied with an error
+pub const EXIT_BVS_ERROR: i32 = 100;
+
+/// Convert between GDT, DICOM, worklists, OPP and VDDS
+#[derive(Parser, Debug)]
+#[command(
+    name = "gdt2dicom",
+    author,
+    version,
+    about,
+    long_about = None,
+    subcommand_negates_reqs = true,
+    arg_required_else_help = true
+)]
+pub struct Cli {
+    /// Config file with the defaults, omitted = gdt2dicom.json next to the executable
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// `gdt2dicom --gdt-file ...` without a command converts like `gdt2dicom convert`
+    #[command(flatten)]
+    pub convert: Option<convert::ConvertArgs>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Convert a GDT file and images or PDFs to DICOM files or a worklist file
+    Convert(convert::ConvertArgs),
+    /// List, edit and remove the entries of a worklist folder
+    Worklist(worklist::WorklistArgs),
+    /// Convert DICOM files to GDT results, one per study
+    Dicom2gdt(dicom2gdt::Dicom2GdtArgs),
+    /// Convert a GDT file to OPP XML with patient info
+    Opp(opp::OppArgs),
+    /// Import the patient of a GDT file into a BVS and fetch its images
+    Vdds(vdds::VddsArgs),
+    /// Run the auto-convert folders and servers of the GUI's state file
+    Serve(serve::ServeArgs),
+    /// Check GDT files and the config file
+    Validate(validate::ValidateArgs),
+    /// De-identify DICOM and GDT files with consistent pseudonyms per patient
+    Anonymize(anonymize::AnonymizeArgs),
+    /// Write DICOM files to a media file-set (DICOMDIR) for CD, DVD or USB
+    Media(media::MediaArgs),
+}
+
+/// Runs the command and returns the exit code, errors are printed to stderr
+pub fn run(cli: Cli) -> i32 {
+    let config = match read_config(cli.config.as_deref()).and_then(|c| {
+        c.apply()?;
+        Ok(c)
+    }) {
+        Ok(c) => c,
+        Err(err) => return exit_with_error(err),
+    };
+    let result = match cli.command {
+        Some(command) => run_command(command, &config),
+        None => match cli.convert {
+            Some(args) => convert::run(args, &config),
+            None => Ok(()),
+        },
+    };
+    return match result {
+        Ok(()) => EXIT_SUCCESS,
+        Err(err) => exit_with_error(err),
+    };
+}
+
+fn run_command(command: Command, config: &Config) -> Result<(), G2DError> {
+    return match command {
+        Command::Convert(args) => convert::run(args, config),
+        Command::Worklist(args) => worklist::run(args),
+        Command::Dicom2gdt(args) => dicom2gdt::run(args),
+        Command::Opp(args) => opp::run(args),
+        Command::Vdds(args) => vdds::run(args),
+        Command::Serve(args) => serve::run(args),
+        Command::Validate(args) => validate::run(args, config),
+        Command::Anonymize(args) => anonymize::run(args),
+        Command::Media(args) => media::run(args),
+    };
+}
+
+/// Parses `args` like `std::env::args_os()` and exits with the code of the command
+pub fn main_with_args<I>(args: I) -> !
+where
+    I: IntoIterator<Item = OsString>,
+{
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let cli = Cli::parse_from(args);
+    std::process::exit(run(cli));
+}
+
+/// `main` of the binaries which are another name for a command of gdt2dicom,
+/// e.g. `dicom2gdt -d x.dcm` runs `gdt2dicom dicom2gdt -d x.dcm`
+pub fn alias_main(command: &str) -> ! {
+    let args = [OsString::from("gdt2dicom"), OsString::from(command)]
+        .into_iter()
+        .chain(std::env::args_os().skip(1));
+    main_with_args(args);
+}
+
+pub fn exit_code(err: &G2DError) -> i32 {
+    return match err {
+        G2DError::InvalidArgument(_) => EXIT_INVALID_ARGUMENT,
+        G2DError::InvalidInput(_) | G2DError::GdtError(_) | G2DError::XmlReaderError(_) => {
+            EXIT_INVALID_INPUT
+        }
+        G2DError::VddsError(VddsError::BvsNotFound(..)) => EXIT_INVALID_ARGUMENT,
+        G2DError::VddsError(VddsError::MultipleBvs(..)) => EXIT_MULTIPLE_BVS,
+        G2DError::VddsError(VddsError::BvsError(..)) => EXIT_BVS_ERROR,
+        _ => EXIT_FAILURE,
+    };
+}
+
+fn exit_with_error(err: G2DError) -> i32 {
+    eprintln!("Error: {}", err);
+    return exit_code(&err);
+}
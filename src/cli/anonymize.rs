@@ -0,0 +1,86 @@
+use clap::Args;
+
+use std::fs::create_dir_all;
+use std::path::PathBuf;
+
+use crate::anonymize::{anonymize_dcm_file, anonymize_gdt_file, AnonymizeOptions, PseudonymMap};
+use crate::dicomdir::{collect_dicom_files, is_dicom_file};
+use crate::error::G2DError;
+
+/// De-identify DICOM and GDT files with consistent pseudonyms per patient
+#[derive(Args, Debug)]
+pub struct AnonymizeArgs {
+    /// DICOM files, GDT files or folders containing them (searched recursively)
+    #[arg(required = true)]
+    pub inputs: Vec<PathBuf>,
+
+    /// Folder for the de-identified files
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// JSON file mapping patient IDs to pseudonyms, created if it doesn't exist.
+    /// Keep it secret, it allows to re-identify the patients.
+    #[arg(short, long)]
+    pub map: PathBuf,
+
+    /// Prefix of new pseudonyms
+    #[arg(long, default_value = "ANON")]
+    pub prefix: String,
+
+    /// Keep study, series and content dates and times
+    #[arg(long)]
+    pub keep_dates: bool,
+}
+
+pub fn run(args: AnonymizeArgs) -> Result<(), G2DError> {
+    let options = AnonymizeOptions {
+        prefix: args.prefix,
+        keep_dates: args.keep_dates,
+    };
+    let mut map = PseudonymMap::load(&args.map)?;
+    create_dir_all(&args.output)?;
+
+    let gdt_files = list_gdt_files(&args.inputs)?;
+    let dicom_files = collect_dicom_files(&args.inputs)?;
+    for (index, input) in gdt_files.iter().enumerate() {
+        // File names can contain the patient name, so they are replaced
+        let output = args.output.join(format!("{:05}.gdt", index + 1));
+        anonymize_gdt_file(input, &output, &mut map, &options)?;
+        println!("{} -> {}", input.display(), output.display());
+    }
+    for (index, input) in dicom_files.iter().enumerate() {
+        let output = args.output.join(format!("{:05}.dcm", index + 1));
+        anonymize_dcm_file(input, &output, &mut map, &options, None)?;
+        println!("{} -> {}", input.display(), output.display());
+    }
+    map.save(&args.map)?;
+
+    println!(
+        "Finished, {} GDT and {} DICOM files written to {}",
+        gdt_files.len(),
+        dicom_files.len(),
+        args.output.display()
+    );
+    return Ok(());
+}
+
+fn list_gdt_files(inputs: &[PathBuf]) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut result = vec![];
+    for input in inputs {
+        if input.is_dir() {
+            let mut entries = std::fs::read_dir(input)?
+                .filter_map(|res| res.ok().map(|e| e.path()))
+                .collect::<Vec<_>>();
+            entries.sort();
+            result.extend(list_gdt_files(&entries)?);
+        } else if input
+            .extension()
+            .map(|s| s.to_ascii_lowercase() == "gdt")
+            .unwrap_or(false)
+            && !is_dicom_file(input)
+        {
+            result.push(input.clone());
+        }
+    }
+    return Ok(result);
+}
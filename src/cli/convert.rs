@@ -0,0 +1,367 @@
+use clap::{Args, ValueEnum};
+use regex::Regex;
+use xml::reader::XmlEvent;
+
+use std::ffi::OsStr;
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+
+use crate::anonymize::{anonymize_dcm_file, AnonymizeOptions, PseudonymMap};
+use crate::command::exec_command;
+use crate::config::Config;
+use crate::dcm_sr::TextReport;
+use crate::dcm_worklist::dcm_xml_to_worklist;
+use crate::dcm_xml::{
+    default_dcm_xml, file_to_xml_events, parse_dcm_xml, study_instance_uid_from_request,
+    xml_events_to_file, DcmTransferType, FileToXmlOptions, RequestTag, RequestTags,
+};
+use crate::dry_run::{
+    dcm_attribute_warnings, dcm_attributes, print_dcm_attributes, print_gdt_file, print_series,
+    print_warnings, DryRunAttribute, DryRunSeries,
+};
+use crate::error::G2DError;
+use crate::filename_template::{FilenameTemplate, FilenameValues};
+use crate::gdt::parse_file;
+use crate::image_import::{
+    convert_input_files, dry_run_series, list_input_files, ImportOptions, InstanceLayout,
+    SeriesGrouping,
+};
+use crate::person_name::NameCase;
+use crate::sop_class::ImageSopClass;
+
+/// Convert a gdt file and a folder of images (JPEG, PNG, TIFF, BMP) and PDFs to dicom files
+#[derive(Args, Debug)]
+pub struct ConvertArgs {
+    #[arg(short, long)]
+    pub gdt_file: PathBuf,
+
+    #[arg(short, long)]
+    pub dicom_xml: Option<PathBuf>,
+
+    /// Folder containing JPEG, PNG, TIFF, BMP or PDF files
+    #[arg(short, long)]
+    pub jpegs: Option<PathBuf>,
+
+    /// A PDF report to wrap into an Encapsulated PDF instance, can be repeated
+    #[arg(long)]
+    pub pdf: Vec<PathBuf>,
+
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Target IOD of the converted images
+    #[arg(long, value_enum, default_value_t = ImageSopClass::SecondaryCapture)]
+    pub sop_class: ImageSopClass,
+
+    /// One instance per image or one multi-frame instance per series
+    #[arg(long, value_enum, default_value_t = InstanceLayout::MultiFrame)]
+    pub instances: InstanceLayout,
+
+    /// How the images are grouped into series
+    #[arg(long, value_enum, default_value_t = SeriesBy::Format)]
+    pub series_by: SeriesBy,
+
+    /// Regular expression for `--series-by pattern`, images with the same match
+    /// (or first capture group) in the file name form a series, e.g. "^([A-Za-z]+)_"
+    #[arg(long, required_if_eq("series_by", "pattern"))]
+    pub series_pattern: Option<String>,
+
+    /// Also write a Basic Text SR with the findings, diagnosis and comments
+    /// of the GDT file (6220, 6205, 6227) into the study
+    #[arg(long)]
+    pub text_sr: bool,
+
+    /// De-identify the written DICOM files, with pseudonyms from this JSON map file
+    /// (created if it doesn't exist)
+    #[arg(long)]
+    pub anonymize: Option<PathBuf>,
+
+    /// Write the worklist into the folder `--output` with this file name,
+    /// e.g. "{first_name}_{last_name}_{timestamp}", `.wl` is appended
+    #[arg(long)]
+    pub worklist_name: Option<FilenameTemplate>,
+
+    /// ScheduledStationAETitle of a worklist file, omitted = `aetitle` of the config file
+    #[arg(long)]
+    pub aetitle: Option<String>,
+
+    /// Letter case of the DICOM PatientName
+    #[arg(long, value_enum, default_value_t = NameCase::Upper)]
+    pub name_case: NameCase,
+
+    /// DICOM attribute the request identifier (8310) is stored in
+    #[arg(long, value_enum, default_value_t = RequestTag::AccessionNumber)]
+    pub request_id_tag: RequestTag,
+
+    /// DICOM attribute the request UID (8314) is stored in
+    #[arg(long, value_enum, default_value_t = RequestTag::PlacerOrderNumber)]
+    pub request_uid_tag: RequestTag,
+
+    /// Print the parsed GDT file and the DICOM attributes which would be written,
+    /// without running dcmtk or writing files
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SeriesBy {
+    /// JPEGs, other images and PDFs each form a series
+    Format,
+    /// One series per subfolder of the image folder
+    Subfolder,
+    /// One series per match of --series-pattern
+    Pattern,
+}
+
+pub fn run(args: ConvertArgs, config: &Config) -> Result<(), G2DError> {
+    let mut input_files = match args.jpegs {
+        Some(ref j) => list_input_files(&j, args.series_by == SeriesBy::Subfolder)?,
+        None => vec![],
+    };
+    input_files.extend(args.pdf.clone());
+    println!(
+        "Found {} input files: \n{}",
+        input_files.len(),
+        input_files
+            .iter()
+            .map(|s| s.as_path().display().to_string())
+            .collect::<Vec<String>>()
+            .join("\n")
+    );
+
+    let dicom_xml_path = match (&args.dicom_xml, &args.jpegs) {
+        (Some(p), _) => Some(PathBuf::from(p)),
+        (None, Some(ref j)) => find_xml_path(&j)?,
+        _ => None,
+    };
+    println!(
+        "Dicom XML file path: {}",
+        dicom_xml_path
+            .clone()
+            .and_then(|p| p.into_os_string().into_string().ok())
+            .unwrap_or("None".to_string())
+    );
+    let is_output_worklist =
+        args.worklist_name.is_some() || args.output.extension() == Some("wl".as_ref());
+    let template_events = match dicom_xml_path {
+        Some(p) => parse_dcm_xml(&p)?,
+        _ => default_dcm_xml(if is_output_worklist {
+            DcmTransferType::LittleEndianExplicit
+        } else {
+            DcmTransferType::JPEGBaseline
+        }),
+    };
+
+    let gdt_file = parse_file(&args.gdt_file)?;
+    let output = match &args.worklist_name {
+        Some(template) => template.unique_path(
+            &args.output,
+            "wl",
+            &FilenameValues::from_gdt_file(&gdt_file),
+        ),
+        None => args.output.clone(),
+    };
+    let text_report = if args.text_sr {
+        let report = TextReport::from_gdt_file(&gdt_file);
+        if report.is_none() {
+            println!("The GDT file has no findings, not writing a text SR");
+        }
+        report
+    } else {
+        None
+    };
+    let series_grouping = match (args.series_by, &args.jpegs, &args.series_pattern) {
+        (SeriesBy::Subfolder, Some(j), _) => SeriesGrouping::Subfolder(j.clone()),
+        (SeriesBy::Pattern, _, Some(p)) => {
+            SeriesGrouping::Pattern(Regex::new(p).map_err(|err| {
+                G2DError::InvalidArgument(format!("Invalid --series-pattern: {}", err))
+            })?)
+        }
+        _ => SeriesGrouping::Format,
+    };
+    let import_options = ImportOptions {
+        sop_class: args.sop_class,
+        instances: args.instances,
+        series_grouping,
+        text_report,
+    };
+    let options = FileToXmlOptions {
+        name_case: args.name_case,
+        request_tags: RequestTags {
+            identifier: args.request_id_tag,
+            uid: args.request_uid_tag,
+        },
+    };
+    // A dry run lists it with the other errors of the GDT file
+    if !args.dry_run {
+        options.request_tags.check(&gdt_file.object_request)?;
+    }
+    // The worklist entry shares the study derived from the request with the images
+    let keep_study_uid = study_instance_uid_from_request(
+        &gdt_file.object_patient.patient_number,
+        &gdt_file.object_request,
+    )
+    .is_some();
+    let xml_events = file_to_xml_events(gdt_file, &template_events, &options);
+    if args.dry_run {
+        let series = if is_output_worklist {
+            vec![]
+        } else {
+            dry_run_series(&xml_events, &input_files, &args.output, &import_options)
+        };
+        let outputs = if is_output_worklist {
+            vec![format!("Worklist file {}", output.display())]
+        } else if !series.is_empty() {
+            series
+                .iter()
+                .flat_map(|s| &s.instances)
+                .map(|i| format!("DICOM file {}", i.output.display()))
+                .collect()
+        } else {
+            vec![format!("DICOM file {}", args.output.display())]
+        };
+        let aetitle = match (is_output_worklist, &args.aetitle, &config.aetitle) {
+            (false, _, _) => None,
+            (true, Some(a), _) => Some((a, "--aetitle")),
+            (true, None, Some(a)) => Some((a, "config")),
+            (true, None, None) => None,
+        };
+        print_dry_run(
+            &args.gdt_file,
+            &template_events,
+            &xml_events,
+            &options.request_tags,
+            aetitle,
+            &series,
+            &outputs,
+        )?;
+        return Ok(());
+    }
+    let temp_file = xml_events_to_file(xml_events.clone())?;
+
+    if is_output_worklist {
+        println!("Exporting Worklist file to {}", output.display());
+        if input_files.len() > 0 {
+            println!("{} input files will be ignored", input_files.len());
+        }
+        dcm_xml_to_worklist(None, &temp_file.path(), &output, keep_study_uid)?;
+        if let Some(aetitle) = args.aetitle.as_ref().or(config.aetitle.as_ref()) {
+            let output = exec_command(
+                "dcmodify",
+                vec![
+                    OsStr::new("-nb"),
+                    OsStr::new("-i"),
+                    OsStr::new(&format!("(0040,0100)[0].(0040,0001)={}", aetitle)),
+                    output.as_os_str(),
+                ],
+                true,
+                None,
+            )?;
+            check_status("dcmodify", &output)?;
+        }
+    } else if input_files.len() > 0 || import_options.text_report.is_some() {
+        let outputs = convert_input_files(
+            &xml_events,
+            &input_files,
+            &args.output,
+            &import_options,
+            None,
+        )?;
+        for output in &outputs {
+            println!("Written: {}", output.display());
+        }
+        if let Some(map_path) = &args.anonymize {
+            anonymize_outputs(&outputs, map_path)?;
+        }
+    } else {
+        let output = exec_command(
+            "xml2dcm",
+            vec![temp_file.path().as_os_str(), OsStr::new(&args.output)],
+            true,
+            None,
+        )?;
+        check_status("xml2dcm", &output)?;
+        if let Some(map_path) = &args.anonymize {
+            anonymize_outputs(&vec![args.output.clone()], map_path)?;
+        }
+    }
+
+    println!("Finished");
+    return Ok(());
+}
+
+fn print_dry_run(
+    gdt_path: &Path,
+    template_events: &Vec<XmlEvent>,
+    xml_events: &Vec<XmlEvent>,
+    request_tags: &RequestTags,
+    aetitle: Option<(&String, &str)>,
+    series: &Vec<DryRunSeries>,
+    outputs: &Vec<String>,
+) -> Result<(), G2DError> {
+    println!();
+    print_gdt_file(gdt_path)?;
+
+    let mut attributes = dcm_attributes(template_events, xml_events, request_tags);
+    if let Some((aetitle, source)) = aetitle {
+        let tag = "(0040,0100)[0].(0040,0001)";
+        attributes.retain(|a| a.tag != tag);
+        attributes.push(DryRunAttribute {
+            tag: tag.to_string(),
+            vr: "AE".to_string(),
+            name: "ScheduledStationAETitle".to_string(),
+            value: aetitle.clone(),
+            source: source.to_string(),
+        });
+    }
+    println!("\nDICOM attributes (tag, VR, name, value, source):");
+    print_dcm_attributes(&attributes);
+    let has_study_uid = attributes
+        .iter()
+        .any(|a| a.tag.eq_ignore_ascii_case("(0020,000d)") && !a.value.is_empty());
+    if !has_study_uid {
+        println!("StudyInstanceUID is generated when writing, without 8314 or 8310");
+    }
+    if !series.is_empty() {
+        println!("\nSeries and instances (tag, VR, name, value, source):");
+        print_series(series);
+    }
+
+    println!("\nWould write:");
+    for output in outputs {
+        println!("  {}", output);
+    }
+
+    println!();
+    print_warnings(gdt_path, request_tags, dcm_attribute_warnings(&attributes));
+    return Ok(());
+}
+
+fn check_status(program: &str, output: &Output) -> Result<(), G2DError> {
+    if output.status.success() {
+        return Ok(());
+    }
+    return Err(G2DError::IoError(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        format!("{} exited with {}", program, output.status),
+    )));
+}
+
+fn anonymize_outputs(outputs: &Vec<PathBuf>, map_path: &Path) -> Result<(), G2DError> {
+    let mut map = PseudonymMap::load(map_path)?;
+    let options = AnonymizeOptions::default();
+    for output in outputs {
+        anonymize_dcm_file(output, output, &mut map, &options, None)?;
+        println!("Anonymized: {}", output.display());
+    }
+    map.save(map_path)?;
+    return Ok(());
+}
+
+fn find_xml_path(dir_name: &Path) -> Result<Option<PathBuf>, std::io::Error> {
+    let xml_file = read_dir(dir_name)?
+        .filter_map(|res| res.ok())
+        .find(|dir| dir.path().extension().and_then(|s| s.to_str()) == Some("xml"));
+    return Ok(xml_file.map(|x| x.path()));
+}
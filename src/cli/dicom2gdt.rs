@@ -0,0 +1,336 @@
+use clap::Args;
+
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+
+use crate::dcm_sr::SrConceptMapping;
+use crate::dcm_study::{collect_study_files, group_by_study, DcmStudy};
+use crate::dcm_xml::{
+    export_images_from_dcm_with_prefix, export_images_from_dcm_with_template, export_pdf_from_dcm,
+    xml_get_document_title, DCMImageFormat, RequestTag, RequestTags,
+};
+use crate::error::G2DError;
+use crate::filename_template::FilenameTemplate;
+use crate::gdt::{file_to_string, GdtAttachment, GdtFile};
+use crate::person_name::NameCase;
+use crate::thumbnails::generate_study_preview;
+
+/// Convert dicom files to GDT results, one per study
+#[derive(Args, Debug)]
+pub struct Dicom2GdtArgs {
+    /// A DICOM file, a folder (searched recursively) or a DICOMDIR
+    #[arg(short, long)]
+    pub dicom_file: PathBuf,
+
+    /// Where to output the GDT file, omitted = stdout.
+    /// With several studies: a folder, or the name is numbered (result_001.gdt, ...)
+    #[arg(short, long)]
+    pub gdt_file: Option<PathBuf>,
+
+    /// Where to output the PNG files, with several studies one subfolder per study.
+    /// `-j` and `--jpegs` are accepted for older scripts.
+    #[arg(short, long, short_alias = 'j', alias = "jpegs")]
+    pub pngs: Option<PathBuf>,
+
+    /// File name of the PNG files, e.g. "{patient_id}_{study_date}_{modality}_{seq}",
+    /// omitted = [patient id]_[image number]_[frame]
+    #[arg(long)]
+    pub png_name: Option<FilenameTemplate>,
+
+    /// Where to output documents of Encapsulated PDF files,
+    /// omitted = next to the GDT file or the current directory
+    #[arg(short, long)]
+    pub attachments: Option<PathBuf>,
+
+    /// Where to write thumbnails per series, a contact sheet and an HTML page
+    /// per study, they are referenced in an Obj_Anhang of the GDT file
+    #[arg(long)]
+    pub previews: Option<PathBuf>,
+
+    /// Letter case of the GDT name fields
+    #[arg(long, value_enum, default_value_t = NameCase::Preserve)]
+    pub name_case: NameCase,
+
+    /// DICOM attribute the request identifier (8310) is read from
+    #[arg(long, value_enum, default_value_t = RequestTag::AccessionNumber)]
+    pub request_id_tag: RequestTag,
+
+    /// DICOM attribute the request UID (8314) is read from
+    #[arg(long, value_enum, default_value_t = RequestTag::PlacerOrderNumber)]
+    pub request_uid_tag: RequestTag,
+
+    /// JSON file mapping SR concept codes to GDT test identifiers (8410),
+    /// omitted = the CodeValue is used
+    #[arg(long)]
+    pub sr_mapping: Option<PathBuf>,
+
+    /// Print the GDT files and what would be exported, without writing files.
+    /// The DICOM files are still read with dcm2xml.
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+pub fn run(args: Dicom2GdtArgs) -> Result<(), G2DError> {
+    let files = collect_study_files(&args.dicom_file)?;
+    let studies = group_by_study(&files, None)?;
+    println!(
+        "Found {} DICOM files in {} studies",
+        files.len(),
+        studies.len()
+    );
+    let several = studies.len() > 1;
+    let sr_mapping = match &args.sr_mapping {
+        Some(path) => SrConceptMapping::load(path)?,
+        None => SrConceptMapping::default(),
+    };
+    let request_tags = RequestTags {
+        identifier: args.request_id_tag,
+        uid: args.request_uid_tag,
+    };
+
+    for (index, study) in studies.iter().enumerate() {
+        let mut file = study.to_gdt_file(args.name_case, &request_tags, &sr_mapping);
+        let name = format!("{}_{:03}", file.object_patient.patient_number, index + 1);
+        let gdt_path = args.gdt_file.as_ref().map(|g| {
+            if several {
+                numbered_path(g, &name, index)
+            } else {
+                g.clone()
+            }
+        });
+
+        if args.dry_run {
+            print_dry_run(&args, study, file, gdt_path.as_deref(), &name, several);
+            continue;
+        }
+
+        let pdfs = study.instances.iter().filter(|i| i.is_pdf());
+        for instance in pdfs {
+            let attachments_path = match (&args.attachments, &gdt_path) {
+                (Some(a), _) => a.clone(),
+                (None, Some(g)) => g
+                    .parent()
+                    .map(|p| p.to_path_buf())
+                    .unwrap_or(PathBuf::from(".")),
+                (None, None) => PathBuf::from("."),
+            };
+            create_dir_all(&attachments_path)?;
+            let pdf_path = export_pdf_from_dcm(&instance.path, &attachments_path, None)?;
+            println!("Exported PDF to {}", pdf_path.display());
+            file.object_annex.attachments.push(GdtAttachment {
+                archive_id: String::new(),
+                file_format: "PDF".to_string(),
+                content: xml_get_document_title(&instance.events).unwrap_or("Befund".to_string()),
+                file_reference: pdf_path.display().to_string(),
+            });
+        }
+
+        if let Some(previews_path) = &args.previews {
+            let preview = generate_study_preview(study, previews_path, None)?;
+            if let Some(contact_sheet) = preview.contact_sheet {
+                file.object_annex.attachments.push(GdtAttachment {
+                    archive_id: String::new(),
+                    file_format: "PNG".to_string(),
+                    content: "Kontaktabzug".to_string(),
+                    file_reference: contact_sheet.display().to_string(),
+                });
+            }
+            file.object_annex.attachments.push(GdtAttachment {
+                archive_id: String::new(),
+                file_format: "HTML".to_string(),
+                content: "Vorschau".to_string(),
+                file_reference: preview.html.display().to_string(),
+            });
+        }
+
+        if let Some(pngs_path) = &args.pngs {
+            let pngs_path = if several {
+                pngs_path.join(&name)
+            } else {
+                pngs_path.clone()
+            };
+            export_study_images(
+                study,
+                &pngs_path,
+                &file.object_patient.patient_number,
+                args.png_name.as_ref(),
+                &request_tags,
+            )?;
+        }
+
+        let gdt_string = file_to_string(file);
+        if let Some(path) = gdt_path {
+            std::fs::write(&path, gdt_string)?;
+            println!("GDT File written to:{}", path.display());
+        } else {
+            println!("GDT File:\r\n{}", &gdt_string);
+        }
+    }
+
+    return Ok(());
+}
+
+fn print_dry_run(
+    args: &Dicom2GdtArgs,
+    study: &DcmStudy,
+    file: GdtFile,
+    gdt_path: Option<&Path>,
+    name: &str,
+    several: bool,
+) {
+    let mut warnings = vec![];
+    let patient = &file.object_patient;
+    let missing = [
+        ("PatientID", "3000", &patient.patient_number),
+        ("PatientName", "3101", &patient.patient_name),
+        ("PatientBirthDate", "3103", &patient.patient_dob),
+        (
+            "StudyDate",
+            "6200",
+            &file.object_request.date_of_examination,
+        ),
+    ];
+    for (attribute, field, value) in missing {
+        if value.trim().is_empty() {
+            warnings.push(format!("{} is missing, {} is left empty", attribute, field));
+        }
+    }
+
+    println!(
+        "\nStudy {} ({} files)",
+        study.study_uid,
+        study.instances.len()
+    );
+    match gdt_path {
+        Some(path) => println!("GDT file which would be written to {}:", path.display()),
+        None => println!("GDT file which would be printed:"),
+    }
+    for line in file_to_string(file).lines() {
+        println!("  {}", line);
+    }
+
+    println!("Would write:");
+    let pdf_count = study.instances.iter().filter(|i| i.is_pdf()).count();
+    if pdf_count > 0 {
+        println!(
+            "  {} PDF documents into {}, referenced in Obj_Anhang",
+            pdf_count,
+            args.attachments
+                .as_deref()
+                .or(gdt_path
+                    .and_then(|g| g.parent())
+                    .filter(|p| !p.as_os_str().is_empty()))
+                .unwrap_or(Path::new("."))
+                .display()
+        );
+    }
+    if let Some(previews_path) = &args.previews {
+        println!(
+            "  Thumbnails and a preview page into {}, referenced in Obj_Anhang",
+            previews_path.display()
+        );
+    }
+    if let Some(pngs_path) = &args.pngs {
+        let pngs_path = if several {
+            pngs_path.join(name)
+        } else {
+            pngs_path.clone()
+        };
+        println!(
+            "  The frames of {} images as PNG into {}",
+            study.image_count(),
+            pngs_path.display()
+        );
+    }
+
+    if warnings.is_empty() {
+        println!("No warnings");
+        return;
+    }
+    println!("Warnings:");
+    for warning in &warnings {
+        println!("  {}", warning);
+    }
+}
+
+/// `folder/[patient id]_[study number].gdt` if `gdt_file` is a folder,
+/// `[stem]_[study number].[extension]` otherwise
+fn numbered_path(gdt_file: &Path, name: &str, index: usize) -> PathBuf {
+    if gdt_file.is_dir() {
+        return gdt_file.join(format!("{}.gdt", name));
+    }
+    let stem = gdt_file
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = gdt_file
+        .extension()
+        .map(|e| e.to_string_lossy().to_string())
+        .unwrap_or("gdt".to_string());
+    return gdt_file.with_file_name(format!("{}_{:03}.{}", stem, index + 1, extension));
+}
+
+fn export_study_images(
+    study: &DcmStudy,
+    pngs_path: &PathBuf,
+    patient_id: &str,
+    png_name: Option<&FilenameTemplate>,
+    request_tags: &RequestTags,
+) -> Result<(), G2DError> {
+    if !pngs_path.exists() {
+        println!(
+            "Path {} doesn't exist, creating directory...",
+            pngs_path.display()
+        );
+        create_dir_all(pngs_path)?;
+        println!("Created {}", pngs_path.display());
+    }
+    if !pngs_path.is_dir() {
+        println!(
+            "{} is not a directory, not exporting png.",
+            pngs_path.display()
+        );
+        return Ok(());
+    }
+    println!("Exporting images to {}", &pngs_path.display());
+    let images: Vec<_> = study.instances.iter().filter(|i| i.is_image()).collect();
+    let mut exported = 0;
+    for (index, instance) in images.iter().enumerate() {
+        let result = match png_name {
+            Some(template) => export_images_from_dcm_with_template(
+                &instance.path,
+                pngs_path,
+                template,
+                DCMImageFormat::Png,
+                &[*request_tags],
+                None,
+            ),
+            None => {
+                // [patient id]_[frame].png for a single instance, as before
+                let prefix = if images.len() == 1 {
+                    format!("{}_", patient_id)
+                } else {
+                    format!("{}_{:03}_", patient_id, index + 1)
+                };
+                export_images_from_dcm_with_prefix(
+                    &instance.path,
+                    pngs_path,
+                    &prefix,
+                    DCMImageFormat::Png,
+                    None,
+                )
+            }
+        };
+        // The GDT file is still written without the images of this instance
+        match result {
+            Ok(_) => exported += 1,
+            Err(err) => eprintln!(
+                "Warning: skipping the images of {}: {}",
+                instance.path.display(),
+                err
+            ),
+        }
+    }
+    println!("Exported {} of {} images", exported, images.len());
+    return Ok(());
+}
@@ -0,0 +1,30 @@
+use clap::Args;
+
+use std::fs::create_dir_all;
+use std::path::PathBuf;
+
+use crate::dicomdir::{write_media, MediaProfile};
+use crate::error::G2DError;
+
+/// Write DICOM files and folders to a media file-set (DICOMDIR) for CD, DVD or USB
+#[derive(Args, Debug)]
+pub struct MediaArgs {
+    /// DICOM files or folders containing DICOM files (searched recursively)
+    #[arg(required = true)]
+    pub inputs: Vec<PathBuf>,
+
+    /// Folder to write the file-set to, e.g. the root of a USB stick
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Application profile of the media
+    #[arg(long, value_enum, default_value_t = MediaProfile::Usb)]
+    pub profile: MediaProfile,
+}
+
+pub fn run(args: MediaArgs) -> Result<(), G2DError> {
+    create_dir_all(&args.output)?;
+    let dicomdir = write_media(&args.inputs, &args.output, args.profile, None)?;
+    println!("Finished, DICOMDIR at {}", dicomdir.display());
+    return Ok(());
+}
@@ -0,0 +1,40 @@
+use clap::Args;
+
+use std::path::PathBuf;
+
+use crate::dry_run::{print_gdt_file, print_warnings, NO_REQUEST_TAGS};
+use crate::error::G2DError;
+use crate::gdt::parse_file;
+use crate::opp_xml::{file_to_xml, file_to_xml_string};
+
+/// Convert a gdt file to opp xml with patient info
+#[derive(Args, Debug)]
+pub struct OppArgs {
+    #[arg(short, long)]
+    pub gdt_file: PathBuf,
+
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Print the parsed GDT file and the OPP XML without writing it
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+pub fn run(args: OppArgs) -> Result<(), G2DError> {
+    let gdt_file = parse_file(&args.gdt_file)?;
+    if args.dry_run {
+        print_gdt_file(&args.gdt_file)?;
+        println!(
+            "\nOPP XML which would be written to {}:",
+            args.output.display()
+        );
+        println!("{}\n", file_to_xml_string(gdt_file)?);
+        print_warnings(&args.gdt_file, &NO_REQUEST_TAGS, vec![]);
+        return Ok(());
+    }
+    file_to_xml(gdt_file, args.output.clone())?;
+
+    println!("Finished, output at {}", args.output.display());
+    return Ok(());
+}
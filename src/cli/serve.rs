@@ -0,0 +1,296 @@
+use chrono::Local;
+use clap::Args;
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use shared_child::SharedChild;
+
+use crate::command::ChildOutput;
+use crate::error::G2DError;
+use crate::servers::{spawn_server, storescp_command, wlmscpfs_command, ReceivedFiles};
+use crate::state::{conversions_request_tags, read_state_file, state_file_path, StateFile};
+use crate::worklist_conversion::WorklistConversion;
+use crate::worklist_lifecycle::{expire_worklist_entries, WorklistLifecycle};
+
+/// How often expired worklist entries are removed
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// First delay before a crashed server is restarted, doubled on every crash
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+/// A server running this long is considered stable, its next crash restarts
+/// it after `MIN_RESTART_DELAY` again
+const STABLE_RUN_TIME: Duration = Duration::from_secs(60);
+
+/// Run the auto-convert folders, the worklist server and the CStore server
+/// of the GUI's state file without the GUI
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    /// The state file written by gdt2dicom-gui, defaults to state.json next
+    /// to the executable
+    #[arg(short, long)]
+    pub state_file: Option<PathBuf>,
+
+    /// Append the log to this file, it is always printed to stdout
+    #[arg(short, long)]
+    pub log_file: Option<PathBuf>,
+}
+
+/// The running server child of a supervisor, killed on shutdown
+type RunningChild = Arc<Mutex<Option<Arc<SharedChild>>>>;
+
+pub fn run(args: ServeArgs) -> Result<(), G2DError> {
+    let log_file = match &args.log_file {
+        Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+        None => None,
+    };
+    let log_sender = spawn_logger(log_file);
+
+    let state_file = match args.state_file {
+        Some(path) => path,
+        None => state_file_path()?,
+    };
+    let state = read_state_file(&state_file).map_err(|err| {
+        G2DError::InvalidInput(format!("Cannot read {}: {}", state_file.display(), err))
+    })?;
+    _ = log_sender.send(format!("Using state file {}", state_file.display()));
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let worklist_dir_arc = Arc::new(Mutex::new(state.worklist_path.clone()));
+    let lifecycle_arc = Arc::new(Mutex::new(state.worklist_lifecycle.clone()));
+    let hooks_arc = Arc::new(Mutex::new(state.hooks.clone()));
+    let request_tags_arc = Arc::new(Mutex::new(conversions_request_tags(&state.conversions)));
+
+    spawn_expiry(&state, log_sender.clone());
+
+    let mut conversions = vec![];
+    for (index, conversion_state) in state.conversions.iter().enumerate() {
+        let Some(ref input_dir) = conversion_state.input_dir_path else {
+            continue;
+        };
+        let prefix = format!("Conversion {}", index + 1);
+        _ = log_sender.send(format!("{}: watching {}", prefix, input_dir.display()));
+        let conversion = WorklistConversion::from_state(
+            conversion_state,
+            prefixed_log_sender(&prefix, &log_sender),
+            worklist_dir_arc.clone(),
+            hooks_arc.clone(),
+        );
+        if conversion.lock().unwrap().input_dir_path().is_none() {
+            _ = log_sender.send(format!("{}: cannot watch {}", prefix, input_dir.display()));
+        }
+        conversions.push(conversion);
+    }
+
+    let mut running_children: Vec<RunningChild> = vec![];
+    let dicom_port = state.dicom_server.as_ref().and_then(|s| s.port);
+    match (dicom_port, &state.worklist_path) {
+        (Some(port), Some(worklist_dir)) => {
+            let worklist_dir = worklist_dir.clone();
+            running_children.push(supervise(
+                "wlmscpfs",
+                stop.clone(),
+                log_sender.clone(),
+                move |sender| {
+                    spawn_server(
+                        &mut wlmscpfs_command(&worklist_dir, port),
+                        "wlmscpfs",
+                        sender,
+                        |_| {},
+                    )
+                },
+            ));
+        }
+        _ => {
+            _ = log_sender.send("No worklist folder or port, not starting wlmscpfs".to_string());
+        }
+    }
+
+    let cstore_state = state.cstore_server.clone().unwrap_or_default();
+    match (cstore_state.port, cstore_state.path.clone()) {
+        (Some(port), Some(dir)) => {
+            let worklist_dir_arc = worklist_dir_arc.clone();
+            let lifecycle_arc = lifecycle_arc.clone();
+            let hooks_arc = hooks_arc.clone();
+            running_children.push(supervise(
+                "storescp",
+                stop.clone(),
+                log_sender.clone(),
+                move |sender| {
+                    let mut received_files = ReceivedFiles::new(
+                        worklist_dir_arc.clone(),
+                        lifecycle_arc.clone(),
+                        hooks_arc.clone(),
+                        request_tags_arc.clone(),
+                        sender.clone(),
+                    );
+                    received_files.jpeg_dir = cstore_state.jpeg_output_path.clone();
+                    received_files.jpeg_filename_template =
+                        cstore_state.jpeg_filename_template.clone();
+                    received_files.preview_dir = cstore_state.preview_output_path.clone();
+                    spawn_server(
+                        &mut storescp_command(&dir, port),
+                        "storescp",
+                        sender,
+                        move |line| received_files.handle_line(line),
+                    )
+                },
+            ));
+        }
+        _ => {
+            _ = log_sender.send("No CStore folder or port, not starting storescp".to_string());
+        }
+    }
+
+    wait_for_shutdown_signal(&runtime)?;
+    _ = log_sender.send("Shutting down".to_string());
+    stop.store(true, Ordering::SeqCst);
+    for running_child in &running_children {
+        if let Some(child) = running_child.lock().unwrap().take() {
+            _ = child.kill();
+            _ = child.wait();
+        }
+    }
+    for conversion in &conversions {
+        let mut conversion = conversion.lock().unwrap();
+        conversion.unwatch_input_dir();
+        // Waits for a conversion which is running
+        conversion.wait_for_scan();
+    }
+    _ = log_sender.send("Stopped".to_string());
+    drop(log_sender);
+    // Let the logger write the last messages
+    std::thread::sleep(Duration::from_millis(100));
+    return Ok(());
+}
+
+/// Waits for SIGTERM or Ctrl+C
+fn wait_for_shutdown_signal(runtime: &tokio::runtime::Runtime) -> Result<(), std::io::Error> {
+    let (sender, receiver) = mpsc::channel::<()>();
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = runtime.block_on(async { signal(SignalKind::terminate()) })?;
+        let sender = sender.clone();
+        runtime.spawn(async move {
+            terminate.recv().await;
+            _ = sender.send(());
+        });
+    }
+    runtime.spawn(async move {
+        _ = tokio::signal::ctrl_c().await;
+        _ = sender.send(());
+    });
+    _ = receiver.recv();
+    return Ok(());
+}
+
+/// Writes the log messages with a timestamp to stdout and the log file
+fn spawn_logger(mut log_file: Option<File>) -> mpsc::Sender<String> {
+    let (sender, receiver) = mpsc::channel::<String>();
+    std::thread::spawn(move || {
+        while let Ok(msg) = receiver.recv() {
+            let line = format!(
+                "{} {}",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                msg.trim_end()
+            );
+            println!("{}", line);
+            if let Some(ref mut f) = log_file {
+                _ = writeln!(f, "{}", line);
+            }
+        }
+    });
+    return sender;
+}
+
+/// A log sender adding `prefix` to the messages
+fn prefixed_log_sender(prefix: &str, log_sender: &mpsc::Sender<String>) -> mpsc::Sender<String> {
+    let (sender, receiver) = mpsc::channel::<String>();
+    let prefix = prefix.to_string();
+    let log_sender = log_sender.clone();
+    std::thread::spawn(move || {
+        while let Ok(msg) = receiver.recv() {
+            _ = log_sender.send(format!("{}: {}", prefix, msg));
+        }
+    });
+    return sender;
+}
+
+/// Removes the expired worklist entries now and then every hour
+fn spawn_expiry(state: &StateFile, log_sender: mpsc::Sender<String>) {
+    let Some(worklist_dir) = state.worklist_path.clone() else {
+        return;
+    };
+    let lifecycle: WorklistLifecycle = state.worklist_lifecycle.clone();
+    std::thread::spawn(move || loop {
+        if let Err(err) = expire_worklist_entries(&worklist_dir, &lifecycle, Some(&log_sender)) {
+            _ = log_sender.send(format!("Cannot remove expired entries: {}", err));
+        }
+        std::thread::sleep(EXPIRY_INTERVAL);
+    });
+}
+
+/// Starts a server with `start` and restarts it whenever it exits until
+/// `stop` is set, waiting longer after each crash in a row
+fn supervise<F>(
+    name: &str,
+    stop: Arc<AtomicBool>,
+    log_sender: mpsc::Sender<String>,
+    mut start: F,
+) -> RunningChild
+where
+    F: FnMut(mpsc::Sender<ChildOutput>) -> Result<Arc<SharedChild>, std::io::Error>
+        + Send
+        + 'static,
+{
+    let running_child: RunningChild = Arc::new(Mutex::new(None));
+    let running_child1 = running_child.clone();
+    let name = name.to_string();
+    std::thread::spawn(move || {
+        let mut delay = MIN_RESTART_DELAY;
+        while !stop.load(Ordering::SeqCst) {
+            let (sender, receiver) = mpsc::channel::<ChildOutput>();
+            let started = Instant::now();
+            match start(sender) {
+                Ok(child) => {
+                    *running_child1.lock().unwrap() = Some(child);
+                    while let Ok(output) = receiver.recv() {
+                        match output {
+                            ChildOutput::Log(msg) => _ = log_sender.send(msg),
+                            ChildOutput::Preview { html, .. } => {
+                                _ = log_sender.send(format!("Preview: {}", html.display()));
+                            }
+                            ChildOutput::Exit(_) => break,
+                        }
+                    }
+                }
+                Err(err) => {
+                    _ = log_sender.send(format!("Cannot start {}: {}", name, err));
+                }
+            }
+            if stop.load(Ordering::SeqCst) {
+                break;
+            }
+            if started.elapsed() > STABLE_RUN_TIME {
+                delay = MIN_RESTART_DELAY;
+            }
+            _ = log_sender.send(format!(
+                "Restarting {} in {} seconds",
+                name,
+                delay.as_secs()
+            ));
+            std::thread::sleep(delay);
+            delay = (delay * 2).min(MAX_RESTART_DELAY);
+        }
+    });
+    return running_child;
+}
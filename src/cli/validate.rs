@@ -0,0 +1,67 @@
+use clap::Args;
+
+use std::path::PathBuf;
+
+use crate::config::Config;
+use crate::dcm_xml::{RequestTag, RequestTags};
+use crate::error::G2DError;
+use crate::validation::validate_gdt_file;
+
+/// Check GDT files without converting them, the config file is checked before
+#[derive(Args, Debug)]
+pub struct ValidateArgs {
+    /// GDT files to check
+    pub gdt_files: Vec<PathBuf>,
+
+    /// DICOM attribute the request identifier (8310) is stored in
+    #[arg(long, value_enum, default_value_t = RequestTag::AccessionNumber)]
+    pub request_id_tag: RequestTag,
+
+    /// DICOM attribute the request UID (8314) is stored in
+    #[arg(long, value_enum, default_value_t = RequestTag::PlacerOrderNumber)]
+    pub request_uid_tag: RequestTag,
+}
+
+pub fn run(args: ValidateArgs, config: &Config) -> Result<(), G2DError> {
+    println!(
+        "Config: AE title {}, UID root {}, character set {}",
+        config.aetitle.as_deref().unwrap_or("-"),
+        config.uid_root.as_deref().unwrap_or("2.25"),
+        config.charset.as_deref().unwrap_or("-")
+    );
+    let request_tags = RequestTags {
+        identifier: args.request_id_tag,
+        uid: args.request_uid_tag,
+    };
+    let mut invalid_count = 0;
+    for path in &args.gdt_files {
+        let validation = validate_gdt_file(path, &request_tags);
+        if validation.errors.is_empty() && validation.warnings.is_empty() {
+            println!("{}: OK", path.display());
+            continue;
+        }
+        println!(
+            "{}: {} errors, {} warnings",
+            path.display(),
+            validation.errors.len(),
+            validation.warnings.len()
+        );
+        for error in &validation.errors {
+            println!("  error: {}", error);
+        }
+        for warning in &validation.warnings {
+            println!("  warning: {}", warning);
+        }
+        if !validation.is_valid() {
+            invalid_count += 1;
+        }
+    }
+    if invalid_count > 0 {
+        return Err(G2DError::InvalidInput(format!(
+            "{} of {} GDT files have errors",
+            invalid_count,
+            args.gdt_files.len()
+        )));
+    }
+    return Ok(());
+}
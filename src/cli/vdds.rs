@@ -0,0 +1,185 @@
+use clap::Args;
+use log::{debug, error, info};
+
+use std::fs;
+use std::path::PathBuf;
+
+use crate::dry_run::{print_gdt_file, print_warnings, NO_REQUEST_TAGS};
+use crate::error::G2DError;
+use crate::filename_template::{FilenameTemplate, FilenameValues};
+use crate::gdt::parse_file;
+use crate::vdds;
+
+/// Convert a gdt file to opp xml with patient info
+#[derive(Args, Debug)]
+pub struct VddsArgs {
+    #[arg(short, long)]
+    pub gdt_file: PathBuf,
+
+    /// Override the path of the VDDS_MMI.ini file, optional.
+    #[arg(long)]
+    pub vdds_mmi: Option<PathBuf>,
+
+    /// The name of the BVS, must be one of the BVS in VDDS_MMI
+    #[arg(long)]
+    pub bvs: Option<String>,
+
+    /// A folder for saving images
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// File name of the saved images, without extension
+    #[arg(long, default_value_t = FilenameTemplate::vdds())]
+    pub file_name: FilenameTemplate,
+
+    /// One of TIF, JPG, PNG, DCM
+    #[arg(short, long, default_value = "JPG")]
+    pub ext: String,
+
+    /// Keep the temp file for debug
+    #[arg(long, default_value_t = false)]
+    pub keep_temp_file: bool,
+
+    /// Print the parsed GDT file and the INI file which would be sent to the BVS,
+    /// without changing VDDS_MMI or running the BVS
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+pub fn run(args: VddsArgs) -> Result<(), G2DError> {
+    let output_attr = fs::metadata(&args.output);
+    match output_attr {
+        Err(err) => {
+            return Err(G2DError::InvalidArgument(format!(
+                "Output has to be a folder: {}",
+                err
+            )));
+        }
+        Ok(attr) if !attr.is_dir() => {
+            return Err(G2DError::InvalidArgument(
+                "Output has to be a folder".to_string(),
+            ));
+        }
+        _ => {}
+    }
+
+    let ext = {
+        let upper = args.ext.to_ascii_uppercase();
+        match upper.as_ref() {
+            "TIF" | "JPG" | "PNG" | "DCM" => upper,
+            _ => {
+                return Err(G2DError::InvalidArgument(
+                    "Invalid EXT, must be one of TIF, JPG, PNG, DCM".to_string(),
+                ));
+            }
+        }
+    };
+
+    if args.dry_run {
+        return print_dry_run(&args, &ext);
+    }
+
+    let vdds_mmi_path = &args.vdds_mmi.unwrap_or_else(vdds::default_vdds_mmi_folder);
+    info!("Loading VDDS_MMI: {}", vdds_mmi_path.display());
+    let mut mmi = vdds::load_ini(vdds_mmi_path)?;
+    vdds::register_pvs(&mut mmi, vdds_mmi_path)?;
+
+    let bvs_name = vdds::select_bvs(&mmi, args.bvs.as_deref())?;
+    info!("Sending to BVS: {}", bvs_name);
+
+    let gdt_file = parse_file(&args.gdt_file)?;
+    vdds::import_patient(&mmi, &bvs_name, &gdt_file, args.keep_temp_file)?;
+
+    let info_export_exe = vdds::bvs_exe(&mmi, &bvs_name, "MMOINFEXPORT")?;
+    info!("Sending MMOINFEXPORT");
+    let vdds_inf_export_req = vdds::ImageInfoRequest {
+        pat_id: gdt_file.object_patient.patient_number.clone(),
+    };
+    let mmo_infos = vdds_inf_export_req.send_vdds_file(
+        info_export_exe,
+        bvs_name.to_string(),
+        args.keep_temp_file,
+    )?;
+    debug!("MMO Infos: {:?}", mmo_infos);
+
+    if mmo_infos.len() == 0 {
+        info!("No images found. Exit.");
+        return Ok(());
+    }
+
+    let mm_export_exe = vdds::bvs_exe(&mmi, &bvs_name, "MMOEXPORT")?;
+    info!("Sending MMOEXPORT");
+    let paths = vdds::ImagesRequest {
+        mmo_infos: mmo_infos.clone(),
+        ext,
+    }
+    .send_vdds_file(mm_export_exe, args.keep_temp_file)?;
+
+    debug!("Image paths {:?}", paths);
+    let mut values = FilenameValues::from_gdt_file(&gdt_file);
+    for (id, path) in paths.iter() {
+        let path_buf: PathBuf = path.into();
+        let ext = path_buf
+            .extension()
+            .map(|e| e.to_string_lossy().to_string())
+            .unwrap_or("".to_string());
+        let info = mmo_infos.get(id).expect("ID in MMO map");
+        values
+            .set("image_date", info.date.clone())
+            .set("image_time", info.time.clone())
+            .set("mmo_id", info.mmo_id.clone());
+        let this_path = args.file_name.unique_path(&args.output, &ext, &values);
+        debug!("Copying {} to {}", &path, this_path.display());
+        let copy_result = std::fs::rename(path, &this_path);
+        if let Err(err) = copy_result {
+            error!(
+                "Cannot copy {} to {}. {:?}",
+                &path,
+                this_path.display(),
+                err
+            );
+        }
+    }
+
+    for (key, info) in mmo_infos {
+        if !paths.contains_key(&key) {
+            error!("File not available: {}", info.mmo_id);
+        }
+    }
+
+    info!("Finished");
+
+    return Ok(());
+}
+
+fn print_dry_run(args: &VddsArgs, ext: &str) -> Result<(), G2DError> {
+    let gdt_file = parse_file(&args.gdt_file)?;
+    print_gdt_file(&args.gdt_file)?;
+
+    let vdds_mmi_path = args
+        .vdds_mmi
+        .clone()
+        .unwrap_or_else(vdds::default_vdds_mmi_folder);
+    let bvs_name = match vdds::load_ini(&vdds_mmi_path) {
+        Ok(mmi) => vdds::select_bvs(&mmi, args.bvs.as_deref())?,
+        Err(err) => {
+            println!(
+                "\nCannot read VDDS_MMI {}: {}, the BVS is not checked",
+                vdds_mmi_path.display(),
+                err
+            );
+            args.bvs.clone().unwrap_or("BVS".to_string())
+        }
+    };
+    println!("\nPATDATIMPORT request for {}:", bvs_name);
+    println!("{}", vdds::patient_import_ini(&gdt_file, &bvs_name)?);
+    println!(
+        "Would then fetch the images of patient {} (MMOINFEXPORT, MMOEXPORT) as {} into {}, named {}\n",
+        gdt_file.object_patient.patient_number,
+        ext,
+        args.output.display(),
+        args.file_name
+    );
+    print_warnings(&args.gdt_file, &NO_REQUEST_TAGS, vec![]);
+    return Ok(());
+}
@@ -0,0 +1,383 @@
+use clap::{Args, Subcommand, ValueEnum};
+
+use std::path::PathBuf;
+
+use crate::dcm_xml::{parse_dcm_as_xml, RequestTag, RequestTags};
+use crate::error::G2DError;
+use crate::person_name::PersonName;
+use crate::worklist_conversion::aetitle_folder;
+use crate::worklist_entries::{
+    edit_worklist_entry, find_worklist_entries, worklist_entries_to_csv, EntryFilter,
+    WorklistEntry, WorklistEntryEdit,
+};
+use crate::worklist_lifecycle::{
+    cancel_worklist_entries, complete_worklist_entries, expire_worklist_entries,
+    remove_worklist_entries, RemovalAction, WorklistLifecycle,
+};
+
+/// List, edit and remove the entries of a worklist folder
+#[derive(Args, Debug)]
+pub struct WorklistArgs {
+    /// The worklist folder, containing the AETitle folders
+    #[arg(short, long)]
+    pub worklist_dir: PathBuf,
+
+    /// Only entries in the folder of this AETitle
+    #[arg(short, long)]
+    pub aetitle: Option<String>,
+
+    /// Delete removed entries instead of moving them into the archive folder
+    #[arg(long)]
+    pub delete: bool,
+
+    #[command(subcommand)]
+    pub command: WorklistCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum WorklistCommand {
+    /// List the entries per AETitle folder
+    List,
+    /// Show all values of one entry
+    Show {
+        /// File name of the entry
+        file: String,
+    },
+    /// Change values of one entry
+    Edit {
+        /// File name of the entry
+        file: String,
+
+        /// Patient name as Last^First
+        #[arg(long)]
+        patient_name: Option<String>,
+
+        /// YYYYMMDD
+        #[arg(long)]
+        birth_date: Option<String>,
+
+        /// M, F or O
+        #[arg(long)]
+        sex: Option<String>,
+
+        #[arg(long)]
+        accession_number: Option<String>,
+
+        #[arg(long)]
+        procedure_description: Option<String>,
+
+        #[arg(long)]
+        modality: Option<String>,
+
+        /// YYYYMMDD
+        #[arg(long)]
+        scheduled_date: Option<String>,
+
+        /// HHMMSS or HHMM
+        #[arg(long)]
+        scheduled_time: Option<String>,
+
+        #[arg(long)]
+        step_description: Option<String>,
+    },
+    /// Delete entries by file name
+    Delete {
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// Write the list of entries to stdout or a file
+    Export {
+        #[arg(short, long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Remove entries older than --max-age-days or --days-after-scheduled
+    Expire {
+        #[arg(long)]
+        max_age_days: Option<u32>,
+
+        #[arg(long)]
+        days_after_scheduled: Option<u32>,
+    },
+    /// Remove the entries the DICOM files belong to
+    Complete {
+        #[arg(short, long, required = true)]
+        dicom_file: Vec<PathBuf>,
+
+        /// DICOM attribute the request identifier (8310) of the entries is stored in
+        #[arg(long, value_enum, default_value_t = RequestTag::AccessionNumber)]
+        request_id_tag: RequestTag,
+    },
+    /// Remove the entries matching all given options
+    Cancel {
+        #[arg(long)]
+        patient_id: Option<String>,
+
+        #[arg(long)]
+        accession_number: Option<String>,
+
+        /// File name of the entry
+        #[arg(long)]
+        file: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+}
+
+pub fn run(args: WorklistArgs) -> Result<(), G2DError> {
+    let action = if args.delete {
+        RemovalAction::Delete
+    } else {
+        RemovalAction::Archive
+    };
+    if let Some(aetitle) = &args.aetitle {
+        if !aetitle_folder(&args.worklist_dir, aetitle).is_dir() {
+            return Err(G2DError::InvalidArgument(format!(
+                "There is no folder for the AETitle {}",
+                aetitle
+            )));
+        }
+    }
+    let all_entries = EntryFilter {
+        aetitle: args.aetitle.clone(),
+        ..Default::default()
+    };
+
+    match args.command {
+        WorklistCommand::List => {
+            let entries = find_worklist_entries(&args.worklist_dir, &all_entries, None)?;
+            print_entries(&entries);
+        }
+        WorklistCommand::Show { file } => {
+            let entry = find_one_entry(&args.worklist_dir, &args.aetitle, &file)?;
+            print_entry(&entry);
+        }
+        WorklistCommand::Edit {
+            file,
+            patient_name,
+            birth_date,
+            sex,
+            accession_number,
+            procedure_description,
+            modality,
+            scheduled_date,
+            scheduled_time,
+            step_description,
+        } => {
+            let entry = find_one_entry(&args.worklist_dir, &args.aetitle, &file)?;
+            let edit = WorklistEntryEdit {
+                patient_name,
+                birth_date,
+                sex,
+                accession_number,
+                procedure_description,
+                modality,
+                scheduled_date,
+                scheduled_time,
+                step_description,
+            };
+            let entry = edit_worklist_entry(&args.worklist_dir, &entry, &edit, None)?;
+            print_entry(&entry);
+        }
+        WorklistCommand::Delete { files } => {
+            let mut count = 0;
+            for file in files {
+                let filter = EntryFilter {
+                    aetitle: args.aetitle.clone(),
+                    file: Some(file.clone()),
+                    ..Default::default()
+                };
+                let removed = remove_worklist_entries(
+                    &args.worklist_dir,
+                    &filter,
+                    RemovalAction::Delete,
+                    "deleted manually",
+                    None,
+                )?;
+                if removed.is_empty() {
+                    println!("No entry {} found", file);
+                }
+                count += removed.len();
+            }
+            println!("Finished, {} entries deleted", count);
+        }
+        WorklistCommand::Export { format, output } => {
+            let entries = find_worklist_entries(&args.worklist_dir, &all_entries, None)?;
+            let data = match format {
+                ExportFormat::Json => serde_json::to_string_pretty(&entries)?,
+                ExportFormat::Csv => worklist_entries_to_csv(&entries),
+            };
+            match output {
+                Some(path) => {
+                    std::fs::write(&path, data)?;
+                    println!("{} entries written to {}", entries.len(), path.display());
+                }
+                None => print!("{}", data),
+            }
+        }
+        WorklistCommand::Expire {
+            max_age_days,
+            days_after_scheduled,
+        } => {
+            if max_age_days.is_none() && days_after_scheduled.is_none() {
+                return Err(G2DError::InvalidArgument(
+                    "Please specify --max-age-days or --days-after-scheduled".to_string(),
+                ));
+            }
+            let lifecycle = WorklistLifecycle {
+                max_age_days,
+                days_after_scheduled,
+                action,
+                ..Default::default()
+            };
+            let removed = expire_worklist_entries(&args.worklist_dir, &lifecycle, None)?;
+            println!("Finished, {} entries removed", removed.len());
+        }
+        WorklistCommand::Complete {
+            dicom_file,
+            request_id_tag,
+        } => {
+            let request_tags = RequestTags {
+                identifier: request_id_tag,
+                ..Default::default()
+            };
+            let lifecycle = WorklistLifecycle {
+                complete_on_images: true,
+                action,
+                ..Default::default()
+            };
+            let mut count = 0;
+            for path in dicom_file {
+                let events = parse_dcm_as_xml(&path)?;
+                count += complete_worklist_entries(
+                    &args.worklist_dir,
+                    &events,
+                    &lifecycle,
+                    &[request_tags],
+                    None,
+                )?
+                .len();
+            }
+            println!("Finished, {} entries removed", count);
+        }
+        WorklistCommand::Cancel {
+            patient_id,
+            accession_number,
+            file,
+        } => {
+            let filter = EntryFilter {
+                aetitle: args.aetitle.clone(),
+                patient_id,
+                accession_number,
+                file,
+            };
+            if filter.is_empty() {
+                return Err(G2DError::InvalidArgument(
+                    "Please specify --patient-id, --accession-number or --file".to_string(),
+                ));
+            }
+            let removed = cancel_worklist_entries(&args.worklist_dir, &filter, action, None)?;
+            println!("Finished, {} entries removed", removed.len());
+        }
+    };
+    return Ok(());
+}
+
+fn find_one_entry(
+    worklist_dir: &PathBuf,
+    aetitle: &Option<String>,
+    file: &str,
+) -> Result<WorklistEntry, G2DError> {
+    let filter = EntryFilter {
+        aetitle: aetitle.clone(),
+        file: Some(file.to_string()),
+        ..Default::default()
+    };
+    let mut entries = find_worklist_entries(worklist_dir, &filter, None)?;
+    if entries.is_empty() {
+        return Err(G2DError::InvalidArgument(format!(
+            "No entry {} found",
+            file
+        )));
+    }
+    if entries.len() > 1 {
+        let paths: Vec<String> = entries
+            .iter()
+            .map(|e| format!("- {}", e.path.display()))
+            .collect();
+        return Err(G2DError::InvalidArgument(format!(
+            "Several entries {} found, please specify --aetitle:\n{}",
+            file,
+            paths.join("\n")
+        )));
+    }
+    return Ok(entries.remove(0));
+}
+
+fn print_entries(entries: &[WorklistEntry]) {
+    let mut aetitle: Option<Option<&String>> = None;
+    for entry in entries {
+        if aetitle != Some(entry.aetitle.as_ref()) {
+            aetitle = Some(entry.aetitle.as_ref());
+            let count = entries
+                .iter()
+                .filter(|e| e.aetitle == entry.aetitle)
+                .count();
+            println!();
+            println!(
+                "{} ({} entries)",
+                entry.aetitle.as_deref().unwrap_or("Worklist folder"),
+                count
+            );
+            println!(
+                "{:<30} {:<12} {:<10} {:<8} {:<10} {:<6} {:<16} {}",
+                "Patient", "ID", "Birth", "Modality", "Date", "Time", "Accession", "File"
+            );
+        }
+        println!(
+            "{:<30} {:<12} {:<10} {:<8} {:<10} {:<6} {:<16} {}",
+            PersonName::parse_dcm(&entry.patient_name).to_free_text(),
+            entry.patient_id,
+            entry.birth_date,
+            entry.modality,
+            entry.scheduled_date,
+            entry
+                .scheduled_time
+                .get(..4)
+                .unwrap_or(&entry.scheduled_time),
+            entry.accession_number,
+            entry.file_name()
+        );
+    }
+    if entries.is_empty() {
+        println!("No entries");
+    }
+}
+
+fn print_entry(entry: &WorklistEntry) {
+    let fields = [
+        ("File", entry.path.display().to_string()),
+        ("AETitle", entry.aetitle.clone().unwrap_or_default()),
+        ("Patient ID", entry.patient_id.clone()),
+        ("Patient name", entry.patient_name.clone()),
+        ("Birth date", entry.birth_date.clone()),
+        ("Sex", entry.sex.clone()),
+        ("Accession number", entry.accession_number.clone()),
+        ("Referring physician", entry.referring_physician.clone()),
+        ("Procedure", entry.procedure_description.clone()),
+        ("Modality", entry.modality.clone()),
+        ("Scheduled date", entry.scheduled_date.clone()),
+        ("Scheduled time", entry.scheduled_time.clone()),
+        ("Step", entry.step_description.clone()),
+        ("StudyInstanceUID", entry.study_uid.clone()),
+    ];
+    for (label, value) in fields {
+        println!("{:<20} {}", format!("{}:", label), value);
+    }
+}
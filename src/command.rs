@@ -1,9 +1,11 @@
 use std::ffi::OsStr;
-use std::io::Write;
+use std::io::{ErrorKind, Read, Write};
 use std::path::PathBuf;
 use std::process::Command;
-use std::process::Output;
+use std::process::{Output, Stdio};
 use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 #[cfg(target_os = "windows")]
 use std::os::windows::process::CommandExt;
@@ -14,6 +16,26 @@ const CREATE_NO_WINDOW: u32 = 0x08000000;
 pub enum ChildOutput {
     Log(String),
     Exit(std::process::ExitStatus),
+    /// The preview of a received study was written
+    Preview {
+        contact_sheet: Option<PathBuf>,
+        html: PathBuf,
+    },
+}
+
+impl From<String> for ChildOutput {
+    fn from(message: String) -> Self {
+        ChildOutput::Log(message)
+    }
+}
+
+/// Sends `message` to the log, or prints it without one
+pub fn log<T: From<String>>(log_sender: Option<&mpsc::Sender<T>>, message: String) {
+    if let Some(l) = log_sender {
+        _ = l.send(T::from(message));
+    } else {
+        println!("{}", message);
+    }
 }
 
 #[cfg(target_os = "linux")]
@@ -100,6 +122,92 @@ pub fn exec_command_with_env<I, S>(
     log_sender: Option<&mpsc::Sender<String>>,
     envs: Vec<(String, PathBuf)>,
 ) -> Result<Output, std::io::Error>
+where
+    I: IntoIterator<Item = S> + Clone,
+    S: AsRef<OsStr>,
+{
+    let mut command = prepare_command(command, arguments, log_sender, envs);
+    let output = command.output()?;
+
+    if print {
+        std::io::stdout().write_all(&output.stdout).unwrap();
+        std::io::stderr().write_all(&output.stderr).unwrap();
+    }
+    return Ok(output);
+}
+
+/// Like `exec_command_with_env`, but the program is killed and a `TimedOut`
+/// error returned if it doesn't exit within `timeout`
+pub fn exec_command_with_timeout<I, S>(
+    command: &str,
+    arguments: I,
+    log_sender: Option<&mpsc::Sender<String>>,
+    envs: Vec<(String, PathBuf)>,
+    timeout: Duration,
+) -> Result<Output, std::io::Error>
+where
+    I: IntoIterator<Item = S> + Clone,
+    S: AsRef<OsStr>,
+{
+    let mut command = prepare_command(command, arguments, log_sender, envs);
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = command.spawn()?;
+    // Read both pipes while waiting, a full pipe would block the program
+    let stdout_reader = child.stdout.take().map(read_to_end_in_thread);
+    let stderr_reader = child.stderr.take().map(read_to_end_in_thread);
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() > timeout {
+            _ = child.kill();
+            _ = child.wait();
+            return Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                format!("killed after {} seconds", timeout.as_secs()),
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    // A background process started by the program can keep the pipes open,
+    // its output is not waited for past the timeout
+    let deadline = (started + timeout).max(Instant::now() + Duration::from_millis(500));
+    let join = |reader: Option<JoinHandle<Vec<u8>>>| {
+        let reader = reader?;
+        while !reader.is_finished() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        if !reader.is_finished() {
+            return None;
+        }
+        return reader.join().ok();
+    };
+    return Ok(Output {
+        status,
+        stdout: join(stdout_reader).unwrap_or_default(),
+        stderr: join(stderr_reader).unwrap_or_default(),
+    });
+}
+
+fn read_to_end_in_thread<R: Read + Send + 'static>(mut reader: R) -> JoinHandle<Vec<u8>> {
+    return std::thread::spawn(move || {
+        let mut data = vec![];
+        _ = reader.read_to_end(&mut data);
+        data
+    });
+}
+
+fn prepare_command<I, S>(
+    command: &str,
+    arguments: I,
+    log_sender: Option<&mpsc::Sender<String>>,
+    envs: Vec<(String, PathBuf)>,
+) -> Command
 where
     I: IntoIterator<Item = S> + Clone,
     S: AsRef<OsStr>,
@@ -132,12 +240,5 @@ where
     command.args(arguments);
     #[cfg(target_os = "windows")]
     command.creation_flags(CREATE_NO_WINDOW);
-
-    let output = command.output()?;
-
-    if print {
-        std::io::stdout().write_all(&output.stdout).unwrap();
-        std::io::stderr().write_all(&output.stderr).unwrap();
-    }
-    return Ok(output);
+    return command;
 }
@@ -0,0 +1,112 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::dcm_xml::{set_specific_character_set, set_uid_root, valid_uid_components};
+use crate::error::G2DError;
+
+/// Name of the config file next to the executables
+pub const CONFIG_FILE_NAME: &str = "gdt2dicom.json";
+
+/// Longest UID root, so at least 23 digits of the UUID follow it
+const MAX_UID_ROOT_LENGTH: usize = 40;
+
+/// Defined terms of SpecificCharacterSet without code extensions
+const CHARACTER_SETS: [&str; 12] = [
+    "ISO_IR 6",
+    "ISO_IR 100",
+    "ISO_IR 101",
+    "ISO_IR 109",
+    "ISO_IR 110",
+    "ISO_IR 126",
+    "ISO_IR 127",
+    "ISO_IR 138",
+    "ISO_IR 144",
+    "ISO_IR 148",
+    "ISO_IR 192",
+    "GB18030",
+];
+
+/// Defaults shared by the gdt2dicom commands, gdt2dicomd and the GUI
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Config {
+    /// ScheduledStationAETitle of the worklist files written by `convert`
+    #[serde(default)]
+    pub aetitle: Option<String>,
+    /// Root of the generated UIDs instead of 2.25
+    #[serde(default)]
+    pub uid_root: Option<String>,
+    /// SpecificCharacterSet of the data sets written from GDT files
+    #[serde(default)]
+    pub charset: Option<String>,
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), G2DError> {
+        if let Some(aetitle) = &self.aetitle {
+            if aetitle.trim().is_empty() || aetitle.len() > 16 || aetitle.contains('\\') {
+                return Err(G2DError::InvalidArgument(format!(
+                    "Invalid AE title \"{}\", it must have 1 to 16 characters",
+                    aetitle
+                )));
+            }
+        }
+        if let Some(root) = &self.uid_root {
+            if !valid_uid_components(root) || root.len() > MAX_UID_ROOT_LENGTH {
+                return Err(G2DError::InvalidArgument(format!(
+                    "Invalid UID root \"{}\", it must be numbers separated by dots without leading zeros and at most {} characters",
+                    root, MAX_UID_ROOT_LENGTH
+                )));
+            }
+        }
+        if let Some(charset) = &self.charset {
+            if !CHARACTER_SETS.contains(&charset.as_str()) {
+                return Err(G2DError::InvalidArgument(format!(
+                    "Unknown character set \"{}\", please use one of {}",
+                    charset,
+                    CHARACTER_SETS.join(", ")
+                )));
+            }
+        }
+        return Ok(());
+    }
+
+    /// Validates the config and sets the UID root and character set for the
+    /// rest of the process
+    pub fn apply(&self) -> Result<(), G2DError> {
+        self.validate()?;
+        if let Some(root) = &self.uid_root {
+            set_uid_root(root.clone());
+        }
+        if let Some(charset) = &self.charset {
+            set_specific_character_set(charset.clone());
+        }
+        return Ok(());
+    }
+}
+
+/// `gdt2dicom.json` next to the executable
+pub fn config_file_path() -> Result<PathBuf, std::io::Error> {
+    let mut current_path = std::env::current_exe()?;
+    current_path.set_file_name(CONFIG_FILE_NAME);
+    return Ok(current_path);
+}
+
+/// Reads the config from `path`, without a path from `gdt2dicom.json` next to
+/// the executable if it exists
+pub fn read_config(path: Option<&Path>) -> Result<Config, G2DError> {
+    let path = match path {
+        Some(p) => p.to_path_buf(),
+        None => {
+            let default_path = config_file_path()?;
+            if !default_path.is_file() {
+                return Ok(Config::default());
+            }
+            default_path
+        }
+    };
+    let data = std::fs::read(&path)?;
+    return serde_json::from_slice::<Config>(&data).map_err(|err| {
+        G2DError::InvalidArgument(format!("Cannot read {}: {}", path.display(), err))
+    });
+}
@@ -0,0 +1,681 @@
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use base64::prelude::*;
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, GrayImage, ImageFormat, RgbImage};
+use xml::reader::XmlEvent;
+
+use crate::command::exec_command;
+use crate::dcm_xml::{build_xml_tree, DCMImageFormat, XmlNode};
+use crate::error::G2DError;
+
+const IMPLICIT_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2";
+const EXPLICIT_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2.1";
+const DEFLATED_EXPLICIT_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2.1.99";
+const JPEG_BASELINE: &str = "1.2.840.10008.1.2.4.50";
+
+/// Attributes of the Image Pixel module needed to render a frame
+#[derive(Debug, Clone)]
+struct PixelModule {
+    transfer_syntax: String,
+    rows: usize,
+    columns: usize,
+    samples_per_pixel: usize,
+    photometric_interpretation: String,
+    bits_allocated: usize,
+    bits_stored: usize,
+    pixel_representation: usize,
+    planar_configuration: usize,
+    number_of_frames: usize,
+    rescale_slope: f64,
+    rescale_intercept: f64,
+    /// Center and width of the first VOI window
+    window: Option<(f64, f64)>,
+}
+
+impl PixelModule {
+    fn read(tree: &XmlNode) -> Result<PixelModule, G2DError> {
+        let number = |tag: &str| -> Option<f64> {
+            tree.child_body(tag)
+                .and_then(|v| v.split('\\').next().and_then(|v| v.trim().parse().ok()))
+        };
+        let required = |tag: &str, name: &str| -> Result<usize, G2DError> {
+            match number(tag) {
+                Some(n) if n >= 0.0 => Ok(n as usize),
+                _ => Err(unsupported(format!("{} is missing", name))),
+            }
+        };
+        let window = match (number("0028,1050"), number("0028,1051")) {
+            (Some(center), Some(width)) if width >= 1.0 => Some((center, width)),
+            _ => None,
+        };
+        return Ok(PixelModule {
+            transfer_syntax: tree
+                .child_body("0002,0010")
+                .unwrap_or(IMPLICIT_LITTLE_ENDIAN.to_string()),
+            rows: required("0028,0010", "Rows")?,
+            columns: required("0028,0011", "Columns")?,
+            samples_per_pixel: number("0028,0002").unwrap_or(1.0) as usize,
+            photometric_interpretation: tree
+                .child_body("0028,0004")
+                .unwrap_or("MONOCHROME2".to_string()),
+            bits_allocated: required("0028,0100", "BitsAllocated")?,
+            bits_stored: number("0028,0101")
+                .map(|b| b as usize)
+                .unwrap_or(required("0028,0100", "BitsAllocated")?),
+            pixel_representation: number("0028,0103").unwrap_or(0.0) as usize,
+            planar_configuration: number("0028,0006").unwrap_or(0.0) as usize,
+            number_of_frames: number("0028,0008").unwrap_or(1.0).max(1.0) as usize,
+            rescale_slope: number("0028,1053").unwrap_or(1.0),
+            rescale_intercept: number("0028,1052").unwrap_or(0.0),
+            window,
+        });
+    }
+
+    fn is_monochrome(&self) -> bool {
+        return self.photometric_interpretation.starts_with("MONOCHROME");
+    }
+}
+
+/// The frames of the DICOM file at `dcm_path`, whose dataset `events` was
+/// read with `parse_dcm_as_xml_with_pixel_data`. Pixel data `decode_frames`
+/// doesn't support (e.g. JPEG Lossless, JPEG-LS, RLE, JPEG 2000) is rendered
+/// with dcmj2pnm.
+pub fn read_frames(dcm_path: &Path, events: &Vec<XmlEvent>) -> Result<Vec<DynamicImage>, G2DError> {
+    let err = match decode_frames(events) {
+        Ok(frames) => return Ok(frames),
+        Err(err) => err,
+    };
+    return render_frames_with_dcmj2pnm(dcm_path).map_err(|dcmj2pnm_err| {
+        let message = |e: G2DError| match e {
+            G2DError::IoError(io_err) => io_err.to_string(),
+            e => e.to_string(),
+        };
+        G2DError::IoError(Error::new(
+            ErrorKind::Other,
+            format!("{}, dcmj2pnm: {}", message(err), message(dcmj2pnm_err)),
+        ))
+    });
+}
+
+/// Renders all frames with dcmj2pnm into a temporary folder and reads them
+fn render_frames_with_dcmj2pnm(dcm_path: &Path) -> Result<Vec<DynamicImage>, G2DError> {
+    let temp_dir = tempfile::tempdir()?;
+    let output_base = temp_dir.path().join("frame");
+    let output = exec_command(
+        "dcmj2pnm",
+        vec![
+            OsStr::new("--write-png"),
+            OsStr::new("--all-frames"),
+            dcm_path.as_os_str(),
+            output_base.as_os_str(),
+        ],
+        false,
+        None,
+    )?;
+    if !output.status.success() {
+        return Err(G2DError::IoError(Error::new(
+            ErrorKind::Other,
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        )));
+    }
+    // The files are named frame.[frame number].png
+    let mut paths = vec![];
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        let frame_number = path
+            .file_stem()
+            .and_then(|s| Path::new(s).extension())
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.parse::<usize>().ok())
+            .unwrap_or(0);
+        paths.push((frame_number, path));
+    }
+    paths.sort();
+    let mut frames = vec![];
+    for (_, path) in paths {
+        frames.push(image::open(&path)?);
+    }
+    if frames.is_empty() {
+        return Err(unsupported("dcmj2pnm wrote no frames".to_string()));
+    }
+    return Ok(frames);
+}
+
+/// Decodes the frames of a dataset read with `parse_dcm_as_xml_with_pixel_data`.
+///
+/// Supported are uncompressed little endian pixel data (MONOCHROME1/2 with
+/// 8 or 16 bits, RGB and YBR_FULL with 8 bits) and JPEG Baseline. Monochrome
+/// frames get the rescale and the first VOI window applied, without a window
+/// the range of the frame is stretched to 8 bits.
+pub fn decode_frames(events: &Vec<XmlEvent>) -> Result<Vec<DynamicImage>, G2DError> {
+    let tree = build_xml_tree(events);
+    let module = PixelModule::read(&tree)?;
+    let Some(pixel_data) = tree.child("7fe0,0010") else {
+        return Err(unsupported("PixelData is missing".to_string()));
+    };
+
+    if pixel_data.kind == "pixel-sequence" {
+        if module.transfer_syntax != JPEG_BASELINE {
+            return Err(unsupported(format!(
+                "Transfer syntax {}",
+                module.transfer_syntax
+            )));
+        }
+        let mut frames = vec![];
+        for data in jpeg_frames(pixel_data, module.number_of_frames)? {
+            let image = image::load_from_memory_with_format(&data, ImageFormat::Jpeg)?;
+            frames.push(match module.photometric_interpretation.as_str() {
+                "MONOCHROME1" => {
+                    let mut gray = image.to_luma8();
+                    image::imageops::invert(&mut gray);
+                    DynamicImage::ImageLuma8(gray)
+                }
+                _ => image,
+            });
+        }
+        return Ok(frames);
+    }
+
+    if ![
+        IMPLICIT_LITTLE_ENDIAN,
+        EXPLICIT_LITTLE_ENDIAN,
+        DEFLATED_EXPLICIT_LITTLE_ENDIAN,
+    ]
+    .contains(&module.transfer_syntax.as_str())
+    {
+        return Err(unsupported(format!(
+            "Transfer syntax {}",
+            module.transfer_syntax
+        )));
+    }
+    if module.bits_allocated != 8 && module.bits_allocated != 16 {
+        return Err(unsupported(format!(
+            "BitsAllocated {}",
+            module.bits_allocated
+        )));
+    }
+    let data = decode_base64(&pixel_data.body)?;
+    let frame_size =
+        module.rows * module.columns * module.samples_per_pixel * module.bits_allocated / 8;
+    if frame_size == 0 || data.len() < frame_size * module.number_of_frames {
+        return Err(unsupported(format!(
+            "PixelData has {} bytes, expected {}",
+            data.len(),
+            frame_size * module.number_of_frames
+        )));
+    }
+    let mut frames = vec![];
+    for frame in data.chunks_exact(frame_size).take(module.number_of_frames) {
+        frames.push(if module.is_monochrome() {
+            DynamicImage::ImageLuma8(monochrome_frame(frame, &module))
+        } else {
+            DynamicImage::ImageRgb8(color_frame(frame, &module)?)
+        });
+    }
+    return Ok(frames);
+}
+
+/// Writes each frame to `[output_dir]/[prefix][frame number].[png|jpg]`,
+/// frames are numbered from 0. Returns the written paths.
+pub fn write_frames(
+    frames: &[DynamicImage],
+    output_dir: &Path,
+    prefix: &str,
+    format: &DCMImageFormat,
+) -> Result<Vec<PathBuf>, G2DError> {
+    let mut paths = vec![];
+    for (index, frame) in frames.iter().enumerate() {
+        let path = output_dir.join(format!("{}{}.{}", prefix, index, format.extension()));
+        write_frame(frame, &path, format)?;
+        paths.push(path);
+    }
+    return Ok(paths);
+}
+
+pub fn write_frame(
+    frame: &DynamicImage,
+    path: &Path,
+    format: &DCMImageFormat,
+) -> Result<(), G2DError> {
+    match format {
+        DCMImageFormat::Png => frame.save_with_format(path, ImageFormat::Png)?,
+        DCMImageFormat::Jpeg => {
+            let encoder = JpegEncoder::new_with_quality(File::create(path)?, 90);
+            frame.write_with_encoder(encoder)?;
+        }
+    }
+    return Ok(());
+}
+
+/// The JPEG streams of the fragments, the basic offset table (first item)
+/// is skipped. A frame can span several fragments, a new frame starts
+/// with a fragment beginning with the SOI marker.
+fn jpeg_frames(pixel_data: &XmlNode, number_of_frames: usize) -> Result<Vec<Vec<u8>>, G2DError> {
+    let mut frames: Vec<Vec<u8>> = vec![];
+    for item in pixel_data.children.iter().skip(1) {
+        let fragment = decode_base64(&item.body)?;
+        if frames.is_empty()
+            || (fragment.starts_with(&[0xff, 0xd8]) && frames.len() < number_of_frames)
+        {
+            frames.push(fragment);
+        } else if let Some(last) = frames.last_mut() {
+            last.extend(fragment);
+        }
+    }
+    if frames.is_empty() {
+        return Err(unsupported("PixelData has no fragments".to_string()));
+    }
+    return Ok(frames);
+}
+
+fn monochrome_frame(data: &[u8], module: &PixelModule) -> GrayImage {
+    let bytes = module.bits_allocated / 8;
+    let bits_stored = module.bits_stored.clamp(1, module.bits_allocated);
+    let mask: u32 = if bits_stored >= 32 {
+        u32::MAX
+    } else {
+        (1 << bits_stored) - 1
+    };
+    let values: Vec<f64> = data
+        .chunks_exact(bytes)
+        .map(|c| {
+            let raw = if bytes == 1 {
+                c[0] as u32
+            } else {
+                u16::from_le_bytes([c[0], c[1]]) as u32
+            } & mask;
+            let stored = if module.pixel_representation == 1 && raw >> (bits_stored - 1) & 1 == 1 {
+                raw as i64 - (1i64 << bits_stored)
+            } else {
+                raw as i64
+            };
+            stored as f64 * module.rescale_slope + module.rescale_intercept
+        })
+        .collect();
+
+    // Linear VOI LUT function of PS3.3 C.11.2.1.2.1
+    let (low, high) = match module.window {
+        Some((center, width)) => (
+            center - 0.5 - (width - 1.0) / 2.0,
+            center - 0.5 + (width - 1.0) / 2.0,
+        ),
+        None if bits_stored <= 8
+            && module.rescale_slope == 1.0
+            && module.rescale_intercept == 0.0
+            && module.pixel_representation == 0 =>
+        {
+            (0.0, 255.0)
+        }
+        None => {
+            let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+            let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            (min, max)
+        }
+    };
+    let invert = module.photometric_interpretation == "MONOCHROME1";
+    let pixels = values
+        .iter()
+        .map(|v| {
+            let scaled = if high <= low {
+                if *v > low {
+                    255.0
+                } else {
+                    0.0
+                }
+            } else {
+                ((v - low) / (high - low) * 255.0).clamp(0.0, 255.0)
+            };
+            let gray = scaled.round() as u8;
+            if invert {
+                255 - gray
+            } else {
+                gray
+            }
+        })
+        .collect();
+    return GrayImage::from_raw(module.columns as u32, module.rows as u32, pixels).unwrap();
+}
+
+fn color_frame(data: &[u8], module: &PixelModule) -> Result<RgbImage, G2DError> {
+    if module.samples_per_pixel != 3 || module.bits_allocated != 8 {
+        return Err(unsupported(format!(
+            "{} with {} samples of {} bits",
+            module.photometric_interpretation, module.samples_per_pixel, module.bits_allocated
+        )));
+    }
+    let pixel_count = module.rows * module.columns;
+    let sample = |pixel: usize, channel: usize| -> u8 {
+        if module.planar_configuration == 1 {
+            data[channel * pixel_count + pixel]
+        } else {
+            data[pixel * 3 + channel]
+        }
+    };
+    let mut pixels = Vec::with_capacity(pixel_count * 3);
+    for pixel in 0..pixel_count {
+        let (a, b, c) = (sample(pixel, 0), sample(pixel, 1), sample(pixel, 2));
+        match module.photometric_interpretation.as_str() {
+            "RGB" => pixels.extend([a, b, c]),
+            "YBR_FULL" => {
+                let (y, cb, cr) = (a as f64, b as f64 - 128.0, c as f64 - 128.0);
+                pixels.extend([
+                    (y + 1.402 * cr).round().clamp(0.0, 255.0) as u8,
+                    (y - 0.344136 * cb - 0.714136 * cr)
+                        .round()
+                        .clamp(0.0, 255.0) as u8,
+                    (y + 1.772 * cb).round().clamp(0.0, 255.0) as u8,
+                ]);
+            }
+            other => {
+                return Err(unsupported(format!("PhotometricInterpretation {}", other)));
+            }
+        }
+    }
+    return Ok(RgbImage::from_raw(module.columns as u32, module.rows as u32, pixels).unwrap());
+}
+
+fn decode_base64(body: &str) -> Result<Vec<u8>, G2DError> {
+    let cleaned: String = body.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+    return Ok(BASE64_STANDARD.decode(cleaned)?);
+}
+
+fn unsupported(message: String) -> G2DError {
+    return G2DError::IoError(Error::new(
+        ErrorKind::Other,
+        format!("Cannot export image: {}", message),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xml::reader::EventReader;
+
+    /// Events like dcm2xml's output for the elements and pixel data
+    fn dataset(elements: &[(&str, &str)], pixel_data: &str) -> Vec<XmlEvent> {
+        let mut xml = String::from("<file-format><data-set>");
+        for (tag, value) in elements {
+            xml.push_str(&format!("<element tag=\"{}\">{}</element>", tag, value));
+        }
+        xml.push_str(pixel_data);
+        xml.push_str("</data-set></file-format>");
+        return EventReader::new(xml.as_bytes())
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+    }
+
+    fn native_pixel_data(data: &[u8]) -> String {
+        return format!(
+            "<element tag=\"7fe0,0010\" binary=\"base64\">{}</element>",
+            BASE64_STANDARD.encode(data)
+        );
+    }
+
+    fn pixel_sequence(fragments: &[Vec<u8>]) -> String {
+        let mut xml = String::from("<pixel-sequence tag=\"7fe0,0010\"><pixel-item></pixel-item>");
+        for fragment in fragments {
+            xml.push_str(&format!(
+                "<pixel-item binary=\"base64\">{}</pixel-item>",
+                BASE64_STANDARD.encode(fragment)
+            ));
+        }
+        xml.push_str("</pixel-sequence>");
+        return xml;
+    }
+
+    fn gray_module(rows: usize, columns: usize, bits: usize) -> PixelModule {
+        return PixelModule {
+            transfer_syntax: EXPLICIT_LITTLE_ENDIAN.to_string(),
+            rows,
+            columns,
+            samples_per_pixel: 1,
+            photometric_interpretation: "MONOCHROME2".to_string(),
+            bits_allocated: bits,
+            bits_stored: bits,
+            pixel_representation: 0,
+            planar_configuration: 0,
+            number_of_frames: 1,
+            rescale_slope: 1.0,
+            rescale_intercept: 0.0,
+            window: None,
+        };
+    }
+
+    fn color_module(photometric_interpretation: &str, planar_configuration: usize) -> PixelModule {
+        return PixelModule {
+            samples_per_pixel: 3,
+            photometric_interpretation: photometric_interpretation.to_string(),
+            planar_configuration,
+            ..gray_module(1, 2, 8)
+        };
+    }
+
+    fn words(values: &[u16]) -> Vec<u8> {
+        return values.iter().flat_map(|v| v.to_le_bytes()).collect();
+    }
+
+    fn jpeg(width: u32, height: u32, value: u8) -> Vec<u8> {
+        let mut data = vec![];
+        let image = GrayImage::from_pixel(width, height, image::Luma([value]));
+        JpegEncoder::new_with_quality(&mut data, 100)
+            .encode_image(&image)
+            .unwrap();
+        return data;
+    }
+
+    #[test]
+    fn decode_frames_reads_8_bit_monochrome_frames() {
+        let events = dataset(
+            &[
+                ("0002,0010", EXPLICIT_LITTLE_ENDIAN),
+                ("0028,0010", "1"),
+                ("0028,0011", "2"),
+                ("0028,0100", "8"),
+                ("0028,0008", "2"),
+            ],
+            &native_pixel_data(&[0, 64, 128, 255]),
+        );
+        let frames = decode_frames(&events).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].to_luma8().into_raw(), vec![0, 64]);
+        assert_eq!(frames[1].to_luma8().into_raw(), vec![128, 255]);
+    }
+
+    #[test]
+    fn decode_frames_reads_16_bit_rgb_and_defaults() {
+        // Without a transfer syntax implicit little endian is assumed
+        let events = dataset(
+            &[
+                ("0028,0010", "1"),
+                ("0028,0011", "2"),
+                ("0028,0100", "16"),
+                ("0028,0101", "12"),
+            ],
+            &native_pixel_data(&words(&[0, 4095])),
+        );
+        let frames = decode_frames(&events).unwrap();
+        assert_eq!(frames[0].to_luma8().into_raw(), vec![0, 255]);
+
+        let events = dataset(
+            &[
+                ("0028,0010", "1"),
+                ("0028,0011", "1"),
+                ("0028,0002", "3"),
+                ("0028,0004", "RGB"),
+                ("0028,0100", "8"),
+            ],
+            &native_pixel_data(&[10, 20, 30]),
+        );
+        let frames = decode_frames(&events).unwrap();
+        assert_eq!(frames[0].to_rgb8().into_raw(), vec![10, 20, 30]);
+    }
+
+    #[test]
+    fn decode_frames_rejects_unsupported_pixel_data() {
+        let elements = [
+            ("0002,0010", EXPLICIT_LITTLE_ENDIAN),
+            ("0028,0010", "2"),
+            ("0028,0011", "2"),
+            ("0028,0100", "8"),
+        ];
+        // Too short for 2x2 pixels
+        let events = dataset(&elements, &native_pixel_data(&[1, 2, 3]));
+        assert!(decode_frames(&events).is_err());
+        // No pixel data
+        assert!(decode_frames(&dataset(&elements, "")).is_err());
+        // No rows
+        let events = dataset(&elements[2..], &native_pixel_data(&[1, 2, 3, 4]));
+        assert!(decode_frames(&events).is_err());
+        // RLE is left to dcmj2pnm
+        let mut rle = elements;
+        rle[0] = ("0002,0010", "1.2.840.10008.1.2.5");
+        let events = dataset(&rle, &native_pixel_data(&[1, 2, 3, 4]));
+        assert!(decode_frames(&events).is_err());
+        let events = dataset(&rle, &pixel_sequence(&[vec![1, 2, 3, 4]]));
+        assert!(decode_frames(&events).is_err());
+        // 32 bits
+        let mut bits = elements;
+        bits[3] = ("0028,0100", "32");
+        let events = dataset(&bits, &native_pixel_data(&[0; 16]));
+        assert!(decode_frames(&events).is_err());
+    }
+
+    #[test]
+    fn decode_frames_decodes_jpeg_baseline() {
+        let elements = [
+            ("0002,0010", JPEG_BASELINE),
+            ("0028,0010", "8"),
+            ("0028,0011", "8"),
+            ("0028,0100", "8"),
+            ("0028,0008", "2"),
+            ("0028,0004", "MONOCHROME2"),
+        ];
+        let events = dataset(
+            &elements,
+            &pixel_sequence(&[jpeg(8, 8, 200), jpeg(8, 8, 50)]),
+        );
+        let frames = decode_frames(&events).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].width(), frames[0].height()), (8, 8));
+        let first = frames[0].to_luma8();
+        let second = frames[1].to_luma8();
+        assert!(first.pixels().all(|p| p.0[0].abs_diff(200) <= 2));
+        assert!(second.pixels().all(|p| p.0[0].abs_diff(50) <= 2));
+
+        let mut monochrome1 = elements;
+        monochrome1[5] = ("0028,0004", "MONOCHROME1");
+        let events = dataset(&monochrome1, &pixel_sequence(&[jpeg(8, 8, 200)]));
+        let frames = decode_frames(&events).unwrap();
+        assert!(frames[0]
+            .to_luma8()
+            .pixels()
+            .all(|p| p.0[0].abs_diff(55) <= 2));
+    }
+
+    #[test]
+    fn monochrome_frame_applies_rescale_and_window() {
+        let mut module = gray_module(1, 4, 16);
+        module.window = Some((1000.0, 200.0));
+        let frame = monochrome_frame(&words(&[800, 1000, 1200, 900]), &module);
+        assert_eq!(frame.into_raw(), vec![0, 128, 255, 0]);
+
+        // The window is applied to the rescaled values
+        module.rescale_slope = 2.0;
+        module.rescale_intercept = -1000.0;
+        let frame = monochrome_frame(&words(&[500, 1000, 1100, 950]), &module);
+        assert_eq!(frame.into_raw(), vec![0, 128, 255, 0]);
+    }
+
+    #[test]
+    fn monochrome_frame_stretches_without_window() {
+        // 8 bits are used as they are
+        let frame = monochrome_frame(&[0, 10, 20], &gray_module(1, 3, 8));
+        assert_eq!(frame.into_raw(), vec![0, 10, 20]);
+        // 16 bits are stretched to the range of the frame
+        let frame = monochrome_frame(&words(&[100, 150, 200]), &gray_module(1, 3, 16));
+        assert_eq!(frame.into_raw(), vec![0, 128, 255]);
+        // A flat frame doesn't divide by zero
+        let frame = monochrome_frame(&words(&[7, 7]), &gray_module(1, 2, 16));
+        assert_eq!(frame.into_raw(), vec![0, 0]);
+    }
+
+    #[test]
+    fn monochrome_frame_inverts_monochrome1() {
+        let mut module = gray_module(1, 3, 8);
+        module.photometric_interpretation = "MONOCHROME1".to_string();
+        let frame = monochrome_frame(&[0, 100, 255], &module);
+        assert_eq!(frame.into_raw(), vec![255, 155, 0]);
+    }
+
+    #[test]
+    fn monochrome_frame_reads_signed_pixels() {
+        let mut module = gray_module(1, 4, 16);
+        module.bits_stored = 12;
+        module.pixel_representation = 1;
+        // 0xffff is masked to 12 bits, so it's -1 like 0x0fff
+        let frame = monochrome_frame(&words(&[0x800, 0xffff, 0, 0x7ff]), &module);
+        assert_eq!(frame.into_raw(), vec![0, 127, 128, 255]);
+
+        // Signed 8 bit pixels are stretched as well
+        let mut module = gray_module(1, 2, 8);
+        module.pixel_representation = 1;
+        let frame = monochrome_frame(&[0x80, 0x7f], &module);
+        assert_eq!(frame.into_raw(), vec![0, 255]);
+    }
+
+    #[test]
+    fn color_frame_converts_ybr_full() {
+        let frame =
+            color_frame(&[128, 128, 128, 76, 85, 255], &color_module("YBR_FULL", 0)).unwrap();
+        assert_eq!(frame.into_raw(), vec![128, 128, 128, 254, 0, 0]);
+    }
+
+    #[test]
+    fn color_frame_reads_planar_configuration() {
+        let data = [1, 2, 3, 4, 5, 6];
+        let frame = color_frame(&data, &color_module("RGB", 0)).unwrap();
+        assert_eq!(frame.into_raw(), vec![1, 2, 3, 4, 5, 6]);
+        let frame = color_frame(&data, &color_module("RGB", 1)).unwrap();
+        assert_eq!(frame.into_raw(), vec![1, 3, 5, 2, 4, 6]);
+    }
+
+    #[test]
+    fn color_frame_rejects_unsupported_formats() {
+        let data = [0; 12];
+        assert!(color_frame(&data, &color_module("PALETTE COLOR", 0)).is_err());
+        let module = PixelModule {
+            bits_allocated: 16,
+            ..color_module("RGB", 0)
+        };
+        assert!(color_frame(&data, &module).is_err());
+    }
+
+    #[test]
+    fn jpeg_frames_joins_fragments_of_a_frame() {
+        let soi = |n: u8| vec![0xff, 0xd8, n];
+        let tree = build_xml_tree(&dataset(
+            &[],
+            &pixel_sequence(&[soi(1), vec![2], soi(3), soi(4)]),
+        ));
+        let pixel_data = tree.child("7fe0,0010").unwrap();
+
+        let frames = jpeg_frames(pixel_data, 2).unwrap();
+        assert_eq!(
+            frames,
+            vec![
+                vec![0xff, 0xd8, 1, 2],
+                soi(3).into_iter().chain(soi(4)).collect()
+            ]
+        );
+        let frames = jpeg_frames(pixel_data, 3).unwrap();
+        assert_eq!(frames, vec![vec![0xff, 0xd8, 1, 2], soi(3), soi(4)]);
+
+        let tree = build_xml_tree(&dataset(&[], &pixel_sequence(&[])));
+        assert!(jpeg_frames(tree.child("7fe0,0010").unwrap(), 1).is_err());
+    }
+}
@@ -0,0 +1,363 @@
+use std::path::Path;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use xml::escape::escape_str_pcdata;
+use xml::reader::{EventReader, XmlEvent};
+
+use crate::dcm_xml::{build_xml_tree, generate_uid, xml_get_element_body, XmlNode};
+use crate::error::G2DError;
+use crate::gdt::{GdtFile, GdtTestResult};
+
+/// A coded concept: CodeValue, CodingSchemeDesignator and CodeMeaning
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SrCode {
+    pub value: String,
+    pub scheme: String,
+    pub meaning: String,
+}
+
+/// A NUM content item of a structured report
+#[derive(Debug, Clone)]
+pub struct SrMeasurement {
+    pub concept: SrCode,
+    pub value: String,
+    /// UCUM unit, None for unitless values ("1")
+    pub unit: Option<SrCode>,
+}
+
+fn code(item: &XmlNode) -> SrCode {
+    return SrCode {
+        value: item.child_body("0008,0100").unwrap_or_default(),
+        scheme: item.child_body("0008,0102").unwrap_or_default(),
+        meaning: item.child_body("0008,0104").unwrap_or_default(),
+    };
+}
+
+/// Collects the NUM content items of the content tree (ContentSequence),
+/// including the ones nested in containers like TID 1500 measurement groups
+pub fn xml_get_sr_measurements(events: &Vec<XmlEvent>) -> Vec<SrMeasurement> {
+    let tree = build_xml_tree(events);
+    let mut result = vec![];
+    collect_measurements(&tree, &mut result);
+    return result;
+}
+
+fn collect_measurements(node: &XmlNode, result: &mut Vec<SrMeasurement>) {
+    for child in &node.children {
+        if child.kind == "sequence" && child.tag == "0040,a730" {
+            for item in &child.children {
+                let concept = item.first_item("0040,a043").map(code).unwrap_or_default();
+                match item.child_body("0040,a040").as_deref() {
+                    Some("NUM") => {
+                        let measured = item.first_item("0040,a300");
+                        let value = measured
+                            .and_then(|m| m.child_body("0040,a30a"))
+                            .unwrap_or_default();
+                        if value.is_empty() {
+                            continue;
+                        }
+                        let unit = measured
+                            .and_then(|m| m.first_item("0040,08ea"))
+                            .map(code)
+                            .filter(|u| !u.value.is_empty() && u.value != "1");
+                        result.push(SrMeasurement {
+                            concept,
+                            value,
+                            unit,
+                        });
+                    }
+                    _ => {
+                        // Containers (measurement groups) and content items with children
+                        collect_measurements(item, result);
+                    }
+                }
+            }
+        } else if child.kind != "element" {
+            collect_measurements(child, result);
+        }
+    }
+}
+
+/// Maps an SR concept to the test identifier expected by the PVS
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SrConceptMappingEntry {
+    /// CodingSchemeDesignator, e.g. "LN" or "DCM"
+    pub scheme: String,
+    /// CodeValue, e.g. "8867-4"
+    pub code: String,
+    /// GDT 8410
+    pub test_id: String,
+    /// GDT 8411, the CodeMeaning if omitted
+    #[serde(default)]
+    pub test_name: Option<String>,
+}
+
+/// Mapping of SR concept codes to GDT test identifiers, stored as JSON:
+///
+/// ```json
+/// {
+///   "only_mapped": true,
+///   "entries": [
+///     { "scheme": "LN", "code": "8867-4", "test_id": "HF", "test_name": "Herzfrequenz" }
+///   ]
+/// }
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SrConceptMapping {
+    /// Skip measurements without an entry instead of using the CodeValue as test identifier
+    #[serde(default)]
+    pub only_mapped: bool,
+    #[serde(default)]
+    pub entries: Vec<SrConceptMappingEntry>,
+}
+
+impl SrConceptMapping {
+    pub fn load(path: &Path) -> Result<SrConceptMapping, G2DError> {
+        let data = std::fs::read(path)?;
+        let mapping: SrConceptMapping = serde_json::from_slice(&data)?;
+        return Ok(mapping);
+    }
+
+    /// GDT test results (8410, 8411, 8420, 8421) for the measurements
+    pub fn to_test_results(&self, measurements: &[SrMeasurement]) -> Vec<GdtTestResult> {
+        let mut results = vec![];
+        for m in measurements {
+            let entry = self
+                .entries
+                .iter()
+                .find(|e| e.scheme == m.concept.scheme && e.code == m.concept.value);
+            let (test_id, test_name) = match entry {
+                Some(e) => (
+                    e.test_id.clone(),
+                    e.test_name.clone().unwrap_or(m.concept.meaning.clone()),
+                ),
+                None if self.only_mapped => continue,
+                None => (m.concept.value.clone(), m.concept.meaning.clone()),
+            };
+            results.push(GdtTestResult {
+                test_id,
+                test_name,
+                value: m.value.clone(),
+                unit: m.unit.as_ref().map(|u| u.value.clone()).unwrap_or_default(),
+            });
+        }
+        return results;
+    }
+}
+
+pub const BASIC_TEXT_SR_SOP_CLASS_UID: &str = "1.2.840.10008.5.1.4.1.1.88.11";
+
+/// Patient, study and request attributes copied from the converted GDT file
+const STUDY_ATTRIBUTES: [(&str, &str, &str); 13] = [
+    ("0008,0020", "DA", "StudyDate"),
+    ("0008,0030", "TM", "StudyTime"),
+    ("0008,0050", "SH", "AccessionNumber"),
+    ("0008,0090", "PN", "ReferringPhysicianName"),
+    ("0010,0010", "PN", "PatientName"),
+    ("0010,0020", "LO", "PatientID"),
+    ("0010,0030", "DA", "PatientBirthDate"),
+    ("0010,0040", "CS", "PatientSex"),
+    ("0020,000d", "UI", "StudyInstanceUID"),
+    ("0020,0010", "SH", "StudyID"),
+    ("0032,1032", "PN", "RequestingPhysician"),
+    ("0040,1001", "SH", "RequestedProcedureID"),
+    ("0040,2016", "LO", "PlacerOrderNumberImagingServiceRequest"),
+];
+
+/// Free text of a GDT file for a Basic Text SR
+#[derive(Debug, Clone, Default)]
+pub struct TextReport {
+    /// 6205
+    pub diagnosis: Vec<String>,
+    /// 6220
+    pub findings: Vec<String>,
+    /// 6227
+    pub comments: Vec<String>,
+}
+
+impl TextReport {
+    /// None if the file has no findings, diagnosis or comments
+    pub fn from_gdt_file(file: &GdtFile) -> Option<TextReport> {
+        if file.object_findings.is_empty() {
+            return None;
+        }
+        return Some(TextReport {
+            diagnosis: file.object_findings.current_diagnosis.clone(),
+            findings: file.object_findings.findings.clone(),
+            comments: file.object_findings.comments.clone(),
+        });
+    }
+}
+
+/// A Basic Text SR "Diagnostic imaging report" in the study of `study_events`:
+/// the diagnosis as "Indications for Procedure", the findings as "Finding"
+/// and the comments as "Comment". Patient, study and requesting physician
+/// are taken from `study_events`.
+pub fn basic_text_sr_events(
+    study_events: &Vec<XmlEvent>,
+    report: &TextReport,
+    series_number: usize,
+) -> Result<Vec<XmlEvent>, G2DError> {
+    let now: DateTime<Local> = Local::now();
+    let sop_instance_uid = generate_uid();
+    let mut data_set = String::new();
+    for (tag, vr, name) in STUDY_ATTRIBUTES {
+        let body =
+            xml_get_element_body(study_events, Some(name.to_string()), Some(tag.to_string()))
+                .unwrap_or_default();
+        data_set.push_str(&element_xml(tag, vr, name, &body));
+    }
+    for (tag, vr, name, body) in [
+        ("0008,0005", "CS", "SpecificCharacterSet", "ISO_IR 192"),
+        (
+            "0008,0016",
+            "UI",
+            "SOPClassUID",
+            BASIC_TEXT_SR_SOP_CLASS_UID,
+        ),
+        ("0008,0018", "UI", "SOPInstanceUID", &sop_instance_uid),
+        (
+            "0008,0023",
+            "DA",
+            "ContentDate",
+            &now.format("%Y%m%d").to_string(),
+        ),
+        (
+            "0008,0033",
+            "TM",
+            "ContentTime",
+            &now.format("%H%M%S").to_string(),
+        ),
+        ("0008,0060", "CS", "Modality", "SR"),
+        ("0008,0070", "LO", "Manufacturer", ""),
+        ("0008,103e", "LO", "SeriesDescription", "GDT"),
+        ("0020,000e", "UI", "SeriesInstanceUID", &generate_uid()),
+        (
+            "0020,0011",
+            "IS",
+            "SeriesNumber",
+            &series_number.to_string(),
+        ),
+        ("0020,0013", "IS", "InstanceNumber", "1"),
+        ("0040,a040", "CS", "ValueType", "CONTAINER"),
+        ("0040,a050", "CS", "ContinuityOfContent", "SEPARATE"),
+        ("0040,a491", "CS", "CompletionFlag", "PARTIAL"),
+        ("0040,a493", "CS", "VerificationFlag", "UNVERIFIED"),
+    ] {
+        data_set.push_str(&element_xml(tag, vr, name, body));
+    }
+    data_set.push_str(r#"<sequence tag="0008,1111" vr="SQ" name="ReferencedPerformedProcedureStepSequence"></sequence>"#);
+    data_set.push_str(
+        r#"<sequence tag="0040,a372" vr="SQ" name="PerformedProcedureCodeSequence"></sequence>"#,
+    );
+    data_set.push_str(&code_sequence_xml(
+        "0040,a043",
+        "ConceptNameCodeSequence",
+        ("18748-4", "LN", "Diagnostic imaging report"),
+    ));
+
+    let mut content = String::new();
+    for (lines, concept) in [
+        (
+            &report.diagnosis,
+            ("121109", "DCM", "Indications for Procedure"),
+        ),
+        (&report.findings, ("121071", "DCM", "Finding")),
+        (&report.comments, ("121106", "DCM", "Comment")),
+    ] {
+        if lines.is_empty() {
+            continue;
+        }
+        content.push_str("<item>");
+        content.push_str(&element_xml(
+            "0040,a010",
+            "CS",
+            "RelationshipType",
+            "CONTAINS",
+        ));
+        content.push_str(&element_xml("0040,a040", "CS", "ValueType", "TEXT"));
+        content.push_str(&code_sequence_xml(
+            "0040,a043",
+            "ConceptNameCodeSequence",
+            concept,
+        ));
+        content.push_str(&element_xml(
+            "0040,a160",
+            "UT",
+            "TextValue",
+            &lines.join("\r\n"),
+        ));
+        content.push_str("</item>");
+    }
+    data_set.push_str(&format!(
+        r#"<sequence tag="0040,a730" vr="SQ" name="ContentSequence">{}</sequence>"#,
+        content
+    ));
+
+    let xml = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<file-format>
+<meta-header xfer="1.2.840.10008.1.2.1" name="Little Endian Explicit">
+{}{}{}
+</meta-header>
+<data-set xfer="1.2.840.10008.1.2.1" name="Little Endian Explicit">
+{}
+</data-set>
+</file-format>"#,
+        element_xml(
+            "0002,0002",
+            "UI",
+            "MediaStorageSOPClassUID",
+            BASIC_TEXT_SR_SOP_CLASS_UID
+        ),
+        element_xml(
+            "0002,0003",
+            "UI",
+            "MediaStorageSOPInstanceUID",
+            &sop_instance_uid
+        ),
+        element_xml(
+            "0002,0010",
+            "UI",
+            "TransferSyntaxUID",
+            "1.2.840.10008.1.2.1"
+        ),
+        data_set
+    );
+    let events = EventReader::new(xml.as_bytes())
+        .into_iter()
+        .filter(|e| {
+            !matches!(
+                e,
+                Ok(XmlEvent::StartDocument { .. }) | Ok(XmlEvent::EndDocument)
+            )
+        })
+        .collect::<Result<Vec<_>, xml::reader::Error>>()?;
+    return Ok(events);
+}
+
+fn element_xml(tag: &str, vr: &str, name: &str, body: &str) -> String {
+    return format!(
+        r#"<element tag="{}" vr="{}" name="{}">{}</element>"#,
+        tag,
+        vr,
+        name,
+        escape_str_pcdata(body)
+    );
+}
+
+fn code_sequence_xml(
+    tag: &str,
+    name: &str,
+    (value, scheme, meaning): (&str, &str, &str),
+) -> String {
+    return format!(
+        r#"<sequence tag="{}" vr="SQ" name="{}"><item>{}{}{}</item></sequence>"#,
+        tag,
+        name,
+        element_xml("0008,0100", "SH", "CodeValue", value),
+        element_xml("0008,0102", "SH", "CodingSchemeDesignator", scheme),
+        element_xml("0008,0104", "LO", "CodeMeaning", meaning)
+    );
+}
@@ -0,0 +1,201 @@
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+use xml::reader::XmlEvent;
+
+use crate::command::log;
+use crate::dcm_sr::{xml_get_sr_measurements, SrConceptMapping};
+use crate::dcm_xml::{
+    parse_dcm_as_xml, xml_get_instance_number, xml_get_modality, xml_get_series_description,
+    xml_get_series_instance_uid, xml_get_series_number, xml_get_sop_class_uid,
+    xml_get_sop_instance_uid, xml_get_study_description, xml_get_study_instance_uid, RequestTags,
+    ENCAPSULATED_PDF_SOP_CLASS_UID,
+};
+use crate::dicomdir::{collect_dicom_files, dicomdir_referenced_files, is_dicomdir};
+use crate::error::G2DError;
+use crate::gdt::{dcm_xml_to_file, GdtFile};
+use crate::person_name::NameCase;
+
+/// GDT record type "Daten einer Untersuchung übermitteln"
+pub const GDT_RECORD_TYPE_RESULT: u32 = 6310;
+
+/// One DICOM file and its dcm2xml events
+pub struct DcmInstance {
+    pub path: PathBuf,
+    pub events: Vec<XmlEvent>,
+    pub series_uid: String,
+    pub series_number: usize,
+    pub instance_number: usize,
+}
+
+impl DcmInstance {
+    pub fn is_pdf(&self) -> bool {
+        return xml_get_sop_class_uid(&self.events).as_deref()
+            == Some(ENCAPSULATED_PDF_SOP_CLASS_UID);
+    }
+
+    /// Structured reports have no pixel data, their measurements are read instead
+    pub fn is_sr(&self) -> bool {
+        return xml_get_modality(&self.events).as_deref() == Some("SR");
+    }
+
+    /// Images, as opposed to encapsulated documents and structured reports
+    pub fn is_image(&self) -> bool {
+        return !self.is_pdf() && !self.is_sr();
+    }
+}
+
+/// Summary of one series of a study
+#[derive(Debug, Clone)]
+pub struct DcmSeriesSummary {
+    pub number: usize,
+    pub description: String,
+    pub modality: String,
+    pub instance_count: usize,
+}
+
+/// The instances of one StudyInstanceUID, ordered by series and instance number
+pub struct DcmStudy {
+    pub study_uid: String,
+    pub instances: Vec<DcmInstance>,
+}
+
+impl DcmStudy {
+    pub fn image_count(&self) -> usize {
+        return self.instances.iter().filter(|i| i.is_image()).count();
+    }
+
+    pub fn series(&self) -> Vec<DcmSeriesSummary> {
+        let mut result: Vec<(String, DcmSeriesSummary)> = vec![];
+        for instance in &self.instances {
+            if let Some((_, s)) = result
+                .iter_mut()
+                .find(|(uid, _)| *uid == instance.series_uid)
+            {
+                s.instance_count += 1;
+                continue;
+            }
+            result.push((
+                instance.series_uid.clone(),
+                DcmSeriesSummary {
+                    number: instance.series_number,
+                    description: xml_get_series_description(&instance.events).unwrap_or_default(),
+                    modality: xml_get_modality(&instance.events).unwrap_or_default(),
+                    instance_count: 1,
+                },
+            ));
+        }
+        return result.into_iter().map(|(_, s)| s).collect();
+    }
+
+    /// A GDT result (6310) with patient and study of the first instance,
+    /// the number of images and the series as findings and the measurements
+    /// of structured reports as test results
+    pub fn to_gdt_file(
+        &self,
+        name_case: NameCase,
+        request_tags: &RequestTags,
+        sr_mapping: &SrConceptMapping,
+    ) -> GdtFile {
+        let first = &self.instances[0].events;
+        let mut file = dcm_xml_to_file(first, name_case, request_tags);
+        file.record_type = GDT_RECORD_TYPE_RESULT;
+        let findings = &mut file.object_findings.findings;
+        if let Some(description) = xml_get_study_description(first).filter(|d| !d.is_empty()) {
+            findings.push(description);
+        }
+        let series = self.series();
+        findings.push(format!(
+            "{} images in {} series",
+            self.image_count(),
+            series.len()
+        ));
+        for s in series {
+            findings.push(format!(
+                "Series {}: {} {} ({} instances)",
+                s.number, s.modality, s.description, s.instance_count
+            ));
+        }
+        for instance in self.instances.iter().filter(|i| i.is_sr()) {
+            let measurements = xml_get_sr_measurements(&instance.events);
+            file.object_test_results
+                .extend(sr_mapping.to_test_results(&measurements));
+        }
+        return file;
+    }
+}
+
+/// Collects the DICOM files of a file, folder (searched recursively) or DICOMDIR
+pub fn collect_study_files(input: &Path) -> Result<Vec<PathBuf>, G2DError> {
+    if is_dicomdir(input) {
+        return dicomdir_referenced_files(input);
+    }
+    if input.is_file() {
+        return Ok(vec![input.to_path_buf()]);
+    }
+    return collect_dicom_files(&[input.to_path_buf()]);
+}
+
+/// Reads the files and groups them by StudyInstanceUID, in order of first
+/// appearance. Files without StudyInstanceUID are skipped.
+pub fn group_by_study(
+    files: &[PathBuf],
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<Vec<DcmStudy>, G2DError> {
+    let mut studies: Vec<DcmStudy> = vec![];
+    let mut sop_instance_uids: Vec<String> = vec![];
+    for path in files {
+        let events = parse_dcm_as_xml(path)?;
+        let Some(study_uid) = xml_get_study_instance_uid(&events).filter(|u| !u.is_empty()) else {
+            log(
+                log_sender,
+                format!("Skipping {}, no StudyInstanceUID", path.display()),
+            );
+            continue;
+        };
+        if let Some(sop_instance_uid) = xml_get_sop_instance_uid(&events) {
+            if sop_instance_uids.contains(&sop_instance_uid) {
+                log(
+                    log_sender,
+                    format!("Skipping duplicate instance {}", path.display()),
+                );
+                continue;
+            }
+            sop_instance_uids.push(sop_instance_uid);
+        }
+        let number = |value: Option<String>| -> usize {
+            value.and_then(|v| v.trim().parse().ok()).unwrap_or(0)
+        };
+        let instance = DcmInstance {
+            path: path.clone(),
+            series_uid: xml_get_series_instance_uid(&events).unwrap_or_default(),
+            series_number: number(xml_get_series_number(&events)),
+            instance_number: number(xml_get_instance_number(&events)),
+            events,
+        };
+        match studies.iter_mut().find(|s| s.study_uid == study_uid) {
+            Some(study) => study.instances.push(instance),
+            None => studies.push(DcmStudy {
+                study_uid,
+                instances: vec![instance],
+            }),
+        }
+    }
+    if studies.is_empty() {
+        return Err(G2DError::IoError(Error::new(
+            ErrorKind::Other,
+            "No DICOM files found",
+        )));
+    }
+    for study in studies.iter_mut() {
+        study.instances.sort_by(|a, b| {
+            (a.series_number, &a.series_uid, a.instance_number).cmp(&(
+                b.series_number,
+                &b.series_uid,
+                b.instance_number,
+            ))
+        });
+    }
+    return Ok(studies);
+}
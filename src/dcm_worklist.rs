@@ -5,6 +5,7 @@ use std::sync::mpsc;
 use tempfile::NamedTempFile;
 
 use crate::command::exec_command;
+use crate::dcm_xml::new_study_uid_arguments;
 
 pub fn dcm_to_worklist(
     log_sender: Option<&mpsc::Sender<String>>,
@@ -22,10 +23,10 @@ pub fn dcm_to_worklist(
         false,
         log_sender,
     )?;
-    std::io::stderr().write_all(&output2.stderr).unwrap();
+    std::io::stderr().write_all(&output2.stderr)?;
     temp_dump_file.write_all(&output2.stdout)?;
     if !output2.status.success() {
-        let err_str = std::str::from_utf8(&output2.stderr).unwrap();
+        let err_str = String::from_utf8_lossy(&output2.stderr).to_string();
         if let Some(l) = log_sender {
             _ = l.send(err_str.to_string());
         }
@@ -44,7 +45,7 @@ pub fn dcm_to_worklist(
         log_sender,
     )?;
     if !output3.status.success() {
-        let err_str = std::str::from_utf8(&output3.stderr).unwrap();
+        let err_str = String::from_utf8_lossy(&output3.stderr).to_string();
         if let Some(l) = log_sender {
             _ = l.send(err_str.to_string());
         }
@@ -54,10 +55,13 @@ pub fn dcm_to_worklist(
     return Ok(());
 }
 
+/// `keep_study_uid` keeps the StudyInstanceUID of the XML file, e.g. one
+/// derived from the GDT request, otherwise a new one is assigned
 pub fn dcm_xml_to_worklist(
     log_sender: Option<&mpsc::Sender<String>>,
     xml_file_path: &Path,
     output_path: &PathBuf,
+    keep_study_uid: bool,
 ) -> Result<(), std::io::Error> {
     // This function is same as this bash:
     // $ xml2dcm [xml_file_path] [temp1]
@@ -73,7 +77,7 @@ pub fn dcm_xml_to_worklist(
         log_sender,
     )?;
     if !output1.status.success() {
-        let err_str = std::str::from_utf8(&output1.stderr).unwrap();
+        let err_str = String::from_utf8_lossy(&output1.stderr).to_string();
         if let Some(l) = log_sender {
             _ = l.send(err_str.to_string());
         }
@@ -81,20 +85,21 @@ pub fn dcm_xml_to_worklist(
         return Err(custom_error);
     }
 
-    // Always assign new study id #72
-    let output2 = exec_command(
-        "dcmodify",
-        vec![OsStr::new("--gen-stud-uid"), temp_dcm_file_path.as_os_str()],
-        true,
-        log_sender,
-    )?;
-    if !output2.status.success() {
-        let err_str = std::str::from_utf8(&output2.stderr).unwrap();
-        if let Some(log_sender) = log_sender {
-            _ = log_sender.send(err_str.to_string());
+    // Assign new study id #72, unless it was derived from the request
+    if !keep_study_uid {
+        let mut arguments: Vec<&OsStr> = vec![];
+        let study_uid_arguments = new_study_uid_arguments();
+        arguments.extend(study_uid_arguments.iter().map(OsStr::new));
+        arguments.push(temp_dcm_file_path.as_os_str());
+        let output2 = exec_command("dcmodify", arguments, true, log_sender)?;
+        if !output2.status.success() {
+            let err_str = String::from_utf8_lossy(&output2.stderr).to_string();
+            if let Some(log_sender) = log_sender {
+                _ = log_sender.send(err_str.to_string());
+            }
+            let custom_error = Error::new(ErrorKind::Other, err_str);
+            return Err(custom_error);
         }
-        let custom_error = Error::new(ErrorKind::Other, err_str);
-        return Err(custom_error);
     }
 
     return dcm_to_worklist(log_sender, temp_dcm_file_path, output_path);
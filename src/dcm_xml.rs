@@ -1,24 +1,31 @@
-use std::ffi::OsStr;
 use std::fs::File;
 use std::io::{Error, ErrorKind, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str::FromStr;
-use std::sync::mpsc;
+use std::sync::{mpsc, OnceLock};
 use std::time::SystemTime;
 
 use chrono::{DateTime, Utc};
-use regex::Regex;
+use log::warn;
+use serde::{Deserialize, Serialize};
 use tempfile::NamedTempFile;
 use xml::attribute::OwnedAttribute;
+use xml::escape::escape_str_pcdata;
 use xml::name::OwnedName;
 use xml::reader::EventReader;
 use xml::reader::XmlEvent;
 use xml::writer::EventWriter;
 
 use crate::command::{exec_command, ChildOutput};
+use crate::dcm_pixel_data::{read_frames, write_frame, write_frames};
 use crate::error::G2DError;
-use crate::gdt::{GdtBasicDiagnosticsObject, GdtFile, GdtPatientGender, GdtPatientObject};
+use crate::filename_template::{FilenameTemplate, FilenameValues};
+use crate::gdt::{
+    GdtBasicDiagnosticsObject, GdtFile, GdtPatientGender, GdtPhysicianIdentificationObject,
+    GdtRequestObject,
+};
+use crate::person_name::{NameCase, PersonName};
 
 pub fn parse_dcm_xml(path: &Path) -> Result<Vec<XmlEvent>, G2DError> {
     let file = File::open(path)?;
@@ -40,77 +47,157 @@ pub fn parse_dcm_as_xml(path: &PathBuf) -> Result<Vec<XmlEvent>, G2DError> {
     return Ok(events);
 }
 
+/// Like `parse_dcm_as_xml`, with the pixel data and other binary values
+/// as base64 (little endian)
+pub fn parse_dcm_as_xml_with_pixel_data(path: &PathBuf) -> Result<Vec<XmlEvent>, G2DError> {
+    let output = Command::new("dcm2xml")
+        .args(["+Wb", "+Eb"])
+        .arg(path)
+        .output()?;
+    std::io::stderr().write_all(&output.stderr).unwrap();
+    if !output.status.success() {
+        let err_str = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(G2DError::IoError(Error::new(ErrorKind::Other, err_str)));
+    }
+    let reader = EventReader::new(output.stdout.as_slice());
+    let events: Vec<XmlEvent> = reader
+        .into_iter()
+        .collect::<Result<Vec<_>, xml::reader::Error>>()?;
+    return Ok(events);
+}
+
 #[derive(Debug)]
 pub enum DCMImageFormat {
     Jpeg,
     Png,
 }
 
-pub fn export_images_from_dcm_with_patient_id(
+impl DCMImageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            DCMImageFormat::Jpeg => "jpg",
+            DCMImageFormat::Png => "png",
+        }
+    }
+}
+
+/// Exports all frames to `[output_path]/[template].[png|jpg]`, the values
+/// of the template are read from the DICOM file, `{request_id}` from the
+/// identifier attribute of `request_tags`
+pub fn export_images_from_dcm_with_template(
     dcm_path: &PathBuf,
     output_path: &PathBuf,
+    template: &FilenameTemplate,
     format: DCMImageFormat,
+    request_tags: &[RequestTags],
     log_sender: Option<&mpsc::Sender<ChildOutput>>,
-) -> Result<Vec<String>, G2DError> {
-    let dcm_xml_events = parse_dcm_as_xml(&dcm_path)?;
-    let patient_id = xml_get_patient_patient_id(&dcm_xml_events);
-    let patient_id = match patient_id {
-        Some(id) => id,
-        None => {
-            if let Some(l) = log_sender {
-                _ = l.send(ChildOutput::Log(
-                    "Cannot patient id from Dicom file".to_string(),
-                ));
-            }
-            let custom_error =
-                Error::new(ErrorKind::Other, "Cannot find patient id from Dicom file");
-            return Err(G2DError::IoError(custom_error));
+) -> Result<Vec<PathBuf>, G2DError> {
+    let export = || -> Result<Vec<PathBuf>, G2DError> {
+        let events = parse_dcm_as_xml_with_pixel_data(dcm_path)?;
+        let frames = read_frames(dcm_path, &events)?;
+        let request_tags = RequestTags::find_in(request_tags, &events);
+        let mut values = FilenameValues::from_dcm_xml(&events, &request_tags);
+        let mut paths = vec![];
+        for (index, frame) in frames.iter().enumerate() {
+            values.set("frame", index.to_string());
+            let path = template.unique_path(output_path, format.extension(), &values);
+            write_frame(frame, &path, &format)?;
+            paths.push(path);
         }
+        return Ok(paths);
     };
-    let mut output_path = output_path.clone();
-    output_path.push("_gdt2dicom_temp_");
-    let saved_files = export_images_from_dcm(dcm_path, &output_path, format, log_sender)?;
-    for saved_file in &saved_files {
-        let new_name = saved_file.replace("_gdt2dicom_temp_.", &format!("{}_", patient_id));
-        std::fs::rename(saved_file, new_name)?;
+    let result = export();
+    if let (Err(e), Some(l)) = (&result, log_sender) {
+        _ = l.send(ChildOutput::Log(format!(
+            "Error exporting images from {}: {}",
+            dcm_path.display(),
+            e
+        )));
+    }
+    return result;
+}
+
+/// Exports all frames to `[output_path]/[prefix][frame number].[png|jpg]`
+pub fn export_images_from_dcm_with_prefix(
+    dcm_path: &PathBuf,
+    output_path: &PathBuf,
+    prefix: &str,
+    format: DCMImageFormat,
+    log_sender: Option<&mpsc::Sender<ChildOutput>>,
+) -> Result<Vec<PathBuf>, G2DError> {
+    let result = parse_dcm_as_xml_with_pixel_data(dcm_path)
+        .and_then(|events| read_frames(dcm_path, &events))
+        .and_then(|frames| write_frames(&frames, output_path, prefix, &format));
+    if let (Err(e), Some(l)) = (&result, log_sender) {
+        _ = l.send(ChildOutput::Log(format!(
+            "Error exporting images from {}: {}",
+            dcm_path.display(),
+            e
+        )));
     }
-    return Ok(saved_files);
+    return result;
 }
 
+/// Exports all frames to `[output_path].[frame number].[png|jpg]`
 pub fn export_images_from_dcm(
     dcm_path: &PathBuf,
     output_path: &PathBuf,
     format: DCMImageFormat,
     log_sender: Option<&mpsc::Sender<ChildOutput>>,
-) -> Result<Vec<String>, G2DError> {
+) -> Result<Vec<PathBuf>, G2DError> {
+    let output_dir = output_path
+        .parent()
+        .map(|p| p.to_path_buf())
+        .unwrap_or_default();
+    let prefix = format!(
+        "{}.",
+        output_path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default()
+    );
+    return export_images_from_dcm_with_prefix(dcm_path, &output_dir, &prefix, format, log_sender);
+}
+
+pub const ENCAPSULATED_PDF_SOP_CLASS_UID: &str = "1.2.840.10008.5.1.4.1.1.104.1";
+
+/// Extracts the document of an Encapsulated PDF instance to
+/// `[output_dir]/[patient id]_[dicom file name].pdf`
+pub fn export_pdf_from_dcm(
+    dcm_path: &PathBuf,
+    output_dir: &PathBuf,
+    log_sender: Option<&mpsc::Sender<ChildOutput>>,
+) -> Result<PathBuf, G2DError> {
+    let dcm_xml_events = parse_dcm_as_xml(&dcm_path)?;
+    let patient_id = xml_get_patient_patient_id(&dcm_xml_events).unwrap_or_default();
+    // Files of different series or studies are often named alike, the
+    // SOPInstanceUID is unique
+    let dcm_name = xml_get_sop_instance_uid(&dcm_xml_events)
+        .map(|uid| uid.trim().to_string())
+        .filter(|uid| !uid.is_empty())
+        .unwrap_or_else(|| {
+            dcm_path
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default()
+        });
+    let mut output_path = output_dir.clone();
+    output_path.push(format!("{}_{}.pdf", patient_id, dcm_name));
     let output = exec_command(
-        "dcmj2pnm",
-        vec![
-            match format {
-                DCMImageFormat::Png => OsStr::new("--write-png"),
-                DCMImageFormat::Jpeg => OsStr::new("--write-jpeg"),
-            },
-            dcm_path.as_os_str(),
-            output_path.as_os_str(),
-            OsStr::new("--all-frames"),
-            OsStr::new("--verbose"),
-        ],
+        "dcm2pdf",
+        vec![dcm_path.as_os_str(), output_path.as_os_str()],
         false,
         None,
     )?;
-    let err_str = std::str::from_utf8(&output.stderr).unwrap();
-    let mut output_filenames = vec![];
-    if output.status.success() {
-        let re = Regex::new(r"I: writing frame [0-9]+ to (.*)").unwrap();
-        for (_, [x]) in re.captures_iter(err_str).map(|c| c.extract()) {
-            output_filenames.push(x.to_string());
-        }
-    } else {
+    if !output.status.success() {
+        let err_str = String::from_utf8_lossy(&output.stderr).to_string();
         if let Some(l) = log_sender {
             _ = l.send(ChildOutput::Log(format!("Error: {:?}", err_str)));
         }
+        let custom_error = Error::new(ErrorKind::Other, err_str);
+        return Err(G2DError::IoError(custom_error));
     }
-    return Ok(output_filenames);
+    return Ok(output_path);
 }
 
 pub fn xml_events_to_file(events: Vec<XmlEvent>) -> Result<NamedTempFile, G2DError> {
@@ -125,7 +212,7 @@ pub fn xml_events_to_file(events: Vec<XmlEvent>) -> Result<NamedTempFile, G2DErr
     return Ok(temp_file);
 }
 
-fn xml_contains(xml_events: &Vec<XmlEvent>, tag: String, name: String) -> bool {
+pub(crate) fn xml_contains(xml_events: &Vec<XmlEvent>, tag: String, name: String) -> bool {
     xml_events.iter().any(|ev| match ev {
         XmlEvent::StartElement {
             name: xml::name::OwnedName { local_name, .. },
@@ -162,7 +249,100 @@ pub struct DcmElement {
     pub body: String,
 }
 
-fn add_element_if_not_exist(events: &mut Vec<XmlEvent>, element: DcmElement) {
+impl DcmElement {
+    pub fn new(tag: &str, vr: &str, name: &str, body: &str) -> DcmElement {
+        DcmElement {
+            tag: tag.to_string(),
+            vr: vr.to_string(),
+            name: name.to_string(),
+            body: body.to_string(),
+        }
+    }
+}
+
+/// A code sequence with a single item, e.g. AnatomicRegionSequence
+#[derive(Debug, Clone)]
+pub struct DcmCodeSequence {
+    pub tag: String,
+    pub name: String,
+    pub code_value: String,
+    pub coding_scheme_designator: String,
+    pub code_meaning: String,
+}
+
+impl DcmCodeSequence {
+    pub fn new(tag: &str, name: &str, code: (&str, &str, &str)) -> DcmCodeSequence {
+        let (code_value, coding_scheme_designator, code_meaning) = code;
+        DcmCodeSequence {
+            tag: tag.to_string(),
+            name: name.to_string(),
+            code_value: code_value.to_string(),
+            coding_scheme_designator: coding_scheme_designator.to_string(),
+            code_meaning: code_meaning.to_string(),
+        }
+    }
+
+    /// `(value, scheme, meaning)`, e.g. for a dry run
+    pub fn code_string(&self) -> String {
+        return format!(
+            "({}, {}, {})",
+            self.code_value, self.coding_scheme_designator, self.code_meaning
+        );
+    }
+}
+
+/// Adds the code sequence at the end of the data set unless it exists
+pub fn add_code_sequence_if_not_exist(
+    events: &mut Vec<XmlEvent>,
+    sequence: DcmCodeSequence,
+) -> Result<(), G2DError> {
+    if xml_contains(events, sequence.tag.clone(), sequence.name.clone()) {
+        return Ok(());
+    }
+    let element = |tag: &str, vr: &str, name: &str, body: &str| {
+        format!(
+            r#"<element tag="{}" vr="{}" name="{}">{}</element>"#,
+            tag,
+            vr,
+            name,
+            escape_str_pcdata(body)
+        )
+    };
+    let xml = format!(
+        r#"<sequence tag="{}" vr="SQ" name="{}"><item>{}{}{}</item></sequence>"#,
+        sequence.tag,
+        sequence.name,
+        element("0008,0100", "SH", "CodeValue", &sequence.code_value),
+        element(
+            "0008,0102",
+            "SH",
+            "CodingSchemeDesignator",
+            &sequence.coding_scheme_designator
+        ),
+        element("0008,0104", "LO", "CodeMeaning", &sequence.code_meaning)
+    );
+    let sequence_events = EventReader::new(xml.as_bytes())
+        .into_iter()
+        .filter(|e| {
+            !matches!(
+                e,
+                Ok(XmlEvent::StartDocument { .. }) | Ok(XmlEvent::EndDocument)
+            )
+        })
+        .collect::<Result<Vec<_>, xml::reader::Error>>()?;
+    let end_data_set_index = events
+        .iter()
+        .position(|e| {
+            matches!(e, XmlEvent::EndElement {
+                name: OwnedName { local_name, .. },
+            } if local_name.as_str() == "data-set")
+        })
+        .unwrap_or(events.len() - 2);
+    events.splice(end_data_set_index..end_data_set_index, sequence_events);
+    return Ok(());
+}
+
+pub fn add_element_if_not_exist(events: &mut Vec<XmlEvent>, element: DcmElement) {
     if xml_contains(events, element.tag.clone(), element.name.clone()) {
         return;
     }
@@ -239,6 +419,128 @@ fn add_element_if_not_exist(events: &mut Vec<XmlEvent>, element: DcmElement) {
     events.insert(end_data_set_index + 2, end);
 }
 
+/// Sets the body of an element, adding the element if it doesn't exist yet
+pub fn set_element(events: &mut Vec<XmlEvent>, element: DcmElement) {
+    let start_index = events.iter().position(|e| match e {
+        XmlEvent::StartElement {
+            name: OwnedName { local_name, .. },
+            attributes,
+            ..
+        } if local_name.as_str() == "element"
+            && attributes_contain(attributes, element.tag.clone(), element.name.clone()) =>
+        {
+            true
+        }
+        _ => false,
+    });
+    let Some(start_index) = start_index else {
+        add_element_if_not_exist(events, element);
+        return;
+    };
+    match &events[start_index + 1] {
+        XmlEvent::Characters(_) => {
+            events[start_index + 1] = XmlEvent::Characters(element.body);
+        }
+        _ => {
+            events.insert(start_index + 1, XmlEvent::Characters(element.body));
+        }
+    }
+}
+
+/// Sets the body of an element only if the element is missing or empty
+pub fn set_element_if_empty(events: &mut Vec<XmlEvent>, element: DcmElement) {
+    if element.body.is_empty() {
+        return;
+    }
+    let current = xml_get_element_body(
+        events,
+        Some(element.name.clone()),
+        Some(element.tag.clone()),
+    );
+    if current.map(|c| c.trim().is_empty()).unwrap_or(true) {
+        set_element(events, element);
+    }
+}
+
+/// Root of the generated UIDs, set once from the config file
+static UID_ROOT: OnceLock<String> = OnceLock::new();
+/// SpecificCharacterSet of the written data sets, set once from the config file
+static SPECIFIC_CHARACTER_SET: OnceLock<String> = OnceLock::new();
+
+/// Generated UIDs start with `root` instead of 2.25, has no effect if it was already set
+pub fn set_uid_root(root: String) {
+    _ = UID_ROOT.set(root);
+}
+
+/// The data sets written from GDT files declare `charset`, xml2dcm converts
+/// the values into it. Has no effect if it was already set.
+pub fn set_specific_character_set(charset: String) {
+    _ = SPECIFIC_CHARACTER_SET.set(charset);
+}
+
+/// The number below the configured root, or below 2.25 which allows the
+/// UUID as a whole. Digits which don't fit into 64 characters are cut off.
+fn uid_from_u128(value: u128) -> String {
+    let Some(root) = UID_ROOT.get() else {
+        return format!("2.25.{}", value);
+    };
+    let digits = value.to_string();
+    let max_digits = 64 - root.len() - 1;
+    return format!("{}.{}", root, &digits[..digits.len().min(max_digits)]);
+}
+
+/// Generates a UID below the 2.25 root (or the configured one) from a random UUID
+pub fn generate_uid() -> String {
+    return uid_from_u128(uuid::Uuid::new_v4().as_u128());
+}
+
+/// Arguments of dcmodify giving a file a new StudyInstanceUID, dcmtk's own
+/// root is only used if no UID root is configured
+pub fn new_study_uid_arguments() -> Vec<String> {
+    if UID_ROOT.get().is_none() {
+        return vec!["--gen-stud-uid".to_string()];
+    }
+    return vec!["-i".to_string(), format!("0020,000d={}", generate_uid())];
+}
+
+/// Numbers separated by dots without leading zeros, like the components of a UID
+pub fn valid_uid_components(value: &str) -> bool {
+    return value.split('.').all(|c| {
+        !c.is_empty()
+            && c.chars().all(|d| d.is_ascii_digit())
+            && !(c.len() > 1 && c.starts_with('0'))
+    });
+}
+
+/// Whether `value` is a valid UID with a root, not just a number
+pub fn is_uid(value: &str) -> bool {
+    return value.len() <= 64 && value.contains('.') && valid_uid_components(value);
+}
+
+/// StudyInstanceUID for the GDT request, so all objects converted for one
+/// request end up in the same study.
+/// 8314 is used as it is if it's already a valid UID, otherwise 8314 or
+/// 8310 (together with the patient ID) is hashed into a name based UUID.
+pub fn study_instance_uid_from_request(
+    patient_id: &str,
+    request: &GdtRequestObject,
+) -> Option<String> {
+    let request_uid = request.request_uid.trim();
+    let request_id = request.request_identifier.trim();
+    let name = if !request_uid.is_empty() {
+        if is_uid(request_uid) {
+            return Some(request_uid.to_string());
+        }
+        format!("gdt-8314:{}", request_uid)
+    } else if !request_id.is_empty() {
+        format!("gdt-8310:{}:{}", patient_id.trim(), request_id)
+    } else {
+        return None;
+    };
+    let uuid = uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_OID, name.as_bytes());
+    return Some(uid_from_u128(uuid.as_u128()));
+}
+
 fn attributes_contain(attrs: &Vec<OwnedAttribute>, tag: String, name: String) -> bool {
     let xml_tag = value_of_attribute(attrs, "tag");
     let xml_name = value_of_attribute(attrs, "name");
@@ -384,15 +686,173 @@ pub fn default_dcm_worklist_xml_str() -> String {
     return xml;
 }
 
-pub fn file_to_xml(file: GdtFile, xml_events: &Vec<XmlEvent>) -> Result<NamedTempFile, G2DError> {
+/// DICOM attribute that carries a GDT request field (8310 or 8314)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum RequestTag {
+    /// AccessionNumber (0008,0050), up to 16 characters
+    AccessionNumber,
+    /// RequestedProcedureID (0040,1001), up to 16 characters
+    RequestedProcedureId,
+    /// StudyID (0020,0010), up to 16 characters
+    StudyId,
+    /// PlacerOrderNumberImagingServiceRequest (0040,2016), up to 64 characters
+    PlacerOrderNumber,
+    /// The field is not mapped
+    None,
+}
+
+impl RequestTag {
+    fn tag_vr_name(&self) -> Option<(&'static str, &'static str, &'static str)> {
+        match self {
+            RequestTag::AccessionNumber => Some(("0008,0050", "SH", "AccessionNumber")),
+            RequestTag::RequestedProcedureId => Some(("0040,1001", "SH", "RequestedProcedureID")),
+            RequestTag::StudyId => Some(("0020,0010", "SH", "StudyID")),
+            RequestTag::PlacerOrderNumber => {
+                Some(("0040,2016", "LO", "PlacerOrderNumberImagingServiceRequest"))
+            }
+            RequestTag::None => None,
+        }
+    }
+
+    /// The element for `value`, None if the tag is `None` or the value
+    /// doesn't fit into the VR (it's not truncated, a shortened request ID
+    /// would match the wrong order)
+    pub fn dcm_element(&self, value: &str) -> Option<DcmElement> {
+        let (tag, vr, name) = self.tag_vr_name()?;
+        let max_length = if vr == "SH" { 16 } else { 64 };
+        if value.chars().count() > max_length || value.contains('\\') {
+            return None;
+        }
+        return Some(DcmElement::new(tag, vr, name, value));
+    }
+
+    /// The value of the attribute, searched in the whole dataset, e.g. also
+    /// in the RequestAttributesSequence of an image
+    pub fn get(&self, events: &Vec<XmlEvent>) -> Option<String> {
+        let (tag, _, name) = self.tag_vr_name()?;
+        return xml_get_element_body(events, Some(name.to_string()), Some(tag.to_string()))
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+    }
+}
+
+/// Where the GDT request identifier (8310) and request UID (8314) are stored,
+/// so results can be matched to the order when converting back to GDT
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestTags {
+    pub identifier: RequestTag,
+    pub uid: RequestTag,
+}
+
+impl Default for RequestTags {
+    fn default() -> Self {
+        RequestTags {
+            identifier: RequestTag::AccessionNumber,
+            uid: RequestTag::PlacerOrderNumber,
+        }
+    }
+}
+
+impl RequestTags {
+    /// The GDT field, the attribute it's stored in and the value of 8310 and 8314
+    pub fn fields<'a>(
+        &self,
+        request: &'a GdtRequestObject,
+    ) -> [(&'static str, RequestTag, &'a str); 2] {
+        return [
+            ("8310", self.identifier, request.request_identifier.trim()),
+            ("8314", self.uid, request.request_uid.trim()),
+        ];
+    }
+
+    /// The first of `candidates` whose identifier attribute is set in the
+    /// dataset, e.g. the tags of the auto-convert folder a received image
+    /// belongs to. Falls back to the first candidate or the default.
+    pub fn find_in(candidates: &[RequestTags], events: &Vec<XmlEvent>) -> RequestTags {
+        return candidates
+            .iter()
+            .find(|t| t.identifier.get(events).is_some())
+            .or(candidates.first())
+            .copied()
+            .unwrap_or_default();
+    }
+
+    /// Fails if 8310 or 8314 of `request` doesn't fit into its attribute,
+    /// the result of a request which isn't stored couldn't be matched to the order
+    pub fn check(&self, request: &GdtRequestObject) -> Result<(), G2DError> {
+        for (field, tag, value) in self.fields(request) {
+            if value.is_empty() || tag == RequestTag::None {
+                continue;
+            }
+            if tag.dcm_element(value).is_none() {
+                return Err(G2DError::InvalidInput(format!(
+                    "GDT {} \"{}\" doesn't fit into {:?}, please store it in placer-order-number",
+                    field, value, tag
+                )));
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// Options for mapping a GDT file onto a DICOM XML dataset
+#[derive(Debug, Clone, Default)]
+pub struct FileToXmlOptions {
+    pub name_case: NameCase,
+    pub request_tags: RequestTags,
+}
+
+pub fn file_to_xml(
+    file: GdtFile,
+    xml_events: &Vec<XmlEvent>,
+    options: &FileToXmlOptions,
+) -> Result<NamedTempFile, G2DError> {
+    return xml_events_to_file(file_to_xml_events(file, xml_events, options));
+}
+
+pub fn file_to_xml_events(
+    file: GdtFile,
+    xml_events: &Vec<XmlEvent>,
+    options: &FileToXmlOptions,
+) -> Vec<XmlEvent> {
     let mut cloned = xml_events.clone();
+    let study_uid =
+        study_instance_uid_from_request(&file.object_patient.patient_number, &file.object_request);
+    // Values which don't fit are refused by `RequestTags::check` before converting
+    for (field, tag, value) in options.request_tags.fields(&file.object_request) {
+        if value.is_empty() || tag == RequestTag::None {
+            continue;
+        }
+        match tag.dcm_element(value) {
+            Some(element) => set_element(&mut cloned, element),
+            None => warn!("GDT {} \"{}\" doesn't fit into {:?}", field, value, tag),
+        }
+    }
+    add_physician_elements(
+        &mut cloned,
+        &file.object_physician_identification,
+        options.name_case,
+    );
+    if let Some(charset) = SPECIFIC_CHARACTER_SET.get() {
+        set_element(
+            &mut cloned,
+            DcmElement {
+                tag: "0008,0005".to_string(),
+                vr: "CS".to_string(),
+                name: "SpecificCharacterSet".to_string(),
+                body: charset.clone(),
+            },
+        );
+    }
     add_element_if_not_exist(
         &mut cloned,
         DcmElement {
             tag: "0010,0010".to_string(),
             vr: "PN".to_string(),
             name: "PatientName".to_string(),
-            body: gdt_get_patient_name(&file.object_patient),
+            body: PersonName::from_gdt_patient(&file.object_patient)
+                .to_dcm_string(options.name_case),
         },
     );
 
@@ -442,11 +902,60 @@ pub fn file_to_xml(file: GdtFile, xml_events: &Vec<XmlEvent>) -> Result<NamedTem
             body: file.object_basic_diagnostics.patient_weight,
         },
     );
-    return xml_events_to_file(cloned);
+    if file.object_request.date_of_examination.len() == 8 {
+        set_element_if_empty(
+            &mut cloned,
+            DcmElement {
+                tag: "0008,0020".to_string(),
+                vr: "DA".to_string(),
+                name: "StudyDate".to_string(),
+                body: gdt_date_to_dcm(file.object_request.date_of_examination),
+            },
+        );
+    }
+    set_element_if_empty(
+        &mut cloned,
+        DcmElement {
+            tag: "0008,0030".to_string(),
+            vr: "TM".to_string(),
+            name: "StudyTime".to_string(),
+            body: file.object_request.time_of_examination,
+        },
+    );
+    if let Some(study_uid) = study_uid {
+        set_element(
+            &mut cloned,
+            DcmElement::new("0020,000d", "UI", "StudyInstanceUID", &study_uid),
+        );
+    }
+    return cloned;
+}
+
+/// 0211 (physician name) as ReferringPhysicianName and RequestingPhysician
+fn add_physician_elements(
+    events: &mut Vec<XmlEvent>,
+    physician: &GdtPhysicianIdentificationObject,
+    name_case: NameCase,
+) {
+    if physician.physician_name.trim().is_empty() {
+        return;
+    }
+    let name = PersonName::parse_free_text(&physician.physician_name).to_dcm_string(name_case);
+    set_element_if_empty(
+        events,
+        DcmElement::new("0008,0090", "PN", "ReferringPhysicianName", &name),
+    );
+    set_element_if_empty(
+        events,
+        DcmElement::new("0032,1032", "PN", "RequestingPhysician", &name),
+    );
 }
 
 fn gdt_date_to_dcm(str: String) -> String {
-    // DDMMYYYY -> YYYYMMDD
+    // DDMMYYYY -> YYYYMMDD, anything else is left empty
+    if str.len() != 8 || !str.is_ascii() {
+        return String::new();
+    }
     let day = &str[0..2];
     let month = &str[2..4];
     let year = &str[4..8];
@@ -460,27 +969,12 @@ fn gdt_gender_to_dcm(gender: &GdtPatientGender) -> String {
     }
 }
 
-fn gdt_get_patient_name(patient: &GdtPatientObject) -> String {
-    if patient.patient_name.len() > 0 && patient.patient_first_name.len() > 0 {
-        return format!(
-            "{}^{}",
-            patient.patient_name.to_uppercase(),
-            patient.patient_first_name.to_uppercase(),
-        )
-        .replace(" ", "^");
-    } else if patient.patient_name.len() > 0 {
-        return format!("{}", patient.patient_name.to_uppercase());
-    } else {
-        return format!("{}", patient.patient_first_name.to_uppercase());
-    }
-}
-
 fn gdt_get_patient_height_in_meters(patient: &GdtBasicDiagnosticsObject) -> Option<String> {
     let num = f64::from_str(&patient.patient_height).ok()?;
     return Some(format!("{}", num / 100.0));
 }
 
-fn xml_get_element_body(
+pub(crate) fn xml_get_element_body(
     events: &Vec<XmlEvent>,
     in_name: Option<String>,
     in_tag: Option<String>,
@@ -567,3 +1061,203 @@ pub fn xml_get_study_time(events: &Vec<XmlEvent>) -> Option<String> {
         Some("0008,0030".to_string()),
     );
 }
+
+pub fn xml_get_study_instance_uid(events: &Vec<XmlEvent>) -> Option<String> {
+    return xml_get_element_body(
+        &events,
+        Some("StudyInstanceUID".to_string()),
+        Some("0020,000d".to_string()),
+    );
+}
+
+pub fn xml_get_sop_class_uid(events: &Vec<XmlEvent>) -> Option<String> {
+    return xml_get_element_body(
+        &events,
+        Some("SOPClassUID".to_string()),
+        Some("0008,0016".to_string()),
+    );
+}
+
+pub fn xml_get_document_title(events: &Vec<XmlEvent>) -> Option<String> {
+    return xml_get_element_body(
+        &events,
+        Some("DocumentTitle".to_string()),
+        Some("0042,0010".to_string()),
+    );
+}
+
+pub fn xml_get_series_instance_uid(events: &Vec<XmlEvent>) -> Option<String> {
+    return xml_get_element_body(
+        &events,
+        Some("SeriesInstanceUID".to_string()),
+        Some("0020,000e".to_string()),
+    );
+}
+
+pub fn xml_get_sop_instance_uid(events: &Vec<XmlEvent>) -> Option<String> {
+    return xml_get_element_body(
+        &events,
+        Some("SOPInstanceUID".to_string()),
+        Some("0008,0018".to_string()),
+    );
+}
+
+pub fn xml_get_modality(events: &Vec<XmlEvent>) -> Option<String> {
+    return xml_get_element_body(
+        &events,
+        Some("Modality".to_string()),
+        Some("0008,0060".to_string()),
+    );
+}
+
+pub fn xml_get_series_description(events: &Vec<XmlEvent>) -> Option<String> {
+    return xml_get_element_body(
+        &events,
+        Some("SeriesDescription".to_string()),
+        Some("0008,103e".to_string()),
+    );
+}
+
+pub fn xml_get_study_description(events: &Vec<XmlEvent>) -> Option<String> {
+    return xml_get_element_body(
+        &events,
+        Some("StudyDescription".to_string()),
+        Some("0008,1030".to_string()),
+    );
+}
+
+pub fn xml_get_series_number(events: &Vec<XmlEvent>) -> Option<String> {
+    return xml_get_element_body(
+        &events,
+        Some("SeriesNumber".to_string()),
+        Some("0020,0011".to_string()),
+    );
+}
+
+pub fn xml_get_instance_number(events: &Vec<XmlEvent>) -> Option<String> {
+    return xml_get_element_body(
+        &events,
+        Some("InstanceNumber".to_string()),
+        Some("0020,0013".to_string()),
+    );
+}
+
+pub fn xml_get_frame_of_reference_uid(events: &Vec<XmlEvent>) -> Option<String> {
+    return xml_get_element_body(
+        &events,
+        Some("FrameOfReferenceUID".to_string()),
+        Some("0020,0052".to_string()),
+    );
+}
+
+/// ReferencedFileIDs of the directory records of a DICOMDIR,
+/// each one as its path components
+pub fn xml_get_referenced_file_ids(events: &Vec<XmlEvent>) -> Vec<Vec<String>> {
+    let mut result = vec![];
+    for (index, event) in events.iter().enumerate() {
+        match event {
+            XmlEvent::StartElement {
+                name: OwnedName { local_name, .. },
+                attributes,
+                ..
+            } if local_name.as_str() == "element"
+                && value_of_attribute(&attributes, "tag").as_deref() == Some("0004,1500") =>
+            {
+                if let Some(XmlEvent::Characters(body)) = events.get(index + 1) {
+                    result.push(body.split('\\').map(|s| s.trim().to_string()).collect());
+                }
+            }
+            _ => {}
+        }
+    }
+    return result;
+}
+
+pub fn xml_get_referring_physician_name(events: &Vec<XmlEvent>) -> Option<String> {
+    return xml_get_element_body(
+        &events,
+        Some("ReferringPhysicianName".to_string()),
+        Some("0008,0090".to_string()),
+    );
+}
+
+pub fn xml_get_requesting_physician(events: &Vec<XmlEvent>) -> Option<String> {
+    return xml_get_element_body(
+        &events,
+        Some("RequestingPhysician".to_string()),
+        Some("0032,1032".to_string()),
+    );
+}
+
+/// One element, sequence or item of the dcm2xml output
+#[derive(Debug, Default)]
+pub(crate) struct XmlNode {
+    pub kind: String,
+    pub tag: String,
+    pub vr: String,
+    pub name: String,
+    pub body: String,
+    pub children: Vec<XmlNode>,
+}
+
+impl XmlNode {
+    pub fn child(&self, tag: &str) -> Option<&XmlNode> {
+        return self.children.iter().find(|c| c.tag == tag);
+    }
+
+    pub fn child_body(&self, tag: &str) -> Option<String> {
+        return self.child(tag).map(|c| c.body.trim().to_string());
+    }
+
+    /// The first item of the sequence `tag`
+    pub fn first_item(&self, tag: &str) -> Option<&XmlNode> {
+        return self.child(tag).and_then(|s| s.children.first());
+    }
+}
+
+/// The elements, sequences and items of the dcm2xml output as a tree,
+/// the root holds the elements of the meta header and the data set
+pub(crate) fn build_xml_tree(events: &Vec<XmlEvent>) -> XmlNode {
+    let mut stack = vec![XmlNode::default()];
+    for event in events {
+        match event {
+            XmlEvent::StartElement {
+                name: OwnedName { local_name, .. },
+                attributes,
+                ..
+            } if is_xml_node(local_name) => {
+                let attribute =
+                    |name: &str| value_of_attribute(attributes, name).unwrap_or_default();
+                stack.push(XmlNode {
+                    kind: local_name.clone(),
+                    tag: attribute("tag"),
+                    vr: attribute("vr"),
+                    name: attribute("name"),
+                    ..Default::default()
+                });
+            }
+            XmlEvent::Characters(body) => {
+                if let Some(node) = stack.last_mut() {
+                    node.body.push_str(body);
+                }
+            }
+            XmlEvent::EndElement {
+                name: OwnedName { local_name, .. },
+            } if is_xml_node(local_name) => {
+                if stack.len() > 1 {
+                    let node = stack.pop().unwrap();
+                    stack.last_mut().unwrap().children.push(node);
+                }
+            }
+            _ => {}
+        }
+    }
+    return stack.swap_remove(0);
+}
+
+fn is_xml_node(local_name: &str) -> bool {
+    return matches!(
+        local_name,
+        "element" | "sequence" | "item" | "pixel-sequence" | "pixel-item"
+    );
+}
@@ -0,0 +1,300 @@
+use std::ffi::OsStr;
+use std::fs::{copy, create_dir_all, read_dir, remove_dir_all, remove_file, File};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+use crate::command::{exec_command, log};
+use crate::dcm_xml::{
+    parse_dcm_as_xml, xml_get_instance_number, xml_get_modality, xml_get_referenced_file_ids,
+    xml_get_series_description, xml_get_series_instance_uid, xml_get_series_number,
+    xml_get_sop_instance_uid, xml_get_study_description, xml_get_study_instance_uid, RequestTags,
+};
+use crate::error::G2DError;
+use crate::gdt::dcm_xml_to_file;
+use crate::person_name::NameCase;
+
+/// Application profile of the file-set, passed to dcmmkdir
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum MediaProfile {
+    /// STD-GEN-CD/DVD-RAM, uncompressed images only
+    Cd,
+    /// STD-GEN-DVD-JPEG
+    Dvd,
+    /// STD-GEN-USB/MMC/CF/SD-JPEG
+    #[default]
+    Usb,
+}
+
+impl MediaProfile {
+    fn dcmmkdir_arg(&self) -> &'static str {
+        match self {
+            MediaProfile::Cd => "--general-purpose",
+            MediaProfile::Dvd => "--general-purpose-dvd",
+            MediaProfile::Usb => "--usb-and-flash",
+        }
+    }
+}
+
+type SeriesList<'a> = Vec<(String, Vec<&'a MediaInstance>)>;
+type StudyList<'a> = Vec<(String, SeriesList<'a>)>;
+
+/// Patient and hierarchy information of one DICOM file
+#[derive(Debug, Clone)]
+pub struct MediaInstance {
+    pub source: PathBuf,
+    pub patient_id: String,
+    pub patient_name: String,
+    pub patient_birth_date: String, // DDMMYYYY, as in GDT 3103
+    pub study_uid: String,
+    pub study_date: String, // DDMMYYYY, as in GDT 6200
+    pub study_description: String,
+    pub series_uid: String,
+    pub series_number: usize,
+    pub series_description: String,
+    pub modality: String,
+    pub sop_instance_uid: String,
+    pub instance_number: usize,
+}
+
+impl MediaInstance {
+    pub fn read(path: &Path) -> Result<MediaInstance, G2DError> {
+        let events = parse_dcm_as_xml(&path.to_path_buf())?;
+        let file = dcm_xml_to_file(&events, NameCase::Preserve, &RequestTags::default());
+        let required = |value: Option<String>, name: &str| -> Result<String, G2DError> {
+            match value.filter(|v| !v.is_empty()) {
+                Some(v) => Ok(v),
+                None => {
+                    let message = format!("{} has no {}", path.display(), name);
+                    Err(G2DError::IoError(Error::new(ErrorKind::Other, message)))
+                }
+            }
+        };
+        let number = |value: Option<String>| -> usize {
+            value.and_then(|v| v.trim().parse().ok()).unwrap_or(0)
+        };
+        return Ok(MediaInstance {
+            source: path.to_path_buf(),
+            patient_name: format!(
+                "{}, {}",
+                file.object_patient.patient_name, file.object_patient.patient_first_name
+            ),
+            patient_id: file.object_patient.patient_number,
+            patient_birth_date: file.object_patient.patient_dob,
+            study_uid: required(xml_get_study_instance_uid(&events), "StudyInstanceUID")?,
+            study_date: file.object_request.date_of_examination,
+            study_description: xml_get_study_description(&events).unwrap_or_default(),
+            series_uid: required(xml_get_series_instance_uid(&events), "SeriesInstanceUID")?,
+            series_number: number(xml_get_series_number(&events)),
+            series_description: xml_get_series_description(&events).unwrap_or_default(),
+            modality: xml_get_modality(&events).unwrap_or_default(),
+            sop_instance_uid: required(xml_get_sop_instance_uid(&events), "SOPInstanceUID")?,
+            instance_number: number(xml_get_instance_number(&events)),
+        });
+    }
+}
+
+/// Lists the DICOM files (with "DICM" preamble) of the given files and folders,
+/// folders are searched recursively. Existing DICOMDIR files are skipped.
+pub fn collect_dicom_files(inputs: &[PathBuf]) -> Result<Vec<PathBuf>, G2DError> {
+    let mut result = vec![];
+    for input in inputs {
+        if input.is_dir() {
+            let mut entries = read_dir(input)?
+                .filter_map(|res| res.ok().map(|e| e.path()))
+                .collect::<Vec<_>>();
+            entries.sort();
+            result.extend(collect_dicom_files(&entries)?);
+        } else if input.is_file()
+            && input.file_name() != Some(OsStr::new("DICOMDIR"))
+            && is_dicom_file(input)
+        {
+            result.push(input.clone());
+        }
+    }
+    return Ok(result);
+}
+
+/// Lists the files referenced by a DICOMDIR, relative to its folder
+pub fn dicomdir_referenced_files(dicomdir: &Path) -> Result<Vec<PathBuf>, G2DError> {
+    let events = parse_dcm_as_xml(&dicomdir.to_path_buf())?;
+    let base = dicomdir.parent().unwrap_or(Path::new("."));
+    let mut result: Vec<PathBuf> = vec![];
+    for components in xml_get_referenced_file_ids(&events) {
+        let mut path = base.to_path_buf();
+        for component in components.iter().filter(|c| !c.is_empty()) {
+            path.push(component);
+        }
+        if path.is_file() && !result.contains(&path) {
+            result.push(path);
+        }
+    }
+    return Ok(result);
+}
+
+pub fn is_dicomdir(path: &Path) -> bool {
+    return path.file_name() == Some(OsStr::new("DICOMDIR")) && path.is_file();
+}
+
+/// Checks for the "DICM" prefix after the 128 byte preamble
+pub fn is_dicom_file(path: &Path) -> bool {
+    let mut header = [0u8; 132];
+    match File::open(path).and_then(|mut f| f.read_exact(&mut header)) {
+        Ok(()) => &header[128..132] == b"DICM",
+        Err(_) => false,
+    }
+}
+
+/// Writes a DICOM media file-set to `output_dir`:
+///
+/// ```text
+/// output_dir/DICOMDIR
+/// output_dir/README.TXT
+/// output_dir/DICOM/PAT00001/STU00001/SER00001/IMG00001
+/// ```
+///
+/// The file IDs are ISO 9660 safe (8 characters, A-Z and 0-9). The DICOMDIR
+/// is created with `dcmmkdir`. Duplicate instances (same SOPInstanceUID) are
+/// written once. Returns the path of the DICOMDIR.
+pub fn write_media(
+    inputs: &[PathBuf],
+    output_dir: &Path,
+    profile: MediaProfile,
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<PathBuf, G2DError> {
+    let dicomdir_path = output_dir.join("DICOMDIR");
+    let dicom_path = output_dir.join("DICOM");
+    if dicomdir_path.exists() || dicom_path.exists() {
+        let message = format!("{} already contains a file-set", output_dir.display());
+        return Err(G2DError::IoError(Error::new(ErrorKind::Other, message)));
+    }
+
+    let mut instances = vec![];
+    for path in collect_dicom_files(inputs)? {
+        let instance = MediaInstance::read(&path)?;
+        if instances
+            .iter()
+            .any(|i: &MediaInstance| i.sop_instance_uid == instance.sop_instance_uid)
+        {
+            log(
+                log_sender,
+                format!("Skipping duplicate instance {}", path.display()),
+            );
+            continue;
+        }
+        instances.push(instance);
+    }
+    if instances.is_empty() {
+        return Err(G2DError::IoError(Error::new(
+            ErrorKind::Other,
+            "No DICOM files found",
+        )));
+    }
+
+    // patient -> study -> series -> instances, in order of first appearance
+    let mut patients: Vec<(String, StudyList)> = vec![];
+    for instance in &instances {
+        let patient = find_or_insert(&mut patients, &instance.patient_id);
+        let study = find_or_insert(patient, &instance.study_uid);
+        let series = find_or_insert(study, &instance.series_uid);
+        series.push(instance);
+    }
+
+    let readme = match write_file_set(&mut patients, output_dir, profile, log_sender) {
+        Ok(readme) => readme,
+        Err(err) => {
+            // A partial file-set would make the next attempt fail
+            _ = remove_dir_all(&dicom_path);
+            _ = remove_file(&dicomdir_path);
+            return Err(err);
+        }
+    };
+    let mut readme_file = File::create(output_dir.join("README.TXT"))?;
+    readme_file.write_all(readme.as_bytes())?;
+    return Ok(dicomdir_path);
+}
+
+/// Copies the instances into `DICOM/` below `output_dir` and creates the
+/// DICOMDIR, returns the text of the README.TXT
+fn write_file_set(
+    patients: &mut [(String, StudyList)],
+    output_dir: &Path,
+    profile: MediaProfile,
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<String, G2DError> {
+    let dicomdir_path = output_dir.join("DICOMDIR");
+    let dicom_path = output_dir.join("DICOM");
+    let mut readme = String::new();
+    for (patient_index, (_, studies)) in patients.iter_mut().enumerate() {
+        let patient_dir = format!("PAT{:05}", patient_index + 1);
+        let first = studies[0].1[0].1[0];
+        readme.push_str(&format!(
+            "{} ({}), ID {}\r\n",
+            first.patient_name, first.patient_birth_date, first.patient_id
+        ));
+        for (study_index, (_, series_list)) in studies.iter_mut().enumerate() {
+            let study_dir = format!("STU{:05}", study_index + 1);
+            let first = series_list[0].1[0];
+            readme.push_str(&format!(
+                "  {} {}\r\n",
+                first.study_date, first.study_description
+            ));
+            series_list.sort_by_key(|(_, s)| s[0].series_number);
+            for (series_index, (_, series)) in series_list.iter_mut().enumerate() {
+                let series_dir = dicom_path
+                    .join(&patient_dir)
+                    .join(&study_dir)
+                    .join(format!("SER{:05}", series_index + 1));
+                create_dir_all(&series_dir)?;
+                series.sort_by_key(|i| i.instance_number);
+                for (instance_index, instance) in series.iter().enumerate() {
+                    let target = series_dir.join(format!("IMG{:05}", instance_index + 1));
+                    copy(&instance.source, &target)?;
+                    log(
+                        log_sender,
+                        format!("{} -> {}", instance.source.display(), target.display()),
+                    );
+                }
+                readme.push_str(&format!(
+                    "    {} {} ({} files)\r\n",
+                    series[0].modality,
+                    series[0].series_description,
+                    series.len()
+                ));
+            }
+        }
+    }
+
+    let output = exec_command(
+        "dcmmkdir",
+        vec![
+            OsStr::new(profile.dcmmkdir_arg()),
+            OsStr::new("--recurse"),
+            OsStr::new("--invent"),
+            OsStr::new("--input-directory"),
+            output_dir.as_os_str(),
+            OsStr::new("--output-file"),
+            dicomdir_path.as_os_str(),
+            OsStr::new("DICOM"),
+        ],
+        true,
+        log_sender,
+    )?;
+    if !output.status.success() {
+        let err_str = String::from_utf8_lossy(&output.stderr).to_string();
+        log(log_sender, err_str.clone());
+        return Err(G2DError::IoError(Error::new(ErrorKind::Other, err_str)));
+    }
+    return Ok(readme);
+}
+
+fn find_or_insert<'a, T: Default>(list: &'a mut Vec<(String, T)>, key: &str) -> &'a mut T {
+    let index = match list.iter().position(|(k, _)| k == key) {
+        Some(i) => i,
+        None => {
+            list.push((key.to_string(), T::default()));
+            list.len() - 1
+        }
+    };
+    return &mut list[index].1;
+}
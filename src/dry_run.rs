@@ -0,0 +1,296 @@
+use std::path::{Path, PathBuf};
+
+use xml::reader::XmlEvent;
+
+use crate::dcm_xml::{build_xml_tree, RequestTag, RequestTags, XmlNode};
+use crate::error::G2DError;
+use crate::gdt::parse_file_lines;
+use crate::validation::validate_gdt_file;
+
+/// One attribute of the data set a conversion would write
+#[derive(Debug, Clone)]
+pub struct DryRunAttribute {
+    /// e.g. `(0010,0010)`, `(0040,0100)[0].(0040,0001)` in a sequence
+    pub tag: String,
+    pub vr: String,
+    pub name: String,
+    pub value: String,
+    /// GDT field(s) the value is taken from, `template`, `config` or an option
+    pub source: String,
+}
+
+impl DryRunAttribute {
+    /// `tag` as in DcmElement, e.g. `0008,0016`
+    pub fn new(tag: &str, vr: &str, name: &str, value: &str, source: &str) -> DryRunAttribute {
+        return DryRunAttribute {
+            tag: format!("({})", tag),
+            vr: vr.to_string(),
+            name: name.to_string(),
+            value: value.to_string(),
+            source: source.to_string(),
+        };
+    }
+}
+
+/// A series written from images, PDFs or the text report of a GDT file,
+/// besides the attributes of the GDT file
+#[derive(Debug, Clone)]
+pub struct DryRunSeries {
+    pub number: usize,
+    pub attributes: Vec<DryRunAttribute>,
+    pub instances: Vec<DryRunInstance>,
+    pub notes: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DryRunInstance {
+    pub output: PathBuf,
+    /// The input files, several for a multi-frame instance
+    pub inputs: Vec<PathBuf>,
+    pub attributes: Vec<DryRunAttribute>,
+    pub notes: Vec<String>,
+}
+
+/// For the warnings of conversions which don't store 8310 and 8314
+pub const NO_REQUEST_TAGS: RequestTags = RequestTags {
+    identifier: RequestTag::None,
+    uid: RequestTag::None,
+};
+
+/// Prints the lines of the GDT file
+pub fn print_gdt_file(path: &Path) -> Result<(), G2DError> {
+    println!("GDT file {}:", path.display());
+    for line in gdt_file_lines(path)? {
+        println!("  {}", line);
+    }
+    return Ok(());
+}
+
+/// Prints the problems of the GDT file found by `validate_gdt_file` and `more_warnings`
+pub fn print_warnings(path: &Path, request_tags: &RequestTags, more_warnings: Vec<String>) {
+    let validation = validate_gdt_file(path, request_tags);
+    let warnings: Vec<String> = validation
+        .errors
+        .into_iter()
+        .chain(validation.warnings)
+        .chain(more_warnings)
+        .collect();
+    if warnings.is_empty() {
+        println!("No warnings");
+        return;
+    }
+    println!("Warnings:");
+    for warning in &warnings {
+        println!("  {}", warning);
+    }
+}
+
+/// The lines of a GDT file with the meaning of the fields gdt2dicom reads
+pub fn gdt_file_lines(path: &Path) -> Result<Vec<String>, G2DError> {
+    let mut lines = vec![];
+    for (index, line) in parse_file_lines(path)?.enumerate() {
+        match line {
+            Ok(line) => lines.push(format!(
+                "{:04} {:<24} {}",
+                line.field_identifier,
+                gdt_field_name(line.field_identifier).unwrap_or(""),
+                line.content
+            )),
+            Err(err) => lines.push(format!("Line {}: {}", index + 1, err)),
+        }
+    }
+    return Ok(lines);
+}
+
+fn gdt_field_name(field: u32) -> Option<&'static str> {
+    return match field {
+        211 => Some("Physician name"),
+        212 => Some("Physician number"),
+        3000 => Some("Patient number"),
+        3100 => Some("Name affix"),
+        3101 => Some("Name"),
+        3102 => Some("First name"),
+        3103 => Some("Birth date"),
+        3104 => Some("Title"),
+        3105 => Some("Insurance number"),
+        3106 => Some("City"),
+        3107 => Some("Street"),
+        3110 => Some("Sex"),
+        3618 => Some("Mobile phone"),
+        3619 => Some("E-mail"),
+        3622 => Some("Height (cm)"),
+        3623 | 3632 => Some("Weight (kg)"),
+        3626 => Some("Phone"),
+        6200 => Some("Examination date"),
+        6201 => Some("Examination time"),
+        6205 => Some("Diagnosis"),
+        6220 => Some("Findings"),
+        6227 => Some("Comment"),
+        6302..=6305 => Some("Attachment"),
+        8000 => Some("Record type"),
+        8100 => Some("Record length"),
+        8200 => Some("Object"),
+        8201 => Some("End of object"),
+        8202 => Some("End of record"),
+        8310 => Some("Request identifier"),
+        8314 => Some("Request UID"),
+        8315 => Some("Receiver ID"),
+        8316 => Some("Sender ID"),
+        8410..=8421 => Some("Test result"),
+        9218 => Some("GDT version"),
+        _ => None,
+    };
+}
+
+/// The attributes of `result`, which was built from `template` and a GDT
+/// file, with the GDT field of each value. Values of the template which
+/// were kept are marked `template`.
+pub fn dcm_attributes(
+    template: &Vec<XmlEvent>,
+    result: &Vec<XmlEvent>,
+    request_tags: &RequestTags,
+) -> Vec<DryRunAttribute> {
+    let mut template_attributes = vec![];
+    collect_attributes(&build_xml_tree(template), "", &mut template_attributes);
+    let mut attributes = vec![];
+    collect_attributes(&build_xml_tree(result), "", &mut attributes);
+
+    let request_tag = |tag: RequestTag| {
+        tag.dcm_element("1")
+            .map(|e| format!("({})", e.tag))
+            .unwrap_or_default()
+    };
+    let identifier_tag = request_tag(request_tags.identifier);
+    let uid_tag = request_tag(request_tags.uid);
+    for attribute in attributes.iter_mut() {
+        let unchanged = template_attributes
+            .iter()
+            .any(|t| t.tag == attribute.tag && t.value == attribute.value);
+        let source = match attribute.tag.to_ascii_lowercase().as_str() {
+            _ if unchanged && !attribute.value.is_empty() => "template",
+            t if t == identifier_tag => "8310",
+            t if t == uid_tag => "8314",
+            "(0008,0005)" => "config",
+            "(0008,0020)" => "6200",
+            "(0008,0030)" => "6201",
+            "(0008,0090)" | "(0032,1032)" => "0211",
+            "(0010,0010)" => "3101, 3102, 3104, 3100",
+            "(0010,0020)" => "3000",
+            "(0010,0030)" => "3103",
+            "(0010,0040)" => "3110",
+            "(0010,1020)" => "3622",
+            "(0010,1030)" => "3623",
+            "(0020,000d)" => "8314, 8310",
+            _ => "template",
+        };
+        attribute.source = source.to_string();
+    }
+    return attributes;
+}
+
+fn collect_attributes(node: &XmlNode, prefix: &str, attributes: &mut Vec<DryRunAttribute>) {
+    for child in &node.children {
+        let tag = format!("{}({})", prefix, child.tag);
+        match child.kind.as_str() {
+            "element" => attributes.push(DryRunAttribute {
+                tag,
+                vr: child.vr.clone(),
+                name: child.name.clone(),
+                value: child.body.trim().to_string(),
+                source: String::new(),
+            }),
+            "sequence" => {
+                for (index, item) in child.children.iter().enumerate() {
+                    collect_attributes(item, &format!("{}[{}].", tag, index), attributes);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Warnings about values longer than their VR allows, missing values are
+/// reported by `validate_gdt_file`
+pub fn dcm_attribute_warnings(attributes: &Vec<DryRunAttribute>) -> Vec<String> {
+    let mut warnings = vec![];
+    for attribute in attributes {
+        let Some(max_length) = vr_max_length(&attribute.vr) else {
+            continue;
+        };
+        // Multiple values and the components of a PN have their own limit
+        let too_long = attribute
+            .value
+            .split(['\\', '='])
+            .any(|v| v.chars().count() > max_length);
+        if too_long {
+            warnings.push(format!(
+                "{} {} \"{}\" is longer than the {} characters of {}",
+                attribute.tag, attribute.name, attribute.value, max_length, attribute.vr
+            ));
+        }
+    }
+    return warnings;
+}
+
+fn vr_max_length(vr: &str) -> Option<usize> {
+    return match vr {
+        "AE" | "CS" | "DS" | "SH" => Some(16),
+        "AS" => Some(4),
+        "DA" => Some(8),
+        "IS" => Some(12),
+        "TM" => Some(14),
+        "LO" | "PN" | "UI" => Some(64),
+        "ST" => Some(1024),
+        "LT" => Some(10240),
+        _ => None,
+    };
+}
+
+pub fn print_dcm_attributes(attributes: &Vec<DryRunAttribute>) {
+    print_indented_attributes(attributes, "");
+}
+
+/// Prints each series with its attributes and instances
+pub fn print_series(series_list: &Vec<DryRunSeries>) {
+    for series in series_list {
+        println!("\nSeries {}:", series.number);
+        for note in &series.notes {
+            println!("  {}", note);
+        }
+        print_indented_attributes(&series.attributes, "  ");
+        for instance in &series.instances {
+            let inputs: Vec<String> = instance
+                .inputs
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect();
+            if inputs.is_empty() {
+                println!("  {}", instance.output.display());
+            } else {
+                println!("  {} from {}", instance.output.display(), inputs.join(", "));
+            }
+            for note in &instance.notes {
+                println!("    {}", note);
+            }
+            print_indented_attributes(&instance.attributes, "    ");
+        }
+    }
+}
+
+fn print_indented_attributes(attributes: &Vec<DryRunAttribute>, indent: &str) {
+    for attribute in attributes {
+        println!(
+            "{}{:<30} {} {:<32} {:<40} {}",
+            indent,
+            attribute.tag,
+            if attribute.vr.is_empty() {
+                "  "
+            } else {
+                &attribute.vr
+            },
+            attribute.name,
+            format!("\"{}\"", attribute.value),
+            attribute.source
+        );
+    }
+}
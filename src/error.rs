@@ -7,6 +7,15 @@ pub enum G2DError {
     GdtError(GdtError),
     XmlReaderError(xml::reader::Error),
     XmlWriterError(xml::writer::Error),
+    ImageError(image::ImageError),
+    TiffError(tiff::TiffError),
+    JsonError(serde_json::Error),
+    Base64Error(base64::DecodeError),
+    VddsError(VddsError),
+    /// A wrong command line option or config file value
+    InvalidArgument(String),
+    /// An input file which cannot be converted
+    InvalidInput(String),
 }
 
 #[derive(Debug)]
@@ -18,6 +27,22 @@ pub enum GdtError {
     InvalidValue(String, String),
 }
 
+#[derive(Debug)]
+pub enum VddsError {
+    NoBvs,
+    /// The preferred BVS and the available ones
+    BvsNotFound(String, Vec<String>),
+    MultipleBvs(Vec<String>),
+    /// A section or key of VDDS_MMI.ini which is not there
+    MissingEntry(String),
+    /// ERRORLEVEL and ERRORTEXT of the reply
+    BvsError(String, String),
+    /// A section or key of the reply which is not there
+    InvalidReply(String),
+    /// The BVS didn't set READY=1 within this many seconds
+    Timeout(u64),
+}
+
 impl From<std::io::Error> for G2DError {
     fn from(error: std::io::Error) -> Self {
         G2DError::IoError(error)
@@ -48,6 +73,36 @@ impl From<xml::writer::Error> for G2DError {
     }
 }
 
+impl From<image::ImageError> for G2DError {
+    fn from(error: image::ImageError) -> Self {
+        G2DError::ImageError(error)
+    }
+}
+
+impl From<tiff::TiffError> for G2DError {
+    fn from(error: tiff::TiffError) -> Self {
+        G2DError::TiffError(error)
+    }
+}
+
+impl From<serde_json::Error> for G2DError {
+    fn from(error: serde_json::Error) -> Self {
+        G2DError::JsonError(error)
+    }
+}
+
+impl From<base64::DecodeError> for G2DError {
+    fn from(error: base64::DecodeError) -> Self {
+        G2DError::Base64Error(error)
+    }
+}
+
+impl From<VddsError> for G2DError {
+    fn from(error: VddsError) -> Self {
+        G2DError::VddsError(error)
+    }
+}
+
 impl fmt::Display for G2DError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -56,6 +111,13 @@ impl fmt::Display for G2DError {
             G2DError::GdtError(e) => write!(f, "GdtError: {}", e),
             G2DError::XmlReaderError(e) => write!(f, "XmlReaderError: {}", e),
             G2DError::XmlWriterError(e) => write!(f, "XmlWriterError: {}", e),
+            G2DError::ImageError(e) => write!(f, "ImageError: {}", e),
+            G2DError::TiffError(e) => write!(f, "TiffError: {}", e),
+            G2DError::JsonError(e) => write!(f, "JsonError: {}", e),
+            G2DError::Base64Error(e) => write!(f, "Base64Error: {}", e),
+            G2DError::VddsError(e) => write!(f, "VddsError: {}", e),
+            G2DError::InvalidArgument(e) => write!(f, "{}", e),
+            G2DError::InvalidInput(e) => write!(f, "{}", e),
         }
     }
 }
@@ -65,3 +127,32 @@ impl std::fmt::Display for GdtError {
         write!(f, "{:?}", self)
     }
 }
+
+impl std::fmt::Display for VddsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VddsError::NoBvs => write!(f, "No BVS found"),
+            VddsError::BvsNotFound(name, available) => write!(
+                f,
+                "Cannot find the BVS {}, please choose from one of these: {}",
+                name,
+                available.join(", ")
+            ),
+            VddsError::MultipleBvs(available) => write!(
+                f,
+                "Multiple BVS available, please specify one of the following: {}",
+                available.join(", ")
+            ),
+            VddsError::MissingEntry(entry) => write!(f, "{} missing in VDDS_MMI", entry),
+            VddsError::BvsError(level, text) => {
+                write!(f, "Error from BVS ({}): {}", level, text)
+            }
+            VddsError::InvalidReply(entry) => {
+                write!(f, "{} missing in the reply of the BVS", entry)
+            }
+            VddsError::Timeout(seconds) => {
+                write!(f, "The BVS didn't reply within {} seconds", seconds)
+            }
+        }
+    }
+}
@@ -0,0 +1,439 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use xml::reader::XmlEvent;
+
+use crate::dcm_xml::{
+    xml_get_instance_number, xml_get_modality, xml_get_patient_birth_date, xml_get_patient_name,
+    xml_get_patient_patient_id, xml_get_series_number, xml_get_study_date, xml_get_study_time,
+    RequestTags,
+};
+use crate::gdt::GdtFile;
+use crate::person_name::PersonName;
+
+/// Worklist files written by the worklist conversion
+pub const WORKLIST_FILENAME_TEMPLATE: &str = "{first_name}_{last_name}_{timestamp}";
+/// Frames exported from DICOM files
+pub const IMAGE_FILENAME_TEMPLATE: &str = "{patient_id}_{frame}";
+/// Images fetched by gdt2vdds
+pub const VDDS_FILENAME_TEMPLATE: &str =
+    "{patient_id}_{first_name}_{last_name}_{image_date}{image_time}_{mmo_id}";
+
+const SEQ: &str = "seq";
+
+/// Variables which can be used in a template, not every variable is known everywhere
+pub const TEMPLATE_VARIABLES: [(&str, &str); 16] = [
+    ("patient_id", "Patient ID, 3000 or PatientID"),
+    ("first_name", "Given name, 3102 or PatientName"),
+    ("last_name", "Family name, 3101 or PatientName"),
+    ("birth_date", "Birth date as YYYYMMDD"),
+    ("request_id", "Request identifier, 8310 or AccessionNumber"),
+    ("study_date", "Date of the examination as YYYYMMDD"),
+    ("study_time", "Time of the examination as HHMMSS"),
+    ("modality", "Modality, e.g. OP"),
+    ("series", "SeriesNumber"),
+    ("instance", "InstanceNumber"),
+    ("frame", "Frame number, starting at 0"),
+    ("image_date", "Date of the image (gdt2vdds)"),
+    ("image_time", "Time of the image (gdt2vdds)"),
+    ("mmo_id", "ID of the image (gdt2vdds)"),
+    ("timestamp", "Current time as DD.MM.YYYY_HH.MM.SS"),
+    (SEQ, "Counter, the first number giving a new file name"),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplatePart {
+    Text(String),
+    /// A variable, numbers are padded with zeros to `width`, e.g. `{seq:03}`
+    Variable {
+        name: String,
+        width: usize,
+    },
+}
+
+/// A file name pattern like `{patient_id}_{study_date}_{modality}_{seq}`,
+/// without extension. The rendered name is made safe for file systems.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct FilenameTemplate {
+    pattern: String,
+    parts: Vec<TemplatePart>,
+}
+
+impl FilenameTemplate {
+    pub fn worklist() -> FilenameTemplate {
+        return FilenameTemplate::from_str(WORKLIST_FILENAME_TEMPLATE).unwrap();
+    }
+
+    pub fn image() -> FilenameTemplate {
+        return FilenameTemplate::from_str(IMAGE_FILENAME_TEMPLATE).unwrap();
+    }
+
+    pub fn vdds() -> FilenameTemplate {
+        return FilenameTemplate::from_str(VDDS_FILENAME_TEMPLATE).unwrap();
+    }
+
+    pub fn pattern(&self) -> &str {
+        return &self.pattern;
+    }
+
+    pub fn uses(&self, variable: &str) -> bool {
+        return self
+            .parts
+            .iter()
+            .any(|p| matches!(p, TemplatePart::Variable { name, .. } if name == variable));
+    }
+
+    /// The sanitised file name, unknown values are left empty
+    pub fn render(&self, values: &FilenameValues) -> String {
+        let mut result = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Text(text) => result.push_str(text),
+                TemplatePart::Variable { name, width } => {
+                    let value = values.get(name).unwrap_or_default();
+                    result.push_str(&format!("{:0>width$}", value, width = width));
+                }
+            }
+        }
+        let name = sanitize_filename(&result);
+        if name.is_empty() {
+            return "unnamed".to_string();
+        }
+        return name;
+    }
+
+    /// `[dir]/[rendered name].[extension]` for a file which doesn't exist yet:
+    /// `{seq}` counts up from 1, without `{seq}` `_2`, `_3`, ... is appended
+    pub fn unique_path(&self, dir: &Path, extension: &str, values: &FilenameValues) -> PathBuf {
+        let path_for = |name: String| -> PathBuf {
+            if extension.is_empty() {
+                return dir.join(name);
+            }
+            return dir.join(format!("{}.{}", name, extension));
+        };
+        if self.uses(SEQ) {
+            let mut values = values.clone();
+            for seq in 1.. {
+                values.set(SEQ, seq.to_string());
+                let path = path_for(self.render(&values));
+                if !path.exists() {
+                    return path;
+                }
+            }
+        }
+        let name = self.render(values);
+        let mut path = path_for(name.clone());
+        let mut counter = 2;
+        while path.exists() {
+            path = path_for(format!("{}_{}", name, counter));
+            counter += 1;
+        }
+        return path;
+    }
+}
+
+impl FromStr for FilenameTemplate {
+    type Err = String;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut rest = pattern;
+        while let Some(start) = rest.find('{') {
+            if rest[..start].contains('}') {
+                return Err(format!("Unexpected }} in file name template {}", pattern));
+            }
+            if start > 0 {
+                parts.push(TemplatePart::Text(rest[..start].to_string()));
+            }
+            let Some(end) = rest[start..].find('}') else {
+                return Err(format!("Unclosed {{ in file name template {}", pattern));
+            };
+            let variable = &rest[start + 1..start + end];
+            let (name, width) = match variable.split_once(':') {
+                Some((name, width)) => match width.parse::<usize>() {
+                    Ok(w) if width.starts_with('0') => (name, w),
+                    _ => {
+                        return Err(format!(
+                            "Invalid width {} in file name template, expected e.g. {{seq:03}}",
+                            width
+                        ));
+                    }
+                },
+                None => (variable, 0),
+            };
+            if !TEMPLATE_VARIABLES.iter().any(|(v, _)| *v == name) {
+                return Err(format!(
+                    "Unknown variable {{{}}} in file name template, expected one of {}",
+                    name,
+                    TEMPLATE_VARIABLES
+                        .iter()
+                        .map(|(v, _)| format!("{{{}}}", v))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ));
+            }
+            parts.push(TemplatePart::Variable {
+                name: name.to_string(),
+                width,
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(format!("Unexpected }} in file name template {}", pattern));
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Text(rest.to_string()));
+        }
+        if parts.is_empty() {
+            return Err("The file name template is empty".to_string());
+        }
+        return Ok(FilenameTemplate {
+            pattern: pattern.to_string(),
+            parts,
+        });
+    }
+}
+
+impl TryFrom<String> for FilenameTemplate {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        return FilenameTemplate::from_str(&value);
+    }
+}
+
+impl From<FilenameTemplate> for String {
+    fn from(template: FilenameTemplate) -> Self {
+        return template.pattern;
+    }
+}
+
+impl fmt::Display for FilenameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.pattern)
+    }
+}
+
+/// Values of the template variables
+#[derive(Debug, Clone, Default)]
+pub struct FilenameValues {
+    values: BTreeMap<String, String>,
+}
+
+impl FilenameValues {
+    /// Only `timestamp` is set
+    pub fn new() -> FilenameValues {
+        let mut values = FilenameValues::default();
+        values.set(
+            "timestamp",
+            Local::now().format("%d.%m.%Y_%H.%M.%S").to_string(),
+        );
+        return values;
+    }
+
+    pub fn from_gdt_file(file: &GdtFile) -> FilenameValues {
+        let mut values = FilenameValues::new();
+        let patient = &file.object_patient;
+        values
+            .set("patient_id", patient.patient_number.clone())
+            .set("first_name", patient.patient_first_name.clone())
+            .set("last_name", patient.patient_name.clone())
+            .set("birth_date", gdt_date_to_iso(&patient.patient_dob))
+            .set("request_id", file.object_request.request_identifier.clone())
+            .set(
+                "study_date",
+                gdt_date_to_iso(&file.object_request.date_of_examination),
+            )
+            .set(
+                "study_time",
+                file.object_request.time_of_examination.clone(),
+            );
+        return values;
+    }
+
+    /// The values of a DICOM file, `request_id` is read from the attribute
+    /// 8310 is stored in
+    pub fn from_dcm_xml(events: &Vec<XmlEvent>, request_tags: &RequestTags) -> FilenameValues {
+        let mut values = FilenameValues::new();
+        let name = PersonName::parse_dcm(&xml_get_patient_name(events).unwrap_or_default());
+        values
+            .set(
+                "patient_id",
+                xml_get_patient_patient_id(events).unwrap_or_default(),
+            )
+            .set("first_name", name.alphabetic.given_names())
+            .set("last_name", name.alphabetic.family.clone())
+            .set(
+                "birth_date",
+                xml_get_patient_birth_date(events).unwrap_or_default(),
+            )
+            .set(
+                "request_id",
+                request_tags.identifier.get(events).unwrap_or_default(),
+            )
+            .set("study_date", xml_get_study_date(events).unwrap_or_default())
+            .set(
+                "study_time",
+                xml_get_study_time(events)
+                    .map(|t| t.chars().take(6).collect::<String>())
+                    .unwrap_or_default(),
+            )
+            .set("modality", xml_get_modality(events).unwrap_or_default())
+            .set("series", xml_get_series_number(events).unwrap_or_default())
+            .set(
+                "instance",
+                xml_get_instance_number(events).unwrap_or_default(),
+            );
+        return values;
+    }
+
+    pub fn set<V: Into<String>>(&mut self, name: &str, value: V) -> &mut FilenameValues {
+        self.values
+            .insert(name.to_string(), value.into().trim().to_string());
+        return self;
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        return self.values.get(name).cloned();
+    }
+}
+
+/// DDMMYYYY -> YYYYMMDD, other values are kept
+pub fn gdt_date_to_iso(date: &str) -> String {
+    if date.len() != 8 || !date.chars().all(|c| c.is_ascii_digit()) {
+        return date.to_string();
+    }
+    return format!("{}{}{}", &date[4..8], &date[2..4], &date[0..2]);
+}
+
+/// Makes a name safe for Windows, macOS and Linux: umlauts and accents are
+/// transliterated, path separators, spaces and other special characters
+/// become `_`, reserved names like `CON` get a leading `_`
+pub fn sanitize_filename(name: &str) -> String {
+    let mut result = String::new();
+    for c in name.chars() {
+        match c {
+            'ä' => result.push_str("ae"),
+            'ö' => result.push_str("oe"),
+            'ü' => result.push_str("ue"),
+            'Ä' => result.push_str("Ae"),
+            'Ö' => result.push_str("Oe"),
+            'Ü' => result.push_str("Ue"),
+            'ß' => result.push_str("ss"),
+            'à' | 'á' | 'â' | 'ã' | 'å' => result.push('a'),
+            'À' | 'Á' | 'Â' | 'Ã' | 'Å' => result.push('A'),
+            'è' | 'é' | 'ê' | 'ë' => result.push('e'),
+            'È' | 'É' | 'Ê' | 'Ë' => result.push('E'),
+            'ì' | 'í' | 'î' | 'ï' => result.push('i'),
+            'Ì' | 'Í' | 'Î' | 'Ï' => result.push('I'),
+            'ò' | 'ó' | 'ô' | 'õ' | 'ø' => result.push('o'),
+            'Ò' | 'Ó' | 'Ô' | 'Õ' | 'Ø' => result.push('O'),
+            'ù' | 'ú' | 'û' => result.push('u'),
+            'Ù' | 'Ú' | 'Û' => result.push('U'),
+            'ç' => result.push('c'),
+            'Ç' => result.push('C'),
+            'ñ' => result.push('n'),
+            'Ñ' => result.push('N'),
+            c if c.is_ascii_alphanumeric() || "-_.+()".contains(c) => result.push(c),
+            _ => result.push('_'),
+        }
+    }
+    while result.contains("__") {
+        result = result.replace("__", "_");
+    }
+    let result = result.trim_matches(|c| c == '_' || c == '.').to_string();
+    let stem = result.split('.').next().unwrap_or_default();
+    let reserved = ["CON", "PRN", "AUX", "NUL"].contains(&stem.to_ascii_uppercase().as_str())
+        || (stem.len() == 4
+            && ["COM", "LPT"].contains(&stem[..3].to_ascii_uppercase().as_str())
+            && stem[3..].chars().all(|c| c.is_ascii_digit()));
+    if reserved {
+        return format!("_{}", result);
+    }
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_filename_transliterates_umlauts_and_accents() {
+        assert_eq!(sanitize_filename("Müller_Jürgen"), "Mueller_Juergen");
+        assert_eq!(sanitize_filename("Straße"), "Strasse");
+        assert_eq!(sanitize_filename("Élodie Çelik"), "Elodie_Celik");
+    }
+
+    #[test]
+    fn sanitize_filename_replaces_separators_and_special_characters() {
+        assert_eq!(sanitize_filename("a/b\\c:d*e?f"), "a_b_c_d_e_f");
+        assert_eq!(sanitize_filename("a  b"), "a_b");
+        assert_eq!(sanitize_filename("__name__."), "name");
+        assert_eq!(sanitize_filename("x-1.2+(3)"), "x-1.2+(3)");
+    }
+
+    #[test]
+    fn sanitize_filename_escapes_reserved_names() {
+        assert_eq!(sanitize_filename("CON"), "_CON");
+        assert_eq!(sanitize_filename("nul.txt"), "_nul.txt");
+        assert_eq!(sanitize_filename("COM1"), "_COM1");
+        assert_eq!(sanitize_filename("LPT9"), "_LPT9");
+        assert_eq!(sanitize_filename("CONSOLE"), "CONSOLE");
+        assert_eq!(sanitize_filename("COMX"), "COMX");
+    }
+
+    #[test]
+    fn template_from_str_parses_text_and_variables() {
+        let template = FilenameTemplate::from_str("{patient_id}_x_{seq:03}").unwrap();
+        assert_eq!(template.pattern(), "{patient_id}_x_{seq:03}");
+        assert_eq!(
+            template.parts,
+            vec![
+                TemplatePart::Variable {
+                    name: "patient_id".to_string(),
+                    width: 0
+                },
+                TemplatePart::Text("_x_".to_string()),
+                TemplatePart::Variable {
+                    name: "seq".to_string(),
+                    width: 3
+                },
+            ]
+        );
+        assert!(template.uses("seq"));
+        assert!(!template.uses("frame"));
+    }
+
+    #[test]
+    fn template_from_str_accepts_the_defaults() {
+        for pattern in [
+            WORKLIST_FILENAME_TEMPLATE,
+            IMAGE_FILENAME_TEMPLATE,
+            VDDS_FILENAME_TEMPLATE,
+            "plain",
+        ] {
+            assert!(FilenameTemplate::from_str(pattern).is_ok(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn template_from_str_rejects_invalid_patterns() {
+        for pattern in [
+            "",
+            "{patient_id",
+            "patient_id}",
+            "}{patient_id}",
+            "{patient_id}}",
+            "{unknown}",
+            "{seq:3}",
+            "{seq:ab}",
+        ] {
+            assert!(FilenameTemplate::from_str(pattern).is_err(), "{}", pattern);
+        }
+    }
+}
@@ -10,10 +10,12 @@ use xml::reader::XmlEvent;
 
 use crate::dcm_xml::{
     xml_get_patient_birth_date, xml_get_patient_height_meter, xml_get_patient_name,
-    xml_get_patient_patient_id, xml_get_patient_sex, xml_get_patient_weight_kg, xml_get_study_date,
-    xml_get_study_time,
+    xml_get_patient_patient_id, xml_get_patient_sex, xml_get_patient_weight_kg,
+    xml_get_referring_physician_name, xml_get_requesting_physician, xml_get_study_date,
+    xml_get_study_time, RequestTags,
 };
 use crate::error::{G2DError, GdtError};
+use crate::person_name::{NameCase, PersonName};
 
 #[derive(Debug, Default)]
 pub struct GdtFile {
@@ -26,6 +28,8 @@ pub struct GdtFile {
     pub object_permanent_diagnosis: GdtPermanentDiagnosisObject, // Obj_Dauerdiagnosis
     pub object_permanent_medication: GdtPermanentMedicationObject, // Obj_Dauermedikament
     pub object_diagnosis: GdtDiagnosisObject, // Obj_Diagnosis
+    pub object_findings: GdtFindingsObject, // Obj_Befund
+    pub object_test_results: Vec<GdtTestResult>, // Obj_Untersuchungsergebnis
     pub object_admission: GdtAdmissionObject, // Obj_Einweisung
     pub object_header_data: GdtHeaderDataObject, // Obj_Kopfdaten
     pub object_patient: GdtPatientObject, // Obj_Patient
@@ -39,17 +43,36 @@ pub struct GdtFile {
 
 #[derive(Debug, Default)]
 pub struct GdtRequestObject {
-    date_of_examination: String, // 6200, DDMMYYYY
-    time_of_examination: String, // 6201, e.g. 110435
-    request_identifier: String,  // 8310
-    request_uid: String,         // 8314
+    pub date_of_examination: String, // 6200, DDMMYYYY
+    pub time_of_examination: String, // 6201, e.g. 110435
+    pub request_identifier: String,  // 8310
+    pub request_uid: String,         // 8314
 }
 
 #[derive(Debug, Default)]
-pub struct GdtAnnexObject {}
+pub struct GdtAnnexObject {
+    pub attachments: Vec<GdtAttachment>, // one Obj_Anhang per attachment
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct GdtAttachment {
+    pub archive_id: String,     // 6302, Dateiarchivierungskennung
+    pub file_format: String,    // 6303, Dateiformat, e.g. PDF
+    pub content: String,        // 6304, Dateiinhalt
+    pub file_reference: String, // 6305, Verweis auf Datei
+}
 
 #[derive(Debug, Default)]
-pub struct GdtPhysicianIdentificationObject {}
+pub struct GdtPhysicianIdentificationObject {
+    pub physician_name: String,   // 0211, Arztname
+    pub physician_number: String, // 0212, LANR
+}
+
+impl GdtPhysicianIdentificationObject {
+    pub fn is_empty(&self) -> bool {
+        self.physician_name.is_empty() && self.physician_number.is_empty()
+    }
+}
 
 #[derive(Debug, Default)]
 pub struct GdtBasicDiagnosticsObject {
@@ -66,6 +89,28 @@ pub struct GdtPermanentMedicationObject {}
 #[derive(Debug, Default)]
 pub struct GdtDiagnosisObject {}
 
+#[derive(Debug, Default)]
+pub struct GdtFindingsObject {
+    pub current_diagnosis: Vec<String>, // 6205, Aktuelle Diagnose
+    pub findings: Vec<String>,          // 6220, Befund, one line per field
+    pub comments: Vec<String>,          // 6227, Kommentar
+}
+
+impl GdtFindingsObject {
+    pub fn is_empty(&self) -> bool {
+        self.current_diagnosis.is_empty() && self.findings.is_empty() && self.comments.is_empty()
+    }
+}
+
+/// One measurement of an examination
+#[derive(Debug, Default, Clone)]
+pub struct GdtTestResult {
+    pub test_id: String,   // 8410, Test-Ident
+    pub test_name: String, // 8411, Testbezeichnung
+    pub value: String,     // 8420, Ergebnis-Wert
+    pub unit: String,      // 8421, Einheit
+}
+
 #[derive(Debug, Default)]
 pub struct GdtAdmissionObject {}
 
@@ -79,6 +124,7 @@ pub struct GdtHeaderDataObject {
 #[derive(Debug, Default)]
 pub struct GdtPatientObject {
     pub patient_number: String,           // 3000
+    pub name_affix: String,               // 3100, Namenszusatz des Patienten
     pub patient_name: String,             // 3101
     pub patient_first_name: String,       // 3102
     pub patient_dob: String,              // 3103, DDMMYYYY
@@ -124,8 +170,8 @@ impl Default for GdtPatientGender {
 
 #[derive(Debug)]
 pub struct RawGdtLine {
-    field_identifier: u32,
-    content: String,
+    pub field_identifier: u32,
+    pub content: String,
 }
 
 pub fn parse_file_lines<P>(
@@ -185,12 +231,39 @@ where
             }) if content.as_str() == "Obj_Patient" => {
                 file.object_patient = read_patient_object(&mut iter)?;
             }
+            Ok(RawGdtLine {
+                field_identifier: 8200,
+                content,
+            }) if content.as_str() == "Obj_Anhang" => {
+                let attachment = read_annex_object(&mut iter)?;
+                file.object_annex.attachments.push(attachment);
+            }
             Ok(RawGdtLine {
                 field_identifier: 8200,
                 content,
             }) if content.as_str() == "Obj_Basisdiagnostik" => {
                 file.object_basic_diagnostics = read_basic_diagnostics_object(&mut iter)?;
             }
+            Ok(RawGdtLine {
+                field_identifier: 8200,
+                content,
+            }) if content.as_str() == "Obj_Befund" => {
+                file.object_findings = read_findings_object(&mut iter)?;
+            }
+            Ok(RawGdtLine {
+                field_identifier: 8200,
+                content,
+            }) if content.as_str() == "Obj_Untersuchungsergebnis" => {
+                let result = read_test_result_object(&mut iter)?;
+                file.object_test_results.push(result);
+            }
+            Ok(RawGdtLine {
+                field_identifier: 8200,
+                content,
+            }) if content.as_str() == "Obj_Arztidentifikation" => {
+                file.object_physician_identification =
+                    read_physician_identification_object(&mut iter)?;
+            }
             Ok(RawGdtLine {
                 field_identifier: 8202,
                 ..
@@ -312,6 +385,12 @@ fn read_patient_object(iter: &mut GdtLineIter) -> Result<GdtPatientObject, G2DEr
             }) => {
                 obj.patient_number = content;
             }
+            Ok(RawGdtLine {
+                field_identifier: 3100,
+                content,
+            }) => {
+                obj.name_affix = content;
+            }
             Ok(RawGdtLine {
                 field_identifier: 3101,
                 content,
@@ -399,6 +478,154 @@ fn read_patient_object(iter: &mut GdtLineIter) -> Result<GdtPatientObject, G2DEr
     return Ok(obj);
 }
 
+fn read_annex_object(iter: &mut GdtLineIter) -> Result<GdtAttachment, G2DError> {
+    let mut obj: GdtAttachment = Default::default();
+    while let Some(r_next_line) = iter.next() {
+        match r_next_line {
+            Err(e) => error!("Error in object: {:?}", e),
+            Ok(RawGdtLine {
+                field_identifier: 6302,
+                content,
+            }) => {
+                obj.archive_id = content;
+            }
+            Ok(RawGdtLine {
+                field_identifier: 6303,
+                content,
+            }) => {
+                obj.file_format = content;
+            }
+            Ok(RawGdtLine {
+                field_identifier: 6304,
+                content,
+            }) => {
+                obj.content = content;
+            }
+            Ok(RawGdtLine {
+                field_identifier: 6305,
+                content,
+            }) => {
+                obj.file_reference = content;
+            }
+            Ok(RawGdtLine {
+                field_identifier: 8201,
+                ..
+            }) => {
+                return Ok(obj);
+            }
+            _ => {}
+        }
+    }
+    return Ok(obj);
+}
+
+fn read_physician_identification_object(
+    iter: &mut GdtLineIter,
+) -> Result<GdtPhysicianIdentificationObject, G2DError> {
+    let mut obj: GdtPhysicianIdentificationObject = Default::default();
+    while let Some(r_next_line) = iter.next() {
+        match r_next_line {
+            Err(e) => error!("Error in object: {:?}", e),
+            Ok(RawGdtLine {
+                field_identifier: 211,
+                content,
+            }) => {
+                obj.physician_name = content;
+            }
+            Ok(RawGdtLine {
+                field_identifier: 212,
+                content,
+            }) => {
+                obj.physician_number = content;
+            }
+            Ok(RawGdtLine {
+                field_identifier: 8201,
+                ..
+            }) => {
+                return Ok(obj);
+            }
+            _ => {}
+        }
+    }
+    return Ok(obj);
+}
+
+fn read_test_result_object(iter: &mut GdtLineIter) -> Result<GdtTestResult, G2DError> {
+    let mut obj: GdtTestResult = Default::default();
+    while let Some(r_next_line) = iter.next() {
+        match r_next_line {
+            Err(e) => error!("Error in object: {:?}", e),
+            Ok(RawGdtLine {
+                field_identifier: 8410,
+                content,
+            }) => {
+                obj.test_id = content;
+            }
+            Ok(RawGdtLine {
+                field_identifier: 8411,
+                content,
+            }) => {
+                obj.test_name = content;
+            }
+            Ok(RawGdtLine {
+                field_identifier: 8420,
+                content,
+            }) => {
+                obj.value = content;
+            }
+            Ok(RawGdtLine {
+                field_identifier: 8421,
+                content,
+            }) => {
+                obj.unit = content;
+            }
+            Ok(RawGdtLine {
+                field_identifier: 8201,
+                ..
+            }) => {
+                return Ok(obj);
+            }
+            _ => {}
+        }
+    }
+    return Ok(obj);
+}
+
+fn read_findings_object(iter: &mut GdtLineIter) -> Result<GdtFindingsObject, G2DError> {
+    let mut obj: GdtFindingsObject = Default::default();
+    while let Some(r_next_line) = iter.next() {
+        match r_next_line {
+            Err(e) => error!("Error in object: {:?}", e),
+            Ok(RawGdtLine {
+                field_identifier: 6205,
+                content,
+            }) => {
+                obj.current_diagnosis.push(content);
+            }
+            Ok(RawGdtLine {
+                field_identifier: 6220,
+                content,
+            }) => {
+                obj.findings.push(content);
+            }
+            Ok(RawGdtLine {
+                field_identifier: 6227,
+                content,
+            }) => {
+                obj.comments.push(content);
+            }
+            Ok(RawGdtLine {
+                field_identifier: 8201,
+                ..
+            }) => {
+                return Ok(obj);
+            }
+            _ => {}
+        }
+    }
+    return Ok(obj);
+}
+
 fn read_basic_diagnostics_object(
     iter: &mut GdtLineIter,
 ) -> Result<GdtBasicDiagnosticsObject, G2DError> {
@@ -438,6 +665,9 @@ fn read_basic_diagnostics_object(
 
 fn dcm_date_to_gdt(str: String) -> String {
     // YYYYMMDD -> DDMMYYYY
+    if str.len() < 8 || !str.is_ascii() {
+        return str;
+    }
     let year = &str[0..4];
     let month = &str[4..6];
     let day = &str[6..8];
@@ -453,7 +683,11 @@ fn dcm_gender_to_gdt(gender_str: String) -> Option<GdtPatientGender> {
     return None;
 }
 
-pub fn dcm_xml_to_file(events: &Vec<XmlEvent>) -> GdtFile {
+pub fn dcm_xml_to_file(
+    events: &Vec<XmlEvent>,
+    name_case: NameCase,
+    request_tags: &RequestTags,
+) -> GdtFile {
     let mut file: GdtFile = Default::default();
     file.object_header_data.version_gdt = "03.00".to_string();
 
@@ -461,7 +695,23 @@ pub fn dcm_xml_to_file(events: &Vec<XmlEvent>) -> GdtFile {
         file.object_request.date_of_examination = dcm_date_to_gdt(date);
     }
     if let Some(time) = xml_get_study_time(&events) {
-        file.object_request.time_of_examination = time;
+        // GDT 6201 is HHMMSS, without the fraction DICOM TM may have
+        file.object_request.time_of_examination = time.chars().take(6).collect();
+    }
+
+    // 8310 and 8314 from where the worklist conversion stored them
+    if let Some(id) = request_tags.identifier.get(&events) {
+        file.object_request.request_identifier = id;
+    }
+    if let Some(uid) = request_tags.uid.get(&events) {
+        file.object_request.request_uid = uid;
+    }
+    if let Some(physician) = xml_get_requesting_physician(&events)
+        .or_else(|| xml_get_referring_physician_name(&events))
+        .filter(|p| !p.trim().is_empty())
+    {
+        file.object_physician_identification.physician_name =
+            PersonName::parse_dcm(&physician).to_free_text();
     }
 
     if let Some(id) = xml_get_patient_patient_id(&events) {
@@ -469,7 +719,7 @@ pub fn dcm_xml_to_file(events: &Vec<XmlEvent>) -> GdtFile {
     }
 
     if let Some(name) = xml_get_patient_name(&events) {
-        file.object_patient.patient_name = name;
+        PersonName::parse_dcm(&name).to_gdt_patient(&mut file.object_patient, name_case);
     }
 
     if let Some(birth_date) = xml_get_patient_birth_date(&events) {
@@ -490,38 +740,52 @@ pub fn dcm_xml_to_file(events: &Vec<XmlEvent>) -> GdtFile {
 }
 
 pub fn file_to_string(file: GdtFile) -> String {
-    let header = "01380006301\r\n".to_string();
-    let (header_lines, header_obj) = obj_header_to_string(file.object_header_data);
-    let (patient_lines, patient) = obj_patient_to_string(file.object_patient);
-    let (basic_diagnostics_lines, basic_diagnostics) =
-        obj_basic_diagnostics_request_to_string(file.object_basic_diagnostics);
-    let (request_lines, request) = obj_gdt_request_to_string(file.object_request);
+    // 6301 (Stammdaten übermitteln) unless another record type is set, e.g. 6310
+    let record_type = if file.record_type == 0 {
+        6301
+    } else {
+        file.record_type
+    };
+    let header = line_body_to_gdt_string(format!("8000{}", record_type));
+    let mut objects = vec![
+        obj_header_to_string(file.object_header_data),
+        obj_patient_to_string(file.object_patient),
+        obj_basic_diagnostics_request_to_string(file.object_basic_diagnostics),
+        obj_gdt_request_to_string(file.object_request),
+    ];
+    if !file.object_physician_identification.is_empty() {
+        objects.push(obj_physician_identification_to_string(
+            file.object_physician_identification,
+        ));
+    }
+    if !file.object_findings.is_empty() {
+        objects.push(obj_findings_to_string(file.object_findings));
+    }
+    for result in file.object_test_results {
+        objects.push(obj_test_result_to_string(result));
+    }
+    for attachment in file.object_annex.attachments {
+        objects.push(obj_annex_to_string(attachment));
+    }
 
     let total_lines = 1 /* 8000 header */
         + 1 /* 8100 record length */
-        + header_lines
-        + patient_lines
-        + basic_diagnostics_lines
-        + request_lines
+        + objects.iter().map(|(lines, _)| lines).sum::<usize>()
         + 1 /* 8202 end of record */;
     let end_of_record = line_body_to_gdt_string(format!("8202{}", total_lines));
 
+    let body = objects
+        .into_iter()
+        .map(|(_, obj)| obj)
+        .collect::<Vec<_>>()
+        .concat();
     let total_length = header.len()
         + 16 /* 8100 record length */
-        + header_obj.len()
-        + patient.len()
-        + basic_diagnostics.len()
-        + request.len()
+        + body.len()
         + end_of_record.len();
     let record_length = line_body_to_gdt_string(format!("8100{:07}", total_length));
 
-    let output = header
-        + &record_length
-        + &header_obj
-        + &patient
-        + &basic_diagnostics
-        + &request
-        + &end_of_record;
+    let output = header + &record_length + &body + &end_of_record;
     return output;
 }
 
@@ -542,6 +806,9 @@ fn obj_patient_to_string(obj: GdtPatientObject) -> (usize, String) {
     if obj.patient_number.len() > 0 {
         lines.push(format!("3000{}", obj.patient_number));
     }
+    if obj.name_affix.len() > 0 {
+        lines.push(format!("3100{}", obj.name_affix));
+    }
     if obj.patient_name.len() > 0 {
         lines.push(format!("3101{}", obj.patient_name));
     }
@@ -549,7 +816,10 @@ fn obj_patient_to_string(obj: GdtPatientObject) -> (usize, String) {
         lines.push(format!("3102{}", obj.patient_first_name));
     }
     if obj.patient_dob.len() > 0 {
-        lines.push(format!("3102{}", obj.patient_dob));
+        lines.push(format!("3103{}", obj.patient_dob));
+    }
+    if obj.patient_title.len() > 0 {
+        lines.push(format!("3104{}", obj.patient_title));
     }
     lines.push(format!(
         "3110{}",
@@ -578,6 +848,63 @@ fn obj_gdt_request_to_string(obj: GdtRequestObject) -> (usize, String) {
     return obj_and_lines_to_gdt_string("Obj_Anforderung", lines);
 }
 
+fn obj_physician_identification_to_string(
+    obj: GdtPhysicianIdentificationObject,
+) -> (usize, String) {
+    let mut lines = Vec::new();
+    if obj.physician_name.len() > 0 {
+        lines.push(format!("0211{}", obj.physician_name));
+    }
+    if obj.physician_number.len() > 0 {
+        lines.push(format!("0212{}", obj.physician_number));
+    }
+    return obj_and_lines_to_gdt_string("Obj_Arztidentifikation", lines);
+}
+
+fn obj_test_result_to_string(obj: GdtTestResult) -> (usize, String) {
+    let mut lines = Vec::new();
+    lines.push(format!("8410{}", obj.test_id));
+    if obj.test_name.len() > 0 {
+        lines.push(format!("8411{}", obj.test_name));
+    }
+    lines.push(format!("8420{}", obj.value));
+    if obj.unit.len() > 0 {
+        lines.push(format!("8421{}", obj.unit));
+    }
+    return obj_and_lines_to_gdt_string("Obj_Untersuchungsergebnis", lines);
+}
+
+fn obj_findings_to_string(obj: GdtFindingsObject) -> (usize, String) {
+    let mut lines = Vec::new();
+    for diagnosis in obj.current_diagnosis {
+        lines.push(format!("6205{}", diagnosis));
+    }
+    for finding in obj.findings {
+        lines.push(format!("6220{}", finding));
+    }
+    for comment in obj.comments {
+        lines.push(format!("6227{}", comment));
+    }
+    return obj_and_lines_to_gdt_string("Obj_Befund", lines);
+}
+
+fn obj_annex_to_string(obj: GdtAttachment) -> (usize, String) {
+    let mut lines = Vec::new();
+    if obj.archive_id.len() > 0 {
+        lines.push(format!("6302{}", obj.archive_id));
+    }
+    if obj.file_format.len() > 0 {
+        lines.push(format!("6303{}", obj.file_format));
+    }
+    if obj.content.len() > 0 {
+        lines.push(format!("6304{}", obj.content));
+    }
+    if obj.file_reference.len() > 0 {
+        lines.push(format!("6305{}", obj.file_reference));
+    }
+    return obj_and_lines_to_gdt_string("Obj_Anhang", lines);
+}
+
 fn obj_basic_diagnostics_request_to_string(obj: GdtBasicDiagnosticsObject) -> (usize, String) {
     let mut lines = Vec::new();
     if obj.patient_height.len() > 0 {
@@ -1,14 +1,20 @@
+use std::str::FromStr;
 use std::sync::OnceLock;
+
+use gtk::prelude::*;
+use gtk::Entry;
 use tokio::runtime::Runtime;
 
+use crate::filename_template::{FilenameTemplate, TEMPLATE_VARIABLES};
+
 pub mod about_dialog;
 pub mod auto_convert;
 pub mod auto_convert_list;
 pub mod copyright_dialog;
 pub mod cstore_server;
 pub mod dicom_server;
-pub mod state;
 pub mod worklist_folder;
+pub mod worklist_lifecycle;
 
 pub fn runtime() -> &'static Runtime {
     static RUNTIME: OnceLock<Runtime> = OnceLock::new();
@@ -20,3 +26,38 @@ pub fn runtime() -> &'static Runtime {
             .expect("Setting up tokio runtime needs to succeed.")
     })
 }
+
+/// The template of a file name entry, the default if it's empty.
+/// An invalid template is marked as error.
+pub fn parse_template_entry(entry: &Entry) -> Option<FilenameTemplate> {
+    let text = entry.buffer().text().as_str().trim().to_string();
+    let text = if text.is_empty() {
+        entry
+            .placeholder_text()
+            .map(|p| p.to_string())
+            .unwrap_or_default()
+    } else {
+        text
+    };
+    match FilenameTemplate::from_str(&text) {
+        Ok(template) => {
+            entry.remove_css_class("error");
+            entry.set_tooltip_text(Some(&template_tooltip()));
+            return Some(template);
+        }
+        Err(err) => {
+            entry.add_css_class("error");
+            entry.set_tooltip_text(Some(&format!("{}\n\n{}", err, template_tooltip())));
+            return None;
+        }
+    }
+}
+
+pub fn template_tooltip() -> String {
+    let mut lines = vec!["Variables:".to_string()];
+    for (name, description) in TEMPLATE_VARIABLES {
+        lines.push(format!("{{{}}} {}", name, description));
+    }
+    lines.push("Numbers can be padded, e.g. {seq:03}".to_string());
+    return lines.join("\n");
+}
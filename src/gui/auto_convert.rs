@@ -1,24 +1,29 @@
 use std::default::Default;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::{mpsc, Arc, Mutex};
 
 use gtk::gio::prelude::FileExt;
 use gtk::glib::{clone, spawn_future_local};
 use gtk::prelude::*;
 use gtk::{
-    glib, AlertDialog, ApplicationWindow, Button, Entry, Expander, FileDialog, Grid, Label,
-    ScrolledWindow, TextView,
+    glib, AlertDialog, ApplicationWindow, Button, DropDown, Entry, Expander, FileDialog, Grid,
+    Label, ScrolledWindow, TextView,
 };
 
-use crate::worklist_conversion::{WorklistConversion, WorklistConversionState};
+use crate::filename_template::FilenameTemplate;
+use crate::hooks::Hook;
+use crate::sop_class::ImageSopClass;
+use crate::worklist_conversion::{SameDayDuplicates, WorklistConversion, WorklistConversionState};
 
-use crate::gui::runtime;
+use crate::gui::{parse_template_entry, runtime, template_tooltip};
 
 pub fn setup_auto_convert_ui<F, G>(
     window: &ApplicationWindow,
     on_delete: F,
     on_updated: G,
     worklist_dir_arc: Arc<Mutex<Option<PathBuf>>>,
+    hooks_arc: Arc<Mutex<Vec<Hook>>>,
     saved_state: Option<&WorklistConversionState>,
 ) -> (Grid, Arc<Mutex<WorklistConversion>>)
 where
@@ -27,11 +32,12 @@ where
 {
     let (sender, receiver) = mpsc::channel();
     let worklist_conversion = if let Some(ss) = saved_state {
-        WorklistConversion::from_state(ss, sender, worklist_dir_arc)
+        WorklistConversion::from_state(ss, sender, worklist_dir_arc, hooks_arc)
     } else {
         Arc::new(Mutex::new(WorklistConversion::new(
             sender,
             worklist_dir_arc,
+            hooks_arc,
         )))
     };
 
@@ -56,6 +62,51 @@ where
         .label("Modality")
         .build();
     let modality_entry = Entry::builder().hexpand(true).build();
+    let sop_class_label = Label::builder()
+        .halign(gtk::Align::End)
+        .label("SOP Class")
+        .build();
+    let sop_classes = ImageSopClass::all();
+    let mut sop_class_labels = vec!["Any"];
+    sop_class_labels.extend(sop_classes.iter().map(|s| s.label()));
+    let sop_class_dropdown = DropDown::from_strings(&sop_class_labels);
+    sop_class_dropdown.set_hexpand(true);
+    let filename_label = Label::builder()
+        .halign(gtk::Align::End)
+        .label("File name")
+        .build();
+    let filename_entry = Entry::builder()
+        .hexpand(true)
+        .placeholder_text(FilenameTemplate::worklist().pattern())
+        .tooltip_text(template_tooltip())
+        .build();
+    let same_day_label = Label::builder()
+        .halign(gtk::Align::End)
+        .label("Same-day duplicates")
+        .build();
+    let same_day_options = [
+        SameDayDuplicates::Add,
+        SameDayDuplicates::Replace,
+        SameDayDuplicates::Skip,
+    ];
+    let same_day_dropdown =
+        DropDown::from_strings(&["Add new entry", "Replace entry", "Keep entry"]);
+    same_day_dropdown.set_hexpand(true);
+    same_day_dropdown.set_tooltip_text(Some(
+        "When the patient already has an entry today for another request. \
+        Entries for the same request (8310) are always updated.",
+    ));
+    let poll_label = Label::builder()
+        .halign(gtk::Align::End)
+        .label("Poll interval (s)")
+        .build();
+    let poll_entry = Entry::builder()
+        .hexpand(true)
+        .placeholder_text("Off, use file system events")
+        .tooltip_text(
+            "Check the input folder regularly, for network shares without file system events",
+        )
+        .build();
 
     if let Some(ss) = saved_state {
         if let Some(s) = &ss.input_dir_path {
@@ -67,6 +118,23 @@ where
         if let Some(s) = &ss.modality {
             modality_entry.buffer().set_text(s);
         }
+        if let Some(s) = &ss.sop_class {
+            if let Some(index) = sop_classes.iter().position(|x| x == s) {
+                sop_class_dropdown.set_selected(index as u32 + 1);
+            }
+        }
+        filename_entry
+            .buffer()
+            .set_text(ss.filename_template.pattern());
+        if let Some(index) = same_day_options
+            .iter()
+            .position(|x| *x == ss.same_day_duplicates)
+        {
+            same_day_dropdown.set_selected(index as u32);
+        }
+        if let Some(seconds) = ss.poll_interval_seconds {
+            poll_entry.buffer().set_text(seconds.to_string());
+        }
     }
 
     let log_text_view = TextView::builder().build();
@@ -104,9 +172,20 @@ where
     grid_layout.attach(&aetitle_entry, 1, 1, 3, 1);
     grid_layout.attach(&modality_label, 0, 2, 1, 1);
     grid_layout.attach(&modality_entry, 1, 2, 3, 1);
+    grid_layout.attach(&sop_class_label, 0, 3, 1, 1);
+    grid_layout.attach(&sop_class_dropdown, 1, 3, 3, 1);
+
+    grid_layout.attach(&filename_label, 0, 4, 1, 1);
+    grid_layout.attach(&filename_entry, 1, 4, 3, 1);
+
+    grid_layout.attach(&same_day_label, 0, 5, 1, 1);
+    grid_layout.attach(&same_day_dropdown, 1, 5, 3, 1);
+
+    grid_layout.attach(&poll_label, 0, 6, 1, 1);
+    grid_layout.attach(&poll_entry, 1, 6, 3, 1);
 
-    grid_layout.attach(&log_expander, 0, 3, 4, 1);
-    grid_layout.attach(&remove_button, 3, 4, 1, 1);
+    grid_layout.attach(&log_expander, 0, 7, 4, 1);
+    grid_layout.attach(&remove_button, 3, 8, 1, 1);
 
     let on_updated2 = on_updated.clone();
     input_button.connect_clicked(clone!(
@@ -197,6 +276,87 @@ where
         }
     ));
 
+    let on_updated2 = on_updated.clone();
+    sop_class_dropdown.connect_selected_notify(clone!(
+        #[weak]
+        worklist_conversion,
+        move |dropdown| {
+            if let std::sync::LockResult::Ok(mut wc) = worklist_conversion.lock() {
+                // The first entry is "Any"
+                let sop_class = (dropdown.selected() as usize)
+                    .checked_sub(1)
+                    .and_then(|i| sop_classes.get(i).copied());
+                wc.set_sop_class(sop_class);
+                on_updated2();
+            };
+        }
+    ));
+
+    let on_updated2 = on_updated.clone();
+    filename_entry.connect_changed(clone!(
+        #[weak]
+        worklist_conversion,
+        move |entry| {
+            let Some(template) = parse_template_entry(entry) else {
+                return;
+            };
+            if let std::sync::LockResult::Ok(mut wc) = worklist_conversion.lock() {
+                wc.set_filename_template(template);
+                on_updated2();
+            };
+        }
+    ));
+
+    let on_updated2 = on_updated.clone();
+    same_day_dropdown.connect_selected_notify(clone!(
+        #[weak]
+        worklist_conversion,
+        move |dropdown| {
+            if let std::sync::LockResult::Ok(mut wc) = worklist_conversion.lock() {
+                let value = same_day_options
+                    .get(dropdown.selected() as usize)
+                    .copied()
+                    .unwrap_or_default();
+                wc.set_same_day_duplicates(value);
+                on_updated2();
+            };
+        }
+    ));
+
+    poll_entry
+        .delegate()
+        .unwrap()
+        .connect_insert_text(move |entry, text, position| {
+            let pattern = |c: char| -> bool { !c.is_ascii_digit() };
+            if text.contains(pattern) {
+                glib::signal::signal_stop_emission_by_name(entry, "insert-text");
+                entry.insert_text(&text.replace(pattern, ""), position);
+            }
+        });
+
+    let on_updated2 = on_updated.clone();
+    poll_entry.connect_changed(clone!(
+        #[weak]
+        window,
+        #[weak]
+        worklist_conversion,
+        move |entry| {
+            let seconds = u64::from_str(entry.buffer().text().as_str()).ok();
+            if let std::sync::LockResult::Ok(mut wc) = worklist_conversion.lock() {
+                let result = wc.set_poll_interval_seconds(seconds, worklist_conversion.clone());
+                if let Err(err) = result {
+                    AlertDialog::builder()
+                        .message("Error")
+                        .detail(err.to_string())
+                        .modal(true)
+                        .build()
+                        .show(Some(&window));
+                }
+                on_updated2();
+            };
+        }
+    ));
+
     let (asender, arecv) = async_channel::unbounded::<String>();
 
     runtime().spawn(async move {
@@ -7,7 +7,8 @@ use gtk::prelude::*;
 use gtk::{glib, ApplicationWindow, Button, Frame, Grid};
 
 use crate::gui::auto_convert::setup_auto_convert_ui;
-use crate::gui::state::WorklistConversionsState;
+use crate::hooks::Hook;
+use crate::state::WorklistConversionsState;
 use crate::worklist_conversion::{WorklistConversion, WorklistConversionState};
 
 pub fn setup_auto_convert_list_ui(
@@ -16,6 +17,7 @@ pub fn setup_auto_convert_list_ui(
     grid: &Grid,
     grid_y_index: i32,
     worklist_dir_arc: Arc<Mutex<Option<PathBuf>>>,
+    hooks_arc: Arc<Mutex<Vec<Hook>>>,
 ) -> (i32, mpsc::Receiver<WorklistConversionsState>) {
     let (state_sender, state_receiver) = mpsc::channel();
     let worklist_conversions: Arc<Mutex<Vec<Arc<Mutex<WorklistConversion>>>>> =
@@ -68,6 +70,7 @@ pub fn setup_auto_convert_list_ui(
                 on_delete,
                 on_updated.clone(),
                 worklist_dir_arc,
+                hooks_arc.clone(),
                 state,
             );
             let mut cs = worklist_conversions1.lock().unwrap();
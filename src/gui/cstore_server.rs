@@ -1,6 +1,7 @@
-use std::io::BufRead;
+use std::cell::RefCell;
 use std::ops::DerefMut;
 use std::path::PathBuf;
+use std::rc::Rc;
 use std::str::FromStr;
 use std::sync::{mpsc, Arc, Mutex};
 
@@ -8,20 +9,29 @@ use gtk::gio::prelude::FileExt;
 use gtk::glib::{clone, spawn_future_local};
 use gtk::prelude::*;
 use gtk::{
-    glib, AlertDialog, ApplicationWindow, Button, Entry, Expander, FileDialog, Frame, Grid, Label,
-    ScrolledWindow, TextView,
+    gio, glib, AlertDialog, ApplicationWindow, Button, Entry, Expander, FileDialog, FileLauncher,
+    Frame, Grid, Label, Picture, ScrolledWindow, TextView,
 };
 
-use crate::command::{binary_to_path, new_command, ChildOutput};
-use crate::dcm_xml::{export_images_from_dcm_with_patient_id, DCMImageFormat};
-use crate::gui::runtime;
-use crate::gui::state::CStoreServerState;
+use crate::command::ChildOutput;
+use crate::dcm_xml::RequestTags;
+use crate::filename_template::FilenameTemplate;
+use crate::gui::{parse_template_entry, runtime, template_tooltip};
+use crate::hooks::Hook;
+use crate::servers::{spawn_server, storescp_command, ReceivedFiles};
+use crate::state::CStoreServerState;
+use crate::thumbnails::THUMBNAIL_SIZE;
+use crate::worklist_lifecycle::WorklistLifecycle;
 
 pub fn setup_cstore_server(
     initial_state: &CStoreServerState,
     window: &ApplicationWindow,
     parent_grid: &Grid,
     grid_y_index: i32,
+    worklist_dir_arc: Arc<Mutex<Option<PathBuf>>>,
+    worklist_lifecycle_arc: Arc<Mutex<WorklistLifecycle>>,
+    hooks_arc: Arc<Mutex<Vec<Hook>>>,
+    request_tags_arc: Arc<Mutex<Vec<RequestTags>>>,
 ) -> (i32, mpsc::Receiver<CStoreServerState>) {
     let (state_sender, state_receiver) = mpsc::channel();
     let frame = Frame::builder()
@@ -57,6 +67,39 @@ pub fn setup_cstore_server(
         .label("Choose...")
         .build();
 
+    let jpeg_name_label = Label::builder()
+        .halign(gtk::Align::End)
+        .label("JPEG file name")
+        .build();
+    let jpeg_name_entry = Entry::builder()
+        .hexpand(true)
+        .placeholder_text(FilenameTemplate::image().pattern())
+        .tooltip_text(template_tooltip())
+        .build();
+
+    let preview_dir_label = Label::builder()
+        .halign(gtk::Align::End)
+        .label("Preview dir")
+        .build();
+    let preview_dir_entry = Entry::builder().hexpand(true).sensitive(false).build();
+    let preview_dir_button = Button::builder()
+        .width_request(100)
+        .hexpand(false)
+        .label("Choose...")
+        .build();
+    let preview_picture = Picture::builder()
+        .height_request(THUMBNAIL_SIZE as i32)
+        .can_shrink(true)
+        .visible(false)
+        .build();
+    let open_preview_button = Button::builder()
+        .width_request(100)
+        .hexpand(false)
+        .label("Open preview")
+        .sensitive(false)
+        .build();
+    let last_preview: Rc<RefCell<Option<PathBuf>>> = Rc::new(RefCell::new(None));
+
     let run_button = Button::builder().label("Run").build();
     let status_label = Label::builder()
         .label("Stopped")
@@ -96,10 +139,20 @@ pub fn setup_cstore_server(
     grid_layout.attach(&jpeg_dir_entry, 1, 2, 2, 1);
     grid_layout.attach(&jpeg_dir_button, 3, 2, 1, 1);
 
-    grid_layout.attach(&run_button, 0, 3, 1, 1);
-    grid_layout.attach(&status_label, 1, 3, 1, 1);
+    grid_layout.attach(&jpeg_name_label, 0, 3, 1, 1);
+    grid_layout.attach(&jpeg_name_entry, 1, 3, 3, 1);
+
+    grid_layout.attach(&preview_dir_label, 0, 4, 1, 1);
+    grid_layout.attach(&preview_dir_entry, 1, 4, 2, 1);
+    grid_layout.attach(&preview_dir_button, 3, 4, 1, 1);
+
+    grid_layout.attach(&run_button, 0, 5, 1, 1);
+    grid_layout.attach(&status_label, 1, 5, 1, 1);
 
-    grid_layout.attach(&log_expander, 0, 4, 4, 1);
+    grid_layout.attach(&preview_picture, 0, 6, 3, 1);
+    grid_layout.attach(&open_preview_button, 3, 6, 1, 1);
+
+    grid_layout.attach(&log_expander, 0, 7, 4, 1);
 
     parent_grid.attach(&frame, 0, grid_y_index, 4, 1);
 
@@ -112,6 +165,12 @@ pub fn setup_cstore_server(
     if let Some(p) = &initial_state.jpeg_output_path {
         jpeg_dir_entry.buffer().set_text(p.display().to_string());
     }
+    jpeg_name_entry
+        .buffer()
+        .set_text(initial_state.jpeg_filename_template.pattern());
+    if let Some(p) = &initial_state.preview_output_path {
+        preview_dir_entry.buffer().set_text(p.display().to_string());
+    }
 
     let notify_state_update = clone!(
         #[weak]
@@ -120,7 +179,14 @@ pub fn setup_cstore_server(
         port_entry,
         #[weak]
         jpeg_dir_entry,
+        #[weak]
+        jpeg_name_entry,
+        #[weak]
+        preview_dir_entry,
         move || {
+            let Some(jpeg_filename_template) = parse_template_entry(&jpeg_name_entry) else {
+                return;
+            };
             let dir = dir_entry.buffer().text().as_str().to_string();
             let dir_path = if dir.is_empty() {
                 None
@@ -139,6 +205,8 @@ pub fn setup_cstore_server(
                 path: dir_path,
                 port: port_int.ok(),
                 jpeg_output_path: jpeg_dir_path,
+                jpeg_filename_template,
+                preview_output_path: optional_path(&preview_dir_entry),
             };
             _ = state_sender.send(state);
         }
@@ -193,6 +261,11 @@ pub fn setup_cstore_server(
         notify_state_update1();
     });
 
+    let notify_state_update1 = notify_state_update.clone();
+    jpeg_name_entry.connect_changed(move |_| {
+        notify_state_update1();
+    });
+
     let notify_state_update1 = notify_state_update.clone();
     jpeg_dir_button.connect_clicked(clone!(
         #[weak]
@@ -226,6 +299,59 @@ pub fn setup_cstore_server(
         }
     ));
 
+    let notify_state_update1 = notify_state_update.clone();
+    preview_dir_button.connect_clicked(clone!(
+        #[weak]
+        window,
+        #[weak]
+        preview_dir_entry,
+        move |_| {
+            let dialog = FileDialog::builder().build();
+            let notify_state_update1 = notify_state_update1.clone();
+            dialog.select_folder(
+                Some(&window),
+                None::<gtk::gio::Cancellable>.as_ref(),
+                clone!(
+                    #[weak]
+                    preview_dir_entry,
+                    move |result| match result {
+                        Err(err) => {
+                            println!("err {:?}", err);
+                        }
+                        Ok(file) => {
+                            if let Some(input_path) = file.path() {
+                                if let Some(p) = input_path.to_str() {
+                                    preview_dir_entry.buffer().set_text(p);
+                                    notify_state_update1();
+                                }
+                            }
+                        }
+                    },
+                ),
+            );
+        }
+    ));
+
+    open_preview_button.connect_clicked(clone!(
+        #[weak]
+        window,
+        #[strong]
+        last_preview,
+        move |_| {
+            if let Some(html) = last_preview.borrow().as_ref() {
+                FileLauncher::new(Some(&gio::File::for_path(html))).launch(
+                    Some(&window),
+                    None::<&gio::Cancellable>,
+                    |result| {
+                        if let Err(err) = result {
+                            println!("err {:?}", err);
+                        }
+                    },
+                );
+            }
+        }
+    ));
+
     let running_child: Arc<Mutex<Option<Arc<shared_child::SharedChild>>>> =
         Arc::new(Mutex::new(None));
 
@@ -241,6 +367,10 @@ pub fn setup_cstore_server(
         #[weak]
         jpeg_dir_button,
         #[weak]
+        jpeg_name_entry,
+        #[weak]
+        preview_dir_button,
+        #[weak]
         dir_button,
         move || {
             spawn_future_local(clone!(
@@ -255,6 +385,10 @@ pub fn setup_cstore_server(
                 #[weak]
                 jpeg_dir_button,
                 #[weak]
+                jpeg_name_entry,
+                #[weak]
+                preview_dir_button,
+                #[weak]
                 dir_button,
                 async move {
                     let rc = running_child.lock().unwrap();
@@ -264,12 +398,16 @@ pub fn setup_cstore_server(
                         port_entry.set_sensitive(false);
                         dir_button.set_sensitive(false);
                         jpeg_dir_button.set_sensitive(false);
+                        jpeg_name_entry.set_sensitive(false);
+                        preview_dir_button.set_sensitive(false);
                     } else {
                         run_button.set_label("Run");
                         status_label.set_label("Stopped");
                         port_entry.set_sensitive(true);
                         dir_button.set_sensitive(true);
                         jpeg_dir_button.set_sensitive(true);
+                        jpeg_name_entry.set_sensitive(true);
+                        preview_dir_button.set_sensitive(true);
                     }
                 }
             ));
@@ -284,6 +422,14 @@ pub fn setup_cstore_server(
         #[weak]
         jpeg_dir_entry,
         #[weak]
+        jpeg_name_entry,
+        #[weak]
+        preview_dir_entry,
+        #[weak]
+        preview_picture,
+        #[weak]
+        open_preview_button,
+        #[weak]
         port_entry,
         move |_| {
             let mut rc = running_child.lock().unwrap();
@@ -310,6 +456,16 @@ pub fn setup_cstore_server(
                 } else {
                     Some(PathBuf::from(jpeg_dir))
                 };
+                let preview_dir_path = optional_path(&preview_dir_entry);
+                let Some(jpeg_filename_template) = parse_template_entry(&jpeg_name_entry) else {
+                    AlertDialog::builder()
+                        .message("Please enter a valid JPEG file name")
+                        .detail(template_tooltip())
+                        .modal(true)
+                        .build()
+                        .show(Some(&window));
+                    return;
+                };
                 let port_str = port_entry.buffer().text();
                 let port_int = match u16::from_str(port_str.as_str()) {
                     Err(_) => {
@@ -323,24 +479,23 @@ pub fn setup_cstore_server(
                     Ok(a) => a,
                 };
 
-                let full_path = binary_to_path("storescp".to_string());
-                let mut command = new_command(full_path);
-                command
-                    .args(vec![
-                        "-v",
-                        "-pm",
-                        "+xy",
-                        "-od",
-                        dir_path.to_str().unwrap(),
-                        &format!("{}", port_int),
-                    ])
-                    .stdout(std::process::Stdio::piped())
-                    .stderr(std::process::Stdio::piped());
-
                 let (sender, receiver) = mpsc::channel::<ChildOutput>();
-                _ = sender.send(ChildOutput::Log(format!("Running command: {:?}", command)));
-
-                let child = match shared_child::SharedChild::spawn(&mut command) {
+                let mut received_files = ReceivedFiles::new(
+                    worklist_dir_arc.clone(),
+                    worklist_lifecycle_arc.clone(),
+                    hooks_arc.clone(),
+                    request_tags_arc.clone(),
+                    sender.clone(),
+                );
+                received_files.jpeg_dir = jpeg_dir_path;
+                received_files.jpeg_filename_template = jpeg_filename_template;
+                received_files.preview_dir = preview_dir_path;
+                let child = match spawn_server(
+                    &mut storescp_command(&dir_path, port_int),
+                    "storescp",
+                    sender,
+                    move |line| received_files.handle_line(line),
+                ) {
                     Ok(c) => c,
                     Err(err) => {
                         AlertDialog::builder()
@@ -352,50 +507,6 @@ pub fn setup_cstore_server(
                         return;
                     }
                 };
-                let stdout = child.take_stdout().expect("stdout");
-                let stderr = child.take_stderr().expect("stderr");
-
-                let err_reader = std::io::BufReader::new(stderr);
-                let err_sender = sender.clone();
-                runtime().spawn(async move {
-                    for line in err_reader.lines() {
-                        if let Ok(msg) = line {
-                            if let Some(saved_dicom_file) =
-                                msg.strip_prefix("I: storing DICOM file: ")
-                            {
-                                _ = err_sender.send(ChildOutput::Log(format!(
-                                    "Detected new DICOM file: {:?}",
-                                    &saved_dicom_file
-                                )));
-                                if let Some(ref j) = jpeg_dir_path {
-                                    let extract_result = export_images_from_dcm_with_patient_id(
-                                        &PathBuf::from(saved_dicom_file),
-                                        j,
-                                        DCMImageFormat::Jpeg,
-                                        Some(&err_sender),
-                                    );
-                                    if let Err(err) = extract_result {
-                                        _ = err_sender.send(ChildOutput::Log(format!(
-                                            "Cannot extract jpeg from {}: {:?}",
-                                            &saved_dicom_file, err
-                                        )));
-                                    }
-                                }
-                            }
-                            _ = err_sender.send(ChildOutput::Log(format!("storescp: {}", msg)));
-                        }
-                    }
-                });
-
-                let out_reader = std::io::BufReader::new(stdout);
-                let out_sender = sender.clone();
-                runtime().spawn(async move {
-                    for line in out_reader.lines() {
-                        if let Ok(msg) = line {
-                            _ = out_sender.send(ChildOutput::Log(format!("storescp: {}", msg)));
-                        }
-                    }
-                });
 
                 let (asender, arecv) = async_channel::unbounded::<ChildOutput>();
                 runtime().spawn(async move {
@@ -405,11 +516,16 @@ pub fn setup_cstore_server(
                 });
 
                 let update_run_status1 = update_run_status.clone();
+                let last_preview = last_preview.clone();
                 spawn_future_local(clone!(
                     #[weak]
                     running_child,
                     #[weak]
                     log_text_view,
+                    #[weak]
+                    preview_picture,
+                    #[weak]
+                    open_preview_button,
                     async move {
                         while let Ok(msg) = arecv.recv().await {
                             let buffer = log_text_view.buffer();
@@ -422,25 +538,28 @@ pub fn setup_cstore_server(
                                     *rc = None;
                                     update_run_status1();
                                 }
+                                ChildOutput::Preview {
+                                    contact_sheet,
+                                    html,
+                                } => {
+                                    buffer.insert(
+                                        &mut buffer.end_iter(),
+                                        &format!("Preview: {}", html.display()),
+                                    );
+                                    if let Some(c) = contact_sheet {
+                                        preview_picture.set_filename(Some(&c));
+                                        preview_picture.set_visible(true);
+                                    }
+                                    *last_preview.borrow_mut() = Some(html);
+                                    open_preview_button.set_sensitive(true);
+                                }
                             }
                             buffer.insert(&mut buffer.end_iter(), "\n");
                         }
                     }
                 ));
 
-                let arc_child = Arc::new(child);
-
-                let child1 = arc_child.clone();
-                let exit_sender = sender.clone();
-                runtime().spawn(async move {
-                    let exit_result = child1.wait().expect("wait");
-                    _ = exit_sender.send(ChildOutput::Log(format!(
-                        "storescp Exited {:?}",
-                        exit_result
-                    )));
-                    _ = exit_sender.send(ChildOutput::Exit(exit_result));
-                });
-                *rc = Some(arc_child);
+                *rc = Some(child);
                 update_run_status();
             }
         }
@@ -448,3 +567,11 @@ pub fn setup_cstore_server(
 
     return (grid_y_index + 1, state_receiver);
 }
+
+fn optional_path(entry: &Entry) -> Option<PathBuf> {
+    let text = entry.buffer().text().as_str().to_string();
+    if text.is_empty() {
+        return None;
+    }
+    return Some(PathBuf::from(text));
+}
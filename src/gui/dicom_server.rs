@@ -1,13 +1,13 @@
 use std::default::Default;
-use std::io::BufRead;
 use std::ops::{Deref, DerefMut};
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::{mpsc, Arc, Mutex};
 
-use crate::command::{binary_to_path, new_command, ChildOutput};
+use crate::command::ChildOutput;
 use crate::gui::runtime;
-use crate::gui::state::DicomServerState;
+use crate::servers::{spawn_server, wlmscpfs_command};
+use crate::state::DicomServerState;
 use gtk::glib::{clone, spawn_future_local};
 use gtk::prelude::*;
 use gtk::{
@@ -185,24 +185,12 @@ pub fn setup_dicom_server(
                 buffer.insert(&mut buffer.end_iter(), "\n");
             } else {
                 let (sender, receiver) = mpsc::channel::<ChildOutput>();
-
-                let full_path = binary_to_path("wlmscpfs".to_string());
-                let mut command = new_command(full_path);
-                command
-                    .args(vec![
-                        "-v",
-                        "-d",
-                        "-dfr",
-                        "-dfp",
-                        worklist_dir.to_str().unwrap(),
-                        &format!("{}", port_int),
-                    ])
-                    .stdout(std::process::Stdio::piped())
-                    .stderr(std::process::Stdio::piped());
-
-                _ = sender.send(ChildOutput::Log(format!("Running command: {:?}", command)));
-
-                let child = match shared_child::SharedChild::spawn(&mut command) {
+                let child = match spawn_server(
+                    &mut wlmscpfs_command(worklist_dir, port_int),
+                    "wlmscpfs",
+                    sender,
+                    |_| {},
+                ) {
                     Ok(c) => c,
                     Err(err) => {
                         AlertDialog::builder()
@@ -214,28 +202,6 @@ pub fn setup_dicom_server(
                         return;
                     }
                 };
-                let stdout = child.take_stdout().expect("stdout");
-                let stderr = child.take_stderr().expect("stderr");
-
-                let err_reader = std::io::BufReader::new(stderr);
-                let err_sender = sender.clone();
-                runtime().spawn(async move {
-                    for line in err_reader.lines() {
-                        if let Ok(msg) = line {
-                            _ = err_sender.send(ChildOutput::Log(msg));
-                        }
-                    }
-                });
-
-                let out_reader = std::io::BufReader::new(stdout);
-                let out_sender = sender.clone();
-                runtime().spawn(async move {
-                    for line in out_reader.lines() {
-                        if let Ok(msg) = line {
-                            _ = out_sender.send(ChildOutput::Log(msg));
-                        }
-                    }
-                });
 
                 let (asender, arecv) = async_channel::unbounded::<ChildOutput>();
                 runtime().spawn(async move {
@@ -261,22 +227,14 @@ pub fn setup_dicom_server(
                                     print!("ChildOutput::Exit");
                                     update_run_status1();
                                 }
+                                ChildOutput::Preview { .. } => {}
                             }
                             buffer.insert(&mut buffer.end_iter(), "\n");
                         }
                     }
                 ));
 
-                let arc_child = Arc::new(child);
-
-                let child1 = arc_child.clone();
-                let exit_sender = sender.clone();
-                runtime().spawn(async move {
-                    let exit_result = child1.wait().expect("wait");
-                    _ = exit_sender.send(ChildOutput::Log(format!("Exited {:?}", exit_result)));
-                    _ = exit_sender.send(ChildOutput::Exit(exit_result));
-                });
-                *rc = Some(arc_child);
+                *rc = Some(child);
             }
             update_run_status();
         }
@@ -0,0 +1,219 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+
+use gtk::glib::{clone, spawn_future_local};
+use gtk::prelude::*;
+use gtk::{
+    glib, Button, CheckButton, DropDown, Entry, Expander, Frame, Grid, Label, ScrolledWindow,
+    TextView,
+};
+
+use crate::gui::runtime;
+use crate::worklist_lifecycle::{expire_worklist_entries, RemovalAction, WorklistLifecycle};
+
+/// How often expired entries are removed while the app is running
+const EXPIRY_INTERVAL_SECONDS: u32 = 60 * 60;
+
+pub fn setup_worklist_lifecycle_ui(
+    initial_state: &WorklistLifecycle,
+    parent_grid: &Grid,
+    grid_y_index: i32,
+    worklist_dir_arc: Arc<Mutex<Option<PathBuf>>>,
+) -> (
+    i32,
+    Arc<Mutex<WorklistLifecycle>>,
+    mpsc::Receiver<WorklistLifecycle>,
+) {
+    let (state_sender, state_receiver) = mpsc::channel();
+    let lifecycle_arc = Arc::new(Mutex::new(initial_state.clone()));
+    let frame = Frame::builder()
+        .label("Worklist cleanup")
+        .vexpand(false)
+        .build();
+
+    let max_age_label = Label::builder()
+        .halign(gtk::Align::End)
+        .label("Max age (days)")
+        .build();
+    let max_age_entry = Entry::builder()
+        .hexpand(true)
+        .placeholder_text("Keep")
+        .build();
+    let after_scheduled_label = Label::builder()
+        .halign(gtk::Align::End)
+        .label("Days after scheduled")
+        .build();
+    let after_scheduled_entry = Entry::builder()
+        .hexpand(true)
+        .placeholder_text("Keep")
+        .build();
+    let complete_check = CheckButton::builder()
+        .label("Remove entries when their images arrive at the CStore server")
+        .build();
+    let action_label = Label::builder()
+        .halign(gtk::Align::End)
+        .label("Removed entries")
+        .build();
+    let action_dropdown = DropDown::from_strings(&["Archive", "Delete"]);
+    action_dropdown.set_hexpand(true);
+    let clean_button = Button::builder()
+        .width_request(100)
+        .hexpand(false)
+        .label("Clean up now")
+        .build();
+
+    let log_text_view = TextView::builder().build();
+    let log_scroll_window = ScrolledWindow::builder()
+        .hexpand(true)
+        .vexpand(true)
+        .height_request(100)
+        .child(&log_text_view)
+        .build();
+    let log_expander = Expander::builder()
+        .label("Logs")
+        .resize_toplevel(true)
+        .child(&log_scroll_window)
+        .build();
+
+    let grid_layout = Grid::builder()
+        .column_spacing(12)
+        .row_spacing(12)
+        .margin_top(12)
+        .margin_bottom(12)
+        .margin_start(12)
+        .margin_end(12)
+        .build();
+
+    frame.set_child(Some(&grid_layout));
+    grid_layout.attach(&max_age_label, 0, 0, 1, 1);
+    grid_layout.attach(&max_age_entry, 1, 0, 3, 1);
+    grid_layout.attach(&after_scheduled_label, 0, 1, 1, 1);
+    grid_layout.attach(&after_scheduled_entry, 1, 1, 3, 1);
+    grid_layout.attach(&complete_check, 1, 2, 3, 1);
+    grid_layout.attach(&action_label, 0, 3, 1, 1);
+    grid_layout.attach(&action_dropdown, 1, 3, 2, 1);
+    grid_layout.attach(&clean_button, 3, 3, 1, 1);
+    grid_layout.attach(&log_expander, 0, 4, 4, 1);
+
+    parent_grid.attach(&frame, 0, grid_y_index, 4, 1);
+
+    if let Some(d) = initial_state.max_age_days {
+        max_age_entry.buffer().set_text(d.to_string());
+    }
+    if let Some(d) = initial_state.days_after_scheduled {
+        after_scheduled_entry.buffer().set_text(d.to_string());
+    }
+    complete_check.set_active(initial_state.complete_on_images);
+    if initial_state.action == RemovalAction::Delete {
+        action_dropdown.set_selected(1);
+    }
+
+    for entry in [&max_age_entry, &after_scheduled_entry] {
+        entry
+            .delegate()
+            .unwrap()
+            .connect_insert_text(move |entry, text, position| {
+                let pattern = |c: char| -> bool { !c.is_ascii_digit() };
+                if text.contains(pattern) {
+                    glib::signal::signal_stop_emission_by_name(entry, "insert-text");
+                    entry.insert_text(&text.replace(pattern, ""), position);
+                }
+            });
+    }
+
+    let notify_state_update = clone!(
+        #[weak]
+        max_age_entry,
+        #[weak]
+        after_scheduled_entry,
+        #[weak]
+        complete_check,
+        #[weak]
+        action_dropdown,
+        #[strong]
+        lifecycle_arc,
+        move || {
+            let lifecycle = WorklistLifecycle {
+                max_age_days: u32::from_str(max_age_entry.buffer().text().as_str()).ok(),
+                days_after_scheduled: u32::from_str(after_scheduled_entry.buffer().text().as_str())
+                    .ok(),
+                complete_on_images: complete_check.is_active(),
+                action: if action_dropdown.selected() == 1 {
+                    RemovalAction::Delete
+                } else {
+                    RemovalAction::Archive
+                },
+            };
+            *lifecycle_arc.lock().unwrap() = lifecycle.clone();
+            _ = state_sender.send(lifecycle);
+        }
+    );
+
+    let notify_state_update1 = notify_state_update.clone();
+    max_age_entry.connect_changed(move |_| {
+        notify_state_update1();
+    });
+    let notify_state_update1 = notify_state_update.clone();
+    after_scheduled_entry.connect_changed(move |_| {
+        notify_state_update1();
+    });
+    let notify_state_update1 = notify_state_update.clone();
+    complete_check.connect_toggled(move |_| {
+        notify_state_update1();
+    });
+    let notify_state_update1 = notify_state_update.clone();
+    action_dropdown.connect_selected_notify(move |_| {
+        notify_state_update1();
+    });
+
+    let (log_sender, log_receiver) = mpsc::channel::<String>();
+    let (asender, arecv) = async_channel::unbounded::<String>();
+    runtime().spawn(async move {
+        while let Ok(msg) = log_receiver.recv() {
+            _ = asender.send(msg).await;
+        }
+    });
+    spawn_future_local(clone!(
+        #[weak]
+        log_text_view,
+        async move {
+            while let Ok(msg) = arecv.recv().await {
+                let buffer = log_text_view.buffer();
+                buffer.insert(&mut buffer.end_iter(), &msg);
+                buffer.insert(&mut buffer.end_iter(), "\n");
+            }
+        }
+    ));
+
+    let expire = clone!(
+        #[strong]
+        lifecycle_arc,
+        #[strong]
+        worklist_dir_arc,
+        move || {
+            let lifecycle = lifecycle_arc.lock().unwrap().clone();
+            let worklist_dir = worklist_dir_arc.lock().unwrap().clone();
+            let log_sender = log_sender.clone();
+            if let Some(dir) = worklist_dir {
+                runtime().spawn(async move {
+                    if let Err(err) = expire_worklist_entries(&dir, &lifecycle, Some(&log_sender)) {
+                        _ = log_sender.send(format!("Cannot remove expired entries: {}", err));
+                    }
+                });
+            }
+        }
+    );
+
+    expire();
+    let expire1 = expire.clone();
+    clean_button.connect_clicked(move |_| {
+        expire1();
+    });
+    glib::timeout_add_seconds_local(EXPIRY_INTERVAL_SECONDS, move || {
+        expire();
+        glib::ControlFlow::Continue
+    });
+
+    return (grid_y_index + 1, lifecycle_arc, state_receiver);
+}
@@ -0,0 +1,191 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use xml::reader::XmlEvent;
+
+use crate::command::{exec_command_with_timeout, log};
+use crate::dcm_xml::{xml_get_patient_name, xml_get_patient_patient_id};
+use crate::error::G2DError;
+use crate::gdt::GdtFile;
+use crate::person_name::PersonName;
+
+/// How long a hook may run if the hook doesn't set `timeout_seconds`
+pub const DEFAULT_HOOK_TIMEOUT_SECONDS: u64 = 60;
+
+/// When a hook runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookEvent {
+    /// A GDT file of an auto-convert folder was handled, also if it failed
+    GdtConverted,
+    /// An auto-convert folder wrote a worklist entry
+    WorklistCreated,
+    /// The CStore server stored a DICOM file
+    DicomReceived,
+    /// Images were exported from a received DICOM file
+    ImagesExported,
+}
+
+impl fmt::Display for HookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookEvent::GdtConverted => write!(f, "gdt-converted"),
+            HookEvent::WorklistCreated => write!(f, "worklist-created"),
+            HookEvent::DicomReceived => write!(f, "dicom-received"),
+            HookEvent::ImagesExported => write!(f, "images-exported"),
+        }
+    }
+}
+
+/// How the context is passed to the command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookPayload {
+    /// `GDT2DICOM_*` environment variables
+    #[default]
+    Env,
+    /// The path of a JSON file with the context as last argument
+    Json,
+}
+
+/// A command which runs after an event, configured in the state file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hook {
+    pub event: HookEvent,
+    pub command: String,
+    #[serde(default)]
+    pub arguments: Vec<String>,
+    #[serde(default)]
+    pub payload: HookPayload,
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+fn default_timeout_seconds() -> u64 {
+    return DEFAULT_HOOK_TIMEOUT_SECONDS;
+}
+
+/// What a hook is told about the event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HookContext {
+    pub event: HookEvent,
+    pub patient_id: String,
+    pub patient_name: String,
+    pub files: Vec<PathBuf>,
+    pub success: bool,
+    /// The error or a summary of the result
+    pub message: String,
+}
+
+impl HookContext {
+    pub fn new(event: HookEvent) -> HookContext {
+        return HookContext {
+            event,
+            patient_id: "".to_string(),
+            patient_name: "".to_string(),
+            files: vec![],
+            success: true,
+            message: "".to_string(),
+        };
+    }
+
+    pub fn from_gdt_file(event: HookEvent, gdt_file: &GdtFile) -> HookContext {
+        return HookContext {
+            patient_id: gdt_file.object_patient.patient_number.clone(),
+            patient_name: PersonName::from_gdt_patient(&gdt_file.object_patient).to_free_text(),
+            ..HookContext::new(event)
+        };
+    }
+
+    pub fn from_dcm_xml(event: HookEvent, events: &Vec<XmlEvent>) -> HookContext {
+        return HookContext {
+            patient_id: xml_get_patient_patient_id(events).unwrap_or_default(),
+            patient_name: PersonName::parse_dcm(&xml_get_patient_name(events).unwrap_or_default())
+                .to_free_text(),
+            ..HookContext::new(event)
+        };
+    }
+
+    fn to_envs(&self) -> Vec<(String, PathBuf)> {
+        let files = std::env::join_paths(&self.files).unwrap_or_default();
+        let first_file = self.files.first().cloned().unwrap_or_default();
+        return vec![
+            ("GDT2DICOM_EVENT".to_string(), self.event.to_string().into()),
+            (
+                "GDT2DICOM_PATIENT_ID".to_string(),
+                self.patient_id.clone().into(),
+            ),
+            (
+                "GDT2DICOM_PATIENT_NAME".to_string(),
+                self.patient_name.clone().into(),
+            ),
+            ("GDT2DICOM_FILE".to_string(), first_file),
+            ("GDT2DICOM_FILES".to_string(), files.into()),
+            (
+                "GDT2DICOM_SUCCESS".to_string(),
+                if self.success { "1" } else { "0" }.into(),
+            ),
+            ("GDT2DICOM_MESSAGE".to_string(), self.message.clone().into()),
+        ];
+    }
+}
+
+/// Runs the hooks of the context's event one after another and logs their
+/// exit status, a failing hook doesn't stop the others
+pub fn run_hooks(hooks: &[Hook], context: &HookContext, log_sender: Option<&mpsc::Sender<String>>) {
+    for hook in hooks.iter().filter(|h| h.event == context.event) {
+        let message = match run_hook(hook, context, log_sender) {
+            Ok(output) if output.status.success() => {
+                format!("Hook {} for {} finished", hook.command, context.event)
+            }
+            Ok(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                format!(
+                    "Hook {} for {} failed with {}: {}",
+                    hook.command,
+                    context.event,
+                    output.status,
+                    stderr.trim()
+                )
+            }
+            Err(err) => format!(
+                "Hook {} for {} failed: {}",
+                hook.command, context.event, err
+            ),
+        };
+        log(log_sender, message);
+    }
+}
+
+fn run_hook(
+    hook: &Hook,
+    context: &HookContext,
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<std::process::Output, G2DError> {
+    let mut arguments: Vec<PathBuf> = hook.arguments.iter().map(PathBuf::from).collect();
+    // Kept until the hook exited
+    let mut payload_file = None;
+    let envs = match hook.payload {
+        HookPayload::Env => context.to_envs(),
+        HookPayload::Json => {
+            let file = NamedTempFile::new()?;
+            serde_json::to_writer_pretty(&file, context)?;
+            arguments.push(file.path().to_path_buf());
+            payload_file = Some(file);
+            vec![]
+        }
+    };
+    let output = exec_command_with_timeout(
+        &hook.command,
+        &arguments,
+        log_sender,
+        envs,
+        Duration::from_secs(hook.timeout_seconds),
+    )?;
+    drop(payload_file);
+    return Ok(output);
+}
@@ -0,0 +1,923 @@
+use std::ffi::OsStr;
+use std::fs::{read_dir, File};
+use std::io::{BufReader, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::sync::mpsc;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageBuffer, ImageDecoder, ImageFormat};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::ColorType;
+use xml::reader::XmlEvent;
+
+use crate::command::{exec_command, log};
+use crate::dcm_sr::{basic_text_sr_events, TextReport, BASIC_TEXT_SR_SOP_CLASS_UID};
+use crate::dcm_xml::{
+    add_code_sequence_if_not_exist, add_element_if_not_exist, generate_uid, set_element,
+    set_element_if_empty, xml_contains, xml_events_to_file, xml_get_element_body,
+    xml_get_study_instance_uid, DcmElement, ENCAPSULATED_PDF_SOP_CLASS_UID,
+};
+use crate::dry_run::{DryRunAttribute, DryRunInstance, DryRunSeries};
+use crate::error::G2DError;
+use crate::image_metadata::ImageMetadata;
+use crate::sop_class::ImageSopClass;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    Jpeg,
+    Png,
+    Tiff,
+    Bmp,
+    Pdf,
+}
+
+impl InputFormat {
+    pub fn from_path(path: &Path) -> Option<InputFormat> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "jpg" | "jpeg" => Some(InputFormat::Jpeg),
+            "png" => Some(InputFormat::Png),
+            "tif" | "tiff" => Some(InputFormat::Tiff),
+            "bmp" => Some(InputFormat::Bmp),
+            "pdf" => Some(InputFormat::Pdf),
+            _ => None,
+        }
+    }
+}
+
+/// Lists all files with a supported extension (case-insensitive), sorted by path.
+/// With `recursive` the files of all subfolders are listed as well.
+pub fn list_input_files(dir_name: &Path, recursive: bool) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut entries = vec![];
+    for entry in read_dir(dir_name)? {
+        let path = entry?.path();
+        if path.is_file() && InputFormat::from_path(&path).is_some() {
+            entries.push(path);
+        } else if recursive && path.is_dir() {
+            entries.extend(list_input_files(&path, recursive)?);
+        }
+    }
+    entries.sort();
+    return Ok(entries);
+}
+
+/// How the images of a series are put into SOP instances
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum InstanceLayout {
+    /// One instance per image
+    PerImage,
+    /// One multi-frame instance per series (Secondary Capture only)
+    #[default]
+    MultiFrame,
+}
+
+/// How input files are grouped into series
+#[derive(Debug, Clone, Default)]
+pub enum SeriesGrouping {
+    /// JPEGs and the other images each form a series
+    #[default]
+    Format,
+    /// One series per subfolder of the given folder
+    Subfolder(PathBuf),
+    /// One series per distinct match in the file name, the first capture
+    /// group is used if the pattern has one
+    Pattern(Regex),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ImportOptions {
+    pub sop_class: ImageSopClass,
+    pub instances: InstanceLayout,
+    pub series_grouping: SeriesGrouping,
+    /// Written as a Basic Text SR in its own series
+    pub text_report: Option<TextReport>,
+}
+
+/// Image files (or converted temp files) with the metadata of their source
+type ImageFiles = Vec<(PathBuf, ImageMetadata)>;
+
+struct ImageSeries {
+    description: String,
+    jpegs: ImageFiles,
+    bitmaps: ImageFiles,
+}
+
+/// The input images of a series, before they are prepared for img2dcm
+struct SeriesFiles {
+    description: String,
+    jpegs: Vec<PathBuf>,
+    bitmaps: Vec<PathBuf>,
+}
+
+/// Converts the input files into DICOM objects of one study.
+///
+/// Images are grouped into series according to `options.series_grouping`.
+/// Within a series the images become one instance each, or one multi-frame
+/// instance for JPEGs and one for the other images if `options.instances`
+/// is `MultiFrame`. PNG, TIFF (every page) and BMP are converted to BMP first.
+/// Frames of one instance must share size and color, otherwise the series
+/// falls back to one instance per image.
+/// PDFs always form their own series with one Encapsulated PDF instance each.
+/// The text report, if any, is written as a Basic Text SR in the last series.
+///
+/// `xml_events` must already contain the patient data. The paths of the
+/// written DICOM files are returned.
+pub fn convert_input_files(
+    xml_events: &Vec<XmlEvent>,
+    input_files: &Vec<PathBuf>,
+    output: &Path,
+    options: &ImportOptions,
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<Vec<PathBuf>, G2DError> {
+    let sop_class = options.sop_class;
+    let multi_frame = options.instances == InstanceLayout::MultiFrame;
+    if multi_frame && !sop_class.is_multi_frame() {
+        log(
+            log_sender,
+            format!(
+                "{} is a single-frame IOD, writing one instance per image",
+                sop_class.label()
+            ),
+        );
+    }
+    let image_files = files_of_format(
+        input_files,
+        &[
+            InputFormat::Jpeg,
+            InputFormat::Png,
+            InputFormat::Tiff,
+            InputFormat::Bmp,
+        ],
+    );
+    let pdfs = files_of_format(input_files, &[InputFormat::Pdf]);
+
+    // Rotated JPEGs and converted bitmaps, deleted when the conversion is done
+    let mut temp_files = vec![];
+    let mut series_list: Vec<ImageSeries> = vec![];
+    for series in group_into_series(&image_files, options) {
+        let mut jpegs = vec![];
+        for path in &series.jpegs {
+            jpegs.push(prepare_jpeg(path, &mut temp_files, log_sender)?);
+        }
+        let mut bitmaps = vec![];
+        for path in &series.bitmaps {
+            bitmaps.extend(prepare_bitmaps(
+                path,
+                sop_class,
+                &mut temp_files,
+                log_sender,
+            )?);
+        }
+        series_list.push(ImageSeries {
+            description: series.description,
+            jpegs,
+            bitmaps,
+        });
+    }
+
+    let instance_groups = |files: &'_ [(PathBuf, ImageMetadata)]| -> Vec<ImageFiles> {
+        if files.is_empty() {
+            vec![]
+        } else if multi_frame && sop_class.is_multi_frame() && frames_compatible(files) {
+            vec![files.to_vec()]
+        } else {
+            if multi_frame && sop_class.is_multi_frame() {
+                log(
+                    log_sender,
+                    "Images differ in size or color, writing one instance per image".to_string(),
+                );
+            }
+            files.iter().map(|f| vec![f.clone()]).collect()
+        }
+    };
+    let series_instances: Vec<(&ImageSeries, Vec<ImageFiles>, Vec<ImageFiles>)> = series_list
+        .iter()
+        .map(|s| (s, instance_groups(&s.jpegs), instance_groups(&s.bitmaps)))
+        .collect();
+    let total_objects = series_instances
+        .iter()
+        .map(|(_, jpegs, bitmaps)| jpegs.len() + bitmaps.len())
+        .sum::<usize>()
+        + pdfs.len()
+        + options.text_report.iter().count();
+
+    let study_uid = xml_get_study_instance_uid(xml_events)
+        .filter(|uid| !uid.is_empty())
+        .unwrap_or_else(generate_uid);
+    let mut study_events = xml_events.clone();
+    set_element(
+        &mut study_events,
+        DcmElement {
+            tag: "0020,000d".to_string(),
+            vr: "UI".to_string(),
+            name: "StudyInstanceUID".to_string(),
+            body: study_uid,
+        },
+    );
+    let mut image_events = study_events.clone();
+    for element in sop_class.template_elements() {
+        if element.body.is_empty() {
+            add_element_if_not_exist(&mut image_events, element);
+        } else {
+            set_element_if_empty(&mut image_events, element);
+        }
+    }
+    for sequence in sop_class.template_code_sequences() {
+        add_code_sequence_if_not_exist(&mut image_events, sequence)?;
+    }
+
+    let mut outputs = vec![];
+    let mut series_number = 0;
+
+    for (series, jpeg_instances, bitmap_instances) in &series_instances {
+        series_number += 1;
+        let series_uid = generate_uid();
+        let mut series_events = image_events.clone();
+        if !series.description.is_empty() {
+            set_element(
+                &mut series_events,
+                DcmElement::new(
+                    "0008,103e",
+                    "LO",
+                    "SeriesDescription",
+                    &series.description.chars().take(64).collect::<String>(),
+                ),
+            );
+        }
+        let mut instance_number = 0;
+        for (format, instances) in [("JPEG", jpeg_instances), ("BMP", bitmap_instances)] {
+            for group in instances {
+                instance_number += 1;
+                let output_path = numbered_output_path(output, outputs.len() + 1, total_objects);
+                let mut events =
+                    instance_xml(&series_events, &series_uid, series_number, instance_number);
+                // A multi-frame instance gets the metadata of its first frame
+                set_metadata_elements(&mut events, &group[0].1);
+                let is_color = if format == "JPEG" {
+                    is_color_image(&group[0].0)
+                } else {
+                    !sop_class.is_grayscale()
+                };
+                let images = group.iter().map(|(p, _)| p.as_path()).collect();
+                image_to_dcm(
+                    events,
+                    sop_class,
+                    is_color,
+                    format,
+                    images,
+                    &output_path,
+                    log_sender,
+                )?;
+                outputs.push(output_path);
+            }
+        }
+    }
+
+    if !pdfs.is_empty() {
+        series_number += 1;
+        let series_uid = generate_uid();
+        for (index, pdf) in pdfs.iter().enumerate() {
+            let output_path = numbered_output_path(output, outputs.len() + 1, total_objects);
+            let temp_xml = xml_events_to_file(instance_xml(
+                &study_events,
+                &series_uid,
+                series_number,
+                index + 1,
+            ))?;
+            let series_dcm = NamedTempFile::new()?;
+            let output = exec_command(
+                "xml2dcm",
+                vec![temp_xml.path().as_os_str(), series_dcm.path().as_os_str()],
+                true,
+                log_sender,
+            )?;
+            check_output(&output, log_sender)?;
+            let output = exec_command(
+                "pdf2dcm",
+                vec![
+                    OsStr::new("--series-from"),
+                    series_dcm.path().as_os_str(),
+                    pdf.as_os_str(),
+                    output_path.as_os_str(),
+                ],
+                true,
+                log_sender,
+            )?;
+            check_output(&output, log_sender)?;
+            let output = exec_command(
+                "dcmodify",
+                vec![
+                    OsStr::new("-nb"),
+                    OsStr::new("-i"),
+                    OsStr::new(&format!("0020,0013={}", index + 1)),
+                    output_path.as_os_str(),
+                ],
+                true,
+                log_sender,
+            )?;
+            check_output(&output, log_sender)?;
+            outputs.push(output_path);
+        }
+    }
+
+    if let Some(report) = &options.text_report {
+        series_number += 1;
+        let output_path = numbered_output_path(output, outputs.len() + 1, total_objects);
+        let temp_xml =
+            xml_events_to_file(basic_text_sr_events(&study_events, report, series_number)?)?;
+        let output = exec_command(
+            "xml2dcm",
+            vec![temp_xml.path().as_os_str(), output_path.as_os_str()],
+            true,
+            log_sender,
+        )?;
+        check_output(&output, log_sender)?;
+        outputs.push(output_path);
+    }
+
+    return Ok(outputs);
+}
+
+/// The series and instances `convert_input_files` would write, with the
+/// attributes taken from the options and the EXIF metadata of the images.
+/// Nothing is converted, so frames of different size or color and the pages
+/// of a TIFF are only mentioned in the notes.
+pub fn dry_run_series(
+    xml_events: &Vec<XmlEvent>,
+    input_files: &Vec<PathBuf>,
+    output: &Path,
+    options: &ImportOptions,
+) -> Vec<DryRunSeries> {
+    let sop_class = options.sop_class;
+    let multi_frame = options.instances == InstanceLayout::MultiFrame;
+    let image_files = files_of_format(
+        input_files,
+        &[
+            InputFormat::Jpeg,
+            InputFormat::Png,
+            InputFormat::Tiff,
+            InputFormat::Bmp,
+        ],
+    );
+    let pdfs = files_of_format(input_files, &[InputFormat::Pdf]);
+
+    let instance_groups = |files: &[PathBuf]| -> Vec<Vec<PathBuf>> {
+        if files.is_empty() {
+            vec![]
+        } else if multi_frame && sop_class.is_multi_frame() {
+            vec![files.to_vec()]
+        } else {
+            files.iter().map(|f| vec![f.clone()]).collect()
+        }
+    };
+    let series_instances: Vec<_> = group_into_series(&image_files, options)
+        .into_iter()
+        .map(|s| {
+            let jpegs = instance_groups(&s.jpegs);
+            let bitmaps = instance_groups(&s.bitmaps);
+            (s, jpegs, bitmaps)
+        })
+        .collect();
+    let total_objects = series_instances
+        .iter()
+        .map(|(_, jpegs, bitmaps)| jpegs.len() + bitmaps.len())
+        .sum::<usize>()
+        + pdfs.len()
+        + options.text_report.iter().count();
+
+    let sop_class_source = if sop_class.needs_sop_class_override() {
+        format!(
+            "--sop-class, img2dcm {} changed by dcmodify",
+            sop_class.img2dcm_args().join(" ")
+        )
+    } else {
+        "--sop-class".to_string()
+    };
+    let grouping_source = match options.series_grouping {
+        SeriesGrouping::Format => "--series-by format",
+        SeriesGrouping::Subfolder(_) => "--series-by subfolder",
+        SeriesGrouping::Pattern(_) => "--series-by pattern",
+    };
+    let mut result = vec![];
+    let mut object_count = 0;
+
+    for (series, jpeg_instances, bitmap_instances) in &series_instances {
+        let mut attributes = vec![DryRunAttribute::new(
+            "0020,0011",
+            "IS",
+            "SeriesNumber",
+            &(result.len() + 1).to_string(),
+            grouping_source,
+        )];
+        if !series.description.is_empty() {
+            attributes.push(DryRunAttribute::new(
+                "0008,103e",
+                "LO",
+                "SeriesDescription",
+                &series.description.chars().take(64).collect::<String>(),
+                grouping_source,
+            ));
+        }
+        for element in sop_class.template_elements() {
+            attributes.push(attribute_from_template(xml_events, element, "--sop-class"));
+        }
+        for sequence in sop_class.template_code_sequences() {
+            // A sequence of the template is kept
+            if xml_contains(xml_events, sequence.tag.clone(), sequence.name.clone()) {
+                continue;
+            }
+            attributes.push(DryRunAttribute::new(
+                &sequence.tag,
+                "SQ",
+                &sequence.name,
+                &sequence.code_string(),
+                "--sop-class",
+            ));
+        }
+        let mut notes = vec![];
+        if multi_frame && !sop_class.is_multi_frame() {
+            notes.push(format!(
+                "{} is a single-frame IOD, one instance per image",
+                sop_class.label()
+            ));
+        }
+
+        let mut instances = vec![];
+        for (is_jpeg, groups) in [(true, jpeg_instances), (false, bitmap_instances)] {
+            for group in groups {
+                object_count += 1;
+                let is_color = if is_jpeg {
+                    is_color_image(&group[0])
+                } else {
+                    !sop_class.is_grayscale()
+                };
+                let mut attributes = vec![DryRunAttribute::new(
+                    "0008,0016",
+                    "UI",
+                    "SOPClassUID",
+                    sop_class.uid(is_color),
+                    &sop_class_source,
+                )];
+                // A multi-frame instance gets the metadata of its first frame
+                let metadata = ImageMetadata::read(&group[0]);
+                for element in metadata.to_dcm_elements() {
+                    attributes.push(attribute_from_template(xml_events, element, "EXIF"));
+                }
+                let mut notes = vec![];
+                if group.len() > 1 {
+                    notes.push(
+                        "One multi-frame instance if the images share size and color, otherwise one instance per image"
+                            .to_string(),
+                    );
+                }
+                for path in group {
+                    let orientation = ImageMetadata::read(path).orientation;
+                    if let Some(o) = orientation.filter(|o| *o > 1) {
+                        notes.push(format!(
+                            "{} is rotated upright (EXIF orientation {})",
+                            path.display(),
+                            o
+                        ));
+                    }
+                    if InputFormat::from_path(path) == Some(InputFormat::Tiff) {
+                        notes.push(format!("Every page of {} is an image", path.display()));
+                    }
+                }
+                instances.push(DryRunInstance {
+                    output: numbered_output_path(output, object_count, total_objects),
+                    inputs: group.clone(),
+                    attributes,
+                    notes,
+                });
+            }
+        }
+        result.push(DryRunSeries {
+            number: result.len() + 1,
+            attributes,
+            instances,
+            notes,
+        });
+    }
+
+    if !pdfs.is_empty() {
+        let attributes = vec![DryRunAttribute::new(
+            "0020,0011",
+            "IS",
+            "SeriesNumber",
+            &(result.len() + 1).to_string(),
+            "PDFs form their own series",
+        )];
+        let mut instances = vec![];
+        for pdf in &pdfs {
+            object_count += 1;
+            instances.push(DryRunInstance {
+                output: numbered_output_path(output, object_count, total_objects),
+                inputs: vec![pdf.clone()],
+                attributes: vec![DryRunAttribute::new(
+                    "0008,0016",
+                    "UI",
+                    "SOPClassUID",
+                    ENCAPSULATED_PDF_SOP_CLASS_UID,
+                    "pdf2dcm",
+                )],
+                notes: vec![],
+            });
+        }
+        result.push(DryRunSeries {
+            number: result.len() + 1,
+            attributes,
+            instances,
+            notes: vec![],
+        });
+    }
+
+    if options.text_report.is_some() {
+        object_count += 1;
+        result.push(DryRunSeries {
+            number: result.len() + 1,
+            attributes: vec![DryRunAttribute::new(
+                "0020,0011",
+                "IS",
+                "SeriesNumber",
+                &(result.len() + 1).to_string(),
+                "--text-sr",
+            )],
+            instances: vec![DryRunInstance {
+                output: numbered_output_path(output, object_count, total_objects),
+                inputs: vec![],
+                attributes: vec![DryRunAttribute::new(
+                    "0008,0016",
+                    "UI",
+                    "SOPClassUID",
+                    BASIC_TEXT_SR_SOP_CLASS_UID,
+                    "--text-sr",
+                )],
+                notes: vec!["Basic Text SR with 6205, 6220 and 6227".to_string()],
+            }],
+            notes: vec![],
+        });
+    }
+    return result;
+}
+
+/// `element` as the conversion sets it: a value of the template is kept
+fn attribute_from_template(
+    xml_events: &Vec<XmlEvent>,
+    element: DcmElement,
+    source: &str,
+) -> DryRunAttribute {
+    let template_value = xml_get_element_body(
+        xml_events,
+        Some(element.name.clone()),
+        Some(element.tag.clone()),
+    )
+    .map(|v| v.trim().to_string())
+    .filter(|v| !v.is_empty());
+    return match template_value {
+        Some(value) => {
+            DryRunAttribute::new(&element.tag, &element.vr, &element.name, &value, "template")
+        }
+        None => DryRunAttribute::new(
+            &element.tag,
+            &element.vr,
+            &element.name,
+            &element.body,
+            source,
+        ),
+    };
+}
+
+fn image_to_dcm(
+    mut events: Vec<XmlEvent>,
+    sop_class: ImageSopClass,
+    is_color: bool,
+    input_format: &str,
+    images: Vec<&Path>,
+    output_path: &Path,
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<(), G2DError> {
+    let sop_class_uid = sop_class.uid(is_color);
+    set_element(
+        &mut events,
+        DcmElement::new("0008,0016", "UI", "SOPClassUID", sop_class_uid),
+    );
+    set_element(
+        &mut events,
+        DcmElement::new("0002,0002", "UI", "MediaStorageSOPClassUID", sop_class_uid),
+    );
+    let temp_xml = xml_events_to_file(events)?;
+    let mut command_args: Vec<&OsStr> = sop_class
+        .img2dcm_args()
+        .into_iter()
+        .map(OsStr::new)
+        .collect();
+    command_args.extend([
+        OsStr::new("-i"),
+        OsStr::new(input_format),
+        OsStr::new("-dx"),
+        temp_xml.path().as_os_str(),
+    ]);
+    command_args.extend(images.iter().map(|x| x.as_os_str()));
+    command_args.push(output_path.as_os_str());
+    let output = exec_command("img2dcm", command_args, true, log_sender)?;
+    check_output(&output, log_sender)?;
+
+    if sop_class.needs_sop_class_override() {
+        let output = exec_command(
+            "dcmodify",
+            vec![
+                OsStr::new("-nb"),
+                OsStr::new("-m"),
+                OsStr::new(&format!("0008,0016={}", sop_class_uid)),
+                output_path.as_os_str(),
+            ],
+            true,
+            log_sender,
+        )?;
+        check_output(&output, log_sender)?;
+    }
+    return Ok(());
+}
+
+/// Groups the images into series according to `options.series_grouping`.
+/// JPEGs are passed to img2dcm as they are, the other images as bitmaps.
+fn group_into_series(image_files: &[PathBuf], options: &ImportOptions) -> Vec<SeriesFiles> {
+    let mut series_list: Vec<SeriesFiles> = vec![];
+    for path in image_files {
+        let description = series_key(path, &options.series_grouping);
+        // Grayscale IODs get every image converted to an 8 bit BMP
+        let is_jpeg = InputFormat::from_path(path) == Some(InputFormat::Jpeg)
+            && !options.sop_class.is_grayscale();
+        let series_index = match series_list.iter().position(|s| {
+            s.description == description
+                && match options.series_grouping {
+                    // Formats don't share a series
+                    SeriesGrouping::Format => is_jpeg != s.jpegs.is_empty(),
+                    _ => true,
+                }
+        }) {
+            Some(i) => i,
+            None => {
+                series_list.push(SeriesFiles {
+                    description,
+                    jpegs: vec![],
+                    bitmaps: vec![],
+                });
+                series_list.len() - 1
+            }
+        };
+        if is_jpeg {
+            series_list[series_index].jpegs.push(path.clone());
+        } else {
+            series_list[series_index].bitmaps.push(path.clone());
+        }
+    }
+    return series_list;
+}
+
+fn series_key(path: &Path, grouping: &SeriesGrouping) -> String {
+    match grouping {
+        SeriesGrouping::Format => String::new(),
+        SeriesGrouping::Subfolder(base) => path
+            .parent()
+            .and_then(|p| p.strip_prefix(base).ok())
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        SeriesGrouping::Pattern(regex) => path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| regex.captures(n))
+            .and_then(|c| c.get(1).or(c.get(0)))
+            .map(|m| m.as_str().to_string())
+            .unwrap_or_default(),
+    }
+}
+
+/// JPEGs are passed to img2dcm as they are. img2dcm copies the JPEG data,
+/// so images which have to be rotated are re-encoded.
+fn prepare_jpeg(
+    path: &Path,
+    temp_files: &mut Vec<NamedTempFile>,
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<(PathBuf, ImageMetadata), G2DError> {
+    let metadata = ImageMetadata::read(path);
+    let orientation = match metadata.orientation_transform() {
+        Some(o) => o,
+        None => return Ok((path.to_path_buf(), metadata)),
+    };
+    log(
+        log_sender,
+        format!("Applying EXIF orientation to {}", path.display()),
+    );
+    let mut image = image::open(path)?;
+    image.apply_orientation(orientation);
+    let temp_file = tempfile::Builder::new().suffix(".jpg").tempfile()?;
+    let encoder = JpegEncoder::new_with_quality(File::create(temp_file.path())?, 95);
+    if image.color().has_color() {
+        DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(encoder)?;
+    } else {
+        DynamicImage::ImageLuma8(image.to_luma8()).write_with_encoder(encoder)?;
+    }
+    let temp_path = temp_file.path().to_path_buf();
+    temp_files.push(temp_file);
+    return Ok((temp_path, metadata));
+}
+
+/// Converts every page of an image to an 8 bit RGB (or grayscale) BMP
+fn prepare_bitmaps(
+    path: &Path,
+    sop_class: ImageSopClass,
+    temp_files: &mut Vec<NamedTempFile>,
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<Vec<(PathBuf, ImageMetadata)>, G2DError> {
+    let metadata = ImageMetadata::read(path);
+    let mut bitmaps = vec![];
+    for (index, image) in decode_pages(path, log_sender)?.iter_mut().enumerate() {
+        log(
+            log_sender,
+            format!("Converting {} (page {})", path.display(), index + 1),
+        );
+        if let Some(orientation) = metadata.orientation_transform() {
+            image.apply_orientation(orientation);
+        }
+        let temp_file = tempfile::Builder::new().suffix(".bmp").tempfile()?;
+        let bitmap = if sop_class.is_grayscale() {
+            DynamicImage::ImageLuma8(image.to_luma8())
+        } else {
+            DynamicImage::ImageRgb8(image.to_rgb8())
+        };
+        bitmap.save_with_format(temp_file.path(), ImageFormat::Bmp)?;
+        bitmaps.push((temp_file.path().to_path_buf(), metadata.clone()));
+        temp_files.push(temp_file);
+    }
+    return Ok(bitmaps);
+}
+
+/// Whether the images can be frames of one instance
+fn frames_compatible(files: &[(PathBuf, ImageMetadata)]) -> bool {
+    let properties: Vec<_> = files
+        .iter()
+        .map(|(p, _)| (image::image_dimensions(p).ok(), is_color_image(p)))
+        .collect();
+    properties
+        .iter()
+        .all(|p| p.0.is_some() && *p == properties[0])
+}
+
+fn set_metadata_elements(events: &mut Vec<XmlEvent>, metadata: &ImageMetadata) {
+    for element in metadata.to_dcm_elements() {
+        set_element_if_empty(events, element);
+    }
+}
+
+fn is_color_image(path: &Path) -> bool {
+    image::ImageReader::open(path)
+        .ok()
+        .and_then(|r| r.with_guessed_format().ok())
+        .and_then(|r| r.into_decoder().ok())
+        .map(|d| d.color_type().has_color())
+        .unwrap_or(true)
+}
+
+fn files_of_format(input_files: &[PathBuf], formats: &[InputFormat]) -> Vec<PathBuf> {
+    input_files
+        .iter()
+        .filter(|p| {
+            InputFormat::from_path(p)
+                .map(|f| formats.contains(&f))
+                .unwrap_or(false)
+        })
+        .cloned()
+        .collect()
+}
+
+fn instance_xml(
+    study_events: &Vec<XmlEvent>,
+    series_uid: &str,
+    series_number: usize,
+    instance_number: usize,
+) -> Vec<XmlEvent> {
+    let mut events = study_events.clone();
+    set_element(
+        &mut events,
+        DcmElement {
+            tag: "0020,000e".to_string(),
+            vr: "UI".to_string(),
+            name: "SeriesInstanceUID".to_string(),
+            body: series_uid.to_string(),
+        },
+    );
+    set_element(
+        &mut events,
+        DcmElement {
+            tag: "0020,0011".to_string(),
+            vr: "IS".to_string(),
+            name: "SeriesNumber".to_string(),
+            body: series_number.to_string(),
+        },
+    );
+    set_element(
+        &mut events,
+        DcmElement {
+            tag: "0020,0013".to_string(),
+            vr: "IS".to_string(),
+            name: "InstanceNumber".to_string(),
+            body: instance_number.to_string(),
+        },
+    );
+    events
+}
+
+/// `output` is used as is for a single object. For more objects, a number is
+/// appended to the file name, or files are numbered inside `output` if it is a directory.
+fn numbered_output_path(output: &Path, index: usize, total: usize) -> PathBuf {
+    if output.is_dir() {
+        return output.join(format!("{:04}.dcm", index));
+    }
+    if total <= 1 {
+        return output.to_path_buf();
+    }
+    let stem = output
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let file_name = match output.extension() {
+        Some(ext) => format!("{}_{:03}.{}", stem, index, ext.to_string_lossy()),
+        None => format!("{}_{:03}", stem, index),
+    };
+    output.with_file_name(file_name)
+}
+
+/// Decodes an image file, every page of a multi-page TIFF is returned as its own image
+fn decode_pages(
+    path: &Path,
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<Vec<DynamicImage>, G2DError> {
+    if InputFormat::from_path(path) != Some(InputFormat::Tiff) {
+        return Ok(vec![image::open(path)?]);
+    }
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+    let mut pages = vec![];
+    loop {
+        let (width, height) = decoder.dimensions()?;
+        let color_type = decoder.colortype()?;
+        let page = match (color_type, decoder.read_image()?) {
+            (ColorType::Gray(8), DecodingResult::U8(d)) => {
+                ImageBuffer::from_raw(width, height, d).map(DynamicImage::ImageLuma8)
+            }
+            (ColorType::Gray(16), DecodingResult::U16(d)) => {
+                ImageBuffer::from_raw(width, height, d).map(DynamicImage::ImageLuma16)
+            }
+            (ColorType::GrayA(8), DecodingResult::U8(d)) => {
+                ImageBuffer::from_raw(width, height, d).map(DynamicImage::ImageLumaA8)
+            }
+            (ColorType::RGB(8), DecodingResult::U8(d)) => {
+                ImageBuffer::from_raw(width, height, d).map(DynamicImage::ImageRgb8)
+            }
+            (ColorType::RGB(16), DecodingResult::U16(d)) => {
+                ImageBuffer::from_raw(width, height, d).map(DynamicImage::ImageRgb16)
+            }
+            (ColorType::RGBA(8), DecodingResult::U8(d)) => {
+                ImageBuffer::from_raw(width, height, d).map(DynamicImage::ImageRgba8)
+            }
+            (ColorType::RGBA(16), DecodingResult::U16(d)) => {
+                ImageBuffer::from_raw(width, height, d).map(DynamicImage::ImageRgba16)
+            }
+            _ => None,
+        };
+        match page {
+            Some(p) => pages.push(p),
+            None => log(
+                log_sender,
+                format!(
+                    "Skipping TIFF page {} of {}: unsupported color type {:?}",
+                    pages.len() + 1,
+                    path.display(),
+                    color_type
+                ),
+            ),
+        }
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image()?;
+    }
+    return Ok(pages);
+}
+
+fn check_output(
+    output: &Output,
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<(), G2DError> {
+    if output.status.success() {
+        return Ok(());
+    }
+    let err_str = String::from_utf8_lossy(&output.stderr).to_string();
+    log(log_sender, err_str.clone());
+    return Err(G2DError::IoError(Error::new(ErrorKind::Other, err_str)));
+}
@@ -0,0 +1,131 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use exif::{DateTime, In, Reader, Tag, Value};
+use image::metadata::Orientation;
+
+use crate::dcm_xml::DcmElement;
+
+/// Camera metadata read from the EXIF block of an image
+#[derive(Debug, Clone, Default)]
+pub struct ImageMetadata {
+    /// DateTimeOriginal as DICOM DT, YYYYMMDDHHMMSS[.FFFFFF][&ZZXX]
+    pub date_time_original: Option<String>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    /// EXIF orientation, 1 to 8
+    pub orientation: Option<u8>,
+}
+
+impl ImageMetadata {
+    /// Reads the EXIF metadata of a JPEG, TIFF or PNG file.
+    /// Files without (valid) EXIF data result in empty metadata.
+    pub fn read(path: &Path) -> ImageMetadata {
+        let exif = match File::open(path).ok().and_then(|f| {
+            Reader::new()
+                .read_from_container(&mut BufReader::new(f))
+                .ok()
+        }) {
+            Some(e) => e,
+            None => return ImageMetadata::default(),
+        };
+        let ascii = |tag: Tag| -> Option<String> {
+            match &exif.get_field(tag, In::PRIMARY)?.value {
+                Value::Ascii(values) => values
+                    .first()
+                    .map(|v| String::from_utf8_lossy(v).trim().to_string())
+                    .filter(|s| !s.is_empty()),
+                _ => None,
+            }
+        };
+        let date_time_original = ascii(Tag::DateTimeOriginal)
+            .or_else(|| ascii(Tag::DateTime))
+            .and_then(|s| DateTime::from_ascii(s.as_bytes()).ok())
+            .map(|mut dt| {
+                if let Some(subsec) = ascii(Tag::SubSecTimeOriginal) {
+                    _ = dt.parse_subsec(subsec.as_bytes());
+                }
+                if let Some(offset) = ascii(Tag::OffsetTimeOriginal) {
+                    _ = dt.parse_offset(offset.as_bytes());
+                }
+                dcm_date_time(&dt)
+            });
+        let orientation = exif
+            .get_field(Tag::Orientation, In::PRIMARY)
+            .and_then(|f| f.value.get_uint(0))
+            .and_then(|o| u8::try_from(o).ok());
+        return ImageMetadata {
+            date_time_original,
+            make: ascii(Tag::Make),
+            model: ascii(Tag::Model),
+            orientation,
+        };
+    }
+
+    /// Rotation/flip needed to display the image upright, None if it already is
+    pub fn orientation_transform(&self) -> Option<Orientation> {
+        self.orientation
+            .and_then(Orientation::from_exif)
+            .filter(|o| *o != Orientation::NoTransforms)
+    }
+
+    /// AcquisitionDateTime, ContentDate, ContentTime, Manufacturer and ManufacturerModelName
+    pub fn to_dcm_elements(&self) -> Vec<DcmElement> {
+        let mut elements = vec![];
+        if let Some(date_time) = &self.date_time_original {
+            let date: String = date_time.chars().take(8).collect();
+            let time: String = date_time
+                .chars()
+                .skip(8)
+                .take_while(|c| *c != '+' && *c != '-')
+                .collect();
+            elements.push(DcmElement::new(
+                "0008,002a",
+                "DT",
+                "AcquisitionDateTime",
+                date_time,
+            ));
+            elements.push(DcmElement::new("0008,0023", "DA", "ContentDate", &date));
+            elements.push(DcmElement::new("0008,0033", "TM", "ContentTime", &time));
+        }
+        if let Some(make) = &self.make {
+            elements.push(DcmElement::new(
+                "0008,0070",
+                "LO",
+                "Manufacturer",
+                &truncate(make),
+            ));
+        }
+        if let Some(model) = &self.model {
+            elements.push(DcmElement::new(
+                "0008,1090",
+                "LO",
+                "ManufacturerModelName",
+                &truncate(model),
+            ));
+        }
+        return elements;
+    }
+}
+
+fn dcm_date_time(dt: &DateTime) -> String {
+    let mut result = format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        dt.year, dt.month, dt.day, dt.hour, dt.minute, dt.second
+    );
+    if let Some(nanosecond) = dt.nanosecond {
+        result.push_str(&format!(".{:06}", nanosecond / 1000));
+    }
+    if let Some(offset) = dt.offset {
+        let sign = if offset < 0 { '-' } else { '+' };
+        let offset = offset.abs();
+        result.push_str(&format!("{}{:02}{:02}", sign, offset / 60, offset % 60));
+    }
+    return result;
+}
+
+fn truncate(value: &str) -> String {
+    // VR LO allows 64 characters
+    value.chars().take(64).collect()
+}
@@ -1,11 +1,32 @@
+pub mod anonymize;
+pub mod cli;
 pub mod command;
+pub mod config;
+pub mod dcm_pixel_data;
+pub mod dcm_sr;
+pub mod dcm_study;
 pub mod dcm_worklist;
 pub mod dcm_xml;
+pub mod dicomdir;
+pub mod dry_run;
 pub mod error;
+pub mod filename_template;
 pub mod gdt;
+pub mod hooks;
+pub mod image_import;
+pub mod image_metadata;
 pub mod opp_xml;
+pub mod output_actions;
+pub mod person_name;
+pub mod servers;
+pub mod sop_class;
+pub mod state;
+pub mod thumbnails;
+pub mod validation;
 pub mod vdds;
 pub mod worklist_conversion;
+pub mod worklist_entries;
+pub mod worklist_lifecycle;
 
 #[cfg(feature = "gui")]
 pub mod gui;
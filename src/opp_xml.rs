@@ -1,4 +1,5 @@
 use std::fs::File;
+use std::io::Write;
 use std::path::PathBuf;
 use xml::attribute::OwnedAttribute;
 use xml::name::OwnedName;
@@ -97,6 +98,20 @@ pub fn insert_patient_to_xml(file: GdtFile, events: &mut Vec<XmlEvent>) {
 }
 
 pub fn file_to_xml(file: GdtFile, output: PathBuf) -> Result<File, G2DError> {
+    let events = file_to_xml_events(file);
+    let file = File::create(output)?;
+    write_xml_events(&file, events)?;
+    return Ok(file);
+}
+
+/// The OPP XML `file_to_xml` writes, for showing it without writing a file
+pub fn file_to_xml_string(file: GdtFile) -> Result<String, G2DError> {
+    let mut buffer: Vec<u8> = vec![];
+    write_xml_events(&mut buffer, file_to_xml_events(file))?;
+    return Ok(String::from_utf8_lossy(&buffer).to_string());
+}
+
+fn file_to_xml_events(file: GdtFile) -> Vec<XmlEvent> {
     let xml_str = default_xml_str();
     let reader = EventReader::new(xml_str.as_bytes());
     let mut events: Vec<XmlEvent> = reader
@@ -108,19 +123,25 @@ pub fn file_to_xml(file: GdtFile, output: PathBuf) -> Result<File, G2DError> {
         .collect::<Result<Vec<_>, xml::reader::Error>>()
         .unwrap();
     insert_patient_to_xml(file, &mut events);
-    let file = File::create(output)?;
-    let mut writer = EventWriter::new(&file);
+    return events;
+}
+
+fn write_xml_events<W: Write>(sink: W, events: Vec<XmlEvent>) -> Result<(), G2DError> {
+    let mut writer = EventWriter::new(sink);
     for e in events {
         match e.as_writer_event() {
             Some(e) => writer.write(e)?,
             _ => (), // events like EndDocument are ignored
         };
     }
-    return Ok(file);
+    return Ok(());
 }
 
 fn gdt_date_to_opp(str: String) -> String {
     // DDMMYYYY -> YYYY-MM-DD
+    if str.len() != 8 || !str.is_ascii() {
+        return "".to_string();
+    }
     let day = &str[0..2];
     let month = &str[2..4];
     let year = &str[4..8];
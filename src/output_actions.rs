@@ -0,0 +1,243 @@
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::command::{exec_command, exec_command_with_timeout};
+use crate::dcm_xml::{
+    default_dcm_xml, file_to_xml_events, xml_events_to_file, DcmTransferType, FileToXmlOptions,
+    RequestTags,
+};
+use crate::error::G2DError;
+use crate::filename_template::{FilenameTemplate, FilenameValues};
+use crate::gdt::parse_file;
+use crate::hooks::DEFAULT_HOOK_TIMEOUT_SECONDS;
+use crate::opp_xml::file_to_xml;
+use crate::vdds;
+
+/// One target an auto-convert folder writes each GDT file to, the folder
+/// runs all of its actions in order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum OutputAction {
+    /// A worklist entry in the folder of the AETitle
+    Worklist,
+    /// A DICOM file with the patient and request, like `gdt2dicom` without images
+    DicomFile { output_dir: PathBuf },
+    /// An Open Practice Protocol XML file, like `gdt2opp`
+    Opp { output_dir: PathBuf },
+    /// Import of the patient into the BVS of VDDS_MMI (PATDATIMPORT), like `gdt2vdds`
+    Vdds {
+        #[serde(default)]
+        vdds_mmi: Option<PathBuf>,
+        #[serde(default)]
+        bvs: Option<String>,
+    },
+    /// A copy of the GDT file
+    GdtCopy { output_dir: PathBuf },
+    /// A program which is run with the path of the GDT file as last argument,
+    /// it's killed after `timeout_seconds` so it can't stop the folder
+    Script {
+        command: String,
+        #[serde(default)]
+        arguments: Vec<String>,
+        #[serde(default = "default_script_timeout_seconds")]
+        timeout_seconds: u64,
+    },
+}
+
+fn default_script_timeout_seconds() -> u64 {
+    return DEFAULT_HOOK_TIMEOUT_SECONDS;
+}
+
+impl OutputAction {
+    /// The default of an auto-convert folder
+    pub fn defaults() -> Vec<OutputAction> {
+        return vec![OutputAction::Worklist];
+    }
+
+    /// Runs the action for a GDT file, returns what was written. Worklist
+    /// entries are written by the worklist conversion, which knows the
+    /// AETitle and existing entries.
+    pub fn run(
+        &self,
+        gdt_path: &Path,
+        filename_template: &FilenameTemplate,
+        request_tags: &RequestTags,
+        log_sender: Option<&mpsc::Sender<String>>,
+    ) -> Result<String, G2DError> {
+        match self {
+            OutputAction::Worklist => {
+                return Err(G2DError::IoError(Error::new(
+                    ErrorKind::Unsupported,
+                    "Worklist entries are written by the worklist conversion",
+                )));
+            }
+            OutputAction::DicomFile { output_dir } => {
+                let gdt_file = parse_file(gdt_path)?;
+                request_tags.check(&gdt_file.object_request)?;
+                let output = filename_template.unique_path(
+                    output_dir,
+                    "dcm",
+                    &FilenameValues::from_gdt_file(&gdt_file),
+                );
+                let options = FileToXmlOptions {
+                    request_tags: *request_tags,
+                    ..Default::default()
+                };
+                let xml_events = default_dcm_xml(DcmTransferType::JPEGBaseline);
+                let events = file_to_xml_events(gdt_file, &xml_events, &options);
+                let temp_file = xml_events_to_file(events)?;
+                let output1 = exec_command(
+                    "xml2dcm",
+                    vec![temp_file.path().as_os_str(), output.as_os_str()],
+                    false,
+                    log_sender,
+                )?;
+                check_status(&output1)?;
+                return Ok(output.display().to_string());
+            }
+            OutputAction::Opp { output_dir } => {
+                let gdt_file = parse_file(gdt_path)?;
+                let output = filename_template.unique_path(
+                    output_dir,
+                    "xml",
+                    &FilenameValues::from_gdt_file(&gdt_file),
+                );
+                file_to_xml(gdt_file, output.clone())?;
+                return Ok(output.display().to_string());
+            }
+            OutputAction::Vdds { vdds_mmi, bvs } => {
+                let gdt_file = parse_file(gdt_path)?;
+                let vdds_mmi_path = vdds_mmi
+                    .clone()
+                    .unwrap_or_else(vdds::default_vdds_mmi_folder);
+                let mut mmi = vdds::load_ini(&vdds_mmi_path)?;
+                vdds::register_pvs(&mut mmi, &vdds_mmi_path)?;
+                let bvs_name = vdds::select_bvs(&mmi, bvs.as_deref())?;
+                vdds::import_patient(&mmi, &bvs_name, &gdt_file, false)?;
+                return Ok(format!("patient imported into {}", bvs_name));
+            }
+            OutputAction::GdtCopy { output_dir } => {
+                let output = output_dir.join(gdt_path.file_name().unwrap_or_default());
+                std::fs::copy(gdt_path, &output)?;
+                return Ok(output.display().to_string());
+            }
+            OutputAction::Script {
+                command,
+                arguments,
+                timeout_seconds,
+            } => {
+                let mut args: Vec<&OsStr> = arguments.iter().map(OsStr::new).collect();
+                args.push(gdt_path.as_os_str());
+                let output = exec_command_with_timeout(
+                    command,
+                    args,
+                    log_sender,
+                    vec![],
+                    Duration::from_secs(*timeout_seconds),
+                )?;
+                check_status(&output)?;
+                return Ok(format!("{} finished", command));
+            }
+        }
+    }
+}
+
+impl fmt::Display for OutputAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputAction::Worklist => write!(f, "Worklist"),
+            OutputAction::DicomFile { output_dir } => {
+                write!(f, "DICOM file ({})", output_dir.display())
+            }
+            OutputAction::Opp { output_dir } => write!(f, "OPP ({})", output_dir.display()),
+            OutputAction::Vdds { bvs, .. } => match bvs {
+                Some(bvs) => write!(f, "VDDS ({})", bvs),
+                None => write!(f, "VDDS"),
+            },
+            OutputAction::GdtCopy { output_dir } => {
+                write!(f, "GDT copy ({})", output_dir.display())
+            }
+            OutputAction::Script { command, .. } => write!(f, "Script ({})", command),
+        }
+    }
+}
+
+/// What an action did with a GDT file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ActionOutcome {
+    /// The file written, or what was done
+    Done(String),
+    /// Nothing was written, e.g. for a duplicate worklist entry, with the reason
+    Skipped(String),
+}
+
+/// The result of every action for one GDT file
+pub struct OutputReport {
+    pub results: Vec<(OutputAction, Result<ActionOutcome, G2DError>)>,
+}
+
+impl OutputReport {
+    pub fn is_success(&self) -> bool {
+        return self.results.iter().all(|(_, result)| result.is_ok());
+    }
+
+    /// Whether the GDT file has to be converted again: the worklist entry
+    /// failed, or any action failed if there is no worklist action
+    pub fn needs_retry(&self) -> bool {
+        let worklist = self
+            .results
+            .iter()
+            .find(|(action, _)| *action == OutputAction::Worklist);
+        return match worklist {
+            Some((_, result)) => result.is_err(),
+            None => !self.is_success(),
+        };
+    }
+
+    /// File name of the worklist entry written for the GDT file, none if it
+    /// was skipped or failed
+    pub fn worklist_file(&self) -> Option<&str> {
+        return self
+            .results
+            .iter()
+            .find_map(|(action, result)| match (action, result) {
+                (OutputAction::Worklist, Ok(ActionOutcome::Done(filename))) => {
+                    Some(filename.as_str())
+                }
+                _ => None,
+            });
+    }
+
+    /// One line per action
+    pub fn summary(&self) -> String {
+        return self
+            .results
+            .iter()
+            .map(|(action, result)| match result {
+                Ok(ActionOutcome::Done(output)) => format!("{}: OK, {}", action, output),
+                Ok(ActionOutcome::Skipped(reason)) => format!("{}: SKIPPED, {}", action, reason),
+                Err(err) => format!("{}: FAILED, {}", action, err),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+}
+
+fn check_status(output: &std::process::Output) -> Result<(), G2DError> {
+    if !output.status.success() {
+        let err_str = String::from_utf8_lossy(&output.stderr);
+        let message = match err_str.trim() {
+            "" => format!("exited with {}", output.status),
+            err_str => err_str.to_string(),
+        };
+        let custom_error = Error::new(ErrorKind::Other, message);
+        return Err(G2DError::IoError(custom_error));
+    }
+    return Ok(());
+}
@@ -0,0 +1,402 @@
+use serde::{Deserialize, Serialize};
+
+use crate::gdt::GdtPatientObject;
+
+// A single component group of a DICOM PN may not be longer than 64 characters
+const MAX_GROUP_LENGTH: usize = 64;
+
+// Stay lower-case with NameCase::Capitalize unless they are the last word,
+// e.g. "van der Berg", "von Weizsäcker", "de la Cruz"
+const NAME_PARTICLES: [&str; 21] = [
+    "da", "das", "de", "del", "della", "den", "der", "des", "di", "do", "dos", "du", "la", "le",
+    "ten", "ter", "van", "vom", "von", "zu", "zur",
+];
+
+/// Letter case applied to name components when converting between GDT and DICOM
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+pub enum NameCase {
+    /// MÜLLER^HANS
+    #[default]
+    Upper,
+    /// Keep the case of the source
+    Preserve,
+    /// Müller^Hans, particles stay lower-case: van der Berg^Jan
+    Capitalize,
+}
+
+/// One component group of a DICOM PN value:
+/// family^given^middle^prefix^suffix
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PersonNameGroup {
+    pub family: String,
+    pub given: String,
+    pub middle: String,
+    pub prefix: String,
+    pub suffix: String,
+}
+
+/// DICOM person name (VR PN) with the alphabetic, ideographic and phonetic
+/// component groups, e.g. `Yamada^Tarou=山田^太郎=やまだ^たろう`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PersonName {
+    pub alphabetic: PersonNameGroup,
+    pub ideographic: Option<PersonNameGroup>,
+    pub phonetic: Option<PersonNameGroup>,
+}
+
+impl PersonNameGroup {
+    pub fn parse(value: &str) -> PersonNameGroup {
+        let mut components = value.split('^').map(|s| s.trim().to_string());
+        PersonNameGroup {
+            family: components.next().unwrap_or_default(),
+            given: components.next().unwrap_or_default(),
+            middle: components.next().unwrap_or_default(),
+            prefix: components.next().unwrap_or_default(),
+            suffix: components.next().unwrap_or_default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.family.is_empty()
+            && self.given.is_empty()
+            && self.middle.is_empty()
+            && self.prefix.is_empty()
+            && self.suffix.is_empty()
+    }
+
+    /// Given name and middle name(s) separated by a space, as used in GDT 3102
+    pub fn given_names(&self) -> String {
+        [&self.given, &self.middle]
+            .iter()
+            .filter(|s| !s.is_empty())
+            .map(|s| s.as_str())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn to_dcm_string(&self, case: NameCase) -> String {
+        let mut components: Vec<String> = [
+            &self.family,
+            &self.given,
+            &self.middle,
+            &self.prefix,
+            &self.suffix,
+        ]
+        .iter()
+        .map(|c| apply_case(&sanitize_component(c), case))
+        .collect();
+        // Trailing empty components and their delimiters are omitted
+        while components.last().map(|c| c.is_empty()).unwrap_or(false) {
+            components.pop();
+        }
+        components
+            .join("^")
+            .chars()
+            .take(MAX_GROUP_LENGTH)
+            .collect()
+    }
+
+    pub fn with_case(&self, case: NameCase) -> PersonNameGroup {
+        PersonNameGroup {
+            family: apply_case(&self.family, case),
+            given: apply_case(&self.given, case),
+            middle: apply_case(&self.middle, case),
+            prefix: apply_case(&self.prefix, case),
+            suffix: apply_case(&self.suffix, case),
+        }
+    }
+}
+
+impl PersonName {
+    /// Builds the name from GDT fields:
+    /// 3101 -> family, 3102 -> given (+ middle), 3104 -> prefix, 3100 -> suffix.
+    /// Spaces inside the family name are kept, so "van der Berg" stays one component.
+    pub fn from_gdt_patient(patient: &GdtPatientObject) -> PersonName {
+        let mut first_names = patient.patient_first_name.split_whitespace();
+        let given = first_names.next().unwrap_or("").to_string();
+        let middle = first_names.collect::<Vec<_>>().join(" ");
+        PersonName {
+            alphabetic: PersonNameGroup {
+                family: patient.patient_name.trim().to_string(),
+                given,
+                middle,
+                prefix: patient.patient_title.trim().to_string(),
+                suffix: patient.name_affix.trim().to_string(),
+            },
+            ideographic: None,
+            phonetic: None,
+        }
+    }
+
+    /// Parses a name written as free text, like GDT 0211 "Dr. med. Hans Müller"
+    /// or "Müller, Hans": words ending with a dot are the prefix, the last
+    /// word (or the part before a comma) is the family name.
+    pub fn parse_free_text(value: &str) -> PersonName {
+        let (family, rest) = match value.split_once(',') {
+            Some((family, rest)) => (family.trim().to_string(), rest.to_string()),
+            None => {
+                let mut words = value.split_whitespace().collect::<Vec<_>>();
+                let family = if words.iter().filter(|w| !w.ends_with('.')).count() > 0 {
+                    words.pop().unwrap_or("").to_string()
+                } else {
+                    String::new()
+                };
+                (family, words.join(" "))
+            }
+        };
+        let (prefix, given): (Vec<&str>, Vec<&str>) =
+            rest.split_whitespace().partition(|w| w.ends_with('.'));
+        let mut given = given.into_iter();
+        PersonName {
+            alphabetic: PersonNameGroup {
+                family,
+                given: given.next().unwrap_or("").to_string(),
+                middle: given.collect::<Vec<_>>().join(" "),
+                prefix: prefix.join(" "),
+                suffix: String::new(),
+            },
+            ideographic: None,
+            phonetic: None,
+        }
+    }
+
+    /// "prefix given middle family suffix" of the alphabetic group
+    pub fn to_free_text(&self) -> String {
+        let group = &self.alphabetic;
+        [
+            &group.prefix,
+            &group.given,
+            &group.middle,
+            &group.family,
+            &group.suffix,
+        ]
+        .iter()
+        .filter(|s| !s.is_empty())
+        .map(|s| s.as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+    }
+
+    pub fn parse_dcm(value: &str) -> PersonName {
+        let mut groups = value.split('=');
+        let alphabetic = PersonNameGroup::parse(groups.next().unwrap_or(""));
+        let ideographic = groups
+            .next()
+            .map(PersonNameGroup::parse)
+            .filter(|g| !g.is_empty());
+        let phonetic = groups
+            .next()
+            .map(PersonNameGroup::parse)
+            .filter(|g| !g.is_empty());
+        PersonName {
+            alphabetic,
+            ideographic,
+            phonetic,
+        }
+    }
+
+    pub fn to_dcm_string(&self, case: NameCase) -> String {
+        let mut groups = vec![self.alphabetic.to_dcm_string(case)];
+        groups.push(
+            self.ideographic
+                .as_ref()
+                .map(|g| g.to_dcm_string(case))
+                .unwrap_or_default(),
+        );
+        groups.push(
+            self.phonetic
+                .as_ref()
+                .map(|g| g.to_dcm_string(case))
+                .unwrap_or_default(),
+        );
+        while groups.len() > 1 && groups.last().map(|g| g.is_empty()).unwrap_or(false) {
+            groups.pop();
+        }
+        groups.join("=")
+    }
+
+    /// Writes the alphabetic group into 3101, 3102, 3104 and 3100.
+    /// Ideographic and phonetic groups have no GDT counterpart and are dropped.
+    pub fn to_gdt_patient(&self, patient: &mut GdtPatientObject, case: NameCase) {
+        let group = self.alphabetic.with_case(case);
+        patient.patient_first_name = group.given_names();
+        patient.patient_name = group.family;
+        patient.patient_title = group.prefix;
+        patient.name_affix = group.suffix;
+    }
+}
+
+fn sanitize_component(component: &str) -> String {
+    // Delimiters of the PN and multi-value syntax are not allowed inside a component
+    component
+        .chars()
+        .filter(|c| !matches!(c, '^' | '=' | '\\'))
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn apply_case(value: &str, case: NameCase) -> String {
+    match case {
+        NameCase::Preserve => value.to_string(),
+        NameCase::Upper => value.to_uppercase(),
+        NameCase::Capitalize => {
+            let words = value.split(' ').collect::<Vec<_>>();
+            words
+                .iter()
+                .enumerate()
+                .map(|(index, word)| {
+                    let lower = word.to_lowercase();
+                    if index + 1 < words.len() && NAME_PARTICLES.contains(&lower.as_str()) {
+                        lower
+                    } else {
+                        capitalize_word(word)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+    }
+}
+
+fn capitalize_word(word: &str) -> String {
+    let mut result = String::with_capacity(word.len());
+    let mut start_of_word = true;
+    for c in word.chars() {
+        if start_of_word {
+            result.extend(c.to_uppercase());
+        } else {
+            result.extend(c.to_lowercase());
+        }
+        start_of_word = matches!(c, '-' | '\'');
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patient(name: &str, first_name: &str, title: &str, affix: &str) -> GdtPatientObject {
+        GdtPatientObject {
+            patient_name: name.to_string(),
+            patient_first_name: first_name.to_string(),
+            patient_title: title.to_string(),
+            name_affix: affix.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_dcm_reads_components_and_groups() {
+        let name = PersonName::parse_dcm("Yamada^Tarou=山田^太郎=やまだ^たろう");
+        assert_eq!(name.alphabetic.family, "Yamada");
+        assert_eq!(name.alphabetic.given, "Tarou");
+        assert_eq!(name.ideographic.unwrap().family, "山田");
+        assert_eq!(name.phonetic.unwrap().given, "たろう");
+
+        let name = PersonName::parse_dcm("Müller^Hans^Peter^Dr. med.^jun.");
+        assert_eq!(
+            name.alphabetic,
+            PersonNameGroup {
+                family: "Müller".to_string(),
+                given: "Hans".to_string(),
+                middle: "Peter".to_string(),
+                prefix: "Dr. med.".to_string(),
+                suffix: "jun.".to_string(),
+            }
+        );
+        assert_eq!(name.alphabetic.given_names(), "Hans Peter");
+        assert_eq!(name.ideographic, None);
+    }
+
+    #[test]
+    fn parse_dcm_skips_empty_groups() {
+        let name = PersonName::parse_dcm("Müller^Hans==");
+        assert_eq!(name.ideographic, None);
+        assert_eq!(name.phonetic, None);
+
+        let name = PersonName::parse_dcm("=山田^太郎");
+        assert!(name.alphabetic.is_empty());
+        assert_eq!(name.ideographic.unwrap().given, "太郎");
+
+        assert!(PersonName::parse_dcm("").alphabetic.is_empty());
+    }
+
+    #[test]
+    fn from_gdt_patient_maps_the_gdt_fields() {
+        let name = PersonName::from_gdt_patient(&patient(
+            " van der Berg ",
+            "Jan  Willem Pieter",
+            "Dr.",
+            "jun.",
+        ));
+        assert_eq!(name.alphabetic.family, "van der Berg");
+        assert_eq!(name.alphabetic.given, "Jan");
+        assert_eq!(name.alphabetic.middle, "Willem Pieter");
+        assert_eq!(name.alphabetic.prefix, "Dr.");
+        assert_eq!(name.alphabetic.suffix, "jun.");
+        assert_eq!(
+            name.to_dcm_string(NameCase::Preserve),
+            "van der Berg^Jan^Willem Pieter^Dr.^jun."
+        );
+
+        let name = PersonName::from_gdt_patient(&patient("Müller", "Hans", "", ""));
+        assert_eq!(name.to_dcm_string(NameCase::Upper), "MÜLLER^HANS");
+    }
+
+    #[test]
+    fn to_dcm_string_removes_delimiters_and_empty_groups() {
+        let name = PersonName::from_gdt_patient(&patient("Mül^ler=", "Hans\\Peter", "", ""));
+        assert_eq!(name.to_dcm_string(NameCase::Preserve), "Müller^HansPeter");
+        let name = PersonName::parse_dcm("Yamada^Tarou==やまだ^たろう");
+        assert_eq!(
+            name.to_dcm_string(NameCase::Preserve),
+            "Yamada^Tarou==やまだ^たろう"
+        );
+    }
+
+    #[test]
+    fn to_dcm_string_truncates_each_group_to_64_characters() {
+        let family = "Ä".repeat(70);
+        let name = PersonName {
+            alphabetic: PersonNameGroup {
+                family: family.clone(),
+                given: "Hans".to_string(),
+                ..Default::default()
+            },
+            ideographic: Some(PersonNameGroup {
+                family: "山".repeat(40),
+                given: "太".repeat(40),
+                ..Default::default()
+            }),
+            phonetic: None,
+        };
+        let value = name.to_dcm_string(NameCase::Preserve);
+        let groups = value.split('=').collect::<Vec<_>>();
+        assert_eq!(groups[0], "Ä".repeat(64));
+        assert_eq!(groups[1].chars().count(), 64);
+        assert_eq!(
+            groups[1],
+            format!("{}^{}", "山".repeat(40), "太".repeat(23))
+        );
+
+        let name = PersonName::from_gdt_patient(&patient(&"x".repeat(60), "Hansjörg", "", ""));
+        let value = name.to_dcm_string(NameCase::Upper);
+        assert_eq!(value, format!("{}^HAN", "X".repeat(60)));
+    }
+
+    #[test]
+    fn capitalize_keeps_particles_lower_case() {
+        let capitalize = |v: &str| apply_case(v, NameCase::Capitalize);
+        assert_eq!(capitalize("MÜLLER-LÜDENSCHEIDT"), "Müller-Lüdenscheidt");
+        assert_eq!(capitalize("o'brien"), "O'Brien");
+        assert_eq!(capitalize("VAN DER BERG"), "van der Berg");
+        assert_eq!(capitalize("von weizsäcker"), "von Weizsäcker");
+        assert_eq!(capitalize("DE LA CRUZ"), "de la Cruz");
+        // A particle on its own is a name
+        assert_eq!(capitalize("DE"), "De");
+        assert_eq!(capitalize("HANS PETER"), "Hans Peter");
+    }
+}
@@ -0,0 +1,301 @@
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+
+use shared_child::SharedChild;
+
+use crate::command::{binary_to_path, new_command, ChildOutput};
+use crate::dcm_xml::{
+    export_images_from_dcm_with_template, parse_dcm_as_xml, DCMImageFormat, RequestTags,
+};
+use crate::error::G2DError;
+use crate::filename_template::FilenameTemplate;
+use crate::hooks::{run_hooks, Hook, HookContext, HookEvent};
+use crate::thumbnails::generate_previews;
+use crate::worklist_lifecycle::{complete_worklist_entries, WorklistLifecycle};
+
+/// wlmscpfs answering worklist queries from the AETitle folders of `worklist_dir`
+pub fn wlmscpfs_command(worklist_dir: &Path, port: u16) -> Command {
+    let full_path = binary_to_path("wlmscpfs".to_string());
+    let mut command = new_command(full_path);
+    command.args(vec![
+        "-v",
+        "-d",
+        "-dfr",
+        "-dfp",
+        worklist_dir.to_str().unwrap(),
+        &format!("{}", port),
+    ]);
+    return command;
+}
+
+/// storescp writing the received files into `dir`
+pub fn storescp_command(dir: &Path, port: u16) -> Command {
+    let full_path = binary_to_path("storescp".to_string());
+    let mut command = new_command(full_path);
+    command.args(vec![
+        "-v",
+        "-pm",
+        "+xy",
+        "-od",
+        dir.to_str().unwrap(),
+        &format!("{}", port),
+    ]);
+    return command;
+}
+
+/// Starts a server and sends its output and exit to `sender`, every line of
+/// stderr is passed to `on_stderr_line` before it is logged
+pub fn spawn_server<F>(
+    command: &mut Command,
+    name: &str,
+    sender: mpsc::Sender<ChildOutput>,
+    mut on_stderr_line: F,
+) -> Result<Arc<SharedChild>, std::io::Error>
+where
+    F: FnMut(&str) + Send + 'static,
+{
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    _ = sender.send(ChildOutput::Log(format!("Running command: {:?}", command)));
+    let child = Arc::new(SharedChild::spawn(command)?);
+    let stdout = child.take_stdout().expect("stdout");
+    let stderr = child.take_stderr().expect("stderr");
+
+    let err_reader = std::io::BufReader::new(stderr);
+    let err_sender = sender.clone();
+    let err_name = name.to_string();
+    std::thread::spawn(move || {
+        for line in err_reader.lines() {
+            if let Ok(msg) = line {
+                on_stderr_line(&msg);
+                _ = err_sender.send(ChildOutput::Log(format!("{}: {}", err_name, msg)));
+            }
+        }
+    });
+
+    let out_reader = std::io::BufReader::new(stdout);
+    let out_sender = sender.clone();
+    let out_name = name.to_string();
+    std::thread::spawn(move || {
+        for line in out_reader.lines() {
+            if let Ok(msg) = line {
+                _ = out_sender.send(ChildOutput::Log(format!("{}: {}", out_name, msg)));
+            }
+        }
+    });
+
+    let child1 = child.clone();
+    let exit_name = name.to_string();
+    std::thread::spawn(move || {
+        let exit_result = child1.wait().expect("wait");
+        _ = sender.send(ChildOutput::Log(format!(
+            "{} exited {:?}",
+            exit_name, exit_result
+        )));
+        _ = sender.send(ChildOutput::Exit(exit_result));
+    });
+    return Ok(child);
+}
+
+/// Handles the files storescp reports as stored: completes their worklist
+/// entries, exports JPEGs, runs the hooks and writes the previews of an
+/// association once it is released
+pub struct ReceivedFiles {
+    pub jpeg_dir: Option<PathBuf>,
+    pub jpeg_filename_template: FilenameTemplate,
+    pub preview_dir: Option<PathBuf>,
+    worklist_dir_arc: Arc<Mutex<Option<PathBuf>>>,
+    worklist_lifecycle_arc: Arc<Mutex<WorklistLifecycle>>,
+    hooks_arc: Arc<Mutex<Vec<Hook>>>,
+    /// Request tags of the auto-convert folders
+    request_tags_arc: Arc<Mutex<Vec<RequestTags>>>,
+    sender: mpsc::Sender<ChildOutput>,
+    hook_log_sender: mpsc::Sender<String>,
+    /// Files of the current association
+    received_files: Vec<PathBuf>,
+}
+
+impl ReceivedFiles {
+    pub fn new(
+        worklist_dir_arc: Arc<Mutex<Option<PathBuf>>>,
+        worklist_lifecycle_arc: Arc<Mutex<WorklistLifecycle>>,
+        hooks_arc: Arc<Mutex<Vec<Hook>>>,
+        request_tags_arc: Arc<Mutex<Vec<RequestTags>>>,
+        sender: mpsc::Sender<ChildOutput>,
+    ) -> ReceivedFiles {
+        let (hook_log_sender, hook_log_receiver) = mpsc::channel::<String>();
+        let hook_sender = sender.clone();
+        std::thread::spawn(move || {
+            while let Ok(msg) = hook_log_receiver.recv() {
+                _ = hook_sender.send(ChildOutput::Log(msg));
+            }
+        });
+        return ReceivedFiles {
+            jpeg_dir: None,
+            jpeg_filename_template: FilenameTemplate::image(),
+            preview_dir: None,
+            worklist_dir_arc,
+            worklist_lifecycle_arc,
+            hooks_arc,
+            request_tags_arc,
+            sender,
+            hook_log_sender,
+            received_files: vec![],
+        };
+    }
+
+    /// Handles a line of storescp's stderr
+    pub fn handle_line(&mut self, msg: &str) {
+        if msg.starts_with("I: Association Release") || msg.starts_with("I: Association Aborted") {
+            let files = std::mem::take(&mut self.received_files);
+            if let Some(ref p) = self.preview_dir {
+                if !files.is_empty() {
+                    let preview_dir = p.clone();
+                    let sender = self.sender.clone();
+                    // Like the hooks, so the next association is not delayed
+                    std::thread::spawn(move || {
+                        preview_received_files(&files, &preview_dir, &sender);
+                    });
+                }
+            }
+        }
+        let Some(saved_dicom_file) = msg.strip_prefix("I: storing DICOM file: ") else {
+            return;
+        };
+        _ = self.sender.send(ChildOutput::Log(format!(
+            "Detected new DICOM file: {:?}",
+            &saved_dicom_file
+        )));
+        let dcm_path = PathBuf::from(saved_dicom_file);
+        self.received_files.push(dcm_path.clone());
+        let request_tags = self.request_tags_arc.lock().unwrap().clone();
+        complete_worklist(
+            &dcm_path,
+            &self.worklist_dir_arc,
+            &self.worklist_lifecycle_arc,
+            &request_tags,
+            &self.sender,
+        );
+        let extract_result = self.jpeg_dir.as_ref().map(|j| {
+            export_images_from_dcm_with_template(
+                &dcm_path,
+                j,
+                &self.jpeg_filename_template,
+                DCMImageFormat::Jpeg,
+                &request_tags,
+                Some(&self.sender),
+            )
+        });
+        if let Some(Err(err)) = &extract_result {
+            _ = self.sender.send(ChildOutput::Log(format!(
+                "Cannot extract jpeg from {}: {:?}",
+                &saved_dicom_file, err
+            )));
+        }
+        let hooks_arc = self.hooks_arc.clone();
+        let hook_log_sender = self.hook_log_sender.clone();
+        // Hooks may take a while, storescp's output is read on
+        std::thread::spawn(move || {
+            run_received_hooks(&dcm_path, extract_result, &hooks_arc, &hook_log_sender);
+        });
+    }
+}
+
+fn preview_received_files(
+    files: &[PathBuf],
+    preview_dir: &PathBuf,
+    sender: &mpsc::Sender<ChildOutput>,
+) {
+    match generate_previews(files, preview_dir, Some(sender)) {
+        Ok(previews) => {
+            for preview in previews {
+                _ = sender.send(ChildOutput::Preview {
+                    contact_sheet: preview.contact_sheet,
+                    html: preview.html,
+                });
+            }
+        }
+        Err(err) => {
+            _ = sender.send(ChildOutput::Log(format!(
+                "Cannot create the preview: {}",
+                err
+            )));
+        }
+    }
+}
+
+/// Runs the hooks for a received file and for the images exported from it
+fn run_received_hooks(
+    dcm_path: &PathBuf,
+    extract_result: Option<Result<Vec<PathBuf>, G2DError>>,
+    hooks_arc: &Arc<Mutex<Vec<Hook>>>,
+    log_sender: &mpsc::Sender<String>,
+) {
+    let hooks = hooks_arc.lock().unwrap().clone();
+    if hooks.is_empty() {
+        return;
+    }
+    let context = match parse_dcm_as_xml(dcm_path) {
+        Ok(events) => HookContext::from_dcm_xml(HookEvent::DicomReceived, &events),
+        Err(_) => HookContext::new(HookEvent::DicomReceived),
+    };
+    let received_context = HookContext {
+        files: vec![dcm_path.clone()],
+        ..context.clone()
+    };
+    run_hooks(&hooks, &received_context, Some(log_sender));
+    let exported_context = match extract_result {
+        None => return,
+        Some(Ok(paths)) => HookContext {
+            event: HookEvent::ImagesExported,
+            files: paths,
+            ..context
+        },
+        Some(Err(err)) => HookContext {
+            event: HookEvent::ImagesExported,
+            files: vec![dcm_path.clone()],
+            success: false,
+            message: err.to_string(),
+            ..context
+        },
+    };
+    run_hooks(&hooks, &exported_context, Some(log_sender));
+}
+
+/// Removes the worklist entries of a received file if the lifecycle says so
+fn complete_worklist(
+    dcm_path: &PathBuf,
+    worklist_dir_arc: &Arc<Mutex<Option<PathBuf>>>,
+    worklist_lifecycle_arc: &Arc<Mutex<WorklistLifecycle>>,
+    request_tags: &[RequestTags],
+    sender: &mpsc::Sender<ChildOutput>,
+) {
+    let lifecycle = worklist_lifecycle_arc.lock().unwrap().clone();
+    let Some(worklist_dir) = worklist_dir_arc.lock().unwrap().clone() else {
+        return;
+    };
+    if !lifecycle.complete_on_images {
+        return;
+    }
+    let result = parse_dcm_as_xml(dcm_path).and_then(|events| {
+        complete_worklist_entries(&worklist_dir, &events, &lifecycle, request_tags, None)
+    });
+    match result {
+        Ok(entries) => {
+            for entry in entries {
+                _ = sender.send(ChildOutput::Log(format!(
+                    "Worklist entry {} completed",
+                    entry.path.display()
+                )));
+            }
+        }
+        Err(err) => {
+            _ = sender.send(ChildOutput::Log(format!(
+                "Cannot complete the worklist entries of {}: {}",
+                dcm_path.display(),
+                err
+            )));
+        }
+    }
+}
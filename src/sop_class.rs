@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+
+use crate::dcm_xml::{DcmCodeSequence, DcmElement};
+
+/// Target IOD for images converted by gdt2dicom
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImageSopClass {
+    /// Multi-frame Secondary Capture (grayscale or true color)
+    #[default]
+    SecondaryCapture,
+    /// VL Photographic Image
+    VlPhotographic,
+    /// Ophthalmic Photography 8 Bit Image
+    OphthalmicPhotography,
+    /// VL Endoscopic Image
+    VlEndoscopic,
+    /// Digital Intra-Oral X-Ray Image, for presentation
+    DentalIntraoral,
+}
+
+impl ImageSopClass {
+    pub fn all() -> Vec<ImageSopClass> {
+        vec![
+            ImageSopClass::SecondaryCapture,
+            ImageSopClass::VlPhotographic,
+            ImageSopClass::OphthalmicPhotography,
+            ImageSopClass::VlEndoscopic,
+            ImageSopClass::DentalIntraoral,
+        ]
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ImageSopClass::SecondaryCapture => "Secondary Capture",
+            ImageSopClass::VlPhotographic => "VL Photographic",
+            ImageSopClass::OphthalmicPhotography => "Ophthalmic Photography 8 Bit",
+            ImageSopClass::VlEndoscopic => "VL Endoscopic",
+            ImageSopClass::DentalIntraoral => "Dental Intra-Oral X-Ray",
+        }
+    }
+
+    pub fn uid(&self, is_color: bool) -> &'static str {
+        match self {
+            ImageSopClass::SecondaryCapture if is_color => "1.2.840.10008.5.1.4.1.1.7.4",
+            ImageSopClass::SecondaryCapture => "1.2.840.10008.5.1.4.1.1.7.2",
+            ImageSopClass::VlPhotographic => "1.2.840.10008.5.1.4.1.1.77.1.4",
+            ImageSopClass::OphthalmicPhotography => "1.2.840.10008.5.1.4.1.1.77.1.5.1",
+            ImageSopClass::VlEndoscopic => "1.2.840.10008.5.1.4.1.1.77.1.1",
+            ImageSopClass::DentalIntraoral => "1.2.840.10008.5.1.4.1.1.1.3",
+        }
+    }
+
+    pub fn modality(&self) -> &'static str {
+        match self {
+            ImageSopClass::SecondaryCapture => "OT",
+            ImageSopClass::VlPhotographic => "XC",
+            ImageSopClass::OphthalmicPhotography => "OP",
+            ImageSopClass::VlEndoscopic => "ES",
+            ImageSopClass::DentalIntraoral => "IO",
+        }
+    }
+
+    /// Output plugin of img2dcm. img2dcm has no plugin for VL Endoscopic and
+    /// Intra-Oral, those are written with a related plugin and the SOP class
+    /// is changed afterwards, see `needs_sop_class_override`.
+    pub fn img2dcm_args(&self) -> Vec<&'static str> {
+        match self {
+            ImageSopClass::SecondaryCapture => vec!["-nsc"],
+            ImageSopClass::VlPhotographic => vec!["-vlp"],
+            ImageSopClass::OphthalmicPhotography => vec!["-oph"],
+            ImageSopClass::VlEndoscopic => vec!["-vlp"],
+            ImageSopClass::DentalIntraoral => vec!["-sc"],
+        }
+    }
+
+    pub fn needs_sop_class_override(&self) -> bool {
+        matches!(
+            self,
+            ImageSopClass::VlEndoscopic | ImageSopClass::DentalIntraoral
+        )
+    }
+
+    /// Only the multi-frame Secondary Capture IOD can hold several images in one instance
+    pub fn is_multi_frame(&self) -> bool {
+        *self == ImageSopClass::SecondaryCapture
+    }
+
+    /// The Intra-Oral X-Ray IOD only allows MONOCHROME2 images
+    pub fn is_grayscale(&self) -> bool {
+        *self == ImageSopClass::DentalIntraoral
+    }
+
+    /// Mandatory attributes of the IOD which img2dcm doesn't fill in
+    pub fn template_elements(&self) -> Vec<DcmElement> {
+        let mut elements = vec![DcmElement::new(
+            "0008,0060",
+            "CS",
+            "Modality",
+            self.modality(),
+        )];
+        match self {
+            ImageSopClass::SecondaryCapture => {
+                elements.push(DcmElement::new("0008,0064", "CS", "ConversionType", "WSD"));
+            }
+            ImageSopClass::VlPhotographic
+            | ImageSopClass::OphthalmicPhotography
+            | ImageSopClass::VlEndoscopic => {
+                elements.push(DcmElement::new(
+                    "0008,0008",
+                    "CS",
+                    "ImageType",
+                    "ORIGINAL\\PRIMARY",
+                ));
+                elements.push(DcmElement::new(
+                    "0028,0301",
+                    "CS",
+                    "BurnedInAnnotation",
+                    "NO",
+                ));
+            }
+            ImageSopClass::DentalIntraoral => {
+                elements.extend(vec![
+                    DcmElement::new("0008,0008", "CS", "ImageType", "ORIGINAL\\PRIMARY"),
+                    DcmElement::new(
+                        "0008,0068",
+                        "CS",
+                        "PresentationIntentType",
+                        "FOR PRESENTATION",
+                    ),
+                    DcmElement::new("0018,1508", "CS", "PositionerType", "NONE"),
+                    DcmElement::new("0018,7004", "CS", "DetectorType", "DIRECT"),
+                    // The Intra-oral Image module only allows R, L and B
+                    DcmElement::new("0020,0062", "CS", "ImageLaterality", "B"),
+                    DcmElement::new("0028,0301", "CS", "BurnedInAnnotation", "NO"),
+                    DcmElement::new("0028,1040", "CS", "PixelIntensityRelationship", "LOG"),
+                    DcmElement::new("0028,1041", "SS", "PixelIntensityRelationshipSign", "1"),
+                    DcmElement::new("0028,1052", "DS", "RescaleIntercept", "0"),
+                    DcmElement::new("0028,1053", "DS", "RescaleSlope", "1"),
+                    DcmElement::new("0028,1054", "LO", "RescaleType", "US"),
+                    // The images are converted to 8 bit
+                    DcmElement::new("0028,1050", "DS", "WindowCenter", "128"),
+                    DcmElement::new("0028,1051", "DS", "WindowWidth", "256"),
+                    DcmElement::new("2050,0020", "CS", "PresentationLUTShape", "IDENTITY"),
+                ]);
+            }
+        }
+        return elements;
+    }
+
+    /// Mandatory code sequences of the IOD, added like `template_elements`
+    pub fn template_code_sequences(&self) -> Vec<DcmCodeSequence> {
+        match self {
+            // The region isn't known from the GDT file, a jaw region fits
+            // every intra-oral image
+            ImageSopClass::DentalIntraoral => vec![DcmCodeSequence::new(
+                "0008,2218",
+                "AnatomicRegionSequence",
+                ("T-D1213", "SRT", "Jaw region"),
+            )],
+            _ => vec![],
+        }
+    }
+}
@@ -1,7 +1,12 @@
 use std::default::Default;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+use crate::dcm_xml::RequestTags;
+use crate::error::G2DError;
+use crate::filename_template::FilenameTemplate;
+use crate::hooks::Hook;
 use crate::worklist_conversion::WorklistConversionState;
+use crate::worklist_lifecycle::WorklistLifecycle;
 
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -15,6 +20,10 @@ pub struct StateFile {
     // Option for backward compatibility
     pub dicom_server: Option<DicomServerState>,
     pub cstore_server: Option<CStoreServerState>,
+    #[serde(default)]
+    pub worklist_lifecycle: WorklistLifecycle,
+    #[serde(default)]
+    pub hooks: Vec<Hook>,
 }
 
 impl Default for StateFile {
@@ -24,6 +33,8 @@ impl Default for StateFile {
             conversions: Vec::new(),
             dicom_server: Some(DicomServerState::default()),
             cstore_server: Some(CStoreServerState::default()),
+            worklist_lifecycle: WorklistLifecycle::default(),
+            hooks: Vec::new(),
         }
     }
 }
@@ -44,6 +55,10 @@ pub struct CStoreServerState {
     pub path: Option<PathBuf>,
     pub port: Option<u16>,
     pub jpeg_output_path: Option<PathBuf>,
+    #[serde(default = "FilenameTemplate::image")]
+    pub jpeg_filename_template: FilenameTemplate,
+    #[serde(default)]
+    pub preview_output_path: Option<PathBuf>,
 }
 
 impl Default for CStoreServerState {
@@ -52,21 +67,42 @@ impl Default for CStoreServerState {
             path: None,
             port: None,
             jpeg_output_path: None,
+            jpeg_filename_template: FilenameTemplate::image(),
+            preview_output_path: None,
         }
     }
 }
 
-pub fn write_state_to_file(state: &StateFile) -> Result<(), std::io::Error> {
-    let state_string = json!(state).to_string();
+/// The distinct request tags of the conversions, received images are
+/// matched to worklist entries with them
+pub fn conversions_request_tags(conversions: &WorklistConversionsState) -> Vec<RequestTags> {
+    let mut result: Vec<RequestTags> = vec![];
+    for conversion in conversions {
+        if !result.contains(&conversion.request_tags) {
+            result.push(conversion.request_tags);
+        }
+    }
+    if result.is_empty() {
+        result.push(RequestTags::default());
+    }
+    return result;
+}
+
+/// `state.json` next to the executable, shared by the GUI and gdt2dicomd
+pub fn state_file_path() -> Result<PathBuf, std::io::Error> {
     let mut current_path = std::env::current_exe()?;
     current_path.set_file_name("state.json");
-    std::fs::write(current_path, state_string)?;
+    return Ok(current_path);
+}
+
+pub fn write_state_to_file(state: &StateFile) -> Result<(), std::io::Error> {
+    let state_string = json!(state).to_string();
+    std::fs::write(state_file_path()?, state_string)?;
     Ok(())
 }
 
 pub fn read_saved_states() -> Result<StateFile, std::io::Error> {
-    let mut current_path = std::env::current_exe()?;
-    current_path.set_file_name("state.json");
+    let current_path = state_file_path()?;
     if !current_path.is_file() {
         return Ok(StateFile::default());
     }
@@ -79,3 +115,9 @@ pub fn read_saved_states() -> Result<StateFile, std::io::Error> {
         }),
     )
 }
+
+/// Reads a state file, unlike `read_saved_states` an invalid file is an error
+pub fn read_state_file(path: &Path) -> Result<StateFile, G2DError> {
+    let data = std::fs::read(path)?;
+    return Ok(serde_json::from_slice::<StateFile>(&data)?);
+}
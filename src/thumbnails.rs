@@ -0,0 +1,292 @@
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+use image::imageops::{overlay, FilterType};
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use serde::{Deserialize, Serialize};
+use xml::escape::{escape_str_attribute, escape_str_pcdata};
+
+use crate::command::{log, ChildOutput};
+use crate::dcm_pixel_data::read_frames;
+use crate::dcm_study::{group_by_study, DcmInstance, DcmStudy};
+use crate::dcm_xml::{
+    parse_dcm_as_xml_with_pixel_data, xml_get_modality, xml_get_patient_name,
+    xml_get_patient_patient_id, xml_get_series_description, xml_get_sop_instance_uid,
+    xml_get_study_date, xml_get_study_description,
+};
+use crate::error::G2DError;
+use crate::filename_template::sanitize_filename;
+use crate::person_name::PersonName;
+
+/// Width and height a thumbnail fits into
+pub const THUMBNAIL_SIZE: u32 = 160;
+pub const CONTACT_SHEET_FILE_NAME: &str = "contact_sheet.png";
+pub const PREVIEW_HTML_FILE_NAME: &str = "index.html";
+const MANIFEST_FILE_NAME: &str = "preview.json";
+const CONTACT_SHEET_COLUMNS: u32 = 4;
+const CONTACT_SHEET_SPACING: u32 = 8;
+const CONTACT_SHEET_BACKGROUND: Rgb<u8> = Rgb([32, 32, 32]);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeriesPreview {
+    pub series_uid: String,
+    pub number: usize,
+    pub modality: String,
+    pub description: String,
+    /// SOPInstanceUIDs received so far
+    pub instance_uids: Vec<String>,
+    /// File name of the thumbnail in the preview folder, none for series
+    /// without images like structured reports
+    pub thumbnail: Option<String>,
+}
+
+/// What was received of a study, stored as `preview.json` in its preview
+/// folder, so studies sent in several associations are merged
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StudyPreview {
+    pub study_uid: String,
+    pub patient_id: String,
+    pub patient_name: String,
+    pub study_date: String,
+    pub study_description: String,
+    pub series: Vec<SeriesPreview>,
+}
+
+/// The files of a generated preview
+#[derive(Debug, Clone)]
+pub struct StudyPreviewFiles {
+    pub dir: PathBuf,
+    pub thumbnails: Vec<PathBuf>,
+    pub contact_sheet: Option<PathBuf>,
+    pub html: PathBuf,
+}
+
+/// `[output_dir]/[patient id]_[StudyInstanceUID]`
+pub fn study_preview_dir(output_dir: &Path, study: &DcmStudy) -> PathBuf {
+    let patient_id = study
+        .instances
+        .first()
+        .and_then(|i| xml_get_patient_patient_id(&i.events))
+        .unwrap_or_default();
+    return output_dir.join(sanitize_filename(&format!(
+        "{}_{}",
+        patient_id.trim(),
+        study.study_uid.trim()
+    )));
+}
+
+/// Writes a thumbnail per series, a contact sheet with all thumbnails and an
+/// HTML page with patient, study and series into the preview folder of the
+/// study. Series already in the folder are kept.
+pub fn generate_study_preview(
+    study: &DcmStudy,
+    output_dir: &Path,
+    log_sender: Option<&mpsc::Sender<ChildOutput>>,
+) -> Result<StudyPreviewFiles, G2DError> {
+    let dir = study_preview_dir(output_dir, study);
+    create_dir_all(&dir)?;
+    let manifest_path = dir.join(MANIFEST_FILE_NAME);
+    let mut preview = if manifest_path.is_file() {
+        serde_json::from_slice(&std::fs::read(&manifest_path)?)?
+    } else {
+        new_study_preview(study)
+    };
+
+    let mut series_uids: Vec<&String> = vec![];
+    for instance in &study.instances {
+        if !series_uids.contains(&&instance.series_uid) {
+            series_uids.push(&instance.series_uid);
+        }
+    }
+    for series_uid in series_uids {
+        let instances: Vec<&DcmInstance> = study
+            .instances
+            .iter()
+            .filter(|i| &i.series_uid == series_uid)
+            .collect();
+        let index = match preview
+            .series
+            .iter()
+            .position(|s| &s.series_uid == series_uid)
+        {
+            Some(index) => index,
+            None => {
+                preview.series.push(SeriesPreview {
+                    series_uid: series_uid.clone(),
+                    number: instances[0].series_number,
+                    modality: xml_get_modality(&instances[0].events).unwrap_or_default(),
+                    description: xml_get_series_description(&instances[0].events)
+                        .unwrap_or_default(),
+                    instance_uids: vec![],
+                    thumbnail: None,
+                });
+                preview.series.len() - 1
+            }
+        };
+        let series = &mut preview.series[index];
+        for instance in &instances {
+            let uid = xml_get_sop_instance_uid(&instance.events)
+                .unwrap_or(instance.path.display().to_string());
+            if !series.instance_uids.contains(&uid) {
+                series.instance_uids.push(uid);
+            }
+        }
+
+        let images: Vec<&&DcmInstance> = instances.iter().filter(|i| i.is_image()).collect();
+        if images.is_empty() {
+            continue;
+        }
+        // The middle image is more telling than the first one of a scan
+        let instance = images[images.len() / 2];
+        match thumbnail(&instance.path) {
+            Ok(thumbnail) => {
+                let name = sanitize_filename(&format!("series_{}.png", series_uid));
+                thumbnail.save_with_format(dir.join(&name), ImageFormat::Png)?;
+                series.thumbnail = Some(name);
+            }
+            Err(err) => log(
+                log_sender,
+                format!(
+                    "Cannot create a thumbnail of {}: {}",
+                    instance.path.display(),
+                    err
+                ),
+            ),
+        }
+    }
+    preview
+        .series
+        .sort_by(|a, b| (a.number, &a.series_uid).cmp(&(b.number, &b.series_uid)));
+    std::fs::write(&manifest_path, serde_json::to_vec_pretty(&preview)?)?;
+
+    let thumbnails: Vec<PathBuf> = preview
+        .series
+        .iter()
+        .filter_map(|s| s.thumbnail.as_ref().map(|t| dir.join(t)))
+        .collect();
+    let contact_sheet = if thumbnails.is_empty() {
+        None
+    } else {
+        let path = dir.join(CONTACT_SHEET_FILE_NAME);
+        contact_sheet(&thumbnails)?.save_with_format(&path, ImageFormat::Png)?;
+        Some(path)
+    };
+    let html = dir.join(PREVIEW_HTML_FILE_NAME);
+    std::fs::write(&html, preview_html(&preview))?;
+    log(
+        log_sender,
+        format!(
+            "Preview of study {} written to {}",
+            study.study_uid,
+            dir.display()
+        ),
+    );
+    return Ok(StudyPreviewFiles {
+        dir,
+        thumbnails,
+        contact_sheet,
+        html,
+    });
+}
+
+/// Groups the files by study and generates the preview of each study
+pub fn generate_previews(
+    files: &[PathBuf],
+    output_dir: &Path,
+    log_sender: Option<&mpsc::Sender<ChildOutput>>,
+) -> Result<Vec<StudyPreviewFiles>, G2DError> {
+    let studies = group_by_study(files, None)?;
+    let mut result = vec![];
+    for study in &studies {
+        result.push(generate_study_preview(study, output_dir, log_sender)?);
+    }
+    return Ok(result);
+}
+
+/// The middle frame of the file, scaled to fit into THUMBNAIL_SIZE
+pub fn thumbnail(dcm_path: &PathBuf) -> Result<DynamicImage, G2DError> {
+    let events = parse_dcm_as_xml_with_pixel_data(dcm_path)?;
+    let frames = read_frames(dcm_path, &events)?;
+    let frame = &frames[frames.len() / 2];
+    return Ok(frame.resize(THUMBNAIL_SIZE, THUMBNAIL_SIZE, FilterType::Triangle));
+}
+
+fn new_study_preview(study: &DcmStudy) -> StudyPreview {
+    let events = &study.instances[0].events;
+    let name = PersonName::parse_dcm(&xml_get_patient_name(events).unwrap_or_default());
+    return StudyPreview {
+        study_uid: study.study_uid.clone(),
+        patient_id: xml_get_patient_patient_id(events).unwrap_or_default(),
+        patient_name: name.to_free_text(),
+        study_date: xml_get_study_date(events).unwrap_or_default(),
+        study_description: xml_get_study_description(events).unwrap_or_default(),
+        series: vec![],
+    };
+}
+
+/// The thumbnails in rows of CONTACT_SHEET_COLUMNS, each centered in its cell
+fn contact_sheet(thumbnails: &[PathBuf]) -> Result<RgbImage, G2DError> {
+    let count = thumbnails.len() as u32;
+    let columns = count.min(CONTACT_SHEET_COLUMNS);
+    let rows = count.div_ceil(CONTACT_SHEET_COLUMNS);
+    let cell = THUMBNAIL_SIZE + CONTACT_SHEET_SPACING;
+    let mut sheet = RgbImage::from_pixel(
+        columns * cell + CONTACT_SHEET_SPACING,
+        rows * cell + CONTACT_SHEET_SPACING,
+        CONTACT_SHEET_BACKGROUND,
+    );
+    for (index, path) in thumbnails.iter().enumerate() {
+        let thumbnail = image::open(path)?.to_rgb8();
+        let (column, row) = (index as u32 % columns, index as u32 / columns);
+        let x = CONTACT_SHEET_SPACING + column * cell + (THUMBNAIL_SIZE - thumbnail.width()) / 2;
+        let y = CONTACT_SHEET_SPACING + row * cell + (THUMBNAIL_SIZE - thumbnail.height()) / 2;
+        overlay(&mut sheet, &thumbnail, x as i64, y as i64);
+    }
+    return Ok(sheet);
+}
+
+fn preview_html(preview: &StudyPreview) -> String {
+    let mut series = String::new();
+    for s in &preview.series {
+        let image = match &s.thumbnail {
+            Some(t) => format!("<img src=\"{}\" alt=\"\">", escape_str_attribute(t)),
+            None => "<div class=\"none\">No image</div>".to_string(),
+        };
+        series.push_str(&format!(
+            "<figure>{}<figcaption>Series {}: {} {}<br>{} instances</figcaption></figure>\n",
+            image,
+            s.number,
+            escape_str_pcdata(&s.modality),
+            escape_str_pcdata(&s.description),
+            s.instance_uids.len()
+        ));
+    }
+    return format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{patient_id} {study_date}</title>
+<style>
+body {{ font-family: sans-serif; background: #202020; color: #e0e0e0; }}
+figure {{ display: inline-block; margin: 8px; width: {size}px; vertical-align: top; }}
+img, .none {{ width: {size}px; height: {size}px; object-fit: contain; background: #000; }}
+.none {{ line-height: {size}px; text-align: center; }}
+figcaption {{ font-size: small; }}
+</style>
+</head>
+<body>
+<h1>{patient_name} ({patient_id})</h1>
+<p>{study_date} {study_description}</p>
+{series}</body>
+</html>
+"#,
+        patient_id = escape_str_pcdata(&preview.patient_id),
+        patient_name = escape_str_pcdata(&preview.patient_name),
+        study_date = escape_str_pcdata(&preview.study_date),
+        study_description = escape_str_pcdata(&preview.study_description),
+        size = THUMBNAIL_SIZE,
+        series = series,
+    );
+}
@@ -0,0 +1,121 @@
+use std::path::Path;
+
+use crate::dcm_xml::RequestTags;
+use crate::gdt::{parse_file, parse_file_lines, GdtFile};
+
+/// Record types of GDT 2.1 and 3.0
+const RECORD_TYPES: [u32; 5] = [6300, 6301, 6302, 6310, 6311];
+
+/// Problems found in a GDT file. Errors make a conversion fail or write a
+/// useless result, warnings are values which are dropped or left empty.
+#[derive(Debug, Default)]
+pub struct GdtValidation {
+    pub file: Option<GdtFile>,
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl GdtValidation {
+    pub fn is_valid(&self) -> bool {
+        return self.errors.is_empty();
+    }
+}
+
+/// Parses the GDT file and checks the values gdt2dicom maps to DICOM,
+/// `request_tags` are the attributes 8310 and 8314 are stored in
+pub fn validate_gdt_file(path: &Path, request_tags: &RequestTags) -> GdtValidation {
+    let mut validation = GdtValidation::default();
+    match parse_file_lines(path) {
+        Ok(lines) => {
+            for (index, line) in lines.enumerate() {
+                if let Err(err) = line {
+                    validation
+                        .errors
+                        .push(format!("Line {}: {}", index + 1, err));
+                }
+            }
+        }
+        Err(err) => {
+            validation.errors.push(err.to_string());
+            return validation;
+        }
+    }
+    let file = match parse_file(path) {
+        Ok(f) => f,
+        Err(err) => {
+            // Mostly a line which was already reported
+            if validation.errors.is_empty() {
+                validation.errors.push(err.to_string());
+            }
+            return validation;
+        }
+    };
+
+    if !RECORD_TYPES.contains(&file.record_type) {
+        validation
+            .warnings
+            .push(format!("Unknown record type (8000) {}", file.record_type));
+    }
+    if let Ok(metadata) = std::fs::metadata(path) {
+        if metadata.len() != file.record_length as u64 {
+            validation.warnings.push(format!(
+                "Record length (8100) is {}, but the file has {} bytes",
+                file.record_length,
+                metadata.len()
+            ));
+        }
+    }
+
+    let patient = &file.object_patient;
+    if patient.patient_number.trim().is_empty() {
+        validation
+            .errors
+            .push("Patient number (3000) is missing".to_string());
+    } else if patient.patient_number.chars().count() > 64 {
+        validation.warnings.push(format!(
+            "Patient number (3000) \"{}\" is longer than 64 characters",
+            patient.patient_number
+        ));
+    }
+    if patient.patient_name.trim().is_empty() {
+        validation
+            .warnings
+            .push("Patient name (3101) is missing".to_string());
+    }
+    let name_components: Vec<usize> = [
+        &patient.patient_name,
+        &patient.patient_first_name,
+        &patient.patient_title,
+        &patient.name_affix,
+    ]
+    .iter()
+    .map(|c| c.trim().chars().count())
+    .filter(|length| *length > 0)
+    .collect();
+    // The components are joined with ^
+    let name_length =
+        name_components.iter().sum::<usize>() + name_components.len().saturating_sub(1);
+    if name_length > 64 {
+        validation.warnings.push(
+            "Patient name (3101, 3102, 3104, 3100) is longer than 64 characters and is cut"
+                .to_string(),
+        );
+    }
+    let dob = &patient.patient_dob;
+    if dob.is_empty() {
+        validation
+            .warnings
+            .push("Birth date (3103) is missing".to_string());
+    } else if dob.len() != 8 || !dob.chars().all(|c| c.is_ascii_digit()) {
+        validation
+            .warnings
+            .push(format!("Birth date (3103) \"{}\" is not DDMMYYYY", dob));
+    }
+
+    if let Err(err) = request_tags.check(&file.object_request) {
+        validation.errors.push(err.to_string());
+    }
+
+    validation.file = Some(file);
+    return validation;
+}
@@ -4,13 +4,18 @@ use log::{debug, error, info};
 use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use tempfile::NamedTempFile;
 
 use crate::command::exec_command;
+use crate::error::{G2DError, VddsError};
 use crate::gdt::{GdtFile, GdtPatientGender};
 
 pub static PVS_NAME: &str = "gdt2dicom_PVS";
 
+/// How long to wait for the BVS to set READY=1, it may show a dialog first
+const REPLY_TIMEOUT: Duration = Duration::from_secs(300);
+
 pub struct VddsPatient {
     id: String,
     first_name: String,
@@ -104,7 +109,7 @@ impl VddsPatient {
         exe_path: P,
         bvs_name: String,
         keep_file: bool,
-    ) -> Result<Ini, std::io::Error>
+    ) -> Result<Ini, G2DError>
     where
         P: Into<PathBuf>,
     {
@@ -150,7 +155,7 @@ impl ImageInfoRequest {
         exe_path: P,
         bvs_name: String,
         keep_file: bool,
-    ) -> Result<ImageInfoResponse, std::io::Error>
+    ) -> Result<ImageInfoResponse, G2DError>
     where
         P: Into<PathBuf>,
     {
@@ -163,21 +168,20 @@ impl ImageInfoRequest {
             keep_file,
         )?;
 
-        let mmos_count_str = result
+        let mmos_count = result
             .section(Some("MMOS"))
             .and_then(|s| s.get("COUNT"))
-            .expect("MMOS COUNT in MMOINFEXPORT reply");
-        let mmos_count = mmos_count_str
-            .to_string()
-            .parse::<u32>()
-            .expect("MMOS COUNT integer");
+            .and_then(|c| c.trim().parse::<u32>().ok())
+            .ok_or(VddsError::InvalidReply("MMOS COUNT".to_string()))?;
 
         let mut infos: HashMap<String, ImageInfo> = HashMap::new();
         for i in 1..=mmos_count {
             let s = result
                 .section(Some(format!("MMO{}", i)))
-                .expect(&format!("MMO Section {}", i));
-            let date = s.get("DATE").expect("DATE");
+                .ok_or(VddsError::InvalidReply(format!("MMO{}", i)))?;
+            let date = s
+                .get("DATE")
+                .ok_or(VddsError::InvalidReply(format!("MMO{} DATE", i)))?;
             let time = s.get("TIME").unwrap_or("0000").replace(":", "");
             if let Some(mmoid) = s.get("MMOID") {
                 infos.insert(
@@ -224,7 +228,7 @@ impl ImagesRequest {
         &self,
         exe_path: P,
         keep_file: bool,
-    ) -> Result<ImagesResponse, std::io::Error>
+    ) -> Result<ImagesResponse, G2DError>
     where
         P: Into<PathBuf>,
     {
@@ -237,7 +241,9 @@ impl ImagesRequest {
             keep_file,
         )?;
 
-        let ids_section = result.section(Some("MMOIDS")).unwrap();
+        let ids_section = result
+            .section(Some("MMOIDS"))
+            .ok_or(VddsError::InvalidReply("MMOIDS".to_string()))?;
 
         let error_level = ids_section.get("ERRORLEVEL");
         let error_text = ids_section.get("ERRORTEXT");
@@ -245,7 +251,9 @@ impl ImagesRequest {
             error!("Error from BVS {:?} {:?}", error_level, error_text);
         }
 
-        let section = result.section(Some("MMOPATH")).expect("MMOPATH in reply");
+        let section = result
+            .section(Some("MMOPATH"))
+            .ok_or(VddsError::InvalidReply("MMOPATH".to_string()))?;
         let mut paths: HashMap<String, String> = HashMap::new();
         for (key, value) in section.iter() {
             paths.insert(key.to_string(), value.to_string());
@@ -260,7 +268,7 @@ pub fn send_and_wait<P>(
     section_name: Option<String>,
     allow_fail: bool,
     keep_file: bool,
-) -> Result<Ini, std::io::Error>
+) -> Result<Ini, G2DError>
 where
     P: Into<PathBuf>,
 {
@@ -275,8 +283,7 @@ where
     let exe_path_str = exe.to_string_lossy();
     info!("Sending ini to {:?}", &exe_path_str);
     exec_command(&exe_path_str, vec![ini_path.clone()], true, None)?;
-    let result = wait_for_ready(&ini_path, section_name, allow_fail, keep_file);
-    return Ok(result);
+    return wait_for_ready(&ini_path, section_name, allow_fail, keep_file);
 }
 
 fn wait_for_ready(
@@ -284,12 +291,15 @@ fn wait_for_ready(
     section_name: Option<String>,
     allow_fail: bool,
     keep_file: bool,
-) -> Ini {
+) -> Result<Ini, G2DError> {
     debug!("Waiting for response: {:?}", path);
+    let deadline = Instant::now() + REPLY_TIMEOUT;
     loop {
         {
-            let ini = load_ini(path).unwrap();
-            let section = ini.section(section_name.clone()).unwrap();
+            let ini = load_ini(path)?;
+            let section = ini
+                .section(section_name.clone())
+                .ok_or_else(|| VddsError::InvalidReply(section_name.clone().unwrap_or_default()))?;
             let ready = section.get("READY");
             if ready == Some("1") {
                 let error_level = section.get("ERRORLEVEL");
@@ -298,15 +308,121 @@ fn wait_for_ready(
                     if !keep_file {
                         _ = fs::remove_file(path);
                     }
-                    return ini;
+                    return Ok(ini);
                 }
                 let error_text = section.get("ERRORTEXT");
-                error!("Error from BVS: ({:?}): {:?}", error_level, error_text);
-                std::process::exit(100);
+                return Err(VddsError::BvsError(
+                    error_level.unwrap_or_default().to_string(),
+                    error_text.unwrap_or_default().to_string(),
+                )
+                .into());
             }
         }
-        std::thread::sleep(std::time::Duration::from_secs(1));
+        if Instant::now() >= deadline {
+            return Err(VddsError::Timeout(REPLY_TIMEOUT.as_secs()).into());
+        }
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// Adds gdt2dicom as PVS to VDDS_MMI unless it is already in it, the file
+/// is written if it was changed
+pub fn register_pvs(mmi: &mut Ini, vdds_mmi_path: &Path) -> Result<(), G2DError> {
+    let need_to_insert_section = if let Some(pvs_section) = mmi.section_mut(Some("PVS")) {
+        let pvs_count = pvs_section.len();
+        let is_inserted_to_vdds_mmi = pvs_section.iter().any(|(_key, value)| value == PVS_NAME);
+        debug!("is {} in VDDS_MMI? {}", PVS_NAME, is_inserted_to_vdds_mmi);
+        if !is_inserted_to_vdds_mmi {
+            let proposed_name = (1..=pvs_count + 1).find_map(|i| {
+                let name = format!("NAME{}", i);
+                if pvs_section.get(&name).is_none() {
+                    Some(name)
+                } else {
+                    None
+                }
+            });
+            debug!("Inserting {} to {:?}", PVS_NAME, proposed_name);
+            match proposed_name {
+                None => error!("Cannot insert {} into VDDS_MMI", PVS_NAME),
+                Some(name) => {
+                    pvs_section.append(name, PVS_NAME);
+                }
+            };
+            true
+        } else {
+            false
+        }
+    } else {
+        mmi.with_section(Some("PVS")).set("NAME1", PVS_NAME);
+        true
+    };
+    if need_to_insert_section {
+        let current_path = std::env::current_exe()?;
+        mmi.with_section(Some(PVS_NAME))
+            .set("MMOINFIMPORT", current_path.to_string_lossy())
+            .set("MMOINFIMPORT_OS", vdds_os())
+            .set("NAME", "gdt2dicom")
+            .set("STAGES", "1234")
+            .set("VERSION", "1.0");
+        info!("Updating VDDS_MMI");
+        mmi.write_to_file(vdds_mmi_path)?;
+    }
+    return Ok(());
+}
+
+/// The BVS to send to: the only one in VDDS_MMI, otherwise the preferred one
+pub fn select_bvs(mmi: &Ini, preferred_bvs: Option<&str>) -> Result<String, G2DError> {
+    let bvs = mmi
+        .section(Some("BVS"))
+        .ok_or(VddsError::MissingEntry("BVS section".to_string()))?;
+    let names: Vec<String> = bvs.iter().map(|(_key, name)| name.to_string()).collect();
+    if names.len() == 1 {
+        return Ok(names[0].clone());
+    }
+    if names.is_empty() {
+        return Err(VddsError::NoBvs.into());
     }
+    return match preferred_bvs {
+        Some(preferred_bvs) if names.iter().any(|name| name == preferred_bvs) => {
+            Ok(preferred_bvs.to_string())
+        }
+        Some(preferred_bvs) => Err(VddsError::BvsNotFound(preferred_bvs.to_string(), names).into()),
+        None => Err(VddsError::MultipleBvs(names).into()),
+    };
+}
+
+/// The program of the BVS for an interface, e.g. PATDATIMPORT
+pub fn bvs_exe(mmi: &Ini, bvs_name: &str, key: &str) -> Result<String, G2DError> {
+    return mmi
+        .section(Some(bvs_name))
+        .and_then(|s| s.get(key))
+        .map(|exe| exe.to_string())
+        .ok_or(VddsError::MissingEntry(format!("{} in BVS {}", key, bvs_name)).into());
+}
+
+/// Sends the patient of the GDT file to the BVS (PATDATIMPORT)
+pub fn import_patient(
+    mmi: &Ini,
+    bvs_name: &str,
+    gdt_file: &GdtFile,
+    keep_file: bool,
+) -> Result<Ini, G2DError> {
+    let patient_import_exe = bvs_exe(mmi, bvs_name, "PATDATIMPORT")?;
+    info!("Sending PATDATIMPORT");
+    return VddsPatient::new(gdt_file).send_vdds_file(
+        patient_import_exe,
+        bvs_name.to_string(),
+        keep_file,
+    );
+}
+
+/// The PATDATIMPORT request `import_patient` sends, without sending it
+pub fn patient_import_ini(gdt_file: &GdtFile, bvs_name: &str) -> Result<String, G2DError> {
+    let mut buffer: Vec<u8> = vec![];
+    VddsPatient::new(gdt_file)
+        .to_ini(bvs_name.to_string())
+        .write_to(&mut buffer)?;
+    return Ok(String::from_utf8_lossy(&buffer).to_string());
 }
 
 fn gdt_date_to_vdds(str: &String) -> String {
@@ -387,5 +503,6 @@ pub fn load_ini<P: AsRef<Path>>(path: P) -> Result<Ini, std::io::Error> {
         enabled_quote: true,
         enabled_escape: false,
     };
-    return Ok(Ini::load_from_str_opt(&file_str, opts).unwrap());
+    return Ini::load_from_str_opt(&file_str, opts)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()));
 }
@@ -1,27 +1,74 @@
-use chrono::prelude::*;
-use notify::{recommended_watcher, Event, EventHandler, RecursiveMode, Watcher};
+use chrono::Local;
+use notify::event::{EventKind, MetadataKind, ModifyKind};
+use notify::{
+    recommended_watcher, Config, Event, EventHandler, PollWatcher, RecursiveMode, Watcher,
+};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs::{create_dir, read_dir, rename, File};
 use std::io::{Error, ErrorKind};
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
-use std::sync::{mpsc, Arc, Mutex};
-use std::time::Duration;
+use std::sync::{mpsc, Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime};
 use tempfile::NamedTempFile;
 
 use crate::command::{exec_command, exec_command_with_env};
 use crate::dcm_worklist::dcm_to_worklist;
-use crate::dcm_xml::{default_dcm_xml, file_to_xml, DcmTransferType};
+use crate::dcm_xml::{
+    default_dcm_xml, file_to_xml_events, new_study_uid_arguments, set_element,
+    study_instance_uid_from_request, xml_events_to_file, DcmElement, DcmTransferType,
+    FileToXmlOptions, RequestTags,
+};
 use crate::error::G2DError;
+use crate::filename_template::{FilenameTemplate, FilenameValues};
 use crate::gdt::parse_file;
+use crate::hooks::{run_hooks, Hook, HookContext, HookEvent};
+use crate::output_actions::{ActionOutcome, OutputAction, OutputReport};
+use crate::sop_class::ImageSopClass;
+use crate::worklist_entries::{list_folder_entries, WorklistEntry};
+
+/// Events arriving within this time of each other are handled by one scan
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(500);
+/// A GDT file is converted once its size and modification time didn't
+/// change for this long, so files still being written are not read
+const STABLE_FILE_DELAY: Duration = Duration::from_millis(500);
+
+/// Record of the converted files in the `processed` folder
+const PROCESSED_RECORD_FILE_NAME: &str = "processed_files.json";
+
+/// What happens to a GDT file of a patient who already has an open entry
+/// scheduled for the same day, but for another or no request (8310).
+/// Entries for the same request are always updated in place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SameDayDuplicates {
+    /// Add a new entry
+    #[default]
+    Add,
+    /// Update the existing entry in place
+    Replace,
+    /// Keep the existing entry and ignore the GDT file
+    Skip,
+}
 
 pub struct WorklistConversion {
     input_watcher: Option<(PathBuf, Box<dyn Watcher + Send>)>,
     worklist_dir_path: Arc<Mutex<Option<PathBuf>>>,
+    hooks: Arc<Mutex<Vec<Hook>>>,
     aetitle: Option<String>,
     modality: Option<String>,
+    sop_class: Option<ImageSopClass>,
+    request_tags: RequestTags,
+    filename_template: FilenameTemplate,
+    same_day_duplicates: SameDayDuplicates,
+    /// Poll the input folder instead of waiting for file system events,
+    /// which network shares often don't deliver
+    poll_interval_seconds: Option<u64>,
+    output_actions: Vec<OutputAction>,
     log_sender: mpsc::Sender<String>,
+    running_scan: Arc<Mutex<()>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,19 +76,40 @@ pub struct WorklistConversionState {
     pub input_dir_path: Option<PathBuf>,
     pub aetitle: Option<String>,
     pub modality: Option<String>,
+    #[serde(default)]
+    pub sop_class: Option<ImageSopClass>,
+    #[serde(default)]
+    pub request_tags: RequestTags,
+    #[serde(default = "FilenameTemplate::worklist")]
+    pub filename_template: FilenameTemplate,
+    #[serde(default)]
+    pub same_day_duplicates: SameDayDuplicates,
+    #[serde(default)]
+    pub poll_interval_seconds: Option<u64>,
+    #[serde(default = "OutputAction::defaults")]
+    pub output_actions: Vec<OutputAction>,
 }
 
 impl WorklistConversion {
     pub fn new(
         log_sender: mpsc::Sender<String>,
         worklist_dir_path: Arc<Mutex<Option<PathBuf>>>,
+        hooks: Arc<Mutex<Vec<Hook>>>,
     ) -> WorklistConversion {
         return WorklistConversion {
             input_watcher: None,
             worklist_dir_path: worklist_dir_path,
+            hooks: hooks,
             aetitle: None,
             modality: None,
+            sop_class: None,
+            request_tags: RequestTags::default(),
+            filename_template: FilenameTemplate::worklist(),
+            same_day_duplicates: SameDayDuplicates::default(),
+            poll_interval_seconds: None,
+            output_actions: OutputAction::defaults(),
             log_sender: log_sender,
+            running_scan: Arc::new(Mutex::new(())),
         };
     }
     pub fn to_state(&self) -> WorklistConversionState {
@@ -50,16 +118,29 @@ impl WorklistConversion {
             input_dir_path: input_dir_path,
             aetitle: self.aetitle.clone(),
             modality: self.modality.clone(),
+            sop_class: self.sop_class,
+            request_tags: self.request_tags,
+            filename_template: self.filename_template.clone(),
+            same_day_duplicates: self.same_day_duplicates,
+            poll_interval_seconds: self.poll_interval_seconds,
+            output_actions: self.output_actions.clone(),
         }
     }
     pub fn from_state(
         state: &WorklistConversionState,
         log_sender: mpsc::Sender<String>,
         worklist_dir_path: Arc<Mutex<Option<PathBuf>>>,
+        hooks: Arc<Mutex<Vec<Hook>>>,
     ) -> Arc<Mutex<WorklistConversion>> {
-        let mut wc = WorklistConversion::new(log_sender, worklist_dir_path);
+        let mut wc = WorklistConversion::new(log_sender, worklist_dir_path, hooks);
         wc.set_aetitle_string(state.aetitle.clone().unwrap_or("".to_string()));
         wc.set_modality_string(state.modality.clone().unwrap_or("".to_string()));
+        wc.set_sop_class(state.sop_class);
+        wc.set_request_tags(state.request_tags);
+        wc.set_filename_template(state.filename_template.clone());
+        wc.set_same_day_duplicates(state.same_day_duplicates);
+        wc.poll_interval_seconds = state.poll_interval_seconds;
+        wc.set_output_actions(state.output_actions.clone());
         let arc = Arc::new(Mutex::new(wc));
         let arc1 = arc.clone();
         let mut wc = arc1.lock().unwrap();
@@ -87,13 +168,28 @@ impl WorklistConversion {
                     .log_sender
                     .send(format!("Unwatching {:?}", &current_path));
             }
+            let (scan_sender, scan_receiver) = mpsc::channel();
+            spawn_debounced_scan(Arc::downgrade(&self_arc), scan_receiver);
             let handler = FSEventHandler {
-                conversion: self_arc,
+                scan_sender: scan_sender.clone(),
+                log_sender: self.log_sender.clone(),
+            };
+            let mut w: Box<dyn Watcher + Send> = match self.poll_interval_seconds {
+                Some(seconds) => {
+                    _ = self.log_sender.send(format!(
+                        "Polling {} every {} seconds",
+                        &new_path.display(),
+                        seconds
+                    ));
+                    let config = Config::default().with_poll_interval(Duration::from_secs(seconds));
+                    Box::new(PollWatcher::new(handler, config)?)
+                }
+                None => Box::new(recommended_watcher(handler)?),
             };
-            let mut w = recommended_watcher(handler)?;
             w.watch(&new_path.as_path(), RecursiveMode::NonRecursive)?;
-            self.input_watcher = Some((new_path, Box::new(w)));
-            self.scan_folder()?;
+            self.input_watcher = Some((new_path, w));
+            // Files which arrived while not watching
+            _ = scan_sender.send(());
         } else {
             self.input_watcher = None;
         }
@@ -124,58 +220,306 @@ impl WorklistConversion {
         }
     }
 
-    pub fn scan_folder(&self) -> Result<(), G2DError> {
-        let output_folder_path = self.output_folder()?;
-        let (input_dir_path, output_folder) = match (&self.input_watcher, output_folder_path) {
-            (Some((input_dir_path, _)), Some(output_dir_path)) => (input_dir_path, output_dir_path),
-            _ => {
-                return Ok(());
+    pub fn sop_class(&self) -> Option<ImageSopClass> {
+        return self.sop_class;
+    }
+
+    pub fn set_sop_class(&mut self, value: Option<ImageSopClass>) {
+        self.sop_class = value;
+    }
+
+    pub fn request_tags(&self) -> RequestTags {
+        return self.request_tags;
+    }
+
+    pub fn set_request_tags(&mut self, value: RequestTags) {
+        self.request_tags = value;
+    }
+
+    pub fn filename_template(&self) -> &FilenameTemplate {
+        return &self.filename_template;
+    }
+
+    pub fn set_filename_template(&mut self, value: FilenameTemplate) {
+        self.filename_template = value;
+    }
+
+    pub fn same_day_duplicates(&self) -> SameDayDuplicates {
+        return self.same_day_duplicates;
+    }
+
+    pub fn set_same_day_duplicates(&mut self, value: SameDayDuplicates) {
+        self.same_day_duplicates = value;
+    }
+
+    pub fn poll_interval_seconds(&self) -> Option<u64> {
+        return self.poll_interval_seconds;
+    }
+
+    /// Switches between polling and file system events, the input folder is
+    /// watched again if it is set
+    pub fn set_poll_interval_seconds(
+        &mut self,
+        value: Option<u64>,
+        self_arc: Arc<Mutex<WorklistConversion>>,
+    ) -> Result<(), G2DError> {
+        let value = value.filter(|v| *v > 0);
+        if self.poll_interval_seconds == value {
+            return Ok(());
+        }
+        self.poll_interval_seconds = value;
+        let input_dir_path = self.input_dir_path();
+        self.unwatch_input_dir();
+        return self.set_input_dir_path(input_dir_path, self_arc);
+    }
+
+    pub fn output_actions(&self) -> &Vec<OutputAction> {
+        return &self.output_actions;
+    }
+
+    pub fn set_output_actions(&mut self, value: Vec<OutputAction>) {
+        self.output_actions = value;
+    }
+
+    /// The settings for one scan of the input folder, so the scan runs
+    /// without holding the lock of the conversion. None if no folder is watched.
+    pub fn folder_scan(&self) -> Option<FolderScan> {
+        let (input_dir_path, _) = self.input_watcher.as_ref()?;
+        // The modality of the selected SOP class is used unless one is set explicitly
+        let modality = self
+            .modality
+            .clone()
+            .or(self.sop_class.map(|s| s.modality().to_string()));
+        return Some(FolderScan {
+            input_dir_path: input_dir_path.clone(),
+            worklist_dir_path: self.worklist_dir_path.clone(),
+            hooks: self.hooks.clone(),
+            aetitle: self.aetitle.clone(),
+            modality,
+            request_tags: self.request_tags,
+            filename_template: self.filename_template.clone(),
+            same_day_duplicates: self.same_day_duplicates,
+            output_actions: self.output_actions.clone(),
+            log_sender: self.log_sender.clone(),
+            running: self.running_scan.clone(),
+        });
+    }
+
+    /// Blocks until a running scan of the input folder is finished
+    pub fn wait_for_scan(&self) {
+        drop(self.running_scan.lock());
+    }
+}
+
+/// The settings of a `WorklistConversion` for one scan of its input folder
+pub struct FolderScan {
+    input_dir_path: PathBuf,
+    worklist_dir_path: Arc<Mutex<Option<PathBuf>>>,
+    hooks: Arc<Mutex<Vec<Hook>>>,
+    aetitle: Option<String>,
+    modality: Option<String>,
+    request_tags: RequestTags,
+    filename_template: FilenameTemplate,
+    same_day_duplicates: SameDayDuplicates,
+    output_actions: Vec<OutputAction>,
+    log_sender: mpsc::Sender<String>,
+    /// Held while scanning, so only one scan of a folder runs at a time
+    running: Arc<Mutex<()>>,
+}
+
+impl FolderScan {
+    /// Runs the output actions for the GDT files of the input folder, which
+    /// are moved into the `processed` folder, or into the `failed` folder
+    /// with a report if they have to be converted again (see
+    /// `OutputReport::needs_retry`). Returns the number of files which
+    /// are still being written and have to be scanned again.
+    pub fn scan_folder(&self) -> Result<usize, G2DError> {
+        let _running = self.running.lock();
+        let input_dir_path = &self.input_dir_path;
+        // Without a worklist folder only the other actions can run
+        let output_folder = if self.output_actions.contains(&OutputAction::Worklist) {
+            match self.output_folder()? {
+                Some(output_folder) => Some(output_folder),
+                None => {
+                    return Ok(0);
+                }
             }
+        } else {
+            None
         };
-        let processed_folder = {
-            let mut p = input_dir_path.clone();
-            p.push("processed");
-            if !p.is_dir() {
+        let processed_folder = self.subfolder(input_dir_path, "processed")?;
+        let failed_folder = self.subfolder(input_dir_path, "failed")?;
+        let modality = self.modality.clone();
+
+        let mut candidates = vec![];
+        for entry in read_dir(&input_dir_path)? {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            if is_gdt_file(&path) {
+                candidates.push((file_state(&path), path));
+            } else {
                 _ = self
                     .log_sender
-                    .send(format!("Creating processed folder at: {}", &p.display()));
-                create_dir(&p)?;
+                    .send(format!("Found non-GDT file, ignored: {}", path.display()));
             }
-            p
-        };
-        let entries = read_dir(&input_dir_path)?;
-        for entry in entries {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_file() {
-                if path
-                    .extension()
-                    .map(|s| s.to_ascii_lowercase() == "gdt")
-                    .unwrap_or(false)
-                {
-                    _ = self
-                        .log_sender
-                        .send(format!("Processing GDT file: {}", &path.display()));
-                    let filename = convert_gdt_file(
-                        Some(&self.log_sender),
-                        &path.as_path(),
-                        &output_folder,
-                        &self.aetitle,
-                        &self.modality,
-                    )?;
-
-                    let mut processed_path = processed_folder.clone();
-                    processed_path.push(&filename);
-                    processed_path.set_extension("gdt");
-                    rename(&path, processed_path)?;
-                } else {
+        }
+        if candidates.is_empty() {
+            return Ok(0);
+        }
+        std::thread::sleep(STABLE_FILE_DELAY);
+
+        let mut processed_files = ProcessedFiles::load(&processed_folder);
+        let mut pending = 0;
+        for (state, path) in candidates {
+            if file_state(&path) != state || is_locked(&path) {
+                _ = self.log_sender.send(format!(
+                    "GDT file is still being written: {}",
+                    &path.display()
+                ));
+                pending += 1;
+                continue;
+            }
+            let key = state.map(|(size, modified)| processed_file_key(&path, size, modified));
+            if let Some(record) = key.as_ref().and_then(|k| processed_files.files.get(k)) {
+                _ = self.log_sender.send(format!(
+                    "Already converted to {}, skipped: {}",
+                    record.processed_name,
+                    &path.display()
+                ));
+                let processed_name = Path::new(&record.processed_name).with_extension("gdt");
+                _ = rename(
+                    &path,
+                    unique_target(&processed_folder, &processed_name.to_string_lossy()),
+                );
+                continue;
+            }
+            _ = self
+                .log_sender
+                .send(format!("Processing GDT file: {}", &path.display()));
+            // Read before the file is moved, for the hooks
+            let hook_context = match parse_file(&path) {
+                Ok(gdt_file) => HookContext::from_gdt_file(HookEvent::GdtConverted, &gdt_file),
+                Err(_) => HookContext::new(HookEvent::GdtConverted),
+            };
+            let report = self.run_output_actions(&path, output_folder.as_ref(), &modality);
+            for line in report.summary().lines() {
+                _ = self.log_sender.send(line.to_string());
+            }
+            // The processed file is named like its worklist entry if there is one
+            let worklist_file = report.worklist_file().map(String::from);
+            let filename = worklist_file.clone().unwrap_or_else(|| {
+                path.file_name()
+                    .map(|n| n.to_string_lossy().to_string())
+                    .unwrap_or_default()
+            });
+            // A failing secondary action doesn't undo the worklist entry,
+            // converting the file again would duplicate it
+            let moved = if !report.needs_retry() {
+                if let Some(key) = key {
+                    processed_files.add(key, &path, &filename);
+                    if let Err(err) = processed_files.save(&processed_folder) {
+                        _ = self.log_sender.send(format!(
+                            "Cannot save the record of processed files: {}",
+                            err
+                        ));
+                    }
+                }
+                let processed_name = Path::new(&filename).with_extension("gdt");
+                let processed_path =
+                    unique_target(&processed_folder, &processed_name.to_string_lossy());
+                rename(&path, &processed_path)
+                    .map(|_| processed_path)
+                    .map_err(G2DError::from)
+            } else {
+                _ = self
+                    .log_sender
+                    .send(format!("Cannot convert {}", &path.display()));
+                move_to_failed(&path, &failed_folder, &report)
+            };
+            let gdt_path = match moved {
+                Ok(moved_path) => moved_path,
+                Err(err) => {
                     _ = self
                         .log_sender
-                        .send(format!("Found non-GDT file, ignored: {}", path.display()));
+                        .send(format!("Cannot move {}: {}", &path.display(), err));
+                    path.clone()
                 }
+            };
+
+            let hooks = self.hooks.lock().unwrap().clone();
+            let worklist_path = worklist_file
+                .as_ref()
+                .zip(output_folder.as_ref())
+                .map(|(file, folder)| folder.join(file));
+            if let Some(worklist_path) = &worklist_path {
+                let context = HookContext {
+                    event: HookEvent::WorklistCreated,
+                    files: vec![worklist_path.clone()],
+                    ..hook_context.clone()
+                };
+                run_hooks(&hooks, &context, Some(&self.log_sender));
             }
+            let context = HookContext {
+                files: std::iter::once(gdt_path).chain(worklist_path).collect(),
+                success: report.is_success(),
+                message: report.summary(),
+                ..hook_context
+            };
+            run_hooks(&hooks, &context, Some(&self.log_sender));
+        }
+        return Ok(pending);
+    }
+
+    /// Runs every output action, a failing action doesn't stop the following ones
+    fn run_output_actions(
+        &self,
+        path: &Path,
+        output_folder: Option<&PathBuf>,
+        modality: &Option<String>,
+    ) -> OutputReport {
+        let mut results = vec![];
+        for action in &self.output_actions {
+            let result = match action {
+                OutputAction::Worklist => match output_folder {
+                    Some(output_folder) => convert_gdt_file(
+                        Some(&self.log_sender),
+                        path,
+                        output_folder,
+                        &self.aetitle,
+                        modality,
+                        &self.request_tags,
+                        &self.filename_template,
+                        self.same_day_duplicates,
+                    ),
+                    None => Err(G2DError::InvalidArgument(
+                        "No worklist folder is set".to_string(),
+                    )),
+                },
+                _ => action
+                    .run(
+                        path,
+                        &self.filename_template,
+                        &self.request_tags,
+                        Some(&self.log_sender),
+                    )
+                    .map(ActionOutcome::Done),
+            };
+            results.push((action.clone(), result));
+        }
+        return OutputReport { results };
+    }
+
+    fn subfolder(&self, input_dir_path: &PathBuf, name: &str) -> Result<PathBuf, G2DError> {
+        let p = input_dir_path.join(name);
+        if !p.is_dir() {
+            _ = self
+                .log_sender
+                .send(format!("Creating {} folder at: {}", name, &p.display()));
+            create_dir(&p)?;
         }
-        Ok(())
+        return Ok(p);
     }
 
     fn output_folder(&self) -> Result<Option<PathBuf>, G2DError> {
@@ -192,8 +536,7 @@ impl WorklistConversion {
             }
             Some(a) => a,
         };
-        let mut result = worklist_dir.clone();
-        result.push(aetitle);
+        let result = aetitle_folder(worklist_dir, aetitle);
         if !result.is_dir() {
             _ = self
                 .log_sender
@@ -209,73 +552,353 @@ impl WorklistConversion {
     }
 }
 
+/// The folder of an AETitle in the worklist folder, where wlmscpfs looks
+/// for the entries of that AETitle
+pub fn aetitle_folder(worklist_dir: &Path, aetitle: &str) -> PathBuf {
+    return worklist_dir.join(aetitle);
+}
+
 struct FSEventHandler {
-    pub conversion: Arc<Mutex<WorklistConversion>>,
+    scan_sender: mpsc::Sender<()>,
+    log_sender: mpsc::Sender<String>,
 }
 
 impl EventHandler for FSEventHandler {
     fn handle_event(&mut self, event: notify::Result<Event>) {
-        if let Ok(event) = event {
-            println!("Event: {:?}", &event);
-            match event.kind {
-                notify::event::EventKind::Create(notify::event::CreateKind::File)
-                | notify::event::EventKind::Create(notify::event::CreateKind::Any) => {
-                    if let std::sync::LockResult::Ok(c) = self.conversion.lock() {
-                        _ = c.log_sender.send(format!("Event: {:?}", &event));
-                        if event.paths.iter().any(|p| {
-                            p.extension()
-                                .map(|s| s.to_ascii_lowercase() == "gdt")
-                                .unwrap_or(false)
-                        }) {
-                            #[cfg(target_os = "windows")]
-                            std::thread::sleep(Duration::from_secs(1));
-                            let result = c.scan_folder();
-                            if let Err(err) = result {
-                                _ = c.log_sender.send(format!("Scan error {:?}", err));
-                            }
-                        } else {
-                            _ = c.log_sender.send(format!(
-                                "Not processing: unrecognised extension. {:?}",
-                                event.paths
-                            ));
-                        }
-                    }
+        match event {
+            Ok(event) => {
+                let relevant = matches!(
+                    event.kind,
+                    EventKind::Create(_)
+                        | EventKind::Modify(ModifyKind::Data(_))
+                        | EventKind::Modify(ModifyKind::Metadata(MetadataKind::WriteTime))
+                        | EventKind::Modify(ModifyKind::Name(_))
+                        | EventKind::Modify(ModifyKind::Any)
+                        | EventKind::Any
+                );
+                if relevant && event.paths.iter().any(|p| is_gdt_file(p)) {
+                    _ = self.scan_sender.send(());
                 }
-                _ => {
-                    // Skip
+            }
+            Err(err) => {
+                _ = self.log_sender.send(format!("Watch error {:?}", err));
+            }
+        }
+    }
+}
+
+/// Scans the input folder once the events sent to `scan_receiver` stop
+/// for DEBOUNCE_DELAY, and again as long as files are still being written.
+/// Ends when the watcher holding the sender is dropped.
+fn spawn_debounced_scan(
+    conversion: Weak<Mutex<WorklistConversion>>,
+    scan_receiver: mpsc::Receiver<()>,
+) {
+    std::thread::spawn(move || {
+        let mut pending = 0;
+        loop {
+            if pending == 0 && scan_receiver.recv().is_err() {
+                return;
+            }
+            loop {
+                match scan_receiver.recv_timeout(DEBOUNCE_DELAY) {
+                    Ok(()) => continue,
+                    Err(mpsc::RecvTimeoutError::Timeout) => break,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return,
                 }
             }
+            let Some(conversion) = conversion.upgrade() else {
+                return;
+            };
+            // The lock is only held for copying the settings, the GUI and
+            // other threads can use the conversion while the files are converted
+            let Some(scan) = conversion.lock().ok().and_then(|c| c.folder_scan()) else {
+                return;
+            };
+            drop(conversion);
+            pending = match scan.scan_folder() {
+                Ok(pending) => pending,
+                Err(err) => {
+                    _ = scan.log_sender.send(format!("Scan error {}", err));
+                    0
+                }
+            };
         }
+    });
+}
+
+/// The GDT files converted from an input folder, so a file which could not
+/// be moved or is written again unchanged isn't converted twice, also after
+/// a restart
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ProcessedFiles {
+    files: HashMap<String, ProcessedFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ProcessedFile {
+    name: String,
+    #[serde(alias = "worklist_file")]
+    processed_name: String,
+    processed_at: String,
+}
+
+impl ProcessedFiles {
+    fn load(processed_folder: &Path) -> ProcessedFiles {
+        return std::fs::read(processed_folder.join(PROCESSED_RECORD_FILE_NAME))
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+    }
+
+    fn save(&self, processed_folder: &Path) -> Result<(), G2DError> {
+        std::fs::write(
+            processed_folder.join(PROCESSED_RECORD_FILE_NAME),
+            serde_json::to_vec_pretty(self)?,
+        )?;
+        return Ok(());
+    }
+
+    fn add(&mut self, key: String, path: &Path, processed_name: &str) {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.files.insert(
+            key,
+            ProcessedFile {
+                name,
+                processed_name: processed_name.to_string(),
+                processed_at: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            },
+        );
     }
 }
 
+/// FNV-1a hash of file name, size and modification time
+fn processed_file_key(path: &Path, size: u64, modified: SystemTime) -> String {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let nanos = modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in format!("{}\0{}\0{}", name, size, nanos).bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    return format!("{:016x}", hash);
+}
+
+fn is_gdt_file(path: &Path) -> bool {
+    return path
+        .extension()
+        .map(|s| s.to_ascii_lowercase() == "gdt")
+        .unwrap_or(false);
+}
+
+fn file_state(path: &Path) -> Option<(u64, SystemTime)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    return Some((metadata.len(), metadata.modified().ok()?));
+}
+
+/// Whether another process still has the file open for writing, only
+/// detectable on Windows where the writer usually denies sharing
+#[cfg(target_os = "windows")]
+fn is_locked(path: &Path) -> bool {
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    return match std::fs::OpenOptions::new().append(true).open(path) {
+        Err(err) => err.raw_os_error() == Some(ERROR_SHARING_VIOLATION),
+        Ok(_) => false,
+    };
+}
+
+#[cfg(not(target_os = "windows"))]
+fn is_locked(_path: &Path) -> bool {
+    return false;
+}
+
+/// A path for `file_name` in `folder` which doesn't exist yet, several GDT
+/// files can belong to the same worklist entry
+fn unique_target(folder: &Path, file_name: &str) -> PathBuf {
+    let mut target = folder.join(file_name);
+    let mut counter = 2;
+    while target.exists() {
+        target = folder.join(format!("{}_{}", counter, file_name));
+        counter += 1;
+    }
+    return target;
+}
+
+/// Moves a GDT file which cannot be converted into the failed folder and
+/// writes `[file name].error.txt` with the result of every action next to it
+fn move_to_failed(
+    path: &Path,
+    failed_folder: &Path,
+    report: &OutputReport,
+) -> Result<PathBuf, G2DError> {
+    let file_name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let target = unique_target(failed_folder, &file_name);
+    rename(path, &target)?;
+    let mut report_path = target.clone().into_os_string();
+    report_path.push(".error.txt");
+    std::fs::write(
+        report_path,
+        format!(
+            "File: {}\nTime: {}\n{}\n",
+            path.display(),
+            Local::now().format("%Y-%m-%d %H:%M:%S"),
+            report.summary()
+        ),
+    )?;
+    return Ok(target);
+}
+
 fn convert_gdt_file(
     log_sender: Option<&mpsc::Sender<String>>,
     input_path: &Path,
     output_dir: &PathBuf,
     aetitle: &Option<String>,
     modality: &Option<String>,
-) -> Result<String, G2DError> {
+    request_tags: &RequestTags,
+    filename_template: &FilenameTemplate,
+    same_day_duplicates: SameDayDuplicates,
+) -> Result<ActionOutcome, G2DError> {
     let gdt_file = parse_file(input_path)?;
-    let local: DateTime<Local> = Local::now();
-    let timestamp = local.format("%d.%m.%Y_%H.%M.%S").to_string();
-    let filename = format!(
-        "{}_{}_{}.wl",
-        &gdt_file.object_patient.patient_first_name,
-        &gdt_file.object_patient.patient_name,
-        timestamp
-    );
-    let mut output_path = output_dir.clone();
-    output_path.push(&filename);
+    request_tags.check(&gdt_file.object_request)?;
+    let duplicate = find_duplicate_entry(
+        log_sender,
+        output_dir,
+        &gdt_file.object_patient.patient_number,
+        &gdt_file.object_request.request_identifier,
+        request_tags,
+        same_day_duplicates,
+    )?;
+    let (output_path, existing_study_uid) = match duplicate {
+        Some((entry, DuplicateReason::SameDay))
+            if same_day_duplicates == SameDayDuplicates::Skip =>
+        {
+            return Ok(ActionOutcome::Skipped(format!(
+                "patient {} already scheduled today in {}",
+                entry.patient_id,
+                entry.path.display()
+            )));
+        }
+        Some((entry, reason)) => {
+            if let Some(l) = log_sender {
+                let description = match reason {
+                    DuplicateReason::SameRequest => format!(
+                        "same patient and request {}",
+                        gdt_file.object_request.request_identifier.trim()
+                    ),
+                    DuplicateReason::SameDay => "same patient scheduled today".to_string(),
+                };
+                _ = l.send(format!(
+                    "Updating {}: {}",
+                    entry.path.display(),
+                    description
+                ));
+            }
+            // Only the same request keeps its study, another request of the
+            // same day replacing the entry is a new one
+            let study_uid = Some(entry.study_uid)
+                .filter(|u| !u.is_empty() && reason == DuplicateReason::SameRequest);
+            (entry.path, study_uid)
+        }
+        None => {
+            let mut values = FilenameValues::from_gdt_file(&gdt_file);
+            values.set("modality", modality.clone().unwrap_or_default());
+            (
+                filename_template.unique_path(output_dir, "wl", &values),
+                None,
+            )
+        }
+    };
+    let filename = output_path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
 
+    // The StudyInstanceUID is derived from the request (8314/8310) if there is one,
+    // an updated entry keeps its own so images already sent still match
+    let keep_study_uid = existing_study_uid.is_some()
+        || study_instance_uid_from_request(
+            &gdt_file.object_patient.patient_number,
+            &gdt_file.object_request,
+        )
+        .is_some();
     let xml_events = default_dcm_xml(DcmTransferType::LittleEndianExplicit);
-    let temp_file = file_to_xml(gdt_file, &xml_events)?;
+    let options = FileToXmlOptions {
+        request_tags: *request_tags,
+        ..Default::default()
+    };
+    let mut events = file_to_xml_events(gdt_file, &xml_events, &options);
+    if let Some(study_uid) = existing_study_uid {
+        set_element(
+            &mut events,
+            DcmElement::new("0020,000d", "UI", "StudyInstanceUID", &study_uid),
+        );
+    }
+    let temp_file = xml_events_to_file(events)?;
     let path = temp_file.path();
 
-    let dcm_file = modify_dcm_file(log_sender, aetitle, modality, &path)?;
+    let dcm_file = modify_dcm_file(log_sender, aetitle, modality, keep_study_uid, &path)?;
     dcm_to_worklist(log_sender, &dcm_file.path(), &output_path)?;
 
-    return Ok(filename);
+    return Ok(ActionOutcome::Done(filename));
+}
+
+/// Why a GDT file duplicates an open worklist entry
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DuplicateReason {
+    /// The entry is of the same patient and request (8310)
+    SameRequest,
+    /// The entry is of the same patient and scheduled for today
+    SameDay,
+}
+
+/// The open entry in `output_dir` a GDT file duplicates and why: an entry of
+/// the patient for the same request (8310), or one scheduled for today
+/// unless same-day duplicates are added
+fn find_duplicate_entry(
+    log_sender: Option<&mpsc::Sender<String>>,
+    output_dir: &PathBuf,
+    patient_id: &str,
+    request_id: &str,
+    request_tags: &RequestTags,
+    same_day_duplicates: SameDayDuplicates,
+) -> Result<Option<(WorklistEntry, DuplicateReason)>, G2DError> {
+    let patient_id = patient_id.trim();
+    let request_id = request_id.trim();
+    if patient_id.is_empty() {
+        return Ok(None);
+    }
+    let today = Local::now().format("%Y%m%d").to_string();
+    let mut same_day = None;
+    for entry in list_folder_entries(output_dir, None, log_sender)? {
+        if entry.patient_id != patient_id {
+            continue;
+        }
+        let entry_request_id = entry
+            .request_value(request_tags.identifier)
+            .unwrap_or_default();
+        if !request_id.is_empty() && entry_request_id == request_id {
+            return Ok(Some((entry, DuplicateReason::SameRequest)));
+        }
+        if same_day_duplicates != SameDayDuplicates::Add
+            && same_day.is_none()
+            && entry.scheduled_date == today
+        {
+            same_day = Some((entry, DuplicateReason::SameDay));
+        }
+    }
+    return Ok(same_day);
 }
 
 #[cfg(target_os = "windows")]
@@ -303,6 +926,7 @@ fn modify_dcm_file(
     log_sender: Option<&mpsc::Sender<String>>,
     aetitle: &Option<String>,
     modality: &Option<String>,
+    keep_study_uid: bool,
     xml_file_path: &Path,
 ) -> Result<NamedTempFile, G2DError> {
     // This function is same as this bash:
@@ -318,7 +942,7 @@ fn modify_dcm_file(
         log_sender,
     )?;
     if !output1.status.success() {
-        let err_str = std::str::from_utf8(&output1.stderr).unwrap();
+        let err_str = String::from_utf8_lossy(&output1.stderr).to_string();
         if let Some(log_sender) = log_sender {
             _ = log_sender.send(err_str.to_string());
         }
@@ -332,21 +956,21 @@ fn modify_dcm_file(
         vec![]
     };
 
-    // Always assign new study id #72
-    let output2 = exec_command_with_env(
-        "dcmodify",
-        vec![OsStr::new("--gen-stud-uid"), temp_dcm_file_path.as_os_str()],
-        true,
-        log_sender,
-        envs.clone(),
-    )?;
-    if !output2.status.success() {
-        let err_str = std::str::from_utf8(&output2.stderr).unwrap();
-        if let Some(log_sender) = log_sender {
-            _ = log_sender.send(err_str.to_string());
+    // Assign new study id #72, unless it was derived from the request
+    if !keep_study_uid {
+        let mut arguments: Vec<&OsStr> = vec![];
+        let study_uid_arguments = new_study_uid_arguments();
+        arguments.extend(study_uid_arguments.iter().map(OsStr::new));
+        arguments.push(temp_dcm_file_path.as_os_str());
+        let output2 = exec_command_with_env("dcmodify", arguments, true, log_sender, envs.clone())?;
+        if !output2.status.success() {
+            let err_str = String::from_utf8_lossy(&output2.stderr).to_string();
+            if let Some(log_sender) = log_sender {
+                _ = log_sender.send(err_str.to_string());
+            }
+            let custom_error = Error::new(ErrorKind::Other, err_str);
+            return Err(G2DError::IoError(custom_error));
         }
-        let custom_error = Error::new(ErrorKind::Other, err_str);
-        return Err(G2DError::IoError(custom_error));
     }
 
     if let Some(aetitle) = aetitle {
@@ -362,7 +986,7 @@ fn modify_dcm_file(
             envs.clone(),
         )?;
         if !output3.status.success() {
-            let err_str = std::str::from_utf8(&output3.stderr).unwrap();
+            let err_str = String::from_utf8_lossy(&output3.stderr).to_string();
             if let Some(log_sender) = log_sender {
                 _ = log_sender.send(err_str.to_string());
             }
@@ -384,7 +1008,7 @@ fn modify_dcm_file(
             envs,
         )?;
         if !output4.status.success() {
-            let err_str = std::str::from_utf8(&output4.stderr).unwrap();
+            let err_str = String::from_utf8_lossy(&output4.stderr).to_string();
             if let Some(log_sender) = log_sender {
                 _ = log_sender.send(err_str.to_string());
             }
@@ -0,0 +1,397 @@
+use std::ffi::OsString;
+use std::fs::read_dir;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::SystemTime;
+
+use chrono::{NaiveDate, NaiveTime};
+use clap::ValueEnum;
+use serde::Serialize;
+use xml::reader::XmlEvent;
+
+use crate::command::{exec_command, log};
+use crate::dcm_xml::{
+    build_xml_tree, parse_dcm_as_xml, xml_get_patient_patient_id, xml_get_study_instance_uid,
+    RequestTag, RequestTags,
+};
+use crate::error::G2DError;
+use crate::worklist_lifecycle::{append_to_lifecycle_log, ARCHIVE_FOLDER_NAME};
+
+/// A `.wl` file in the worklist folder
+#[derive(Debug, Clone, Serialize)]
+pub struct WorklistEntry {
+    pub path: PathBuf,
+    /// Name of the AETitle folder, none for entries in the worklist folder itself
+    pub aetitle: Option<String>,
+    pub patient_id: String,
+    pub patient_name: String,
+    pub birth_date: String,
+    pub sex: String,
+    pub accession_number: String,
+    pub study_uid: String,
+    pub referring_physician: String,
+    pub procedure_description: String,
+    pub modality: String,
+    pub scheduled_date: String,
+    pub scheduled_time: String,
+    pub step_description: String,
+    /// The attributes a GDT request (8310, 8314) can be stored in, with a value
+    #[serde(skip)]
+    pub request_values: Vec<(RequestTag, String)>,
+    #[serde(skip)]
+    pub modified: Option<SystemTime>,
+}
+
+impl WorklistEntry {
+    pub fn read(path: &Path, aetitle: Option<String>) -> Result<WorklistEntry, G2DError> {
+        let events = parse_dcm_as_xml(&path.to_path_buf())?;
+        return Ok(WorklistEntry::from_events(path, aetitle, &events));
+    }
+
+    /// The entry from the dcm2xml output of the file at `path`
+    pub fn from_events(
+        path: &Path,
+        aetitle: Option<String>,
+        events: &Vec<XmlEvent>,
+    ) -> WorklistEntry {
+        let root = build_xml_tree(events);
+        let step = root.first_item("0040,0100");
+        let step_body = |tag: &str| step.and_then(|s| s.child_body(tag)).unwrap_or_default();
+        let body = |tag: &str| root.child_body(tag).unwrap_or_default();
+        return WorklistEntry {
+            path: path.to_path_buf(),
+            aetitle,
+            patient_id: body("0010,0020"),
+            patient_name: body("0010,0010"),
+            birth_date: body("0010,0030"),
+            sex: body("0010,0040"),
+            accession_number: body("0008,0050"),
+            study_uid: body("0020,000d"),
+            referring_physician: body("0008,0090"),
+            procedure_description: body("0032,1060"),
+            modality: step_body("0008,0060"),
+            scheduled_date: step_body("0040,0002"),
+            scheduled_time: step_body("0040,0003"),
+            step_description: step_body("0040,0007"),
+            request_values: RequestTag::value_variants()
+                .iter()
+                .filter_map(|tag| tag.get(events).map(|value| (*tag, value)))
+                .collect(),
+            modified: std::fs::metadata(path).and_then(|m| m.modified()).ok(),
+        };
+    }
+
+    pub fn file_name(&self) -> String {
+        return self
+            .path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+    }
+
+    /// The value of the attribute `tag`, e.g. the request 8310 is stored in
+    pub fn request_value(&self, tag: RequestTag) -> Option<&str> {
+        return self
+            .request_values
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, value)| value.as_str());
+    }
+
+    pub fn scheduled(&self) -> Option<NaiveDate> {
+        return NaiveDate::parse_from_str(self.scheduled_date.trim(), "%Y%m%d").ok();
+    }
+
+    /// Whether the DICOM file belongs to this entry, by StudyInstanceUID or
+    /// by the request identifier (8310) and patient ID, the identifier is
+    /// compared in the attribute `request_tags` stores it in
+    pub fn matches_dcm(&self, events: &Vec<XmlEvent>, request_tags: &RequestTags) -> bool {
+        if let Some(study_uid) = xml_get_study_instance_uid(events) {
+            if !self.study_uid.is_empty() && study_uid.trim() == self.study_uid {
+                return true;
+            }
+        }
+        let Some(request_id) = self.request_value(request_tags.identifier) else {
+            return false;
+        };
+        let patient_id = xml_get_patient_patient_id(events).map(|p| p.trim().to_string());
+        return request_tags.identifier.get(events).as_deref() == Some(request_id)
+            && patient_id.as_deref() == Some(self.patient_id.as_str());
+    }
+}
+
+/// Selects entries, all given fields have to match
+#[derive(Debug, Clone, Default)]
+pub struct EntryFilter {
+    pub aetitle: Option<String>,
+    pub patient_id: Option<String>,
+    pub accession_number: Option<String>,
+    /// File name of the entry, with or without `.wl`
+    pub file: Option<String>,
+}
+
+impl EntryFilter {
+    /// Whether the filter selects nothing more specific than an AETitle folder
+    pub fn is_empty(&self) -> bool {
+        return self.patient_id.is_none() && self.accession_number.is_none() && self.file.is_none();
+    }
+
+    pub fn matches(&self, entry: &WorklistEntry) -> bool {
+        if let Some(a) = &self.aetitle {
+            if entry.aetitle.as_ref() != Some(a) {
+                return false;
+            }
+        }
+        if let Some(p) = &self.patient_id {
+            if &entry.patient_id != p {
+                return false;
+            }
+        }
+        if let Some(a) = &self.accession_number {
+            if &entry.accession_number != a {
+                return false;
+            }
+        }
+        if let Some(f) = &self.file {
+            let name = entry.file_name();
+            if &name != f && name.strip_suffix(".wl") != Some(f.as_str()) {
+                return false;
+            }
+        }
+        return true;
+    }
+}
+
+/// The `.wl` files in the worklist folder and its AETitle folders, entries
+/// which cannot be read are logged and skipped
+pub fn list_worklist_entries(
+    worklist_dir: &Path,
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<Vec<WorklistEntry>, G2DError> {
+    let mut result = list_folder_entries(worklist_dir, None, log_sender)?;
+    let mut folders = read_dir(worklist_dir)?
+        .filter_map(|res| res.ok().map(|e| e.path()))
+        .filter(|p| p.is_dir())
+        .collect::<Vec<_>>();
+    folders.sort();
+    for folder in folders {
+        let aetitle = folder
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if aetitle == ARCHIVE_FOLDER_NAME {
+            continue;
+        }
+        result.extend(list_folder_entries(&folder, Some(aetitle), log_sender)?);
+    }
+    return Ok(result);
+}
+
+/// The `.wl` files of one folder, not searched recursively
+pub fn list_folder_entries(
+    dir: &Path,
+    aetitle: Option<String>,
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<Vec<WorklistEntry>, G2DError> {
+    let mut paths = read_dir(dir)?
+        .filter_map(|res| res.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.is_file()
+                && p.extension()
+                    .map(|s| s.to_ascii_lowercase() == "wl")
+                    .unwrap_or(false)
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+    let mut result = vec![];
+    for path in paths {
+        match WorklistEntry::read(&path, aetitle.clone()) {
+            Ok(entry) => result.push(entry),
+            Err(err) => log(
+                log_sender,
+                format!("Cannot read worklist entry {}: {}", path.display(), err),
+            ),
+        }
+    }
+    return Ok(result);
+}
+
+/// The entries matching the filter
+pub fn find_worklist_entries(
+    worklist_dir: &Path,
+    filter: &EntryFilter,
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<Vec<WorklistEntry>, G2DError> {
+    return Ok(list_worklist_entries(worklist_dir, log_sender)?
+        .into_iter()
+        .filter(|e| filter.matches(e))
+        .collect());
+}
+
+/// New values for an entry, unset fields are kept
+#[derive(Debug, Clone, Default)]
+pub struct WorklistEntryEdit {
+    /// DICOM person name, `Last^First`
+    pub patient_name: Option<String>,
+    /// YYYYMMDD
+    pub birth_date: Option<String>,
+    /// M, F or O
+    pub sex: Option<String>,
+    pub accession_number: Option<String>,
+    pub procedure_description: Option<String>,
+    pub modality: Option<String>,
+    /// YYYYMMDD
+    pub scheduled_date: Option<String>,
+    /// HHMMSS or HHMM
+    pub scheduled_time: Option<String>,
+    pub step_description: Option<String>,
+}
+
+impl WorklistEntryEdit {
+    fn dcmodify_values(&self) -> Vec<(&'static str, &String)> {
+        let values = [
+            ("(0010,0010)", &self.patient_name),
+            ("(0010,0030)", &self.birth_date),
+            ("(0010,0040)", &self.sex),
+            ("(0008,0050)", &self.accession_number),
+            ("(0032,1060)", &self.procedure_description),
+            ("(0040,0100)[0].(0008,0060)", &self.modality),
+            ("(0040,0100)[0].(0040,0002)", &self.scheduled_date),
+            ("(0040,0100)[0].(0040,0003)", &self.scheduled_time),
+            ("(0040,0100)[0].(0040,0007)", &self.step_description),
+        ];
+        return values
+            .into_iter()
+            .filter_map(|(path, value)| value.as_ref().map(|v| (path, v)))
+            .collect();
+    }
+
+    fn validate(&self) -> Result<(), G2DError> {
+        for date in [&self.birth_date, &self.scheduled_date]
+            .into_iter()
+            .flatten()
+        {
+            if NaiveDate::parse_from_str(date, "%Y%m%d").is_err() {
+                return Err(invalid_value(format!(
+                    "Invalid date {}, use YYYYMMDD",
+                    date
+                )));
+            }
+        }
+        if let Some(time) = &self.scheduled_time {
+            if NaiveTime::parse_from_str(time, "%H%M%S").is_err()
+                && NaiveTime::parse_from_str(time, "%H%M").is_err()
+            {
+                return Err(invalid_value(format!(
+                    "Invalid time {}, use HHMMSS or HHMM",
+                    time
+                )));
+            }
+        }
+        if let Some(sex) = &self.sex {
+            if !matches!(sex.as_str(), "M" | "F" | "O" | "") {
+                return Err(invalid_value(format!("Invalid sex {}, use M, F or O", sex)));
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// Changes the entry with `dcmodify` and logs the change to the lifecycle log
+pub fn edit_worklist_entry(
+    worklist_dir: &Path,
+    entry: &WorklistEntry,
+    edit: &WorklistEntryEdit,
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<WorklistEntry, G2DError> {
+    edit.validate()?;
+    let values = edit.dcmodify_values();
+    if values.is_empty() {
+        return Ok(entry.clone());
+    }
+    let mut args: Vec<OsString> = vec!["-nb".into()];
+    for (path, value) in &values {
+        args.push("-i".into());
+        args.push(format!("{}={}", path, value).into());
+    }
+    args.push(entry.path.as_os_str().to_os_string());
+    let result = exec_command("dcmodify", &args, true, log_sender)?;
+    if !result.status.success() {
+        let err_str = String::from_utf8_lossy(&result.stderr).to_string();
+        if let Some(l) = log_sender {
+            _ = l.send(err_str.clone());
+        }
+        return Err(G2DError::IoError(Error::new(ErrorKind::Other, err_str)));
+    }
+    let changes = values
+        .iter()
+        .map(|(path, value)| format!("{}={}", path, value))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let message = format!("Edited {}: {}", entry.path.display(), changes);
+    append_to_lifecycle_log(worklist_dir, entry, &message)?;
+    log(log_sender, message);
+    return WorklistEntry::read(&entry.path, entry.aetitle.clone());
+}
+
+const CSV_COLUMNS: [&str; 14] = [
+    "aetitle",
+    "file",
+    "patient_id",
+    "patient_name",
+    "birth_date",
+    "sex",
+    "modality",
+    "scheduled_date",
+    "scheduled_time",
+    "accession_number",
+    "procedure_description",
+    "step_description",
+    "referring_physician",
+    "study_uid",
+];
+
+/// The entries as CSV with a header line
+pub fn worklist_entries_to_csv(entries: &[WorklistEntry]) -> String {
+    let mut result = CSV_COLUMNS.join(",");
+    result.push('\n');
+    for entry in entries {
+        let file_name = entry.file_name();
+        let row = [
+            entry.aetitle.as_deref().unwrap_or(""),
+            &file_name,
+            &entry.patient_id,
+            &entry.patient_name,
+            &entry.birth_date,
+            &entry.sex,
+            &entry.modality,
+            &entry.scheduled_date,
+            &entry.scheduled_time,
+            &entry.accession_number,
+            &entry.procedure_description,
+            &entry.step_description,
+            &entry.referring_physician,
+            &entry.study_uid,
+        ];
+        result.push_str(
+            &row.iter()
+                .map(|v| csv_field(v))
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+        result.push('\n');
+    }
+    return result;
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        return format!("\"{}\"", value.replace('"', "\"\""));
+    }
+    return value.to_string();
+}
+
+fn invalid_value(message: String) -> G2DError {
+    return G2DError::InvalidArgument(message);
+}
@@ -0,0 +1,219 @@
+use std::fs::{create_dir_all, remove_file, rename, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime};
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use xml::reader::XmlEvent;
+
+use crate::command::log;
+use crate::dcm_xml::RequestTags;
+use crate::error::G2DError;
+use crate::worklist_entries::{list_worklist_entries, EntryFilter, WorklistEntry};
+
+/// Subfolder of an AETitle folder for removed entries
+pub const ARCHIVE_FOLDER_NAME: &str = "archive";
+/// Every expiry, completion, cancellation and edit is appended to this file
+/// in the worklist folder
+pub const LIFECYCLE_LOG_FILE_NAME: &str = "lifecycle.log";
+
+/// What happens to a worklist entry that is no longer needed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum RemovalAction {
+    /// Move the entry into the `archive` subfolder of its AETitle folder
+    #[default]
+    Archive,
+    /// Delete the entry
+    Delete,
+}
+
+/// When worklist entries are removed, every rule is optional
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorklistLifecycle {
+    /// Remove entries whose file is older than this
+    pub max_age_days: Option<u32>,
+    /// Remove entries this many days after their scheduled date (0040,0002)
+    pub days_after_scheduled: Option<u32>,
+    /// Remove entries when images of them arrive at the CStore server
+    pub complete_on_images: bool,
+    pub action: RemovalAction,
+}
+
+impl WorklistLifecycle {
+    /// Why the entry is expired, none if it isn't
+    pub fn expiry_reason(&self, entry: &WorklistEntry, now: SystemTime) -> Option<String> {
+        if let (Some(days), Some(modified)) = (self.max_age_days, entry.modified) {
+            let max_age = Duration::from_secs(days as u64 * 24 * 60 * 60);
+            if now.duration_since(modified).unwrap_or_default() > max_age {
+                return Some(format!("older than {} days", days));
+            }
+        }
+        if let (Some(days), Some(scheduled)) = (self.days_after_scheduled, entry.scheduled()) {
+            let today = chrono::DateTime::<Local>::from(now).date_naive();
+            if today > scheduled + chrono::Days::new(days as u64) {
+                return Some(format!(
+                    "scheduled for {}, more than {} days ago",
+                    scheduled, days
+                ));
+            }
+        }
+        return None;
+    }
+}
+
+/// Removes the entries which are expired by the lifecycle rules, returns the
+/// removed entries
+pub fn expire_worklist_entries(
+    worklist_dir: &Path,
+    lifecycle: &WorklistLifecycle,
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<Vec<WorklistEntry>, G2DError> {
+    if lifecycle.max_age_days.is_none() && lifecycle.days_after_scheduled.is_none() {
+        return Ok(vec![]);
+    }
+    let now = SystemTime::now();
+    let mut removed = vec![];
+    for entry in list_worklist_entries(worklist_dir, log_sender)? {
+        if let Some(reason) = lifecycle.expiry_reason(&entry, now) {
+            remove_entry(
+                worklist_dir,
+                &entry,
+                lifecycle.action,
+                &format!("expired, {}", reason),
+                log_sender,
+            )?;
+            removed.push(entry);
+        }
+    }
+    return Ok(removed);
+}
+
+/// Removes the entries the received DICOM file belongs to, does nothing
+/// unless `complete_on_images` is set. `request_tags` are those of the
+/// conversions writing into `worklist_dir`, an entry matches with any of them.
+pub fn complete_worklist_entries(
+    worklist_dir: &Path,
+    dcm_events: &Vec<XmlEvent>,
+    lifecycle: &WorklistLifecycle,
+    request_tags: &[RequestTags],
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<Vec<WorklistEntry>, G2DError> {
+    if !lifecycle.complete_on_images {
+        return Ok(vec![]);
+    }
+    let mut removed = vec![];
+    for entry in list_worklist_entries(worklist_dir, log_sender)? {
+        if request_tags
+            .iter()
+            .any(|t| entry.matches_dcm(dcm_events, t))
+        {
+            remove_entry(
+                worklist_dir,
+                &entry,
+                lifecycle.action,
+                "completed, images received",
+                log_sender,
+            )?;
+            removed.push(entry);
+        }
+    }
+    return Ok(removed);
+}
+
+/// Removes the entries matching the filter, e.g. for a cancelled appointment
+pub fn cancel_worklist_entries(
+    worklist_dir: &Path,
+    filter: &EntryFilter,
+    action: RemovalAction,
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<Vec<WorklistEntry>, G2DError> {
+    return remove_worklist_entries(worklist_dir, filter, action, "cancelled", log_sender);
+}
+
+/// Removes the entries matching the filter and logs the reason, an empty
+/// filter removes nothing
+pub fn remove_worklist_entries(
+    worklist_dir: &Path,
+    filter: &EntryFilter,
+    action: RemovalAction,
+    reason: &str,
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<Vec<WorklistEntry>, G2DError> {
+    if filter.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut removed = vec![];
+    for entry in list_worklist_entries(worklist_dir, log_sender)? {
+        if filter.matches(&entry) {
+            remove_entry(worklist_dir, &entry, action, reason, log_sender)?;
+            removed.push(entry);
+        }
+    }
+    return Ok(removed);
+}
+
+fn remove_entry(
+    worklist_dir: &Path,
+    entry: &WorklistEntry,
+    action: RemovalAction,
+    reason: &str,
+    log_sender: Option<&mpsc::Sender<String>>,
+) -> Result<(), G2DError> {
+    let message = match action {
+        RemovalAction::Delete => {
+            remove_file(&entry.path)?;
+            format!("Deleted {}: {}", entry.path.display(), reason)
+        }
+        RemovalAction::Archive => {
+            let archive_dir = entry
+                .path
+                .parent()
+                .unwrap_or(worklist_dir)
+                .join(ARCHIVE_FOLDER_NAME);
+            create_dir_all(&archive_dir)?;
+            let mut archive_path = archive_dir.join(entry.file_name());
+            let mut counter = 2;
+            while archive_path.exists() {
+                archive_path = archive_dir.join(format!(
+                    "{}_{}.wl",
+                    entry.file_name().trim_end_matches(".wl"),
+                    counter
+                ));
+                counter += 1;
+            }
+            rename(&entry.path, &archive_path)?;
+            format!(
+                "Archived {} to {}: {}",
+                entry.path.display(),
+                archive_path.display(),
+                reason
+            )
+        }
+    };
+    append_to_lifecycle_log(worklist_dir, entry, &message)?;
+    log(log_sender, message);
+    return Ok(());
+}
+
+pub(crate) fn append_to_lifecycle_log(
+    worklist_dir: &Path,
+    entry: &WorklistEntry,
+    message: &str,
+) -> Result<(), G2DError> {
+    let mut log_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(worklist_dir.join(LIFECYCLE_LOG_FILE_NAME))?;
+    writeln!(
+        log_file,
+        "{} {} (patient {}, accession number {})",
+        Local::now().format("%Y-%m-%d %H:%M:%S"),
+        message,
+        entry.patient_id,
+        entry.accession_number
+    )?;
+    return Ok(());
+}